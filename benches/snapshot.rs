@@ -0,0 +1,94 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Benchmark loading of snapshots with and without extra threads
+//! (see `Partition::set_load_threads()`).
+//! 
+//! Run with `cargo bench`.
+#![feature(test)]
+#![feature(box_syntax)]
+
+extern crate test;
+extern crate pippin;
+
+use std::io::{Read, Write, ErrorKind};
+use std::any::Any;
+
+use test::Bencher;
+
+use pippin::{PartId, Partition, PartitionIO};
+use pippin::error::{make_io_err, Result};
+
+/// In-memory snapshots; commit logs are not supported.
+struct SnapshotStreams {
+    ss: Vec<Vec<u8>>,
+}
+
+impl PartitionIO for SnapshotStreams {
+    fn as_any(&self) -> &Any { self }
+    fn ss_len(&self) -> usize { self.ss.len() }
+    fn ss_cl_len(&self, _: usize) -> usize { 0 }
+    fn read_ss<'a>(&'a self, ss_num: usize) -> Result<Option<Box<Read+'a>>> {
+        Ok(self.ss.get(ss_num).map(|data| box &data[..] as Box<Read+'a>))
+    }
+    fn read_ss_cl<'a>(&'a self, _: usize, _: usize) -> Result<Option<Box<Read+'a>>> {
+        Ok(None)
+    }
+    fn new_ss<'a>(&'a mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>> {
+        if ss_num < self.ss.len() {
+            return Ok(None);
+        }
+        self.ss.resize(ss_num + 1, Vec::new());
+        Ok(Some(box &mut self.ss[ss_num]))
+    }
+    fn append_ss_cl<'a>(&'a mut self, _: usize, _: usize) -> Result<Option<Box<Write+'a>>> {
+        Ok(None)
+    }
+    fn new_ss_cl<'a>(&'a mut self, _: usize, _: usize) -> Result<Option<Box<Write+'a>>> {
+        make_io_err(ErrorKind::InvalidInput, "commit logs not supported")
+    }
+}
+
+const PART_ID: u64 = 1;
+
+// Create a partition with a snapshot of `n` elements and return its IO
+fn make_snapshot(n: usize) -> Box<PartitionIO> {
+    let io = box SnapshotStreams { ss: Vec::new() };
+    let mut part = Partition::<String>::create_part(io, "bench", PartId::from_num(PART_ID))
+            .expect("creating partition");
+    let text = "Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do \
+        eiusmod tempor incididunt ut labore et dolore magna aliqua. ";
+    part.bulk_load((0..n).map(|i| format!("{} {}", i, text))).expect("bulk load");
+    part.unwrap_io()
+}
+
+fn bench_load(b: &mut Bencher, threads: usize) {
+    let mut io = Some(make_snapshot(20_000));
+    b.iter(|| {
+        let mut part = Partition::<String>::open(io.take().unwrap(), PartId::from_num(PART_ID));
+        part.set_load_threads(threads);
+        part.load(false).expect("load");
+        io = Some(part.unwrap_io());
+    });
+}
+
+#[bench]
+fn load_1_thread(b: &mut Bencher) {
+    bench_load(b, 1);
+}
+
+#[bench]
+fn load_2_threads(b: &mut Bencher) {
+    bench_load(b, 2);
+}
+
+#[bench]
+fn load_4_threads(b: &mut Bencher) {
+    bench_load(b, 4);
+}
+
+#[bench]
+fn load_8_threads(b: &mut Bencher) {
+    bench_load(b, 8);
+}
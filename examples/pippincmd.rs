@@ -34,7 +34,7 @@ Usage:
   pippincmd [-h] -n PREFIX [-N NAME] DIR
   pippincmd [-h] [-P] FILE...
   pippincmd [-h] [-p PART] [-S] [-C] FILE...
-  pippincmd [-h] [-f] [-p PART] [-c COMMIT] [-s] [-E | -g ELT | -e ELT | -v ELT | -d ELT] FILE...
+  pippincmd [-h] [-f] [-p PART] [-c COMMIT] [-s] [-E | -g ELT | -e ELT | -v ELT | -d ELT | -t TAG | -T] FILE...
   pippincmd --help | --version
 
 Options:
@@ -49,8 +49,11 @@ Options:
   -p --partition PART   Select partition PART
   -S --snapshots        List all snapshots loaded
   -C --commits          List all commits loaded (from snapshots and logs)
-  -c --commit COMMIT    Select commit COMMIT. If not specified, most operations
-                        on commits will use the head (i.e. the latest state).
+  -c --commit COMMIT    Select commit COMMIT (a tag name or a state-sum
+                        prefix). If not specified, most operations on commits
+                        will use the head (i.e. the latest state).
+  -T --tags             List all tags
+  -t --tag TAG          Tag the selected commit with name TAG.
   -E --elements         List all elements
   -g --get ELT          Read the contents of an element to standard output.
   -e --edit ELT         Write an element to a temporary file and invoke the
@@ -79,6 +82,8 @@ struct Args {
     flag_snapshots: bool,
     flag_commits: bool,
     flag_commit: Option<String>,
+    flag_tags: bool,
+    flag_tag: Option<String>,
     flag_elements: bool,
     flag_get: Option<String>,
     flag_edit: Option<String>,
@@ -96,6 +101,8 @@ enum PartitionOp {
     EltGet(String),
     EltEdit(String, Editor),
     EltDelete(String),
+    ListTags,
+    Tag(String),
 }
 #[derive(Debug)]
 enum Editor { Cmd, Visual }
@@ -142,6 +149,10 @@ fn main() {
                 Operation::OnPartition(PartitionOp::EltEdit(elt, Editor::Visual))
             } else if let Some(elt) = args.flag_delete {
                 Operation::OnPartition(PartitionOp::EltDelete(elt))
+            } else if args.flag_tags {
+                Operation::OnPartition(PartitionOp::ListTags)
+            } else if let Some(name) = args.flag_tag {
+                Operation::OnPartition(PartitionOp::Tag(name))
             } else {
                 Operation::Default
             };
@@ -291,6 +302,15 @@ fn inner(files: Vec<String>, op: Operation, args: Rest) -> Result<()>
                             let id: u64 = try!(elt.parse());
                             try!(state.remove(id.into()));
                         },
+                        PartitionOp::ListTags => {
+                            println!("Tags:");
+                            for (name, sum) in part.tags() {
+                                println!("  {}: {}", name, sum);
+                            }
+                        },
+                        PartitionOp::Tag(name) => {
+                            try!(part.tag(&name, state.statesum()));
+                        },
                     }
                 }       // destroy reference `state`
                 
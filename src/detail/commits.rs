@@ -6,15 +6,19 @@
 
 use std::collections::{HashSet, HashMap, hash_map};
 use std::clone::Clone;
-use std::rc::Rc;
+use std::io::Write;
+use std::sync::Arc;
+use std::slice;
 use std::u32;
 
 use hashindexed::HashIndexed;
 use chrono::{DateTime, NaiveDateTime, UTC};
+use byteorder::{BigEndian, WriteBytesExt};
 
 use detail::states::PartitionStateSumComparator;
 use detail::readwrite::CommitReceiver;
 use partition::{PartitionState, State};
+use sign::{Signature, SignatureStatus, SigningKey, TrustPolicy, Verdict};
 use {ElementT, EltId, Sum};
 use error::{Result, ReplayError, PatchOp};
 
@@ -93,6 +97,18 @@ impl<E: ElementT> CommitQueue<E> {
     pub fn len(&self) -> usize {
         self.commits.len()
     }
+    /// Iterate over commits, in order
+    pub fn iter(&self) -> slice::Iter<Commit<E>> {
+        self.commits.iter()
+    }
+    /// Take the commits, in order
+    pub fn into_commits(self) -> Vec<Commit<E>> {
+        self.commits
+    }
+    /// Keep only commits for which `f` returns true, in order
+    pub fn retain<F: FnMut(&Commit<E>) -> bool>(&mut self, f: F) {
+        self.commits.retain(f)
+    }
 }
 
 impl<E: ElementT> CommitReceiver<E> for CommitQueue<E> {
@@ -119,6 +135,8 @@ pub struct Commit<E: ElementT> {
     changes: HashMap<EltId, EltChange<E>>,
     /// Meta-data
     meta: CommitMeta,
+    /// Signature, if signed
+    signature: Option<Signature>,
 }
 
 /// Per-element changes
@@ -127,21 +145,22 @@ pub enum EltChange<E: ElementT> {
     /// Element was deleted
     Deletion,
     /// Element was added (full data)
-    Insertion(Rc<E>),
+    Insertion(Arc<E>),
     /// Element was replaced (full data)
-    Replacement(Rc<E>),
+    Replacement(Arc<E>),
     /// Element has been moved, must be removed from this partition; new identity mentioned
     MovedOut(EltId),
-    /// Same as `MovedOut` except that the element has already been removed from the partition
+    /// Same as `MovedOut` except that the element has already been removed from the partition.
+    /// If the new identity is the element's own, any note is removed instead.
     Moved(EltId),
 }
 impl<E: ElementT> EltChange<E> {
     /// Create an `Insertion`
-    pub fn insertion(elt: Rc<E>) -> EltChange<E> {
+    pub fn insertion(elt: Arc<E>) -> EltChange<E> {
         EltChange::Insertion(elt)
     }
     /// Create a `Replacement`
-    pub fn replacement(elt: Rc<E>) -> EltChange<E> {
+    pub fn replacement(elt: Arc<E>) -> EltChange<E> {
         EltChange::Replacement(elt)
     }
     /// Create a `Deletion`
@@ -159,7 +178,7 @@ impl<E: ElementT> EltChange<E> {
         }
     }
     /// Get `Some(elt)` if an element is contained, `None` otherwise
-    pub fn element(&self) -> Option<&Rc<E>> {
+    pub fn element(&self) -> Option<&Arc<E>> {
         match self {
             &EltChange::Deletion => None,
             &EltChange::Insertion(ref elt) => Some(elt),
@@ -193,15 +212,14 @@ impl<E: ElementT> Commit<E> {
     {
         assert!(parents.len() >= 1 && parents.len() < 0x100);
         Commit { statesum: statesum, parents: parents, changes: changes,
-                meta: meta }
+                meta: meta, signature: None }
     }
     
     /// Create a commit from an old state and a new state. Return the commit if
     /// there are any differences or None if the states are identical.
     /// 
-    /// This is one of two ways to create a commit; the other would be to track
-    /// changes to a state (possibly the latter is the more sensible approach
-    /// for most applications).
+    /// This compares all elements of both states; when the new state was
+    /// created via `old_state.clone_child()`, `from_state()` is faster.
     pub fn from_diff(old_state: &PartitionState<E>, new_state: &PartitionState<E>) -> Option<Commit<E>> {
         let mut state = new_state.clone_exact();
         let mut changes = HashMap::new();
@@ -229,16 +247,19 @@ impl<E: ElementT> Commit<E> {
                     changes.insert(*id, EltChange::moved(new_id2, false));
                 }
             } else {
-                // we seem to have forgotten that an element was moved
-                // TODO: why? Should we track this so patches can make states *forget*?
-                // TODO: should we warn about it?
+                // The note was removed (e.g. reverting an alias); a note to
+                // the identifier itself removes it
+                changes.insert(*id, EltChange::moved(*id, false));
             }
         }
         for (id, new_elt) in elt_map {
             changes.insert(id, EltChange::insertion(new_elt));
         }
         for (id, new_id) in moved_map {
-            changes.insert(id, EltChange::moved(new_id, true));
+            // New notes may be aliases of elements moved here, not elements
+            // moved out
+            let removed = old_state.map().contains_key(&id) && !new_state.map().contains_key(&id);
+            changes.insert(id, EltChange::moved(new_id, removed));
         }
         
         if changes.is_empty() {
@@ -249,11 +270,69 @@ impl<E: ElementT> Commit<E> {
                 parents: vec![old_state.statesum().clone()],
                 changes: changes,
                 meta: CommitMeta::new_from(old_state.meta().number, None),
+                signature: None,
             })
         }
     }
     
-    /// Apply this commit to a given state, thus updating the state.
+    /// Create a commit from a parent state and a child state whose changes
+    /// were tracked (see `PartitionState::clone_child()`). Return the commit
+    /// if there are any differences or None if the states are identical.
+    /// 
+    /// This only examines elements which were changed, so is usually much
+    /// faster than `from_diff()`, which it falls back to when `new_state` does
+    /// not track changes or is not a child of `old_state`.
+    pub fn from_state(old_state: &PartitionState<E>, new_state: &PartitionState<E>) -> Option<Commit<E>> {
+        let changed = match new_state.changed_ids() {
+            Some(ids) if new_state.parents().first() == Some(old_state.statesum()) => ids,
+            _ => { return Commit::from_diff(old_state, new_state); }
+        };
+        
+        let mut changes = HashMap::new();
+        for id in changed {
+            let old_elt = old_state.map().get(id);
+            let new_elt = new_state.map().get(id);
+            match (old_elt, new_elt) {
+                (Some(old_elt), Some(new_elt)) => {
+                    if new_elt != old_elt {
+                        changes.insert(*id, EltChange::replacement(new_elt.clone()));
+                    }
+                },
+                (Some(_), None) => {
+                    changes.insert(*id, EltChange::deletion());
+                },
+                (None, Some(new_elt)) => {
+                    changes.insert(*id, EltChange::insertion(new_elt.clone()));
+                },
+                (None, None) => {},
+            }
+            match (old_state.is_moved(*id), new_state.is_moved(*id)) {
+                (old_note, Some(new_id)) if old_note != Some(new_id) => {
+                    let removed = old_elt.is_some() && new_elt.is_none();
+                    changes.insert(*id, EltChange::moved(new_id, removed));
+                },
+                (Some(_), None) if !changes.contains_key(id) => {
+                    changes.insert(*id, EltChange::moved(*id, false));
+                },
+                _ => {},
+            }
+        }
+        
+        if changes.is_empty() {
+            None
+        } else {
+            Some(Commit {
+                statesum: new_state.statesum().clone(),
+                parents: vec![old_state.statesum().clone()],
+                changes: changes,
+                meta: CommitMeta::new_from(old_state.meta().number, None),
+                signature: None,
+            })
+        }
+    }
+    
+    /// Apply this commit to a given state, thus updating the state. The
+    /// state's meta-data is set to that of the commit.
     /// 
     /// Fails if the given state's initial state-sum is not equal to this
     /// commit's parent or if there are any errors in applying this patch.
@@ -272,12 +351,16 @@ impl<E: ElementT> Commit<E> {
                     try!(state.insert_with_id(*id, elt.clone()));
                 }
                 &EltChange::Replacement(ref elt) => {
-                    try!(state.replace_rc(*id, elt.clone()));
+                    try!(state.replace_arc(*id, elt.clone()));
                 }
                 &EltChange::MovedOut(new_id) => {
                     try!(state.remove(*id));
                     state.set_move(*id, new_id);
                 }
+                &EltChange::Moved(new_id) if new_id == *id => {
+                    // A note to the identifier itself removes the note
+                    state.remove_move(*id);
+                }
                 &EltChange::Moved(new_id) => {
                     state.set_move(*id, new_id);
                 }
@@ -285,6 +368,7 @@ impl<E: ElementT> Commit<E> {
         }
         
         if state.statesum() != self.statesum() { return Err(PatchOp::PatchApply); }
+        state.set_meta(self.meta.clone());
         Ok(())
     }
     
@@ -293,14 +377,93 @@ impl<E: ElementT> Commit<E> {
     /// Get the parents. There must be at least one. The first is the primary,
     /// which can be patched by this commit.
     pub fn parents(&self) -> &Vec<Sum> { &self.parents }
+    /// Replace the parents. This removes any signature.
+    /// 
+    /// This panics if the first parent differs from the current one (the
+    /// changes are relative to it) or if parents.len() >= 256.
+    pub fn set_parents(&mut self, parents: Vec<Sum>) {
+        assert!(parents.first() == self.parents.first() && parents.len() < 0x100);
+        self.signature = None;
+        self.parents = parents;
+    }
     /// Get the number of changes in the "patch"
     pub fn num_changes(&self) -> usize { self.changes.len() }
     /// Get an iterator over changes
     pub fn changes_iter(&self) -> hash_map::Iter<EltId, EltChange<E>> { self.changes.iter() }
     /// Access the commit's meta-data
     pub fn meta(&self) -> &CommitMeta { &self.meta }
-    /// Write acces to the commit's meta-data
-    pub fn meta_mut(&mut self) -> &mut CommitMeta { &mut self.meta }
+    /// Write acces to the commit's meta-data. This removes any signature
+    /// (which covers the meta-data).
+    pub fn meta_mut(&mut self) -> &mut CommitMeta {
+        self.signature = None;
+        &mut self.meta
+    }
+    
+    /// Get the signature, if signed
+    pub fn signature(&self) -> Option<&Signature> { self.signature.as_ref() }
+    /// Set or remove the signature. Normally `sign()` should be used instead.
+    pub fn set_signature(&mut self, signature: Option<Signature>) {
+        self.signature = signature;
+    }
+    /// Sign the commit, replacing any existing signature
+    pub fn sign(&mut self, key: &SigningKey) {
+        self.signature = Some(key.sign(&self.signed_data()));
+    }
+    /// Check the signature against the commit's contents
+    pub fn signature_status(&self) -> SignatureStatus {
+        match self.signature {
+            None => SignatureStatus::Unsigned,
+            Some(ref sig) if sig.verify(&self.signed_data()) =>
+                SignatureStatus::Valid(sig.public_key()),
+            Some(_) => SignatureStatus::Invalid,
+        }
+    }
+    
+    // The data covered by a signature: state-sum, parents, meta-data and
+    // changes (in order of identifier, with element sums).
+    fn signed_data(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(b"PIPPIN COMMIT\x00\x00\x00");
+        // Writing to a Vec does not fail
+        self.statesum.write(&mut data).expect("writing to Vec");
+        for parent in &self.parents {
+            parent.write(&mut data).expect("writing to Vec");
+        }
+        data.write_u32::<BigEndian>(self.meta.number).expect("writing to Vec");
+        data.write_i64::<BigEndian>(self.meta.timestamp).expect("writing to Vec");
+        if let Some(ref txt) = self.meta.extra {
+            data.write_u64::<BigEndian>(txt.len() as u64 + 1).expect("writing to Vec");
+            data.extend_from_slice(txt.as_bytes());
+        } else {
+            data.write_u64::<BigEndian>(0).expect("writing to Vec");
+        }
+        
+        let mut ids: Vec<&EltId> = self.changes.keys().collect();
+        ids.sort();
+        for id in ids {
+            data.write_u64::<BigEndian>((*id).into()).expect("writing to Vec");
+            match self.changes[id] {
+                EltChange::Deletion => data.push(b'D'),
+                EltChange::Insertion(ref elt) => {
+                    data.push(b'I');
+                    elt.sum().write(&mut data).expect("writing to Vec");
+                },
+                EltChange::Replacement(ref elt) => {
+                    data.push(b'R');
+                    elt.sum().write(&mut data).expect("writing to Vec");
+                },
+                EltChange::MovedOut(new_id) => {
+                    data.push(b'O');
+                    data.write_u64::<BigEndian>(new_id.into()).expect("writing to Vec");
+                },
+                EltChange::Moved(new_id) => {
+                    data.push(b'M');
+                    data.write_u64::<BigEndian>(new_id.into()).expect("writing to Vec");
+                },
+            }
+        }
+        data
+    }
 }
 
 
@@ -315,53 +478,143 @@ pub type StatesSet<E> = HashIndexed<PartitionState<E>, Sum, PartitionStateSumCom
 /// but could be modified.
 pub struct LogReplay<'a, E: ElementT+'a> {
     states: &'a mut StatesSet<E>,
-    tips: &'a mut HashSet<Sum>
+    tips: &'a mut HashSet<Sum>,
+    verify: bool,
+    trust: Option<Arc<TrustPolicy>>,
+    // States removed by compaction
+    removed: HashSet<Sum>,
+    quarantine: Vec<Commit<E>>,
+    // Sums of all quarantined commits, including those passed in
+    quarantined: HashSet<Sum>,
 }
 
 impl<'a, E: ElementT> LogReplay<'a, E> {
     /// Create the structure, binding to two sets. These may be empty; in this
     /// case call `add_state()` to add an initial state.
     pub fn from_sets(states: &'a mut StatesSet<E>, tips: &'a mut HashSet<Sum>) -> LogReplay<'a, E> {
-        LogReplay { states: states, tips: tips }
+        LogReplay { states: states, tips: tips, verify: false, trust: None,
+                removed: HashSet::new(), quarantine: Vec::new(),
+                quarantined: HashSet::new() }
+    }
+    
+    /// Enable or disable verification. When enabled, states recreated from
+    /// commits are compared with any known state with the same sum, and
+    /// replay fails with `PatchOp::Collision` if they differ.
+    pub fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+    }
+    
+    /// Set a policy used to judge commit signatures (see `sign`). Without a
+    /// policy, signatures are not checked.
+    /// 
+    /// Commits with verdict `Reject` cause replay to fail. Commits with
+    /// verdict `Quarantine` are not replayed but kept; see
+    /// `take_quarantined()`.
+    pub fn set_trust_policy(&mut self, trust: Option<Arc<TrustPolicy>>) {
+        self.trust = trust;
+    }
+    
+    /// Add the sums of states removed by compaction (see
+    /// `Partition::compact()`). Commits whose parent state is not known but
+    /// is one of these are quarantined instead of causing replay to fail.
+    /// Commits whose own state is already known are skipped in any case.
+    pub fn add_removed_sums<'b, I: IntoIterator<Item = &'b Sum>>(&mut self, sums: I) {
+        self.removed.extend(sums.into_iter().cloned());
+    }
+    
+    /// Add the sums of commits quarantined earlier (e.g. while reading
+    /// another log). Commits whose parent is quarantined (and not otherwise
+    /// known) are quarantined too, with or without a trust policy.
+    pub fn add_quarantined_sums<'b, I: IntoIterator<Item = &'b Sum>>(&mut self, sums: I) {
+        self.quarantined.extend(sums.into_iter().cloned());
+    }
+    
+    /// Take the commits quarantined so far
+    pub fn take_quarantined(&mut self) -> Vec<Commit<E>> {
+        ::std::mem::replace(&mut self.quarantine, Vec::new())
     }
     
     /// Recreate all known states from a set of commits. On success, return the
     /// number of edits (insertions, deletions or replacements).
     /// 
     /// Will fail if a commit applies to an unknown state or
-    /// any checksum is incorrect.
+    /// any checksum is incorrect, or (when verifying) on a collision.
     pub fn replay(&mut self, commits: CommitQueue<E>) -> Result<usize> {
         let mut edits = 0;
         for commit in commits.commits {
-            let mut state = try!(self.states.get(&commit.parents()[0])
-                .ok_or(ReplayError::new("parent state of commit not found")))
-                .clone_child();
-            if self.states.contains(&commit.statesum) {
-                // #0022: could verify that this state matches that derived from
-                // the commit and warn if not.
+            let parent_quarantined = {
+                let quarantined = &self.quarantined;
+                let states = &*self.states;
+                commit.parents().iter().any(|p|
+                    !states.contains(p) && quarantined.contains(p))
+            };
+            if parent_quarantined {
+                warn!("Quarantining commit {} (parent quarantined)", commit.statesum());
+                self.quarantined.insert(commit.statesum().clone());
+                self.quarantine.push(commit);
+                continue;
+            }
+            if let Some(ref trust) = self.trust {
+                match trust.judge(&commit.signature_status()) {
+                    Verdict::Accept => {},
+                    Verdict::Quarantine => {
+                        warn!("Quarantining commit {} (untrusted signature)", commit.statesum());
+                        self.quarantined.insert(commit.statesum().clone());
+                        self.quarantine.push(commit);
+                        continue;
+                    },
+                    Verdict::Reject => {
+                        return ReplayError::err("commit rejected by trust policy");
+                    },
+                }
+            }
+            
+            let mut state = match self.states.get(&commit.parents()[0]) {
+                Some(parent) => parent.clone_child(),
+                None => {
+                    if self.states.contains(&commit.statesum) {
+                        // Parent unknown (e.g. removed by compaction), but
+                        // the result is known already
+                        continue;
+                    }
+                    if self.removed.contains(&commit.parents()[0]) {
+                        warn!("Quarantining commit {} (parent state removed by compaction)",
+                            commit.statesum());
+                        self.quarantined.insert(commit.statesum().clone());
+                        self.quarantine.push(commit);
+                        continue;
+                    }
+                    return ReplayError::err("parent state of commit not found");
+                }
+            };
+            if let Some(existing) = self.states.get(&commit.statesum) {
+                if self.verify {
+                    try!(commit.patch(&mut state));
+                    if !existing.eq_elements(&state) {
+                        return Err(box PatchOp::Collision);
+                    }
+                }
                 
                 // Since the state is already known, it either is already
-                // marked a tip or it has been unmarked. Do not set again!
+                // marked a tip or it has been unmarked. Do not set again,
+                // unless it replaces a parent which is a tip of this set (the
+                // state may be known from another branch, or the commit may
+                // be a fast-forward merge).
                 // However, we now know that the parent states aren't tips, which
                 // might not have been known before (if new state is a snapshot).
+                let mut parent_was_tip = false;
                 for parent in commit.parents() {
-                    self.tips.remove(parent);
+                    parent_was_tip |= self.tips.remove(parent);
+                }
+                if parent_was_tip {
+                    self.tips.insert(commit.statesum.clone());
                 }
                 continue;
             }
             
             try!(commit.patch(&mut state));
             
-            let has_existing = if let Some(existing) = self.states.get(&state.statesum()) {
-                if *existing != state {
-                    // Collision. We can't do much in this case, so just warn about it.
-                    // #0017: warn about collision
-                }
-                true
-            } else { false };
-            if !has_existing {
-                self.states.insert(state);
-            }
+            self.states.insert(state);
             
             for parent in commit.parents() {
                 self.tips.remove(parent);
@@ -399,13 +652,13 @@ mod tests {
     #[test]
     fn commit_creation_and_replay(){
         use {PartId, State};
-        use std::rc::Rc;
+        use std::sync::Arc;
         
         let p = PartId::from_num(1);
         let mut commits = CommitQueue::<String>::new();
         
         let insert = |state: &mut PartitionState<_>, num, string: &str| -> Result<_, _> {
-            state.insert_with_id(p.elt_id(num), Rc::new(string.to_string()))
+            state.insert_with_id(p.elt_id(num), Arc::new(string.to_string()))
         };
         
         let mut state_a = PartitionState::new(p);
@@ -441,4 +694,62 @@ mod tests {
         let replayed_state = states.remove(&tip_sum).unwrap();
         assert_eq!(replayed_state, state_d);
     }
+    
+    #[test]
+    fn commit_from_tracked_changes() {
+        use {PartId, State};
+        use std::sync::Arc;
+        
+        let p = PartId::from_num(1);
+        let insert = |state: &mut PartitionState<_>, num, string: &str| -> Result<_, _> {
+            state.insert_with_id(p.elt_id(num), Arc::new(string.to_string()))
+        };
+        // Compare commits ignoring meta-data
+        fn changes<E: ElementT>(c: Option<&Commit<E>>) -> Option<(&Sum, &Vec<Sum>, &HashMap<EltId, EltChange<E>>)> {
+            c.map(|c| (&c.statesum, &c.parents, &c.changes))
+        }
+        
+        let mut parent = PartitionState::new(p);
+        for num in 1..6 {
+            insert(&mut parent, num, &format!("element {}", num)).unwrap();
+        }
+        
+        let mut tracked = parent.clone_child();
+        insert(&mut tracked, 6, "six").unwrap();
+        tracked.replace(p.elt_id(1), "ONE".to_string()).unwrap();
+        tracked.remove(p.elt_id(2)).unwrap();
+        tracked.replace(p.elt_id(3), "element 3".to_string()).unwrap();
+        insert(&mut tracked, 7, "seven").unwrap();
+        tracked.remove(p.elt_id(7)).unwrap();
+        tracked.remove(p.elt_id(4)).unwrap();
+        tracked.set_move(p.elt_id(4), PartId::from_num(2).elt_id(4));
+        assert!(tracked.changed_ids().is_some());
+        let expected = Commit::from_diff(&parent, &tracked).unwrap();
+        assert_eq!(expected.num_changes(), 4);
+        assert_eq!(changes(Commit::from_state(&parent, &tracked).as_ref()), changes(Some(&expected)));
+        
+        // The same state built without tracking gives the same commit
+        let meta = CommitMeta::new_from(parent.meta().number, None);
+        let mut untracked = PartitionState::new_with(p, vec![parent.statesum().clone()], meta);
+        for (id, elt) in tracked.map() {
+            untracked.insert_with_id(*id, elt.clone()).unwrap();
+        }
+        for (id, new_id) in tracked.moved_map() {
+            untracked.set_move(*id, *new_id);
+        }
+        assert!(untracked.changed_ids().is_none());
+        assert_eq!(changes(Commit::from_state(&parent, &untracked).as_ref()), changes(Some(&expected)));
+        
+        // Tracked changes relative to another state are not used
+        let mut grandchild = tracked.clone_child();
+        insert(&mut grandchild, 8, "eight").unwrap();
+        assert_eq!(changes(Commit::from_state(&parent, &grandchild).as_ref()),
+                changes(Commit::from_diff(&parent, &grandchild).as_ref()));
+        
+        // Changes which cancel out give no commit
+        let mut unchanged = parent.clone_child();
+        insert(&mut unchanged, 9, "nine").unwrap();
+        unchanged.remove(p.elt_id(9)).unwrap();
+        assert!(Commit::from_state(&parent, &unchanged).is_none());
+    }
 }
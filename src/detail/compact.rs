@@ -0,0 +1,205 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! History compaction and garbage collection
+//!
+//! `Partition::compact()` rewrites history, keeping only some historical
+//! states; a `CompactSchedule` decides which. Kept states are unchanged (so
+//! have the same state-sums) and are linked by new commits to their nearest
+//! kept ancestors.
+//!
+//! The choice only depends on the states' time-stamps and sums and on the
+//! reference time (by default the latest time-stamp of the states, not the
+//! clock), so nodes compacting the same history keep the same states, and
+//! compacting in several stages keeps the same states as compacting once.
+//!
+//! `Partition::gc()` removes states which are not reachable from any tip or
+//! tag, reporting what was removed in a `GcReport`; `Partition::gc_report()`
+//! gives the same report without removing anything.
+
+use std::collections::{HashMap, HashSet};
+
+use Sum;
+use error::{Result, ArgError};
+
+/// Seconds in a day
+pub const DAY: i64 = 24 * 3600;
+/// Seconds in a week
+pub const WEEK: i64 = 7 * DAY;
+
+/// Rules choosing which historical states survive compaction.
+/// 
+/// Each rule is a pair `(age, period)` (both in seconds). Time is divided
+/// into periods of length `period`, counted from the UNIX epoch; when the end
+/// of a period is at least `age` before the reference time (see `at()`), only
+/// the latest state in that period (by time-stamp, then state-sum) is kept.
+/// Where several rules apply, the one with the longest period is used. States
+/// not covered by any rule are all kept.
+/// 
+/// Periods must increase with age and each must be a multiple of the
+/// previous one, so that periods only ever merge into longer ones.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct CompactSchedule {
+    // (age, period) pairs, ordered by age
+    rules: Vec<(i64, i64)>,
+    // Reference time, if not the latest time-stamp
+    now: Option<i64>,
+}
+
+impl CompactSchedule {
+    /// Create from `(age, period)` rules (in any order).
+    /// 
+    /// Fails if an age is negative, a period is not positive, or periods are
+    /// not increasing multiples of one another (ordered by age).
+    pub fn new(mut rules: Vec<(i64, i64)>) -> Result<CompactSchedule> {
+        rules.sort();
+        let mut prev: Option<(i64, i64)> = None;
+        for &(age, period) in &rules {
+            if age < 0 || period <= 0 {
+                return ArgError::err("compaction ages must not be negative and periods must be positive");
+            }
+            if let Some((prev_age, prev_period)) = prev {
+                if age == prev_age || period <= prev_period || period % prev_period != 0 {
+                    return ArgError::err("compaction periods must be increasing multiples of each other");
+                }
+            }
+            prev = Some((age, period));
+        }
+        Ok(CompactSchedule { rules: rules, now: None })
+    }
+    
+    /// A schedule keeping all states from the last day, daily states for four
+    /// weeks, weekly states for a year (52 weeks) and four-weekly states after
+    /// that.
+    pub fn standard() -> CompactSchedule {
+        CompactSchedule::new(vec![(DAY, DAY), (4 * WEEK, WEEK), (52 * WEEK, 4 * WEEK)])
+                .expect("valid schedule")
+    }
+    
+    /// Use a fixed reference time (a UNIX time-stamp). By default the latest
+    /// time-stamp of the states considered is used; the current time is
+    /// never used, since nodes would then choose differently.
+    pub fn at(mut self, now: i64) -> CompactSchedule {
+        self.now = Some(now);
+        self
+    }
+    
+    /// Get the rules, ordered by age
+    pub fn rules(&self) -> &[(i64, i64)] {
+        &self.rules
+    }
+    
+    /// Get the period applying to a state with the given time-stamp at time
+    /// `now`, or `None` if the state should be kept.
+    pub fn period_of(&self, timestamp: i64, now: i64) -> Option<i64> {
+        // Periods increase with age, so check the longest first
+        self.rules.iter().rev().find(|&&(age, period)| {
+            let end = (div_floor(timestamp, period) + 1) * period;
+            now - end >= age
+        }).map(|&(_, period)| period)
+    }
+    
+    /// Choose the states to keep from `(state-sum, time-stamp)` pairs.
+    pub fn select<'a, I>(&self, states: I) -> HashSet<Sum>
+        where I: IntoIterator<Item = (&'a Sum, i64)>
+    {
+        let states: Vec<(&'a Sum, i64)> = states.into_iter().collect();
+        let now = match self.now {
+            Some(now) => now,
+            None => states.iter().map(|&(_, timestamp)| timestamp).max().unwrap_or(0),
+        };
+        let mut keep = HashSet::new();
+        // Latest state found in each (period, period number)
+        let mut latest: HashMap<(i64, i64), (i64, &'a Sum)> = HashMap::new();
+        for (sum, timestamp) in states {
+            let period = match self.period_of(timestamp, now) {
+                Some(period) => period,
+                None => {
+                    keep.insert(sum.clone());
+                    continue;
+                }
+            };
+            let entry = latest.entry((period, div_floor(timestamp, period)))
+                    .or_insert((timestamp, sum));
+            if (timestamp, sum) > *entry {
+                *entry = (timestamp, sum);
+            }
+        }
+        keep.extend(latest.values().map(|&(_, sum)| sum.clone()));
+        keep
+    }
+}
+
+/// What garbage collection removes (or would remove); see `Partition::gc()`.
+#[derive(Clone, PartialEq, Eq, Default, Debug)]
+pub struct GcReport {
+    /// Unreachable states, ordered by commit number
+    pub states: Vec<Sum>,
+    /// Log files holding only unreachable commits, as (snapshot number, log
+    /// number); these are deleted
+    pub deleted_logs: Vec<(usize, usize)>,
+    /// Log files holding both unreachable and other commits; these are
+    /// rewritten without the unreachable commits
+    pub rewritten_logs: Vec<(usize, usize)>,
+}
+
+impl GcReport {
+    /// True if there is nothing to collect
+    pub fn is_empty(&self) -> bool {
+        self.states.is_empty() && self.deleted_logs.is_empty() && self.rewritten_logs.is_empty()
+    }
+}
+
+// Division rounding towards negative infinity (`b` must be positive)
+fn div_floor(a: i64, b: i64) -> i64 {
+    if a >= 0 { a / b } else { -((-a + b - 1) / b) }
+}
+
+
+#[test]
+fn schedule_rules() {
+    assert!(CompactSchedule::new(vec![(DAY, DAY), (WEEK, 36 * 3600)]).is_err());
+    assert!(CompactSchedule::new(vec![(DAY, DAY), (WEEK, DAY)]).is_err());
+    assert!(CompactSchedule::new(vec![(DAY, 0)]).is_err());
+    let s = CompactSchedule::new(vec![(WEEK, WEEK), (0, DAY)]).unwrap();
+    assert_eq!(s.rules(), &[(0, DAY), (WEEK, WEEK)]);
+    
+    assert_eq!(div_floor(-1, DAY), -1);
+    assert_eq!(s.period_of(10, DAY - 1), None);
+    assert_eq!(s.period_of(10, DAY), Some(DAY));
+    assert_eq!(s.period_of(10, 2 * WEEK), Some(WEEK));
+}
+
+#[test]
+fn schedule_stages() {
+    // Compacting in stages keeps the same states as compacting once
+    let sums: Vec<Sum> = (0..200u8).map(|i| Sum::load(&[i; 32])).collect();
+    let times: Vec<i64> = (0..200).map(|i| 1000_000 + i * 7919).collect();
+    let s = CompactSchedule::new(vec![(3600, 3600), (4 * 3600, 2 * 3600), (DAY, DAY)]).unwrap();
+    let end = 1000_000 + 200 * 7919;
+    
+    let once = s.clone().at(end + DAY).select(sums.iter().zip(times.iter().cloned()));
+    let mut staged: HashSet<Sum> = sums.iter().cloned().collect();
+    for now in (0..20).map(|i| end - 3 * DAY + i * 9000) {
+        staged = s.clone().at(now).select(sums.iter().zip(times.iter().cloned())
+                .filter(|&(sum, _)| staged.contains(sum)));
+    }
+    staged = s.at(end + DAY).select(sums.iter().zip(times.iter().cloned())
+            .filter(|&(sum, _)| staged.contains(sum)));
+    assert!(once.len() < 50);
+    assert_eq!(once, staged);
+}
+
+#[test]
+fn schedule_default_time() {
+    // Without `at()` the latest time-stamp is the reference time, whatever
+    // the clock says
+    let sums: Vec<Sum> = (0..100u8).map(|i| Sum::load(&[i; 32])).collect();
+    let times: Vec<i64> = (0..100).map(|i| 1000_000 + i * 3600).collect();
+    let s = CompactSchedule::new(vec![(DAY, DAY)]).unwrap();
+    let latest = 1000_000 + 99 * 3600;
+    let keep = s.select(sums.iter().zip(times.iter().cloned()));
+    assert_eq!(keep, s.clone().at(latest).select(sums.iter().zip(times.iter().cloned())));
+    assert!(keep.len() > 24 && keep.len() < 100);
+}
@@ -0,0 +1,177 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Differences between states
+//!
+//! A `StateDiff` lists the elements which differ between two states of a
+//! partition, giving access to both old and new versions. Unlike a commit, a
+//! diff is not intended to be applied to a state; it is intended for display
+//! and auditing.
+
+use std::collections::HashMap;
+use std::collections::hash_map::Iter;
+use std::sync::Arc;
+
+use partition::PartitionState;
+use {ElementT, EltId, Sum};
+
+/// The differences between two states of a partition.
+///
+/// The two states need not be related (though they normally are, and should
+/// be from the same partition for the result to be meaningful). Creation is
+/// `O(A + B)` where `A` and `B` are the numbers of elements in each state.
+#[derive(PartialEq, Debug)]
+pub struct StateDiff<E: ElementT> {
+    old_sum: Sum,
+    new_sum: Sum,
+    // Elements in the new state only
+    inserted: HashMap<EltId, Arc<E>>,
+    // Elements in the old state only
+    deleted: HashMap<EltId, Arc<E>>,
+    // Elements in both states which differ; old then new version
+    replaced: HashMap<EltId, (Arc<E>, Arc<E>)>,
+    // Notes on moved elements which are new or changed in the new state
+    moved: HashMap<EltId, EltId>,
+    // Notes on moved elements in the old state only
+    removed_moves: HashMap<EltId, EltId>,
+}
+
+impl<E: ElementT> StateDiff<E> {
+    /// Compare two states. The result lists changes needed to get from `old`
+    /// to `new`.
+    pub fn between(old: &PartitionState<E>, new: &PartitionState<E>) -> StateDiff<E> {
+        let mut inserted = new.map().clone();
+        let mut deleted = HashMap::new();
+        let mut replaced = HashMap::new();
+        for (id, old_elt) in old.map() {
+            match inserted.remove(id) {
+                Some(new_elt) => {
+                    if new_elt != *old_elt {
+                        replaced.insert(*id, (old_elt.clone(), new_elt));
+                    }
+                },
+                None => {
+                    deleted.insert(*id, old_elt.clone());
+                }
+            }
+        }
+
+        let mut moved = HashMap::new();
+        for (id, new_id) in new.moved_map() {
+            if old.is_moved(*id) != Some(*new_id) {
+                moved.insert(*id, *new_id);
+            }
+        }
+        let mut removed_moves = HashMap::new();
+        for (id, new_id) in old.moved_map() {
+            if new.is_moved(*id).is_none() {
+                removed_moves.insert(*id, *new_id);
+            }
+        }
+
+        StateDiff {
+            old_sum: old.statesum().clone(),
+            new_sum: new.statesum().clone(),
+            inserted: inserted,
+            deleted: deleted,
+            replaced: replaced,
+            moved: moved,
+            removed_moves: removed_moves,
+        }
+    }
+
+    /// Get the state-sum of the old state
+    pub fn old_statesum(&self) -> &Sum { &self.old_sum }
+    /// Get the state-sum of the new state
+    pub fn new_statesum(&self) -> &Sum { &self.new_sum }
+
+    /// Iterate over elements present in the new state but not the old
+    pub fn inserted(&self) -> Iter<EltId, Arc<E>> { self.inserted.iter() }
+    /// Iterate over elements present in the old state but not the new
+    pub fn deleted(&self) -> Iter<EltId, Arc<E>> { self.deleted.iter() }
+    /// Iterate over elements present in both states but with different
+    /// values. Values are given as a pair: `(old, new)`.
+    pub fn replaced(&self) -> Iter<EltId, (Arc<E>, Arc<E>)> { self.replaced.iter() }
+    /// Iterate over move notes (old identifier, new identifier) which are
+    /// present in the new state but not the old (including those whose new
+    /// identifier changed).
+    pub fn moved(&self) -> Iter<EltId, EltId> { self.moved.iter() }
+    /// Iterate over move notes (old identifier, new identifier) which are
+    /// present in the old state but not the new.
+    pub fn removed_moves(&self) -> Iter<EltId, EltId> { self.removed_moves.iter() }
+
+    /// Get the old version of an element, if it was deleted or replaced.
+    pub fn old_value(&self, id: EltId) -> Option<&Arc<E>> {
+        self.deleted.get(&id).or_else(|| self.replaced.get(&id).map(|v| &v.0))
+    }
+    /// Get the new version of an element, if it was inserted or replaced.
+    pub fn new_value(&self, id: EltId) -> Option<&Arc<E>> {
+        self.inserted.get(&id).or_else(|| self.replaced.get(&id).map(|v| &v.1))
+    }
+
+    /// Number of elements inserted
+    pub fn num_inserted(&self) -> usize { self.inserted.len() }
+    /// Number of elements deleted
+    pub fn num_deleted(&self) -> usize { self.deleted.len() }
+    /// Number of elements replaced
+    pub fn num_replaced(&self) -> usize { self.replaced.len() }
+    /// Number of new or changed move notes
+    pub fn num_moved(&self) -> usize { self.moved.len() }
+    /// Number of removed move notes
+    pub fn num_removed_moves(&self) -> usize { self.removed_moves.len() }
+    /// Total number of changes (sum of the above)
+    pub fn num_changes(&self) -> usize {
+        self.inserted.len() + self.deleted.len() + self.replaced.len() + self.moved.len() +
+            self.removed_moves.len()
+    }
+    /// True if there are no differences (other than possibly parents and
+    /// meta-data).
+    pub fn is_empty(&self) -> bool { self.num_changes() == 0 }
+}
+
+#[test]
+fn state_diff() {
+    use {PartId, State};
+
+    let p = PartId::from_num(3);
+    let mut old = PartitionState::<String>::new(p);
+    let e1 = old.insert("one".to_string()).unwrap();
+    let e2 = old.insert("two".to_string()).unwrap();
+    let e3 = old.insert("three".to_string()).unwrap();
+
+    let mut new = old.clone_child();
+    assert!(StateDiff::between(&old, &new).is_empty());
+    new.remove(e1).unwrap();
+    new.replace(e2, "TWO".to_string()).unwrap();
+    new.replace(e3, "three".to_string()).unwrap();    // same value
+    let e4 = new.insert("four".to_string()).unwrap();
+    let moved_to = PartId::from_num(4).elt_id(1);
+    new.set_move(e1, moved_to);
+
+    let diff = StateDiff::between(&old, &new);
+    assert_eq!(diff.old_statesum(), old.statesum());
+    assert_eq!(diff.new_statesum(), new.statesum());
+    assert_eq!((diff.num_inserted(), diff.num_deleted(), diff.num_replaced(), diff.num_moved()),
+            (1, 1, 1, 1));
+    assert_eq!(diff.num_changes(), 4);
+    assert_eq!(diff.new_value(e4), Some(&Arc::new("four".to_string())));
+    assert_eq!(diff.old_value(e1), Some(&Arc::new("one".to_string())));
+    assert_eq!(diff.old_value(e2), Some(&Arc::new("two".to_string())));
+    assert_eq!(diff.new_value(e2), Some(&Arc::new("TWO".to_string())));
+    assert_eq!(diff.old_value(e3), None);
+    assert_eq!(diff.moved().next(), Some((&e1, &moved_to)));
+    assert_eq!(diff.num_removed_moves(), 0);
+
+    // Removing a move note is a change; changing one is not a removal
+    let mut newer = new.clone_child();
+    newer.remove_move(e1);
+    let diff = StateDiff::between(&new, &newer);
+    assert!(!diff.is_empty());
+    assert_eq!((diff.num_moved(), diff.num_removed_moves()), (0, 1));
+    assert_eq!(diff.removed_moves().next(), Some((&e1, &moved_to)));
+    let mut newer = new.clone_child();
+    newer.set_move(e1, PartId::from_num(5).elt_id(1));
+    let diff = StateDiff::between(&new, &newer);
+    assert_eq!((diff.num_moved(), diff.num_removed_moves()), (1, 0));
+}
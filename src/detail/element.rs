@@ -67,7 +67,7 @@ impl Into<u64> for PartId {
 /// Supports `From` (`EltId::from(n)`) to convert from a `u64` (this panics if
 /// the value is not a valid identifier). Supports `Into` (`pn.into()`) to
 /// convert to a `u64`.
-#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
 pub struct EltId {
     // #0018: optimise usage as Option with NonZero?
     id: u64,
@@ -0,0 +1,166 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Element identifier allocation
+//!
+//! Each partition has a 24-bit space of element numbers. An `IdAllocator`
+//! chooses the number for each new element; see
+//! `PartitionState::set_id_allocator()` and `Partition::set_id_allocator()`.
+//!
+//! As the space fills, finding a free number gets slower and eventually
+//! fails; `PartitionState::id_usage()` and `Partition::ids_low()` allow the
+//! partition to be divided before this happens.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::HashSet;
+use std::fmt::Debug;
+
+use rand::random;
+
+use {PartId, EltId};
+use error::ElementOp;
+
+/// Fraction of the element identifier space in use above which a partition
+/// is considered to be running low on identifiers and should be divided.
+pub const ID_USAGE_WARN: f64 = 0.5;
+
+/// Number of identifiers in a partition's space (`EltId::max() + 1`).
+pub fn id_space() -> usize {
+    EltId::max() as usize + 1
+}
+
+/// Chooses identifiers for new elements.
+pub trait IdAllocator: Debug + Send + Sync {
+    /// Allocate an identifier within partition `part_id` for which `is_free`
+    /// returns true.
+    ///
+    /// Should return `ElementOp::IdGenFailure` if no free identifier is found
+    /// (the partition may be full).
+    fn allocate(&self, part_id: PartId, is_free: &Fn(EltId) -> bool) ->
+        Result<EltId, ElementOp>;
+    
+    /// Allocate `n` distinct identifiers, as `allocate()`. Used for bulk
+    /// insertion (see `PartitionState::insert_bulk()`).
+    fn allocate_batch(&self, part_id: PartId, n: usize, is_free: &Fn(EltId) -> bool) ->
+        Result<Vec<EltId>, ElementOp>
+    {
+        let mut ids = Vec::with_capacity(n);
+        let mut taken = HashSet::with_capacity(n);
+        for _ in 0..n {
+            let id = try!(self.allocate(part_id, &|id| is_free(id) && !taken.contains(&id)));
+            taken.insert(id);
+            ids.push(id);
+        }
+        Ok(ids)
+    }
+}
+
+/// Allocate numbers from a random starting point, taking the next free number
+/// if that is in use. This is the default.
+/// 
+/// Random identifiers make it unlikely that two independent edits of a
+/// partition allocate the same identifier, avoiding renames on merge.
+#[derive(Debug, Default)]
+pub struct RandomIds;
+
+impl IdAllocator for RandomIds {
+    fn allocate(&self, part_id: PartId, is_free: &Fn(EltId) -> bool) ->
+        Result<EltId, ElementOp>
+    {
+        // Generate an identifier: (1) use a random sample, (2) increment if
+        // taken, (3) add the partition identifier.
+        let initial = part_id.elt_id(random::<u32>() & 0xFF_FFFF);
+        probe(initial, is_free)
+    }
+    
+    fn allocate_batch(&self, part_id: PartId, n: usize, is_free: &Fn(EltId) -> bool) ->
+        Result<Vec<EltId>, ElementOp>
+    {
+        // One random sample, then a single sequential scan for `n` free
+        // identifiers (so the batch is contiguous where the space allows)
+        let mut ids = Vec::with_capacity(n);
+        if n == 0 { return Ok(ids); }
+        let initial = part_id.elt_id(random::<u32>() & 0xFF_FFFF);
+        let mut id = initial;
+        loop {
+            if is_free(id) {
+                ids.push(id);
+                if ids.len() == n { return Ok(ids); }
+            }
+            id = id.next_elt();
+            if id == initial {
+                return Err(ElementOp::IdGenFailure);
+            }
+        }
+    }
+}
+
+/// Allocate numbers sequentially, starting from 1.
+/// 
+/// Allocation is fast and identifiers are compact, but concurrent edits of a
+/// partition will likely allocate the same identifiers, requiring renames
+/// when merging.
+#[derive(Debug)]
+pub struct SequentialIds {
+    next: AtomicUsize,
+}
+
+impl SequentialIds {
+    /// Create, starting from number 1
+    pub fn new() -> SequentialIds {
+        SequentialIds::starting_at(1)
+    }
+    /// Create, starting from number `n` (must be no more than `EltId::max()`)
+    pub fn starting_at(n: u32) -> SequentialIds {
+        assert!(n <= EltId::max(), "SequentialIds::starting_at(n): n is invalid");
+        SequentialIds { next: AtomicUsize::new(n as usize) }
+    }
+}
+
+impl IdAllocator for SequentialIds {
+    fn allocate(&self, part_id: PartId, is_free: &Fn(EltId) -> bool) ->
+        Result<EltId, ElementOp>
+    {
+        // Only advance `next` if no other thread did meanwhile, so that
+        // concurrent allocations get distinct identifiers
+        let mut next = self.next.load(Ordering::SeqCst);
+        loop {
+            let id = try!(probe(part_id.elt_id(next as u32), is_free));
+            let after = id.next_elt().elt_num() as usize;
+            match self.next.compare_exchange(next, after, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => { return Ok(id); },
+                Err(prev) => { next = prev; },
+            }
+        }
+    }
+}
+
+/// Do not allocate identifiers: the caller must supply them via
+/// `PartitionState::insert_with_id()`. Plain insertion fails with
+/// `ElementOp::IdRequired`.
+/// 
+/// Note that merges may need to allocate an identifier when resolving a
+/// clash; with this strategy such merges fail.
+#[derive(Debug, Default)]
+pub struct ExplicitIds;
+
+impl IdAllocator for ExplicitIds {
+    fn allocate(&self, _: PartId, _: &Fn(EltId) -> bool) -> Result<EltId, ElementOp> {
+        Err(ElementOp::IdRequired)
+    }
+}
+
+// Find the first free identifier, starting from `initial` and wrapping.
+fn probe(initial: EltId, is_free: &Fn(EltId) -> bool) -> Result<EltId, ElementOp> {
+    let mut id = initial;
+    loop {
+        if is_free(id) { return Ok(id); }
+        id = id.next_elt();
+        // #0019: is this too many to check exhaustively? We could use a
+        // lower limit, and possibly resample a few times.
+        if id == initial {
+            return Err(ElementOp::IdGenFailure);
+        }
+    }
+}
@@ -0,0 +1,276 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Secondary indexes over element values
+//!
+//! An index maps keys, extracted from elements by a user-supplied function,
+//! to element identifiers. Indexes are held by `PartitionState` and updated
+//! as elements are inserted, replaced and removed; see
+//! `PartitionState::add_index()` and `Partition::add_index()`.
+//!
+//! Indexes are shared between a state and its clones (e.g. from
+//! `clone_child()`) and only copied when a clone next changes, so states
+//! which are not modified do not pay for a copy. Even then only recent changes
+//! are copied: each index keeps a shared base plus a small set of changes,
+//! which is folded into the base when it grows past roughly `√n` entries.
+
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::Hash;
+use std::sync::Arc;
+
+use {ElementT, EltId};
+
+/// Operations on an index, independent of the key type.
+pub trait IndexT<E: ElementT>: Send + Sync {
+    /// Note an element
+    fn insert(&mut self, id: EltId, elt: &E);
+    /// Remove the note on an element
+    fn remove(&mut self, id: EltId, elt: &E);
+    /// Clone the index. This is `O(√n)`: the bulk of entries is shared.
+    fn box_clone(&self) -> Box<IndexT<E>>;
+    /// Create an empty index using the same key extractor
+    fn new_empty(&self) -> Box<IndexT<E>>;
+    /// Convert to `&Any` (for access to keys)
+    fn as_any(&self) -> &Any;
+}
+
+type KeyMap<K> = HashMap<K, HashSet<EltId>>;
+
+/// An index with keys of type `K`.
+pub struct Index<E: ElementT, K: Hash + Eq> {
+    extract: Arc<Fn(&E) -> Option<K> + Send + Sync>,
+    // Entries shared with clones, and their number
+    base: Arc<KeyMap<K>>,
+    base_len: usize,
+    // Changes since `base`: entries added, entries of `base` removed, and
+    // the number of both
+    added: KeyMap<K>,
+    removed: KeyMap<K>,
+    changed_len: usize,
+}
+
+impl<E: ElementT, K: Hash + Eq> Index<E, K> {
+    /// Create an empty index
+    pub fn new(extract: Arc<Fn(&E) -> Option<K> + Send + Sync>) -> Index<E, K> {
+        Index {
+            extract: extract,
+            base: Arc::new(HashMap::new()),
+            base_len: 0,
+            added: HashMap::new(),
+            removed: HashMap::new(),
+            changed_len: 0,
+        }
+    }
+    
+    /// Get identifiers of all elements with the given key, in order
+    pub fn get(&self, key: &K) -> Vec<EltId> {
+        let removed = self.removed.get(key);
+        let mut ids: Vec<EltId> = self.base.get(key).into_iter()
+                .flat_map(|ids| ids.iter())
+                .filter(|id| removed.map_or(true, |removed| !removed.contains(id)))
+                .chain(self.added.get(key).into_iter().flat_map(|ids| ids.iter()))
+                .cloned().collect();
+        ids.sort();
+        ids.dedup();
+        ids
+    }
+}
+
+impl<E: ElementT, K: Hash + Eq + Clone> Index<E, K> {
+    // Fold changes into the base once there are too many to copy cheaply.
+    // The base is copied first if shared with clones.
+    fn fold_if_large(&mut self) {
+        if self.changed_len * self.changed_len <= self.base_len + 1024 {
+            return;
+        }
+        let base = Arc::make_mut(&mut self.base);
+        for (key, ids) in self.removed.drain() {
+            let empty = match base.get_mut(&key) {
+                Some(base_ids) => {
+                    for id in ids {
+                        self.base_len -= base_ids.remove(&id) as usize;
+                    }
+                    base_ids.is_empty()
+                },
+                None => false,
+            };
+            if empty {
+                base.remove(&key);
+            }
+        }
+        for (key, ids) in self.added.drain() {
+            let base_ids = base.entry(key).or_insert_with(HashSet::new);
+            for id in ids {
+                self.base_len += base_ids.insert(id) as usize;
+            }
+        }
+        self.changed_len = 0;
+    }
+}
+
+// Add `id` under `key`. Returns true if not already present.
+fn add_entry<K: Hash + Eq>(map: &mut KeyMap<K>, key: K, id: EltId) -> bool {
+    map.entry(key).or_insert_with(HashSet::new).insert(id)
+}
+
+// Remove `id` from under `key`. Returns true if found.
+fn remove_entry<K: Hash + Eq>(map: &mut KeyMap<K>, key: &K, id: EltId) -> bool {
+    let (found, empty) = match map.get_mut(key) {
+        Some(ids) => (ids.remove(&id), ids.is_empty()),
+        None => (false, false),
+    };
+    if empty {
+        map.remove(key);
+    }
+    found
+}
+
+impl<E: ElementT + 'static, K: Hash + Eq + Clone + Send + Sync + 'static> IndexT<E> for Index<E, K> {
+    fn insert(&mut self, id: EltId, elt: &E) {
+        if let Some(key) = (self.extract)(elt) {
+            if remove_entry(&mut self.removed, &key, id) {
+                self.changed_len -= 1;
+            } else if add_entry(&mut self.added, key, id) {
+                self.changed_len += 1;
+            }
+            self.fold_if_large();
+        }
+    }
+    fn remove(&mut self, id: EltId, elt: &E) {
+        if let Some(key) = (self.extract)(elt) {
+            if remove_entry(&mut self.added, &key, id) {
+                self.changed_len -= 1;
+            } else if self.base.get(&key).map_or(false, |ids| ids.contains(&id)) {
+                if add_entry(&mut self.removed, key, id) {
+                    self.changed_len += 1;
+                }
+            }
+            self.fold_if_large();
+        }
+    }
+    fn box_clone(&self) -> Box<IndexT<E>> {
+        box Index {
+            extract: self.extract.clone(),
+            base: self.base.clone(),
+            base_len: self.base_len,
+            added: self.added.clone(),
+            removed: self.removed.clone(),
+            changed_len: self.changed_len,
+        }
+    }
+    fn new_empty(&self) -> Box<IndexT<E>> {
+        box Index::new(self.extract.clone())
+    }
+    fn as_any(&self) -> &Any { self }
+}
+
+/// A set of named indexes. Cloning is cheap: indexes are copied on write
+/// (see `IndexT::box_clone()`).
+pub struct Indexes<E: ElementT> {
+    indexes: HashMap<String, Arc<Box<IndexT<E>>>>,
+}
+
+impl<E: ElementT> Indexes<E> {
+    /// Create, with no indexes
+    pub fn new() -> Indexes<E> {
+        Indexes { indexes: HashMap::new() }
+    }
+    /// True if there are no indexes
+    pub fn is_empty(&self) -> bool { self.indexes.is_empty() }
+    /// Check whether an index exists
+    pub fn contains(&self, name: &str) -> bool { self.indexes.contains_key(name) }
+    /// Get an index by name
+    pub fn get(&self, name: &str) -> Option<&IndexT<E>> {
+        self.indexes.get(name).map(|index| &***index)
+    }
+    /// Add an index, replacing any with the same name
+    pub fn add(&mut self, name: String, index: Box<IndexT<E>>) {
+        self.indexes.insert(name, Arc::new(index));
+    }
+    /// Iterate over indexes as `(name, index)` pairs
+    pub fn iter<'a>(&'a self) -> Box<Iterator<Item = (&'a String, &'a IndexT<E>)> + 'a> {
+        box self.indexes.iter().map(|(name, index)| (name, &***index))
+    }
+    /// Note an element in all indexes
+    pub fn insert(&mut self, id: EltId, elt: &E) {
+        for index in self.indexes.values_mut() {
+            Self::make_mut(index).insert(id, elt);
+        }
+    }
+    /// Remove an element from all indexes
+    pub fn remove(&mut self, id: EltId, elt: &E) {
+        for index in self.indexes.values_mut() {
+            Self::make_mut(index).remove(id, elt);
+        }
+    }
+    
+    // Get a mutable reference, copying the index first if it is shared
+    fn make_mut(index: &mut Arc<Box<IndexT<E>>>) -> &mut IndexT<E> {
+        if Arc::get_mut(index).is_none() {
+            *index = Arc::new(index.box_clone());
+        }
+        &mut **Arc::get_mut(index).expect("index not shared")
+    }
+}
+
+impl<E: ElementT> Clone for Indexes<E> {
+    fn clone(&self) -> Indexes<E> {
+        Indexes { indexes: self.indexes.clone() }
+    }
+}
+
+impl<E: ElementT> fmt::Debug for Indexes<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut names: Vec<&String> = self.indexes.keys().collect();
+        names.sort();
+        write!(f, "Indexes{:?}", names)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use PartId;
+    
+    #[test]
+    fn index_clones_share_entries() {
+        let p = PartId::from_num(1);
+        let elt = |n: u32| (n % 10).to_string();
+        let mut index: Index<String, String> = Index::new(Arc::new(|elt: &String| Some(elt.clone())));
+        for n in 0..2000 {
+            index.insert(p.elt_id(n), &elt(n));
+        }
+        assert!(index.changed_len * index.changed_len <= index.base_len + 1024);
+        
+        // A clone shares the base; changing it copies only recent changes
+        let mut clone = index.box_clone();
+        clone.remove(p.elt_id(3), &elt(3));
+        clone.insert(p.elt_id(2003), &elt(3));
+        clone.remove(p.elt_id(13), &elt(13));
+        clone.insert(p.elt_id(13), &elt(13));
+        let clone = clone.as_any().downcast_ref::<Index<String, String>>().unwrap();
+        assert!(Arc::ptr_eq(&index.base, &clone.base));
+        
+        let ids = |index: &Index<String, String>, key: &str| index.get(&key.to_string());
+        assert_eq!(ids(&index, "3").len(), 200);
+        assert_eq!(ids(&index, "3")[0], p.elt_id(3));
+        assert_eq!(ids(clone, "3").len(), 200);
+        assert_eq!(ids(clone, "3")[0], p.elt_id(13));
+        assert_eq!(ids(clone, "3")[199], p.elt_id(2003));
+        
+        // Many changes are folded into a copy of the base
+        let mut clone = index.box_clone();
+        for n in 0..1000 {
+            clone.remove(p.elt_id(n), &elt(n));
+        }
+        let clone = clone.as_any().downcast_ref::<Index<String, String>>().unwrap();
+        assert!(!Arc::ptr_eq(&index.base, &clone.base));
+        assert_eq!(ids(&index, "7").len(), 200);
+        assert_eq!(ids(clone, "7").len(), 100);
+        assert_eq!(ids(clone, "7")[0], p.elt_id(1007));
+    }
+}
@@ -0,0 +1,269 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Key-value access to a partition
+//!
+//! `KeyedPartition` wraps a `Partition` whose elements are `Record`s (key and
+//! value pairs), allowing elements to be addressed by key instead of by
+//! `EltId`. Keys are unique within the partition.
+
+use std::collections::BTreeMap;
+use std::collections::Bound;
+use std::hash::Hash;
+use std::io::Write;
+use std::sync::Arc;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use {ElementT, EltId, Sum};
+use partition::{Partition, State};
+use merge::{TwoWaySolver, EltMerge};
+use error::{Result, ReadError, ArgError, OtherError};
+
+/// An element consisting of a key and a value.
+/// 
+/// The serialisation is the length of the key's serialisation (as a 64-bit
+/// number), followed by the key and the value.
+#[derive(PartialEq, Debug)]
+pub struct Record<K, V> {
+    /// The key (unique within a `KeyedPartition`)
+    pub key: K,
+    /// The value
+    pub value: V,
+}
+
+impl<K: ElementT, V: ElementT> ElementT for Record<K, V> {
+    fn write_buf(&self, writer: &mut Write) -> Result<()> {
+        let mut key_buf = Vec::new();
+        try!(self.key.write_buf(&mut &mut key_buf));
+        try!(writer.write_u64::<BigEndian>(key_buf.len() as u64));
+        try!(writer.write_all(&key_buf));
+        try!(self.value.write_buf(writer));
+        Ok(())
+    }
+    fn read_buf(buf: &[u8]) -> Result<Self> {
+        if buf.len() < 8 {
+            return ReadError::err("record too short", 0, (0, buf.len()));
+        }
+        let len = try!((&buf[0..8]).read_u64::<BigEndian>()) as usize;
+        if len > buf.len() - 8 {
+            return ReadError::err("record key length too long", 0, (0, 8));
+        }
+        Ok(Record {
+            key: try!(K::read_buf(&buf[8..8 + len])),
+            value: try!(V::read_buf(&buf[8 + len..])),
+        })
+    }
+}
+
+/// A partition of key-value records, accessed by key.
+/// 
+/// A map from keys to element identifiers is kept for the tip; it is rebuilt
+/// when the tip changes other than through this interface (e.g. after
+/// loading). Operations fail when the partition is not loaded or requires a
+/// merge (see `merge()`).
+pub struct KeyedPartition<K: ElementT + Ord + Hash + Clone, V: ElementT> {
+    part: Partition<Record<K, V>>,
+    keys: BTreeMap<K, EltId>,
+    // Tip for which `keys` is correct, if any
+    keys_sum: Option<Sum>,
+}
+
+impl<K: ElementT + Ord + Hash + Clone, V: ElementT> KeyedPartition<K, V> {
+    /// Wrap a partition. The partition need not be loaded yet.
+    pub fn new(part: Partition<Record<K, V>>) -> KeyedPartition<K, V> {
+        KeyedPartition { part: part, keys: BTreeMap::new(), keys_sum: None }
+    }
+    /// Get the underlying partition
+    pub fn partition(&self) -> &Partition<Record<K, V>> {
+        &self.part
+    }
+    /// Get the underlying partition, e.g. to load or write. Changing
+    /// elements with duplicate keys will cause other operations to fail.
+    pub fn partition_mut(&mut self) -> &mut Partition<Record<K, V>> {
+        &mut self.part
+    }
+    /// Unwrap the partition
+    pub fn into_partition(self) -> Partition<Record<K, V>> {
+        self.part
+    }
+    
+    /// Get the value for a key, if present.
+    pub fn get(&mut self, key: &K) -> Result<Option<&V>> {
+        try!(self.sync());
+        match self.keys.get(key) {
+            Some(id) => Ok(Some(&try!(try!(self.part.tip()).get(*id)).value)),
+            None => Ok(None),
+        }
+    }
+    
+    /// Set the value for a key, committing the change (see
+    /// `Partition::push_state()`). Returns the record replaced, if any.
+    pub fn put(&mut self, key: K, value: V) -> Result<Option<Arc<Record<K, V>>>> {
+        try!(self.sync());
+        let mut state = try!(self.part.tip()).clone_child();
+        let elt = Record { key: key.clone(), value: value };
+        let (id, old) = match self.keys.get(&key) {
+            Some(id) => (*id, Some(try!(state.replace(*id, elt)))),
+            None => (try!(state.insert(elt)), None),
+        };
+        try!(self.part.push_state(state));
+        self.keys.insert(key, id);
+        self.keys_sum = Some(try!(self.part.tip_key()).clone());
+        Ok(old)
+    }
+    
+    /// Remove a key and its value, committing the change. Returns the record
+    /// removed, if any.
+    pub fn delete(&mut self, key: &K) -> Result<Option<Arc<Record<K, V>>>> {
+        try!(self.sync());
+        let id = match self.keys.get(key) {
+            Some(id) => *id,
+            None => { return Ok(None); },
+        };
+        let mut state = try!(self.part.tip()).clone_child();
+        let old = try!(state.remove(id));
+        try!(self.part.push_state(state));
+        self.keys.remove(key);
+        self.keys_sum = Some(try!(self.part.tip_key()).clone());
+        Ok(Some(old))
+    }
+    
+    /// Get all keys and values with keys in the given range, in order of key.
+    pub fn range(&mut self, min: Bound<&K>, max: Bound<&K>) -> Result<Vec<(&K, &V)>> {
+        try!(self.sync());
+        let tip = try!(self.part.tip());
+        let mut result = Vec::new();
+        for (_, id) in self.keys.range((min, max)) {
+            let record = try!(tip.get(*id));
+            result.push((&record.key, &record.value));
+        }
+        Ok(result)
+    }
+    
+    /// Get the number of keys
+    pub fn len(&mut self) -> Result<usize> {
+        try!(self.sync());
+        Ok(self.keys.len())
+    }
+    
+    /// Merge tips of the partition (see `Partition::merge()`), then resolve
+    /// cases where multiple elements have the same key (e.g. because the key
+    /// was inserted on two tips).
+    /// 
+    /// For each such key, the solver is called with two of the records as
+    /// `a` and `b` (in order of identifier) and no common ancestor. The
+    /// results `A`, `B`, `Elt(_)` (with the same key) and `NoElt` are
+    /// accepted; other results cause the merge to fail.
+    pub fn merge<S: TwoWaySolver<Record<K, V>>+?Sized>(&mut self, solver: &S) -> Result<()> {
+        try!(self.part.merge(solver));
+        self.keys_sum = None;
+    
+        let mut state = try!(self.part.tip()).clone_child();
+        let mut by_key: BTreeMap<K, Vec<EltId>> = BTreeMap::new();
+        for (id, record) in state.iter_ordered() {
+            by_key.entry(record.key.clone()).or_insert_with(Vec::new).push(id);
+        }
+        for (key, ids) in by_key {
+            let mut winner: Option<EltId> = None;
+            for id in ids {
+                let a_id = match winner {
+                    Some(a_id) => a_id,
+                    None => { winner = Some(id); continue; },
+                };
+                let a = try!(state.get_arc(a_id)).clone();
+                let b = try!(state.get_arc(id)).clone();
+                match solver.solve(Some(&a), Some(&b), None) {
+                    EltMerge::A => {
+                        try!(state.remove(id));
+                    },
+                    EltMerge::B => {
+                        try!(state.remove(a_id));
+                        winner = Some(id);
+                    },
+                    EltMerge::Elt(elt) => {
+                        if elt.key != key {
+                            return ArgError::err("solver changed key of record");
+                        }
+                        try!(state.replace_arc(a_id, elt));
+                        try!(state.remove(id));
+                    },
+                    EltMerge::NoElt => {
+                        try!(state.remove(a_id));
+                        try!(state.remove(id));
+                        winner = None;
+                    },
+                    EltMerge::Rename | EltMerge::NoResult => {
+                        return OtherError::err("solver left duplicate key unresolved");
+                    },
+                }
+            }
+        }
+        try!(self.part.push_state(state));
+        Ok(())
+    }
+    
+    // Rebuild the key map if the tip changed.
+    fn sync(&mut self) -> Result<()> {
+        let tip = try!(self.part.tip());
+        if self.keys_sum.as_ref() == Some(tip.statesum()) {
+            return Ok(());
+        }
+        trace!("KeyedPartition {}: rebuilding key map", tip.part_id().into_num());
+        self.keys.clear();
+        self.keys_sum = None;
+        for (id, record) in tip.iter() {
+            if self.keys.insert(record.key.clone(), id).is_some() {
+                return OtherError::err("duplicate key in partition (merge required)");
+            }
+        }
+        self.keys_sum = Some(tip.statesum().clone());
+        Ok(())
+    }
+}
+
+#[test]
+fn keyed() {
+    use partition::PartitionDummyIO;
+    use merge::{AncestorSolver2W, TwoWaySolveUseB, TwoWaySolverChain};
+    
+    let io = box PartitionDummyIO::new();
+    let part = Partition::<Record<String, String>>::create(io, "keyed").expect("partition creation");
+    let mut kp = KeyedPartition::new(part);
+    assert_eq!(kp.put("b".to_string(), "bee".to_string()).expect("put"), None);
+    kp.put("a".to_string(), "ay".to_string()).expect("put");
+    kp.put("c".to_string(), "see".to_string()).expect("put");
+    let old = kp.put("b".to_string(), "BEE".to_string()).expect("put");
+    assert_eq!(old.map(|r| r.value.clone()), Some("bee".to_string()));
+    assert_eq!(kp.get(&"b".to_string()).expect("get"), Some(&"BEE".to_string()));
+    assert_eq!(kp.len().expect("len"), 3);
+    
+    {
+        let (a, b) = ("a".to_string(), "b".to_string());
+        let range = kp.range(Bound::Included(&a), Bound::Included(&b)).expect("range");
+        let keys: Vec<&str> = range.iter().map(|&(k, _)| k.as_str()).collect();
+        assert_eq!(keys, vec!["a", "b"]);
+    }
+    assert!(kp.delete(&"a".to_string()).expect("delete").is_some());
+    assert_eq!(kp.delete(&"a".to_string()).expect("delete"), None);
+    assert_eq!(kp.get(&"a".to_string()).expect("get"), None);
+    
+    // The same key inserted on two tips is a conflict
+    {
+        let part = kp.partition_mut();
+        let parent = part.tip().expect("getting tip").clone_child();
+        for value in &["one", "two"] {
+            let mut state = parent.clone_exact();
+            state.insert(Record { key: "d".to_string(), value: value.to_string() })
+                    .expect("inserting elt");
+            part.push_state(state).expect("committing");
+        }
+    }
+    assert!(kp.get(&"d".to_string()).is_err());
+    let (s1, s2) = (AncestorSolver2W::new(), TwoWaySolveUseB::new());
+    kp.merge(&TwoWaySolverChain::new(&s1, &s2)).expect("merging");
+    assert_eq!(kp.len().expect("len"), 3);
+    let value = kp.get(&"d".to_string()).expect("get").cloned().expect("has value");
+    assert!(value == "one" || value == "two");
+}
@@ -32,10 +32,11 @@
 
 use std::collections::HashMap;
 use std::marker::PhantomData;
-use std::rc::Rc;
+use std::sync::Arc;
 use std::cmp::max;
 
 use detail::{EltId, Commit, CommitMeta, EltChange};
+use detail::states::note_sum;
 use partition::{PartitionState, State};
 use {ElementT, Sum};
 
@@ -86,10 +87,10 @@ impl<'a, E: ElementT> TwoWayMerge<'a, E> {
     /// of them.
     /// 
     /// Operation is `O(X)`.
-    pub fn solve<S>(&mut self, s: &S) where S: TwoWaySolver<E> {
+    pub fn solve<S>(&mut self, s: &S) where S: TwoWaySolver<E> + ?Sized {
         for &mut (id, ref mut result) in self.v.iter_mut() {
             if *result == EltMerge::NoResult {
-                *result = s.solve(self.a.get_rc(id).ok(), self.b.get_rc(id).ok(), self.c.get_rc(id).ok());
+                *result = s.solve(self.a.get_arc(id).ok(), self.b.get_arc(id).ok(), self.c.get_arc(id).ok());
             }
         }
     }
@@ -114,9 +115,9 @@ impl<'a, E: ElementT> TwoWayMerge<'a, E> {
     /// cases.
     /// 
     /// Operation is `O(1)`.
-    pub fn solve_one<S>(&mut self, i: usize, s: &S) where S: TwoWaySolver<E> {
+    pub fn solve_one<S>(&mut self, i: usize, s: &S) where S: TwoWaySolver<E> + ?Sized {
         let id = self.v[i].0;
-        self.v[i].1 = s.solve(self.a.get_rc(id).ok(), self.b.get_rc(id).ok(), self.c.get_rc(id).ok());
+        self.v[i].1 = s.solve(self.a.get_arc(id).ok(), self.b.get_arc(id).ok(), self.c.get_arc(id).ok());
     }
     
     /// Get the number of unsolved conflicts.
@@ -142,8 +143,48 @@ impl<'a, E: ElementT> TwoWayMerge<'a, E> {
     /// 
     /// Operation is `O(X)`.
     pub fn make_commit(self) -> Option<Commit<E>> {
-        // We build change-lists from the perspective of state1 and state2, then
-        // pick whichever is smaller.
+        let (a_sum, b_sum) = (self.a.statesum().clone(), self.b.statesum().clone());
+        let cnum = max(self.a.meta().number, self.b.meta().number);
+        let (sum, c1, c2) = match self.make_changes() {
+            Some(result) => result,
+            None => { return None; }
+        };
+        
+        let (parents, changes) = if c1.len() < c2.len() {
+            trace!("Created merge from first parent: {}", a_sum);
+            (vec![a_sum, b_sum], c1)
+        } else {
+            trace!("Created merge from second parent: {}", b_sum);
+            (vec![b_sum, a_sum], c2)
+        };
+        let meta = CommitMeta::new_from(cnum, None);
+        Some(Commit::new(sum, parents, changes, meta))
+    }
+    
+    /// Create a normal (single-parent) commit on top of the first state, `a`.
+    /// 
+    /// This is like `make_commit()` except that the result does not record
+    /// `b` as a parent; it is used to apply changes taken from some other
+    /// state without claiming to have merged that state (e.g. when reverting
+    /// or cherry-picking a commit).
+    /// 
+    /// This succeeds if and only if `is_solved()` returns true. The commit
+    /// returned may have no changes, in which case its state-sum equals that
+    /// of `a`.
+    /// 
+    /// Operation is `O(X)`.
+    pub fn make_commit_on_a(self) -> Option<Commit<E>> {
+        let a_sum = self.a.statesum().clone();
+        let cnum = self.a.meta().number;
+        self.make_changes().map(|(sum, c1, _)| {
+            trace!("Created commit from merge onto: {}", a_sum);
+            Commit::new(sum, vec![a_sum], c1, CommitMeta::new_from(cnum, None))
+        })
+    }
+    
+    // Build change-lists from the perspective of state1 and state2, along with
+    // the resultant state-sum. Fails if any conflict is unsolved.
+    fn make_changes(self) -> Option<(Sum, HashMap<EltId, EltChange<E>>, HashMap<EltId, EltChange<E>>)> {
         let mut c1 = HashMap::new();
         let mut c2 = HashMap::new();
         // We calculate the new state-sums too.
@@ -151,8 +192,8 @@ impl<'a, E: ElementT> TwoWayMerge<'a, E> {
         let mut sum2: Sum = self.b.statesum().clone();
         
         for (id, result) in self.v.into_iter() {
-            let a = self.a.get_rc(id);
-            let b = self.b.get_rc(id);
+            let a = self.a.get_arc(id);
+            let b = self.b.get_arc(id);
             match result {
                 EltMerge::A => {
                     if let Ok(elt1) = a {
@@ -230,10 +271,15 @@ impl<'a, E: ElementT> TwoWayMerge<'a, E> {
                                 }
                             };
                             
+                            // `a`'s element keeps the identifier; `b`'s is
+                            // renamed
                             c1.insert(new_id, EltChange::insertion(elt2.clone()));
                             sum1.permute(&elt2.sum());
-                            c2.insert(new_id, EltChange::insertion(elt1.clone()));
+                            c2.insert(id, EltChange::replacement(elt1.clone()));
+                            sum2.permute(&elt2.sum());
                             sum2.permute(&elt1.sum());
+                            c2.insert(new_id, EltChange::insertion(elt2.clone()));
+                            sum2.permute(&elt2.sum());
                         } else {
                             c2.insert(id, EltChange::insertion(elt1.clone()));
                             sum2.permute(&elt1.sum());
@@ -250,18 +296,16 @@ impl<'a, E: ElementT> TwoWayMerge<'a, E> {
                 }
             }
         }
-        assert_eq!(sum1, sum2); // sums must be equal
-        
-        let (sum, parents, changes) = if c1.len() < c2.len() {
-            trace!("Created merge from first parent: {}", self.a.statesum());
-            (sum1, vec![self.a.statesum().clone(), self.b.statesum().clone()], c1)
-        } else {
-            trace!("Created merge from second parent: {}", self.b.statesum());
-            (sum2, vec![self.b.statesum().clone(), self.a.statesum().clone()], c2)
-        };
-        let cnum = max(self.a.meta().number, self.b.meta().number);
-        let meta = CommitMeta::new_from(cnum, None);
-        Some(Commit::new(sum, parents, changes, meta))
+        // Take the union of move notes (where both have a note for the same
+        // identifier, that of `a` wins).
+        merge_aliases(self.a, self.b, &mut c1, &mut sum1, true);
+        merge_aliases(self.b, self.a, &mut c2, &mut sum2, false);
+        if sum1 != sum2 {
+            // sums must be equal; they can only differ when notes clash with
+            // elements of the result
+            return None;
+        }
+        Some((sum1, c1, c2))
     }
     
     /* One could in theory just go through elements once, like this. This is
@@ -369,6 +413,35 @@ impl<'a, E: ElementT> TwoWayMerge<'a, E> {
     */
 }
 
+// Add changes to `changes` (which convert `to` into the merge result) so that
+// the result also has the alias notes of `from`. If `keep` is true, notes of
+// `to` take precedence. Notes are skipped for identifiers of elements present
+// in the result. `sum` is updated for notes which are aliases.
+fn merge_aliases<E: ElementT>(to: &PartitionState<E>, from: &PartitionState<E>,
+    changes: &mut HashMap<EltId, EltChange<E>>, sum: &mut Sum, keep: bool)
+{
+    for (id, new_id) in from.moved_map() {
+        let old_note = to.is_moved(*id);
+        match old_note {
+            Some(old) if old == *new_id || keep => { continue; },
+            _ => {},
+        }
+        let removed = match changes.get(id) {
+            Some(&EltChange::Deletion) => true,
+            Some(_) => { continue; },
+            None if to.is_avail(*id) => { continue; },
+            None => false,
+        };
+        changes.insert(*id, EltChange::moved(*new_id, removed));
+        let part_id = to.part_id();
+        for note_sum in old_note.and_then(|old| note_sum(part_id, *id, old)).into_iter()
+                .chain(note_sum(part_id, *id, *new_id))
+        {
+            sum.permute(&note_sum);
+        }
+    }
+}
+
 /// Return type of a by-element merge solver.
 /// 
 /// Note that there is no direct way to specify the ancestor value, but this
@@ -381,11 +454,13 @@ pub enum EltMerge<E: ElementT> {
     /// Use the value from the second state
     B,
     /// Use a custom value (specified in full)
-    Elt(Rc<E>),
+    Elt(Arc<E>),
     /// Remove the element
     NoElt,
     /// Rename one element and include both; where only one element is present
-    /// that element is used in both.
+    /// that element is used in both. State A's element keeps the identifier
+    /// and B's gets a new one. (No alias is recorded for the renamed element
+    /// since its old identifier remains in use.)
     Rename,
     /// Give up
     NoResult,
@@ -396,8 +471,8 @@ pub trait TwoWaySolver<E: ElementT> {
     /// This function should take possibly-present elements from states A, B
     /// and common ancestor state C, which all have the same identifier, and
     /// return an `EltMerge` object.
-    fn solve<'a>(&self, a: Option<&'a Rc<E>>, b: Option<&'a Rc<E>>,
-        c: Option<&'a Rc<E>>) -> EltMerge<E>;
+    fn solve<'a>(&self, a: Option<&'a Arc<E>>, b: Option<&'a Arc<E>>,
+        c: Option<&'a Arc<E>>) -> EltMerge<E>;
 }
 
 /// Implementation of TwoWaySolver which always selects state A.
@@ -411,8 +486,8 @@ impl<E: ElementT> TwoWaySolveUseA<E> {
     }
 }
 impl<E: ElementT> TwoWaySolver<E> for TwoWaySolveUseA<E> {
-    fn solve(&self, _: Option<&Rc<E>>, _: Option<&Rc<E>>,
-        _: Option<&Rc<E>>) -> EltMerge<E>
+    fn solve(&self, _: Option<&Arc<E>>, _: Option<&Arc<E>>,
+        _: Option<&Arc<E>>) -> EltMerge<E>
     {
         EltMerge::A
     }
@@ -428,8 +503,8 @@ impl<E: ElementT> TwoWaySolveUseB<E> {
     }
 }
 impl<E: ElementT> TwoWaySolver<E> for TwoWaySolveUseB<E> {
-    fn solve(&self, _: Option<&Rc<E>>, _: Option<&Rc<E>>,
-        _: Option<&Rc<E>>) -> EltMerge<E>
+    fn solve(&self, _: Option<&Arc<E>>, _: Option<&Arc<E>>,
+        _: Option<&Arc<E>>) -> EltMerge<E>
     {
         EltMerge::B
     }
@@ -445,8 +520,8 @@ impl<E: ElementT> TwoWaySolveUseC<E> {
     }
 }
 impl<E: ElementT> TwoWaySolver<E> for TwoWaySolveUseC<E> {
-    fn solve(&self, _: Option<&Rc<E>>, _: Option<&Rc<E>>,
-        c: Option<&Rc<E>>) -> EltMerge<E>
+    fn solve(&self, _: Option<&Arc<E>>, _: Option<&Arc<E>>,
+        c: Option<&Arc<E>>) -> EltMerge<E>
     {
         match c {
             Some(ref elt) => EltMerge::Elt((*elt).clone()),
@@ -465,8 +540,8 @@ impl<E: ElementT> TwoWaySolveNoResult<E> {
     }
 }
 impl<E: ElementT> TwoWaySolver<E> for TwoWaySolveNoResult<E> {
-    fn solve(&self, _: Option<&Rc<E>>, _: Option<&Rc<E>>,
-        _: Option<&Rc<E>>) -> EltMerge<E>
+    fn solve(&self, _: Option<&Arc<E>>, _: Option<&Arc<E>>,
+        _: Option<&Arc<E>>) -> EltMerge<E>
     {
         EltMerge::NoResult
     }
@@ -475,12 +550,12 @@ impl<E: ElementT> TwoWaySolver<E> for TwoWaySolveNoResult<E> {
 /// Chains two solvers. Calls the second if and only if the first returns
 /// `NoResult`.
 pub struct TwoWaySolverChain<'a, E: ElementT,
-    S: TwoWaySolver<E>+'a, T: TwoWaySolver<E>+'a>
+    S: TwoWaySolver<E>+?Sized+'a, T: TwoWaySolver<E>+?Sized+'a>
 {
     s: &'a S, t: &'a T,
     p: PhantomData<E>
 }
-impl<'a, E: ElementT, S: TwoWaySolver<E>+'a, T: TwoWaySolver<E>+'a>
+impl<'a, E: ElementT, S: TwoWaySolver<E>+?Sized+'a, T: TwoWaySolver<E>+?Sized+'a>
     TwoWaySolverChain<'a, E, S, T>
 {
     /// Create an instance, based on two other solvers
@@ -488,11 +563,11 @@ impl<'a, E: ElementT, S: TwoWaySolver<E>+'a, T: TwoWaySolver<E>+'a>
         TwoWaySolverChain{ s: s, t: t, p: PhantomData }
     }
 }
-impl<'a, E: ElementT, S: TwoWaySolver<E>+'a, T: TwoWaySolver<E>+'a> TwoWaySolver<E>
+impl<'a, E: ElementT, S: TwoWaySolver<E>+?Sized+'a, T: TwoWaySolver<E>+?Sized+'a> TwoWaySolver<E>
     for TwoWaySolverChain<'a, E, S, T>
 {
-    fn solve(&self, a: Option<&Rc<E>>, b: Option<&Rc<E>>,
-        c: Option<&Rc<E>>) -> EltMerge<E>
+    fn solve(&self, a: Option<&Arc<E>>, b: Option<&Arc<E>>,
+        c: Option<&Arc<E>>) -> EltMerge<E>
     {
         let result = self.s.solve(a, b, c);
         if result != EltMerge::NoResult {
@@ -522,8 +597,8 @@ impl<E: ElementT> AncestorSolver2W<E> {
     }
 }
 impl<E: ElementT> TwoWaySolver<E> for AncestorSolver2W<E> {
-    fn solve<'a>(&self, a: Option<&'a Rc<E>>, b: Option<&'a Rc<E>>,
-        c: Option<&'a Rc<E>>) -> EltMerge<E>
+    fn solve<'a>(&self, a: Option<&'a Arc<E>>, b: Option<&'a Arc<E>>,
+        c: Option<&'a Arc<E>>) -> EltMerge<E>
     {
         // Assumption: a != b
         if a == c {
@@ -549,8 +624,8 @@ impl<E: ElementT> RenamingSolver2W<E> {
     }
 }
 impl<E: ElementT> TwoWaySolver<E> for RenamingSolver2W<E> {
-    fn solve(&self, _: Option<&Rc<E>>, _: Option<&Rc<E>>,
-        c: Option<&Rc<E>>) -> EltMerge<E>
+    fn solve(&self, _: Option<&Arc<E>>, _: Option<&Arc<E>>,
+        c: Option<&Arc<E>>) -> EltMerge<E>
     {
         if c == None {
             EltMerge::Rename
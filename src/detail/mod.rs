@@ -14,6 +14,15 @@ pub mod readwrite;
 pub mod partition;
 pub mod repo;
 pub mod merge;
+pub mod diff;
+pub mod stats;
+pub mod index;
+pub mod ids;
+pub mod keyed;
+pub mod observe;
+pub mod writer;
+pub mod sign;
+pub mod compact;
 
 mod sum;
 mod states;
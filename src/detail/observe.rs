@@ -0,0 +1,118 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Notification of changes
+//!
+//! Observers subscribed via `Partition::subscribe()` or `Repo::subscribe()`
+//! receive a `ChangeEvent` for each new committed state: those created
+//! locally (`push_state()`, `push_commit()`, merges) and those found while
+//! loading (replayed from logs or read from snapshots).
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use {ElementT, EltId, PartId, Sum};
+use detail::{Commit, EltChange};
+
+/// How an element changed
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ChangeKind {
+    /// Element was inserted
+    Inserted,
+    /// Element was replaced
+    Replaced,
+    /// Element was removed
+    Removed,
+    /// Element was moved to the given identifier (the note may also be an
+    /// alias; see `PartitionState::add_alias()`)
+    Moved(EltId),
+}
+
+/// Notification of a new state.
+#[derive(Clone, PartialEq, Debug)]
+pub struct ChangeEvent {
+    /// The partition
+    pub part_id: PartId,
+    /// The named branch the state is on, or `None` for the default branch
+    pub branch: Option<String>,
+    /// Sum of the new state
+    pub statesum: Sum,
+    /// Sums of parent states (the first is the state changes are relative to)
+    pub parents: Vec<Sum>,
+    /// Changed elements, in order of identifier, or `None` if not known (when
+    /// the state was read from a snapshot or bulk loaded)
+    pub changes: Option<Vec<(EltId, ChangeKind)>>,
+}
+
+impl ChangeEvent {
+    /// Create from a commit
+    pub fn from_commit<E: ElementT>(part_id: PartId, branch: Option<&str>, commit: &Commit<E>) ->
+        ChangeEvent
+    {
+        let mut changes: Vec<(EltId, ChangeKind)> = commit.changes_iter()
+            .map(|(id, change)| (*id, match *change {
+                EltChange::Insertion(_) => ChangeKind::Inserted,
+                EltChange::Replacement(_) => ChangeKind::Replaced,
+                EltChange::Deletion => ChangeKind::Removed,
+                EltChange::MovedOut(new_id) | EltChange::Moved(new_id) => ChangeKind::Moved(new_id),
+            }))
+            .collect();
+        changes.sort_by_key(|&(id, _)| id);
+        ChangeEvent {
+            part_id: part_id,
+            branch: branch.map(|name| name.to_string()),
+            statesum: commit.statesum().clone(),
+            parents: commit.parents().clone(),
+            changes: Some(changes),
+        }
+    }
+}
+
+/// Identifies a subscription, for `unsubscribe()`.
+/// 
+/// Identifiers are unique within the process, thus a `Repo` can use the same
+/// identifier on all its partitions.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct ObserverId(usize);
+
+static NEXT_OBSERVER: AtomicUsize = AtomicUsize::new(0);
+
+impl ObserverId {
+    /// Allocate a new identifier
+    pub fn new() -> ObserverId {
+        ObserverId(NEXT_OBSERVER.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// A set of subscribed observers.
+pub struct Observers {
+    v: Vec<(ObserverId, Box<FnMut(&ChangeEvent) + Send>)>,
+}
+
+impl Observers {
+    /// Create, with no observers
+    pub fn new() -> Observers {
+        Observers { v: Vec::new() }
+    }
+    /// True if there are no observers (in which case events need not be
+    /// created)
+    pub fn is_empty(&self) -> bool {
+        self.v.is_empty()
+    }
+    /// Add an observer
+    pub fn add(&mut self, id: ObserverId, f: Box<FnMut(&ChangeEvent) + Send>) {
+        self.v.push((id, f));
+    }
+    /// Remove an observer. Returns true if found.
+    pub fn remove(&mut self, id: ObserverId) -> bool {
+        let len = self.v.len();
+        self.v.retain(|&(id2, _)| id2 != id);
+        self.v.len() < len
+    }
+    /// Send an event to all observers
+    pub fn notify(&mut self, event: &ChangeEvent) {
+        for &mut (_, ref mut f) in &mut self.v {
+            f(event);
+        }
+    }
+}
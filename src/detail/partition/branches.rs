@@ -0,0 +1,196 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Named branches within a partition
+//! 
+//! Commits on a named branch are saved to their own log files, whose headers
+//! name the branch and the state it was created from.
+
+use std::collections::{HashSet, VecDeque};
+
+use detail::readwrite::validate_branch_name;
+use detail::Commit;
+use merge::{TwoWayMerge, TwoWaySolver, TwoWaySolverChain, AncestorSolver2W};
+use super::{Partition, PartitionState};
+use {ElementT, Sum};
+use error::{Result, TipError, OtherError};
+
+// A named branch within a partition. Commits on a branch are saved to their
+// own log files.
+pub struct Branch<E: ElementT> {
+    // States on this branch without a known successor
+    pub tips: HashSet<Sum>,
+    // Commits on this branch not yet saved to disk; use as queue
+    pub unsaved: VecDeque<Commit<E>>,
+    // The state the branch was created from; written in each log header
+    pub base: Sum,
+    // The state the branch was created from, until this is saved
+    pub unsaved_base: Option<Sum>,
+}
+
+impl<E: ElementT> Branch<E> {
+    // Create, with a single tip `base`
+    pub fn new(base: Sum) -> Branch<E> {
+        let mut tips = HashSet::new();
+        tips.insert(base.clone());
+        Branch { tips: tips, unsaved: VecDeque::new(), base: base, unsaved_base: None }
+    }
+}
+
+impl<E: ElementT> Partition<E> {
+    /// Create a named branch, starting from state `from`.
+    /// 
+    /// Branches allow changes to be staged without affecting the default
+    /// branch (whose latest state is returned by `tip()`). States are added to
+    /// a branch with `push_state_to()` and branches are combined with
+    /// `merge_branch()`. Branch heads are saved by `write()` and restored by
+    /// `load()`.
+    /// 
+    /// Fails if the name is invalid, if the branch already exists or if the
+    /// state is not loaded.
+    pub fn create_branch(&mut self, name: &str, from: &Sum) -> Result<()> {
+        try!(validate_branch_name(name));
+        if self.branches.contains_key(name) {
+            return OtherError::err("branch already exists");
+        }
+        if !self.states.contains(from) {
+            return OtherError::err("cannot create branch from a state which is not loaded");
+        }
+        let mut branch = Branch::new(from.clone());
+        branch.unsaved_base = Some(from.clone());
+        self.branches.insert(name.to_string(), branch);
+        Ok(())
+    }
+    
+    /// Delete a named branch. States on the branch are not removed, and stay
+    /// reachable if the branch was merged. Returns true if the branch existed.
+    pub fn delete_branch(&mut self, name: &str) -> bool {
+        if self.branches.remove(name).is_some() {
+            self.deleted_branches.push(name.to_string());
+            true
+        } else {
+            false
+        }
+    }
+    
+    /// Get the names of all named branches, sorted.
+    pub fn branches(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.branches.keys().map(|name| name.as_str()).collect();
+        names.sort();
+        names
+    }
+    
+    /// Get the state-sum (key) of the tip of a named branch. Fails if the
+    /// branch does not exist or if it has multiple tips.
+    pub fn branch_tip_key(&self, name: &str) -> Result<&Sum> {
+        let branch = match self.branches.get(name) {
+            Some(branch) => branch,
+            None => { return OtherError::err("branch not found"); }
+        };
+        if branch.tips.len() == 1 {
+            Ok(branch.tips.iter().next().unwrap())
+        } else if branch.tips.is_empty() {
+            Err(box TipError::NotReady)
+        } else {
+            Err(box TipError::MergeRequired)
+        }
+    }
+    
+    /// Get a read-only reference to the tip of a named branch. Fails when
+    /// `branch_tip_key()` fails.
+    pub fn branch_tip(&self, name: &str) -> Result<&PartitionState<E>> {
+        let key = try!(self.branch_tip_key(name));
+        match self.states.get(key) {
+            Some(state) => Ok(state),
+            None => OtherError::err("branch tip not loaded"),
+        }
+    }
+    
+    /// Merge the tip of branch `from` into the tip of branch `into`, or into
+    /// the default branch if `into` is `None`. Branch `from` is not changed.
+    /// 
+    /// Conflicts are resolved by comparison with the latest common ancestor
+    /// and then by `solver`. If `into` has not changed since the branches
+    /// diverged, the tip of `from` becomes the tip of `into` (a merge commit
+    /// is still recorded).
+    /// 
+    /// Returns true if `into` changed, false if it already included all
+    /// changes on `from`. Fails if either tip is not available (see
+    /// `branch_tip_key()` and `tip_key()`) or if `solver` leaves any conflict
+    /// unresolved.
+    pub fn merge_branch<S: TwoWaySolver<E>+?Sized>(&mut self, from: &str, into: Option<&str>,
+        solver: &S) -> Result<bool>
+    {
+        let from_key = try!(self.branch_tip_key(from)).clone();
+        let into_key = match into {
+            Some(name) => try!(self.branch_tip_key(name)).clone(),
+            None => try!(self.tip_key()).clone(),
+        };
+        let common = try!(self.latest_common_ancestor(&into_key, &from_key));
+        if common == from_key {
+            return Ok(false);
+        }
+        try!(self.restore(Some(&common).into_iter()));
+        let commit = {
+            let (into_state, from_state, common_state) = match (self.states.get(&into_key),
+                self.states.get(&from_key), self.states.get(&common))
+            {
+                (Some(a), Some(b), Some(c)) => (a, b, c),
+                _ => { return OtherError::err("state not found"); }
+            };
+            let mut merger = TwoWayMerge::new(into_state, from_state, common_state);
+            let ancestor_solver = AncestorSolver2W::new();
+            merger.solve(&TwoWaySolverChain::new(&ancestor_solver, solver));
+            if !merger.is_solved() {
+                return OtherError::err("solver left conflicts unresolved");
+            }
+            match merger.make_commit() {
+                Some(commit) => commit,
+                None => { return OtherError::err("unable to create commit"); }
+            }
+        };
+        trace!("Pushing merge commit from branch {}: {}", from, commit.statesum());
+        try!(self.push_commit_on(into, commit));
+        try!(self.flush_if_due());
+        Ok(true)
+    }
+    
+    /// As `push_state()`, but adds the state to the named branch (see
+    /// `create_branch()`).
+    /// 
+    /// Fails if the branch does not exist.
+    pub fn push_state_to(&mut self, branch: &str, state: PartitionState<E>) -> Result<bool> {
+        if !self.branches.contains_key(branch) {
+            return OtherError::err("branch not found");
+        }
+        try!(self.restore(state.parents().iter().take(1)));
+        if let Some(commit) = try!(self.commit_from_state(&state)) {
+            self.add_pair(Some(branch), commit, Some(state));
+            try!(self.flush_if_due());
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+    
+    // Get the states on a branch: those reachable from its tips which are not
+    // its base or an ancestor of the base. States may be evicted.
+    pub(super) fn branch_states(&self, branch: &Branch<E>) -> Vec<Sum> {
+        let mut before = HashSet::new();
+        let mut next = vec![&branch.base];
+        while let Some(sum) = next.pop() {
+            if before.insert(sum) {
+                next.extend(self.parents_of(sum).into_iter().flat_map(|parents| parents.iter()));
+            }
+        }
+        let mut found = HashSet::new();
+        let mut next: Vec<&Sum> = branch.tips.iter().collect();
+        while let Some(sum) = next.pop() {
+            if !before.contains(sum) && found.insert(sum) {
+                next.extend(self.parents_of(sum).into_iter().flat_map(|parents| parents.iter()));
+            }
+        }
+        found.into_iter().cloned().collect()
+    }
+}
@@ -0,0 +1,356 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! History compaction and garbage collection of a partition
+//! 
+//! See the `compact` module for the choice of states to keep.
+
+use std::collections::{HashSet, VecDeque};
+use std::io::ErrorKind;
+
+use detail::readwrite::{FileHeader, FileType, read_head, write_head, read_log, start_log,
+    write_commit};
+use detail::{Commit, CommitQueue};
+use compact::{CompactSchedule, GcReport};
+use super::{Partition, PartitionIO};
+use {ElementT, Sum};
+use error::{Result, OtherError, make_io_err};
+
+impl<E: ElementT> Partition<E> {
+    /// Compact history: keep only the tip, tagged states, branch tips, the
+    /// first state and those historical states chosen by `schedule`, then
+    /// rewrite the partition's files to match. Returns the number of states
+    /// removed.
+    /// 
+    /// Kept states are unchanged (including their state-sums and meta-data);
+    /// each is linked by a new commit to its nearest kept ancestors. New
+    /// commits are signed if a signing key is set.
+    /// 
+    /// Only the history of the tip is compacted; other states (e.g. on named
+    /// branches, other than branch tips) are removed. All history is loaded
+    /// and unsaved changes written first. Fails if a merge is required or if a
+    /// tagged state would be removed.
+    /// 
+    /// Superseded files are deleted (see `PartitionIO::delete_ss()`) once the
+    /// new files have been made durable (see `PartitionIO::sync_ss()`); this
+    /// fails without changing anything if the IO does not support deletion.
+    /// Only files read while compacting are deleted: logs written meanwhile
+    /// are kept, along with their snapshot. If deletion fails an error is
+    /// returned, though the partition is already compacted.
+    /// 
+    /// Since kept states are unchanged, compacted files can be merged with
+    /// those of peers which have not compacted, and vice-versa. Removed
+    /// states are recorded in snapshots; commits based on them are
+    /// quarantined when loaded (see `load()` and `quarantined()`).
+    pub fn compact(&mut self, schedule: &CompactSchedule) -> Result<usize> {
+        // All history must be held in memory
+        let limit = self.history_limit.take();
+        let result = self.compact_loaded(schedule);
+        self.history_limit = limit;
+        self.evict();
+        result
+    }
+    
+    /// Find what `gc()` would remove, without removing anything (other than
+    /// loading all history, this changes nothing).
+    pub fn gc_report(&mut self, horizon: Option<i64>) -> Result<GcReport> {
+        let limit = self.history_limit.take();
+        let result = self.gc_loaded(horizon, true);
+        self.history_limit = limit;
+        self.evict();
+        result
+    }
+    
+    /// Garbage collection: remove states which are not reachable, from
+    /// memory and from disk. Returns what was removed (see `gc_report()` to
+    /// find this first).
+    /// 
+    /// Reachable states are the tips of the default and named branches,
+    /// tagged states and their ancestors. If `horizon` (a UNIX time-stamp) is
+    /// given, states older than this are kept regardless, along with their
+    /// ancestors; old history can be reduced with `compact()` instead.
+    /// Snapshots are not changed, so states stored in snapshots (and their
+    /// ancestors) are also kept.
+    /// 
+    /// All history is loaded first. Log files where all commits give
+    /// unreachable states are deleted; other logs with such commits are
+    /// rewritten without them (see `PartitionIO::delete_ss_cl()`). Fails
+    /// without changing anything if the IO does not support deletion.
+    pub fn gc(&mut self, horizon: Option<i64>) -> Result<GcReport> {
+        let limit = self.history_limit.take();
+        let result = self.gc_loaded(horizon, false);
+        self.history_limit = limit;
+        self.evict();
+        result
+    }
+    
+    // Body of `compact()`, called with no history limit
+    fn compact_loaded(&mut self, schedule: &CompactSchedule) -> Result<usize> {
+        if !self.io.can_delete() {
+            return make_io_err(ErrorKind::InvalidInput, "deletion not supported");
+        }
+        try!(self.write(true));
+        // Files to delete: those existing now, all of which are loaded below
+        let old_files: Vec<(usize, usize)> = (0..self.io.ss_len())
+                .map(|ss| (ss, self.io.ss_cl_len(ss)))
+                .collect();
+        try!(self.load(true));
+        let tip_key = try!(self.tip_key()).clone();
+        
+        // Find the history of the tip and check no tagged state is lost
+        let mut history = HashSet::new();
+        let mut next = vec![tip_key.clone()];
+        while let Some(sum) = next.pop() {
+            if let Some(state) = self.states.get(&sum) {
+                if history.insert(sum.clone()) {
+                    next.extend(state.parents().iter().cloned());
+                }
+            }
+        }
+        // States on branches are not compacted
+        let branch_states: HashSet<Sum> = self.branches.values()
+                .flat_map(|b| self.branch_states(b))
+                .collect();
+        for sum in self.tags.values() {
+            if self.states.contains(sum) && !history.contains(sum) && !branch_states.contains(sum) {
+                return OtherError::err("tagged state is not in the history of the tip");
+            }
+        }
+        
+        // Choose states to keep; the first (by number) of the states without
+        // a known parent is the base of the new history.
+        let mut keep = schedule.select(history.iter()
+                .map(|sum| (sum, self.states.get(sum).expect("state").meta().timestamp)));
+        keep.insert(tip_key.clone());
+        keep.extend(self.tags.values().filter(|sum| history.contains(*sum)).cloned());
+        // Branch bases and other parents of branch states are kept, so that
+        // branch logs can still be replayed
+        keep.extend(self.branches.values().map(|b| &b.base)
+                .chain(branch_states.iter()
+                        .flat_map(|sum| self.states.get(sum).expect("state").parents()))
+                .filter(|sum| history.contains(*sum))
+                .cloned());
+        let mut roots: Vec<(u32, Sum)> = history.iter()
+                .map(|sum| self.states.get(sum).expect("state"))
+                .filter(|state| !state.parents().iter().any(|p| history.contains(p)))
+                .map(|state| (state.meta().number, state.statesum().clone()))
+                .collect();
+        roots.sort();
+        let root = roots[0].1.clone();
+        keep.extend(roots.into_iter().map(|(_, sum)| sum));
+        
+        // Link each kept state to its nearest kept ancestors. Ordering by
+        // number puts parents before children.
+        let mut order: Vec<(u32, Sum)> = keep.iter()
+                .map(|sum| (self.states.get(sum).expect("state").meta().number, sum.clone()))
+                .collect();
+        order.sort();
+        let mut queue = VecDeque::new();
+        let mut new_parents = Vec::new();
+        for (_, sum) in order {
+            if sum == root {
+                continue;
+            }
+            let mut parents = self.kept_ancestors(&sum, &keep);
+            if parents.is_empty() {
+                parents.push(root.clone());
+            }
+            {
+                let state = self.states.get(&sum).expect("state");
+                let parent = self.states.get(&parents[0]).expect("state");
+                if let Some(mut commit) = Commit::from_diff(parent, state) {
+                    commit.set_parents(parents.clone());
+                    *commit.meta_mut() = state.meta().clone();
+                    if let Some(ref key) = self.signing_key {
+                        commit.sign(key);
+                    }
+                    queue.push_back(commit);
+                }
+            }
+            new_parents.push((sum, parents));
+        }
+        
+        // States to remove, recorded in new snapshots
+        let removed: Vec<Sum> = self.states.iter()
+                .map(|state| state.statesum())
+                .filter(|sum| !keep.contains(*sum) && !branch_states.contains(*sum))
+                .cloned().collect();
+        self.removed.extend(removed.iter().cloned());
+        
+        // Write the new history: a snapshot of the base and a log, then a
+        // snapshot of the tip (which also carries branches forward)
+        try!(self.write_state_snapshot(&root));
+        let root_ss = self.ss_num;
+        let header = FileHeader::new(FileType::CommitLog(0), self.repo_name.clone(), Some(self.part_id));
+        let n = queue.len();
+        try!(Self::write_log(&mut *self.io.lock().io, self.ss_num, &header, &mut queue, n));
+        try!(self.write_snapshot());
+        // Nothing is deleted until the new files are durable
+        try!(self.io.sync_ss(root_ss));
+        try!(self.io.sync_ss(self.ss_num));
+        
+        // Update states held in memory to match
+        for sum in &removed {
+            self.states.remove(sum);
+            self.sources.remove(sum);
+        }
+        self.evicted.retain(|sum, _| keep.contains(sum));
+        for (sum, parents) in new_parents {
+            if let Some(mut state) = self.states.remove(&sum) {
+                state.set_parents(parents);
+                self.states.insert(state);
+            }
+        }
+        info!("Partition {}: compacted history, removing {} states",
+            self.part_id.into_num(), removed.len());
+        
+        // Delete superseded files. A snapshot with logs written since
+        // loading is kept.
+        for (ss, cl_len) in old_files {
+            for cl in 0..cl_len {
+                try!(self.io.delete_ss_cl(ss, cl));
+            }
+            if self.io.ss_cl_len(ss) <= cl_len {
+                try!(self.io.delete_ss(ss));
+            }
+        }
+        Ok(removed.len())
+    }
+    
+    // Body of `gc()` and `gc_report()`, called with no history limit
+    fn gc_loaded(&mut self, horizon: Option<i64>, dry_run: bool) -> Result<GcReport> {
+        if !dry_run && !self.io.can_delete() {
+            return make_io_err(ErrorKind::InvalidInput, "deletion not supported");
+        }
+        try!(self.load(true));
+        
+        // Mark reachable states. States kept regardless (old or in a
+        // snapshot) are roots too, so their ancestors are kept.
+        let mut marked = HashSet::new();
+        let mut next: Vec<Sum> = self.tips.iter()
+                .chain(self.tags.values())
+                .chain(self.branches.values()
+                        .flat_map(|b| b.tips.iter().chain(Some(&b.base))))
+                .chain(self.states.iter()
+                        .filter(|state| horizon.map_or(false, |h| state.meta().timestamp < h))
+                        .map(|state| state.statesum()))
+                .chain(self.snapshot_states.iter())
+                .cloned().collect();
+        while let Some(sum) = next.pop() {
+            if !marked.insert(sum.clone()) {
+                continue;
+            }
+            if let Some(state) = self.states.get(&sum) {
+                next.extend(state.parents().iter().cloned());
+            }
+        }
+        let mut unreachable: Vec<(u32, Sum)> = self.states.iter()
+                .filter(|state| !marked.contains(state.statesum()))
+                .map(|state| (state.meta().number, state.statesum().clone()))
+                .collect();
+        unreachable.sort();
+        let mut report = GcReport::default();
+        report.states = unreachable.into_iter().map(|(_, sum)| sum).collect();
+        let unreachable: HashSet<Sum> = report.states.iter().cloned().collect();
+        
+        // Find logs with unreachable commits. A log is only deleted if
+        // nothing else is recorded in it.
+        let mut rewrites = Vec::new();
+        for ss in 0..self.io.ss_len() {
+            for cl in 0..self.io.ss_cl_len(ss) {
+                let (header, commits) = match try!(self.io.read_ss_cl(ss, cl)) {
+                    Some(mut r) => {
+                        let header = try!(read_head(&mut r));
+                        let mut queue = CommitQueue::new();
+                        try!(read_log(&mut r, &mut queue));
+                        (header, queue.into_commits())
+                    },
+                    None => continue,
+                };
+                let num = commits.iter().filter(|c| unreachable.contains(c.statesum())).count();
+                if num == 0 {
+                    continue;
+                }
+                let live_branch = header.branch.as_ref()
+                        .map_or(false, |&(ref name, _)| self.branches.contains_key(name));
+                if num == commits.len() && header.tags.is_empty() &&
+                    header.deleted_branches.is_empty() && !live_branch
+                {
+                    report.deleted_logs.push((ss, cl));
+                } else {
+                    report.rewritten_logs.push((ss, cl));
+                    if !dry_run {
+                        let commits: Vec<_> = commits.into_iter()
+                                .filter(|c| !unreachable.contains(c.statesum()))
+                                .collect();
+                        rewrites.push((ss, cl, header, commits));
+                    }
+                }
+            }
+        }
+        if dry_run {
+            return Ok(report);
+        }
+        
+        for sum in &report.states {
+            self.states.remove(sum);
+            self.sources.remove(sum);
+        }
+        info!("Partition {}: collected {} unreachable states",
+            self.part_id.into_num(), report.states.len());
+        for &(ss, cl) in &report.deleted_logs {
+            try!(self.io.delete_ss_cl(ss, cl));
+        }
+        for (ss, cl, mut header, commits) in rewrites {
+            // Logs are replayed in order, so the log keeps its number. A copy
+            // is written first so that nothing is lost if interrupted.
+            header.ftype = FileType::CommitLog(0);
+            let copy = self.io.ss_cl_len(ss);
+            try!(Self::write_log_at(&mut *self.io.lock().io, ss, copy, &header, &commits));
+            try!(self.io.delete_ss_cl(ss, cl));
+            try!(Self::write_log_at(&mut *self.io.lock().io, ss, cl, &header, &commits));
+            try!(self.io.delete_ss_cl(ss, copy));
+        }
+        Ok(report)
+    }
+    
+    // Write a log file with the given number, which must not exist.
+    fn write_log_at(io: &mut PartitionIO, ss_num: usize, cl_num: usize, header: &FileHeader,
+        commits: &[Commit<E>]) -> Result<()>
+    {
+        match try!(io.new_ss_cl(ss_num, cl_num)) {
+            Some(mut writer) => {
+                try!(write_head(header, &mut writer));
+                try!(start_log(&mut writer));
+                for commit in commits {
+                    try!(write_commit(commit, &mut writer));
+                }
+                Ok(())
+            },
+            None => OtherError::err("log file already exists"),
+        }
+    }
+    
+    // Find the nearest ancestors of a state which are in `keep`, searching
+    // from the first parent first.
+    fn kept_ancestors(&self, sum: &Sum, keep: &HashSet<Sum>) -> Vec<Sum> {
+        let mut result = Vec::new();
+        let mut seen = HashSet::new();
+        let mut next: Vec<&Sum> = match self.states.get(sum) {
+            Some(state) => state.parents().iter().rev().collect(),
+            None => Vec::new(),
+        };
+        while let Some(p) = next.pop() {
+            if !seen.insert(p) {
+                continue;
+            }
+            if keep.contains(p) {
+                result.push(p.clone());
+            } else if let Some(state) = self.states.get(p) {
+                next.extend(state.parents().iter().rev());
+            }
+        }
+        result
+    }
+}
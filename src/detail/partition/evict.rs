@@ -0,0 +1,178 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Limiting the number of states held in memory
+//! 
+//! States dropped from memory (see `Partition::set_history_limit()`) are
+//! remembered with their parents and the snapshot they can be reloaded from.
+
+use std::cmp;
+use std::collections::HashSet;
+
+use detail::readwrite::{read_head, read_log};
+use detail::{CommitQueue, LogReplay};
+use super::Partition;
+use {ElementT, Sum};
+use error::Result;
+
+// Where a state can be reloaded from: snapshot `ss` or a later one, or (if
+// not `snapshot`) the logs of these
+#[derive(Clone, Copy, Debug)]
+pub struct Source {
+    pub ss: usize,
+    pub snapshot: bool,
+}
+
+// A state dropped from memory (see `Partition::set_history_limit()`)
+pub struct Evicted {
+    pub parents: Vec<Sum>,
+    pub source: Source,
+}
+
+impl<E: ElementT> Partition<E> {
+    /// Get the limit on the number of states held in memory, if any.
+    pub fn history_limit(&self) -> Option<usize> { self.history_limit }
+    
+    /// Limit the number of states held in memory (`None` for no limit, the
+    /// default).
+    /// 
+    /// When there are more, the oldest historical states are dropped from
+    /// memory (their sums and parents are remembered). Tips, tagged states,
+    /// branch bases and states with unsaved commits are never dropped, so the
+    /// number of states may exceed the limit.
+    /// 
+    /// Dropped states are reloaded from disk when needed (e.g. for a merge,
+    /// `diff()` or `load_state()`); this reads the files of the snapshot the
+    /// oldest needed state came from and later ones, so may be slow. Reloaded
+    /// states are dropped again by later operations.
+    pub fn set_history_limit(&mut self, limit: Option<usize>) {
+        self.history_limit = limit;
+        self.evict();
+    }
+    
+    // Drop old states from memory as required by `history_limit`.
+    pub(super) fn evict(&mut self) {
+        let limit = match self.history_limit {
+            Some(limit) => limit,
+            None => { return; }
+        };
+        if self.states.len() <= limit {
+            return;
+        }
+        
+        let mut keep: HashSet<&Sum> = self.tips.iter().collect();
+        keep.extend(self.tags.values());
+        keep.extend(self.unsaved.iter().map(|c| c.statesum()));
+        keep.extend(self.unsaved_snapshot.iter());
+        for branch in self.branches.values() {
+            keep.extend(branch.tips.iter());
+            keep.insert(&branch.base);
+            keep.extend(branch.unsaved.iter().map(|c| c.statesum()));
+        }
+        let mut candidates: Vec<(u32, Sum)> = self.states.iter()
+                .filter(|state| !keep.contains(state.statesum()))
+                .map(|state| (state.meta().number, state.statesum().clone()))
+                .collect();
+        candidates.sort_by_key(|&(number, _)| number);
+        
+        let n = self.states.len() - limit;
+        trace!("Partition {}: evicting up to {} states from memory",
+            self.part_id.into_num(), n);
+        for (_, sum) in candidates.into_iter().take(n) {
+            if let Some(state) = self.states.remove(&sum) {
+                // Without a known source, history is replayed from the start
+                let source = self.sources.remove(&sum)
+                        .unwrap_or(Source { ss: 0, snapshot: false });
+                self.evicted.insert(sum, Evicted {
+                    parents: state.parents().clone(),
+                    source: source,
+                });
+            }
+        }
+    }
+    
+    // Reload any of the given states which were evicted. States restored
+    // previously are evicted again first (as far as the limit requires).
+    // 
+    // Evicted states are replayed from the files they came from, along with
+    // evicted first-parent ancestors needed to replay them. Files are read
+    // from the earliest snapshot needed only until all are found. States not
+    // found remain evicted.
+    pub(super) fn restore<'a, I: Iterator<Item = &'a Sum>>(&mut self, sums: I) -> Result<()> {
+        let sums: Vec<&Sum> = sums.collect();
+        if !sums.iter().any(|sum| self.evicted.contains_key(sum)) {
+            return Ok(());
+        }
+        self.evict();
+        
+        let mut needed = HashSet::new();
+        let mut start = usize::max_value();
+        for sum in sums {
+            let mut sum = sum.clone();
+            while let Some(evicted) = self.evicted.get(&sum) {
+                start = cmp::min(start, evicted.source.ss);
+                if !needed.insert(sum) || evicted.source.snapshot {
+                    break;
+                }
+                sum = match evicted.parents.first() {
+                    Some(parent) => parent.clone(),
+                    None => break,
+                };
+            }
+        }
+        if needed.is_empty() {
+            return Ok(());
+        }
+        info!("Partition {}: reloading {} evicted states from snapshot {}",
+            self.part_id.into_num(), needed.len(), start);
+        
+        // New states are added to `self.states`; tips are not needed
+        let mut tips = HashSet::new();
+        let mut found = Vec::new();
+        for ss in start..self.io.ss_len() {
+            if let Some(mut r) = try!(self.io.read_ss(ss)) {
+                let head = try!(read_head(&mut r));
+                let mut state = try!(self.decode_snapshot(&mut r, head.ftype.ver()));
+                if needed.remove(state.statesum()) {
+                    self.init_state(&mut state);
+                    found.push((state.statesum().clone(), Source { ss: ss, snapshot: true }));
+                    self.states.insert(state);
+                }
+            }
+            for cl in 0..self.io.ss_cl_len(ss) {
+                if needed.is_empty() {
+                    break;
+                }
+                if let Some(mut r) = try!(self.io.read_ss_cl(ss, cl)) {
+                    try!(read_head(&mut r));
+                    let mut queue = CommitQueue::new();
+                    try!(read_log(&mut r, &mut queue));
+                    queue.retain(|commit| needed.contains(commit.statesum()));
+                    for commit in queue.iter() {
+                        needed.remove(commit.statesum());
+                        found.push((commit.statesum().clone(), Source { ss: ss, snapshot: false }));
+                    }
+                    let mut replayer = LogReplay::from_sets(&mut self.states, &mut tips);
+                    replayer.set_verify(self.verify);
+                    // Quarantined commits are already known
+                    replayer.set_trust_policy(Some(self.trust.clone()));
+                    replayer.add_quarantined_sums(self.quarantine.iter().map(|c| c.statesum()));
+                    replayer.add_removed_sums(self.removed.iter());
+                    try!(replayer.replay(queue));
+                }
+            }
+            if needed.is_empty() {
+                break;
+            }
+        }
+        
+        for (sum, source) in found {
+            if self.states.contains(&sum) {
+                self.evicted.remove(&sum);
+                self.sources.insert(sum, source);
+            }
+        }
+        Ok(())
+    }
+}
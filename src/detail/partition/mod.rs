@@ -0,0 +1,2890 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: partition
+
+use std::io::{self, Read, Write, ErrorKind};
+use std::collections::{HashSet, HashMap, VecDeque};
+use std::collections::hash_map;
+use std::result;
+use std::any::Any;
+use std::mem;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, MutexGuard, Condvar};
+use std::time::{Duration, Instant};
+use hashindexed::HashIndexed;
+
+pub use detail::states::{State, PartitionState, EltIter};
+
+use detail::readwrite::{FileHeader, FileType, read_head, write_head, validate_repo_name,
+    validate_tag_name};
+use detail::readwrite::{read_snapshot, read_snapshot_parallel, write_snapshot,
+    snapshot_signature_status};
+use detail::readwrite::{read_log, start_log, write_commit};
+use detail::states::{PartitionStateSumComparator};
+use detail::{Commit, CommitQueue, LogReplay};
+use diff::StateDiff;
+use stats::PartitionStats;
+use index::{Index, Indexes};
+use ids::{IdAllocator, RandomIds};
+use observe::{ChangeEvent, ObserverId, Observers};
+use sign::{Signature, SigningKey, TrustPolicy, AcceptAll, Verdict};
+use merge::{TwoWayMerge, TwoWaySolver, TwoWaySolverChain, AncestorSolver2W};
+use {ElementT, Sum, PartId, EltId};
+use error::{Result, TipError, PatchOp, MatchError, ArgError, OtherError, ReplayError,
+    make_io_err};
+
+// Further methods of `Partition`, by topic
+mod branches;
+mod compaction;
+mod evict;
+
+use self::branches::Branch;
+use self::evict::{Source, Evicted};
+
+/// Maximum number of times `Partition::transaction()` and
+/// `Repo::transaction()` run the closure before giving up, when other
+/// writers keep changing the tip and no solver is given.
+pub const MAX_TRANSACTION_ATTEMPTS: usize = 10;
+
+/// An interface providing read and/or write access to a suitable location.
+/// 
+/// Note: lifetimes on some functions are more restrictive than might seem
+/// necessary; this is to allow an implementation which reads and writes to
+/// internal streams.
+pub trait PartitionIO: Send {
+    /// Convert self to a `&Any`
+    fn as_any(&self) -> &Any;
+    
+    /// Return one greater than the snapshot number of the latest snapshot file
+    /// or log file found.
+    /// 
+    /// The idea is that each snapshot and each set of log files can be put
+    /// into a sparse vector with this length (sparse because entries may be
+    /// missing; especially old entries may have been deleted).
+    /// 
+    /// Snapshots and commit logs with a number greater than or equal to this
+    /// number probably won't exist and may in any case be ignored.
+    /// 
+    /// Convention: snapshot "zero" may not be an actual snapshot but
+    /// either way the snapshot should be empty (no elements and the state-sum
+    /// should be zero).
+    /// 
+    /// This number must not change except to increase when write_snapshot()
+    /// is called.
+    fn ss_len(&self) -> usize;
+    
+    /// One greater than the number of the last log file available for some snapshot
+    fn ss_cl_len(&self, ss_num: usize) -> usize;
+    
+    /// Get a snapshot with the given number. If no snapshot is present or if
+    /// ss_num is too large, None will be returned.
+    /// 
+    /// Returns a heap-allocated read stream, either on some external resource
+    /// (such as a file) or on an internal data-structure.
+    /// 
+    /// This can fail due to IO operations failing.
+    fn read_ss<'a>(&'a self, ss_num: usize) -> Result<Option<Box<Read+'a>>>;
+    
+    /// Get a commit log (numbered `cl_num`) file for a snapshot (numbered
+    /// `ss_num`). If none is found, return Ok(None).
+    /// 
+    /// Returns a heap-allocated read stream, either on some external resource
+    /// (such as a file) or on an internal data-structure.
+    /// 
+    /// This can fail due to IO operations failing.
+    fn read_ss_cl<'a>(&'a self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Read+'a>>>;
+    
+    /// Get the size in bytes of a snapshot, or `None` if not present.
+    /// 
+    /// The default implementation reads the whole snapshot via `read_ss()`;
+    /// implementations should override this where there is a cheaper way.
+    fn ss_size(&self, ss_num: usize) -> Result<Option<u64>> {
+        Ok(match try!(self.read_ss(ss_num)) {
+            Some(mut r) => Some(try!(io::copy(&mut r, &mut io::sink()))),
+            None => None,
+        })
+    }
+    
+    /// Get the size in bytes of a commit log, or `None` if not present.
+    /// 
+    /// The default implementation reads the whole log via `read_ss_cl()`.
+    fn ss_cl_size(&self, ss_num: usize, cl_num: usize) -> Result<Option<u64>> {
+        Ok(match try!(self.read_ss_cl(ss_num, cl_num)) {
+            Some(mut r) => Some(try!(io::copy(&mut r, &mut io::sink()))),
+            None => None,
+        })
+    }
+    
+    /// Open a write stream on a new snapshot file, numbered ss_num.
+    /// This will increase the number returned by ss_len().
+    /// 
+    /// Returns None if a snapshot with number ss_num already exists.
+    /// 
+    /// Returns a heap-allocated write stream, either to some external resource
+    /// (such as a file) or to an internal data-structure.
+    /// 
+    /// This can fail due to IO operations failing.
+    fn new_ss<'a>(&'a mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>>;
+    
+    /// Open an append-write stream on an existing commit file. Writes may be
+    /// atomic. Each commit should be written via a single write operation.
+    /// 
+    /// Returns None if no commit file with this `ss_num` and `cl_num` exists.
+    /// 
+    /// Returns a heap-allocated write stream, either to some external resource
+    /// (such as a file) or to an internal data-structure.
+    /// 
+    /// This can fail due to IO operations failing.
+    // #0012: verify atomicity of writes
+    fn append_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>>;
+    
+    /// Open a write-stream on a new commit file. As with the append version,
+    /// the file will be opened in append mode, thus writes may be atomic.
+    /// Each commit (and the header, including commit section marker) should be
+    /// written via a single write operation.
+    /// 
+    /// Returns None if a commit log with number `cl_num` for snapshot `ss_num`
+    /// already exists.
+    /// 
+    /// Returns a heap-allocated write stream, either to some external resource
+    /// (such as a file) or to an internal data-structure.
+    /// 
+    /// This can fail due to IO operations failing.
+    // #0012: verify atomicity of writes
+    fn new_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>>;
+    
+    /// Delete snapshot `ss_num` (but not its commit logs). Returns false if
+    /// no such snapshot exists.
+    /// 
+    /// This is used to remove data superseded by compaction. The latest
+    /// snapshot is never deleted, and `ss_len()` must not decrease.
+    /// 
+    /// The default implementation fails (deletion is not supported).
+    fn delete_ss(&mut self, _ss_num: usize) -> Result<bool> {
+        make_io_err(ErrorKind::InvalidInput, "deletion not supported")
+    }
+    
+    /// Delete commit log `cl_num` of snapshot `ss_num`. Returns false if no
+    /// such log exists.
+    /// 
+    /// The default implementation fails (deletion is not supported).
+    fn delete_ss_cl(&mut self, _ss_num: usize, _cl_num: usize) -> Result<bool> {
+        make_io_err(ErrorKind::InvalidInput, "deletion not supported")
+    }
+    
+    /// True if `delete_ss()` and `delete_ss_cl()` are supported.
+    /// 
+    /// The default implementation returns false.
+    fn can_delete(&self) -> bool {
+        false
+    }
+    
+    /// Make snapshot `ss_num` and its commit logs durable (e.g. by flushing
+    /// file system buffers to disk).
+    /// 
+    /// This is called before deleting data superseded by the snapshot (see
+    /// `Partition::compact()`). The default implementation does nothing,
+    /// which is correct when completed writes are already durable.
+    fn sync_ss(&mut self, _ss_num: usize) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Doesn't provide any IO.
+/// 
+/// Can be used for testing but big fat warning: this does not provide any
+/// method to save your data. Write operations fail with `ErrorKind::InvalidInput`.
+pub struct PartitionDummyIO {
+    // The internal buffer allows us to accept write operations. Data gets
+    // written over on the next write.
+    buf: Vec<u8>
+}
+impl PartitionDummyIO {
+    /// Create a new instance
+    pub fn new() -> PartitionDummyIO {
+        PartitionDummyIO { buf: Vec::new() }
+    }
+}
+
+impl PartitionIO for PartitionDummyIO {
+    fn as_any(&self) -> &Any { self }
+    fn ss_len(&self) -> usize { 0 }
+    fn ss_cl_len(&self, _ss_num: usize) -> usize { 0 }
+    fn read_ss(&self, _ss_num: usize) -> Result<Option<Box<Read+'static>>> {
+        Ok(None)
+    }
+    fn read_ss_cl(&self, _ss_num: usize, _cl_num: usize) -> Result<Option<Box<Read+'static>>> {
+        Ok(None)
+    }
+    fn new_ss<'a>(&'a mut self, _ss_num: usize) -> Result<Option<Box<Write+'a>>> {
+        self.buf.clear();
+        Ok(Some(Box::new(&mut self.buf)))
+    }
+    fn append_ss_cl<'a>(&'a mut self, _ss_num: usize, _cl_num: usize) -> Result<Option<Box<Write+'a>>> {
+        self.buf.clear();
+        Ok(Some(Box::new(&mut self.buf)))
+    }
+    fn new_ss_cl<'a>(&'a mut self, _ss_num: usize, _cl_num: usize) -> Result<Option<Box<Write+'a>>> {
+        self.buf.clear();
+        Ok(Some(Box::new(&mut self.buf)))
+    }
+}
+
+// The IO provider, shared with `PendingWrite`s so that logs can be written
+// without access to the partition. Short operations lock it internally;
+// files are read into memory so that the lock is not held while decoding.
+struct SharedIO {
+    inner: Mutex<IOInner>,
+    // Signalled when a log-writing ticket is served
+    cond: Condvar,
+}
+
+struct IOInner {
+    io: Box<PartitionIO>,
+    // Logs are written in the order their commits were taken (see
+    // `Partition::take_unsaved()`): this is the next ticket to serve.
+    served: u64,
+}
+
+impl SharedIO {
+    fn new(io: Box<PartitionIO>) -> Arc<SharedIO> {
+        Arc::new(SharedIO {
+            inner: Mutex::new(IOInner { io: io, served: 0 }),
+            cond: Condvar::new(),
+        })
+    }
+    fn lock(&self) -> MutexGuard<IOInner> {
+        // IO providers are not expected to leave themselves inconsistent on panic
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    // Lock once all tickets before `ticket` have been served
+    fn lock_turn(&self, ticket: u64) -> MutexGuard<IOInner> {
+        let mut inner = self.lock();
+        while inner.served < ticket {
+            inner = self.cond.wait(inner).unwrap_or_else(|e| e.into_inner());
+        }
+        inner
+    }
+    // Mark `ticket` served and unlock
+    fn serve(&self, mut inner: MutexGuard<IOInner>, ticket: u64) {
+        inner.served = ticket + 1;
+        drop(inner);
+        self.cond.notify_all();
+    }
+    
+    fn ss_len(&self) -> usize { self.lock().io.ss_len() }
+    fn ss_cl_len(&self, ss_num: usize) -> usize { self.lock().io.ss_cl_len(ss_num) }
+    fn ss_size(&self, ss_num: usize) -> Result<Option<u64>> { self.lock().io.ss_size(ss_num) }
+    fn ss_cl_size(&self, ss_num: usize, cl_num: usize) -> Result<Option<u64>> {
+        self.lock().io.ss_cl_size(ss_num, cl_num)
+    }
+    fn read_ss(&self, ss_num: usize) -> Result<Option<io::Cursor<Vec<u8>>>> {
+        let inner = self.lock();
+        let result = match try!(inner.io.read_ss(ss_num)) {
+            Some(r) => read_all(r).map(Some),
+            None => Ok(None),
+        };
+        result
+    }
+    fn read_ss_cl(&self, ss_num: usize, cl_num: usize) -> Result<Option<io::Cursor<Vec<u8>>>> {
+        let inner = self.lock();
+        let result = match try!(inner.io.read_ss_cl(ss_num, cl_num)) {
+            Some(r) => read_all(r).map(Some),
+            None => Ok(None),
+        };
+        result
+    }
+    fn can_delete(&self) -> bool { self.lock().io.can_delete() }
+    fn sync_ss(&self, ss_num: usize) -> Result<()> { self.lock().io.sync_ss(ss_num) }
+    fn delete_ss(&self, ss_num: usize) -> Result<bool> { self.lock().io.delete_ss(ss_num) }
+    fn delete_ss_cl(&self, ss_num: usize, cl_num: usize) -> Result<bool> {
+        self.lock().io.delete_ss_cl(ss_num, cl_num)
+    }
+}
+
+fn read_all<'a>(mut r: Box<Read+'a>) -> Result<io::Cursor<Vec<u8>>> {
+    let mut buf = Vec::new();
+    try!(r.read_to_end(&mut buf));
+    Ok(io::Cursor::new(buf))
+}
+
+/// Unsaved changes taken from a partition by `Partition::take_unsaved()`.
+/// 
+/// Call `write()` (this does not need access to the partition, so the
+/// partition may be used meanwhile), then return this with
+/// `Partition::finish_write()`, which restores anything not written.
+/// Dropping this without `finish_write()` loses the taken commits from the
+/// list of unsaved commits (though not from memory).
+/// 
+/// Logs taken from a partition are written in the order they were taken;
+/// `write()` waits for earlier writes to finish.
+pub struct PendingWrite<E: ElementT> {
+    io: Arc<SharedIO>,
+    ticket: u64,
+    served: bool,
+    ss_num: usize,
+    // Logs to write, in order
+    logs: Vec<PendingLog<E>>,
+    // Number of logs written completely
+    written: usize,
+}
+
+// A log file to be written
+struct PendingLog<E: ElementT> {
+    header: FileHeader,
+    commits: VecDeque<Commit<E>>,
+}
+
+impl<E: ElementT> PendingWrite<E> {
+    /// True if there is nothing to write
+    pub fn is_empty(&self) -> bool {
+        self.logs.len() == self.written
+    }
+    
+    /// Write taken logs. Commits are written in order; on failure, those
+    /// not written are restored by `Partition::finish_write()`.
+    pub fn write(&mut self) -> Result<()> {
+        let mut inner = self.io.lock_turn(self.ticket);
+        let mut result = Ok(());
+        while self.written < self.logs.len() {
+            let log = &mut self.logs[self.written];
+            let n = log.commits.len();
+            if let Err(e) = Partition::<E>::write_log(&mut *inner.io, self.ss_num, &log.header,
+                    &mut log.commits, n) {
+                result = Err(e);
+                break;
+            }
+            self.written += 1;
+        }
+        self.io.serve(inner, self.ticket);
+        self.served = true;
+        result
+    }
+}
+
+impl<E: ElementT> Drop for PendingWrite<E> {
+    fn drop(&mut self) {
+        // Later writes wait for this ticket
+        if !self.served {
+            let inner = self.io.lock_turn(self.ticket);
+            self.io.serve(inner, self.ticket);
+        }
+    }
+}
+
+/// Determines when to write a new snapshot automatically.
+struct SnapshotPolicy {
+    required: bool,
+    commits: usize,
+    edits: usize,
+}
+impl SnapshotPolicy {
+    /// Create a new instance. Assume we have a fresh snapshot.
+    fn new() -> SnapshotPolicy { SnapshotPolicy {
+            required: false,
+            commits: 0,
+            edits: 0
+        }
+    }
+    /// Report that we definitely need a new snapshot
+    fn require(&mut self) { self.required = true; }
+    /// Report `n_commits` commits since last event.
+    fn add_commits(&mut self, n_commits: usize) { self.commits += n_commits; }
+    /// Report `n_edits` edits since last event.
+    fn add_edits(&mut self, n_edits: usize) { self.edits += n_edits; }
+    /// Report that we have a fresh snapshot
+    fn reset(&mut self) {
+        self.required = false;
+        self.commits = 0;
+        self.edits = 0;
+    }
+    /// Return true when we should write a snapshot
+    fn snapshot(&self) -> bool { self.required || self.commits * 5 + self.edits > 150 }
+}
+
+/// When commits are written to disk (see `Partition::set_durability()`).
+/// 
+/// Commits not yet written are lost if the program exits or crashes before
+/// `write()` is called.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Durability {
+    /// Commits are only written when `write()` is called (the default).
+    Manual,
+    /// Each commit is written before `push_state()` (or similar) returns.
+    WriteThrough,
+    /// Commits are written once there are at least `commits` unsaved commits
+    /// or the oldest unsaved commit is at least `max_age_millis` milliseconds
+    /// old.
+    /// 
+    /// This is checked when the next commit is made or `write_if_due()` is
+    /// called; there is no timer. If commits may stop for a while, call
+    /// `write_if_due()` periodically so that unsaved commits do not get older
+    /// than intended.
+    Group {
+        /// Maximum number of unsaved commits
+        commits: usize,
+        /// Maximum age of unsaved commits, in milliseconds, checked on the
+        /// next commit or call to `write_if_due()`
+        max_age_millis: u64,
+    },
+}
+
+/// A *partition* is a sub-set of the entire set such that (a) each element is
+/// in exactly one partition, (b) a partition is small enough to be loaded into
+/// memory in its entirety, (c) there is some user control over the number of
+/// partitions and how elements are assigned partitions and (d) each partition
+/// can be managed independently of other partitions.
+///
+/// Partitions are the *only* method by which the entire set may grow beyond
+/// available memory, thus smart allocation of elements to partitions will be
+/// essential for some use-cases.
+/// 
+/// A partition is in one of three possible states: (1) unloaded, (2) loaded
+/// but requiring a merge (multiple tips), (3) ready for use.
+pub struct Partition<E: ElementT> {
+    // IO provider
+    io: Arc<SharedIO>,
+    // Next ticket for writing logs (see `SharedIO`)
+    next_ticket: u64,
+    // Partition name. Used to identify loaded files.
+    repo_name: String,
+    // Partition identifier
+    part_id: PartId,
+    // Number of the current snapshot file
+    ss_num: usize,
+    // Determines when to write new snapshots
+    ss_policy: SnapshotPolicy,
+    // Known committed states indexed by statesum 
+    states: HashIndexed<PartitionState<E>, Sum, PartitionStateSumComparator>,
+    // All states without a known successor
+    tips: HashSet<Sum>,
+    // Commits created but not yet saved to disk. First in at front; use as queue.
+    unsaved: VecDeque<Commit<E>>,
+    // Named tags on states
+    tags: HashMap<String, Sum>,
+    // Tag changes not yet saved to disk, in order (`None` means removal)
+    unsaved_tags: Vec<(String, Option<Sum>)>,
+    // Named branches. The default branch is not included (see `tips`).
+    branches: HashMap<String, Branch<E>>,
+    // Branches deleted but not yet recorded on disk
+    deleted_branches: Vec<String>,
+    // A state from `bulk_load()` not yet saved as a snapshot
+    unsaved_snapshot: Option<Sum>,
+    // When commits are written
+    durability: Durability,
+    // Time the oldest unsaved commit was made, if any
+    unsaved_since: Option<Instant>,
+    // Maximum number of states to hold in memory, if limited
+    history_limit: Option<usize>,
+    // Where each state held in memory can be reloaded from (where known)
+    sources: HashMap<Sum, Source>,
+    // States dropped from memory to save space
+    evicted: HashMap<Sum, Evicted>,
+    // If true, compare states whenever sums match
+    verify: bool,
+    // Index definitions (empty), added to states loaded from snapshots
+    indexes: Indexes<E>,
+    // Identifier allocation strategy, given to states loaded from snapshots
+    allocator: Arc<IdAllocator>,
+    // Subscribers to change events
+    observers: Observers,
+    // Key used to sign new commits
+    signing_key: Option<Arc<SigningKey>>,
+    // Policy used to judge commits when replaying logs
+    trust: Arc<TrustPolicy>,
+    // Commits not replayed due to the trust policy
+    quarantine: Vec<Commit<E>>,
+    // States removed by compaction (here or by a peer), recorded in snapshots
+    removed: HashSet<Sum>,
+    // States read from or written to snapshots (kept by `gc()`)
+    snapshot_states: HashSet<Sum>,
+    // Reads snapshots if not `read_snapshot` (see `set_load_threads()`)
+    ss_reader: Option<Box<Fn(&mut Read, PartId, u32)
+            -> Result<(PartitionState<E>, Option<Signature>)> + Send>>,
+}
+
+// Methods creating a partition and loading its data
+impl<E: ElementT> Partition<E> {
+    /// Create a partition, assigning an IO provider (this can only be done at
+    /// time of creation). Create a blank state in the partition, write an
+    /// empty snapshot to the provided `PartitionIO`, and mark self as *ready
+    /// for use*.
+    /// 
+    /// Example:
+    /// 
+    /// ```
+    /// use pippin::partition::{Partition, PartitionDummyIO};
+    /// 
+    /// let io = Box::new(PartitionDummyIO::new());
+    /// let partition = Partition::<String>::create(io, "example repo");
+    /// ```
+    pub fn create(io: Box<PartitionIO>, name: &str) -> Result<Partition<E>> {
+        Self::create_part(io, name, PartId::from_num(1))
+    }
+    
+    /// As `create(io, name)` but with a specified partition number. For
+    /// single-partition usage the number isn't important; for multiple
+    /// partitions it is handled by the classifier and forwarded by `Repo`.
+    pub fn create_part(mut io: Box<PartitionIO>, name: &str,
+        part_id: PartId) -> Result<Partition<E>>
+    {
+        try!(validate_repo_name(name));
+        let ss = 0;
+        info!("Creating partiton {}; writing snapshot {}", part_id.into_num(), ss);
+        
+        let state = PartitionState::new(part_id);
+        let header = FileHeader::new(FileType::Snapshot(0), name.to_string(), Some(part_id));
+        if let Some(mut writer) = try!(io.new_ss(ss)) {
+            try!(write_head(&header, &mut writer));
+            try!(write_snapshot(&state, None, &mut writer));
+        } else {
+            return make_io_err(ErrorKind::AlreadyExists, "snapshot already exists");
+        }
+        
+        let mut part = Partition::open(io, part_id);
+        part.repo_name = header.name;
+        part.tips.insert(state.statesum().clone());
+        part.snapshot_states.insert(state.statesum().clone());
+        part.states.insert(state);
+        
+        Ok(part)
+    }
+    
+    /// Open a partition, assigning an IO provider (this can only be done at
+    /// time of creation).
+    /// 
+    /// The partition will not be *ready to use* until data is loaded with one
+    /// of the load operations. Until then most operations will fail.
+    /// 
+    /// If the repository name is known (e.g. from another partition), then
+    /// setting this with `set_repo_name()` will ensure that the value is
+    /// checked when loading files.
+    /// 
+    /// Example:
+    /// 
+    /// ```no_run
+    /// use std::path::Path;
+    /// use pippin::{PartId, Partition};
+    /// use pippin::discover::DiscoverPartitionFiles;
+    /// 
+    /// let path = Path::new(".");
+    /// let io = DiscoverPartitionFiles::from_dir_basename(path, "my-partition").unwrap();
+    /// let part_id = PartId::from_num(1);
+    /// let partition = Partition::<String>::open(Box::new(io), part_id);
+    /// ```
+    pub fn open(io: Box<PartitionIO>, part_id: PartId) -> Partition<E> {
+        trace!("Opening partition {}", part_id.into_num());
+        Partition {
+            io: SharedIO::new(io),
+            next_ticket: 0,
+            repo_name: "".to_string() /*temporary value; checked before usage elsewhere*/,
+            part_id: part_id,
+            ss_num: 0,
+            ss_policy: SnapshotPolicy::new(),
+            states: HashIndexed::new(),
+            tips: HashSet::new(),
+            unsaved: VecDeque::new(),
+            tags: HashMap::new(),
+            unsaved_tags: Vec::new(),
+            branches: HashMap::new(),
+            deleted_branches: Vec::new(),
+            unsaved_snapshot: None,
+            durability: Durability::Manual,
+            unsaved_since: None,
+            history_limit: None,
+            sources: HashMap::new(),
+            evicted: HashMap::new(),
+            verify: false,
+            indexes: Indexes::new(),
+            allocator: Arc::new(RandomIds),
+            observers: Observers::new(),
+            signing_key: None,
+            trust: Arc::new(AcceptAll),
+            quarantine: Vec::new(),
+            removed: HashSet::new(),
+            snapshot_states: HashSet::new(),
+            ss_reader: None,
+        }
+    }
+    
+    /// Set the repo name. This is left empty by `open()`. Once set,
+    /// partition operations will fail when loading a file with a different
+    /// name.
+    /// 
+    /// This will fail if the repo name has already been set *and* is not
+    /// equal to the `repo_name` parameter.
+    pub fn set_repo_name(&mut self, repo_name: &str) -> Result<()> {
+        if self.repo_name.len() == 0 {
+            self.repo_name = repo_name.to_string();
+        } else if self.repo_name != repo_name {
+            return OtherError::err("repository name does not match when loading (wrong repo?)");
+        }
+        Ok(())
+    }
+    
+    /// Get the repo name.
+    /// 
+    /// If this partition was created with `create()`, not `new()`, and no
+    /// partition has been loaded yet, then this function will read a snapshot
+    /// file header in order to find this name.
+    /// 
+    /// Returns the repo_name on success. Fails if it cannot read a header.
+    pub fn get_repo_name(&mut self) -> Result<&str> {
+        if self.repo_name.len() > 0 {
+            return Ok(&self.repo_name);
+        }
+        for ss in (0 .. self.io.ss_len()).rev() {
+            if let Some(mut ssf) = try!(self.io.read_ss(ss)) {
+                let header = try!(read_head(&mut ssf));
+                try!(Self::verify_head(header, &mut self.repo_name, self.part_id));
+                return Ok(&self.repo_name);
+            }
+        }
+        return OtherError::err("no snapshot found for first partition");
+    }
+    
+    /// Load either all history available or only that required to find the
+    /// latest state of the partition. Uses snapshot and log files provided by
+    /// the provided `PartitionIO`.
+    /// 
+    /// If `all_history == true`, all snapshots and commits found are loaded.
+    /// In this case it is possible that the history graph is not connected
+    /// (i.e. it has multiple unconnected sub-graphs). If this is the case,
+    /// the usual merge strategy will fail.
+    /// 
+    /// If the partition contains data before the load, any changes will be
+    /// committed (in-memory only) and newly loaded data will be seamlessly
+    /// merged with that already loaded. A merge may be required; it is also
+    /// possible that tips may be part of disconnected graphs and thus
+    /// unmergable as with `load_everything()`.
+    /// 
+    /// After the operation, the repository may be in one of three states: no
+    /// known states, one directed graph of one or more states with a single
+    /// tip (latest state), or a graph with multiple tips (requiring a merge
+    /// operation).
+    /// 
+    /// Commits whose parent was removed by compaction (see `compact()`), e.g.
+    /// those of a peer which had not seen the compaction, are quarantined
+    /// (see `quarantined()`). Loading fails on commits whose parent is not
+    /// found for any other reason.
+    pub fn load(&mut self, all_history: bool) -> Result<()> {
+        info!("Loading partition {} data", self.part_id.into_num());
+        let ss_len = self.io.ss_len();
+        if ss_len == 0 {
+            return make_io_err(ErrorKind::NotFound, "no snapshot files found");
+        }
+        // Replay needs parent states, which may have been evicted
+        let evicted: Vec<Sum> = self.evicted.keys().cloned().collect();
+        try!(self.restore(evicted.iter()));
+        let mut num = ss_len - 1;
+        
+        // Load a snapshot (if found); return Ok(true) if successful, Ok(false)
+        // if not found.
+        let load_ss = |p: &mut Partition<E>, ss: usize| -> Result<bool> {
+            if let Some(mut r) = try!(p.io.read_ss(ss)) {
+                let head = try!(read_head(&mut r));
+                let file_ver = head.ftype.ver();
+                let tags = head.tags.clone();
+                p.removed.extend(head.removed_states.iter().cloned());
+                try!(Self::verify_head(head, &mut p.repo_name, p.part_id));
+                let mut state = try!(p.decode_snapshot(&mut r, file_ver));
+                
+                // Snapshot headers list all tags
+                p.tags.clear();
+                apply_tag_changes(&mut p.tags, tags);
+                p.snapshot_states.insert(state.statesum().clone());
+                p.sources.insert(state.statesum().clone(), Source { ss: ss, snapshot: true });
+                
+                // If the state is already known (e.g. when reloading or
+                // replayed from logs), it is either a tip already or has a
+                // known successor.
+                if let Some(existing) = p.states.get(state.statesum()) {
+                    if p.verify && !existing.eq_elements(&state) {
+                        return Err(box PatchOp::Collision);
+                    }
+                    return Ok(true);
+                }
+                p.init_state(&mut state);
+                if !p.observers.is_empty() {
+                    p.observers.notify(&ChangeEvent {
+                        part_id: p.part_id,
+                        branch: None,
+                        statesum: state.statesum().clone(),
+                        parents: state.parents().clone(),
+                        changes: None,
+                    });
+                }
+                p.tips.insert(state.statesum().clone());
+                p.states.insert(state);
+                Ok(true)
+            } else { Ok(false) }
+        };
+        // Load and replay all found log files for the given range of snapshot
+        // numbers. Each file is replayed onto the tips of the default branch
+        // or of the named branch given in its header. If `latest`, logs of
+        // branches whose base is not loaded are skipped. Returns the number
+        // of commits and of edits, and whether any log was skipped.
+        let load_cl = |p: &mut Partition<E>, range, latest: bool| -> Result<(usize, usize, bool)> {
+            let (mut num_commits, mut num_edits, mut skipped) = (0, 0, false);
+            for ss in range {
+                for cl in 0..p.io.ss_cl_len(ss) {
+                    if let Some(mut r) = try!(p.io.read_ss_cl(ss, cl)) {
+                        let head = try!(read_head(&mut r));
+                        if let Some((ref name, ref base)) = head.branch {
+                            if latest && !p.branches.contains_key(name) &&
+                                    !p.states.contains(base) {
+                                skipped = true;
+                                continue;
+                            }
+                        }
+                        let tags = head.tags.clone();
+                        let branch = head.branch.clone();
+                        let deleted = head.deleted_branches.clone();
+                        try!(Self::verify_head(head, &mut p.repo_name, p.part_id));
+                        apply_tag_changes(&mut p.tags, tags);
+                        for name in deleted {
+                            p.branches.remove(&name);
+                        }
+                        
+                        let mut queue = CommitQueue::new();
+                        try!(read_log(&mut r, &mut queue));
+                        num_commits += queue.len();
+                        let new_sums: Vec<Sum> = queue.iter()
+                                .map(|commit| commit.statesum())
+                                .filter(|sum| !p.states.contains(sum))
+                                .cloned().collect();
+                        // Events for commits giving new states, sent once replayed
+                        let mut events = Vec::new();
+                        if !p.observers.is_empty() {
+                            let name = branch.as_ref().map(|&(ref name, _)| name.as_str());
+                            for commit in queue.iter() {
+                                if !p.states.contains(commit.statesum()) {
+                                    events.push(ChangeEvent::from_commit(p.part_id, name, commit));
+                                }
+                            }
+                        }
+                        let tips = match branch {
+                            Some((name, base)) => &mut p.branches.entry(name)
+                                    .or_insert_with(|| Branch::new(base)).tips,
+                            None => &mut p.tips,
+                        };
+                        let quarantined = {
+                            let mut replayer = LogReplay::from_sets(&mut p.states, tips);
+                            replayer.set_verify(p.verify);
+                            replayer.set_trust_policy(Some(p.trust.clone()));
+                            replayer.add_quarantined_sums(p.quarantine.iter().map(|c| c.statesum()));
+                            replayer.add_removed_sums(p.removed.iter());
+                            num_edits += try!(replayer.replay(queue));
+                            replayer.take_quarantined()
+                        };
+                        Self::add_quarantined(&mut p.quarantine, quarantined);
+                        for sum in new_sums {
+                            if p.states.contains(&sum) {
+                                p.sources.insert(sum, Source { ss: ss, snapshot: false });
+                            }
+                        }
+                        for event in events {
+                            // Quarantined commits give no state
+                            if p.states.contains(&event.statesum) {
+                                p.observers.notify(&event);
+                            }
+                        }
+                    }
+                }
+            }
+            Ok((num_commits, num_edits, skipped))
+        };
+        
+        let mut all_history = all_history;
+        if !all_history {
+            // Latest only: load only the latest snapshot and subsequent commits
+            loop {
+                if try!(load_ss(self, num)) {
+                    break;  // we stop at the most recent snapshot we find
+                }
+                if num == 0 {
+                    // no more snapshot numbers to try; below we insert an empty state
+                    // #0017: we should warn about the missing snapshot file
+                    break;
+                }
+                num -= 1;
+            }
+            
+            if self.tips.is_empty() {
+                // Only for the case we couldn't find a snapshot file (see "num == 0" above)
+                let mut state = PartitionState::new(self.part_id);
+                self.init_state(&mut state);
+                self.tips.insert(state.statesum().clone());
+                self.states.insert(state);
+            }
+            let (num_commits, num_edits, skipped) = try!(load_cl(self, num..ss_len, true));
+            if skipped {
+                // A branch is based on a state from before the snapshot; its
+                // history and the default branch's are needed to merge it
+                all_history = true;
+            } else {
+                self.ss_policy.add_commits(num_commits);
+                self.ss_policy.add_edits(num_edits);
+            }
+        }
+        if all_history {
+            // Removed states are needed to replay logs of old snapshots; the
+            // latest snapshot lists all
+            for ss in (0..ss_len).rev() {
+                if let Some(mut r) = try!(self.io.read_ss(ss)) {
+                    let head = try!(read_head(&mut r));
+                    self.removed.extend(head.removed_states.into_iter());
+                    break;
+                }
+            }
+            
+            // All history: load all snapshots and commits in order
+            let mut num_commits = 0;
+            let mut num_edits = 0;
+            for ss in 0..ss_len {
+                try!(load_ss(self, ss));
+                
+                // final values are numbers after last snapshot
+                let (commits, edits, _) = try!(load_cl(self, ss..(ss+1), false));
+                num_commits = commits;
+                num_edits = edits;
+            }
+            self.ss_policy.add_commits(num_commits);
+            self.ss_policy.add_edits(num_edits);
+        }
+        
+        // Changes not yet saved take precedence over those loaded:
+        apply_tag_changes(&mut self.tags, self.unsaved_tags.clone());
+        for name in &self.deleted_branches {
+            if self.branches.get(name).map_or(false, |b| b.unsaved_base.is_none()) {
+                self.branches.remove(name);
+            }
+        }
+        
+        self.ss_num = ss_len - 1;
+        if num < ss_len -1 {
+            self.ss_policy.require();
+        } else {
+        }
+        
+        if self.tips.is_empty() {
+            make_io_err(ErrorKind::NotFound, "load operation found no states")
+        } else {
+            // success, but a merge may still be required
+            self.evict();
+            Ok(())
+        }
+    }
+    
+    /// Returns true when elements have been loaded (though also see
+    /// `merge_required`).
+    pub fn is_loaded(&self) -> bool {
+        self.tips.len() > 0
+    }
+    
+    /// Returns true when ready for use (this is equivalent to
+    /// `part.is_loaded() && !part.merge_required()`).
+    pub fn is_ready(&self) -> bool {
+        self.tips.len() == 1
+    }
+    
+    /// Returns true while a merge is required.
+    /// 
+    /// Returns false if not ready or no tip is found as well as when a single
+    /// tip is present and ready to use.
+    pub fn merge_required(&self) -> bool {
+        self.tips.len() > 1
+    }
+    
+    /// Verify values in a header match those we expect.
+    /// 
+    /// This function is called for every file loaded. It does not take self as
+    /// an argument, since it is called in situations where self.io is in use.
+    pub fn verify_head(head: FileHeader, self_name: &mut String,
+        self_partid: PartId) -> Result<()>
+    {
+        if self_name.len() == 0 {
+            *self_name = head.name;
+        } else if *self_name != head.name{
+            return OtherError::err("repository name does not match when loading (wrong repo?)");
+        }
+        if let Some(h_pid) = head.part_id {
+            if self_partid != h_pid {
+                return OtherError::err("partition identifier differs from previous value");
+            }
+        }
+        Ok(())
+    }
+    
+    /// Unload data from memory. Note that unless `force == true` the operation
+    /// will fail if any changes (including tag changes) have not yet been
+    /// saved to disk.
+    /// 
+    /// Returns true if data was unloaded, false if not (implies `!force` and 
+    /// that unsaved changes exist).
+    pub fn unload(&mut self, force: bool) -> bool {
+        trace!("Unloading partition {} data", self.part_id.into_num());
+        if force || !self.has_unsaved() {
+            self.states.clear();
+            self.sources.clear();
+            self.evicted.clear();
+            self.tips.clear();
+            self.tags.clear();
+            self.branches.clear();
+            self.unsaved_snapshot = None;
+            true
+        } else {
+            false
+        }
+    }
+    
+    /// Consume the `Partition` and return the held `PartitionIO`.
+    /// 
+    /// This destroys all states held internally, but states may be cloned
+    /// before unwrapping. Since `Element`s are copy-on-write, cloning
+    /// shouldn't be too expensive.
+    pub fn unwrap_io(self) -> Box<PartitionIO> {
+        // We can't move out of a type implementing `Drop`, so swap instead
+        let mut io: Box<PartitionIO> = box PartitionDummyIO::new();
+        mem::swap(&mut io, &mut self.io.lock().io);
+        io
+    }
+    
+    /// Get the partition's number
+    pub fn part_id(&self) -> PartId {
+        self.part_id
+    }
+}
+
+// Methods requiring thread-safe elements
+impl<E: ElementT + Send + Sync + 'static> Partition<E> {
+    /// Set the number of threads used to checksum and decode elements when
+    /// reading snapshots. Reading itself remains sequential; elements are
+    /// decoded in chunks by worker threads started for each snapshot. The
+    /// default is 1 (no extra threads). Loaded states are the same either way.
+    pub fn set_load_threads(&mut self, threads: usize) {
+        self.ss_reader = if threads > 1 {
+            Some(box (move |r: &mut Read, part_id: PartId, file_ver: u32|
+                read_snapshot_parallel(r, part_id, file_ver, threads)))
+        } else {
+            None
+        };
+    }
+}
+
+// Methods saving a partition's data
+impl<E: ElementT> Partition<E> {
+    /// Get the state-sum (key) of the tip. Fails when `tip()` fails.
+    pub fn tip_key(&self) -> result::Result<&Sum, TipError> {
+        if self.tips.len() == 1 {
+            Ok(self.tips.iter().next().unwrap())
+        } else if self.tips.is_empty() {
+            Err(TipError::NotReady)
+        } else {
+            Err(TipError::MergeRequired)
+        }
+    }
+    
+    /// Get a reference to the PartitionState of the current tip. You can read
+    /// this directly or make a clone in order to make your modifications.
+    /// 
+    /// This operation will fail if no data has been loaded yet or a merge is
+    /// required.
+    /// 
+    /// The operation requires some copying but uses copy'c,d-on-write elements
+    /// internally. This copy is needed to create a commit from the diff of the
+    /// last committed state and the new state.
+    pub fn tip(&self) -> result::Result<&PartitionState<E>, TipError> {
+        Ok(&self.states.get(try!(self.tip_key())).unwrap())
+    }
+    
+    /// Add a secondary index over elements (see `PartitionState::add_index()`).
+    /// 
+    /// The index is built on all tips (including those of branches); new
+    /// states inherit it from their parents and states loaded from snapshots
+    /// have it rebuilt. Other historical states are not indexed.
+    /// 
+    /// Fails if an index with this name already exists.
+    pub fn add_index<K, F>(&mut self, name: &str, extract: F) -> Result<()>
+        where E: 'static, K: Hash + Eq + Clone + Send + Sync + 'static,
+            F: Fn(&E) -> Option<K> + Send + Sync + 'static
+    {
+        if self.indexes.contains(name) {
+            return ArgError::err("index already exists");
+        }
+        let index: Index<E, K> = Index::new(Arc::new(extract));
+        let mut tips: HashSet<Sum> = self.tips.clone();
+        for branch in self.branches.values() {
+            tips.extend(branch.tips.iter().cloned());
+        }
+        for tip in tips {
+            if let Some(mut state) = self.states.remove(&tip) {
+                state.add_index_like(name, &index);
+                self.states.insert(state);
+            }
+        }
+        self.indexes.add(name.to_string(), box index);
+        Ok(())
+    }
+    
+    /// Check whether an index with this name exists (see `add_index()`).
+    pub fn has_index(&self, name: &str) -> bool {
+        self.indexes.contains(name)
+    }
+    
+    /// Subscribe to change events: `f` is called for each new state, whether
+    /// committed locally (including merges) or found while loading. See
+    /// `observe::ChangeEvent`.
+    /// 
+    /// States reloaded after eviction (see `set_history_limit()`) are not
+    /// reported.
+    pub fn subscribe<F: FnMut(&ChangeEvent) + Send + 'static>(&mut self, f: F) -> ObserverId {
+        let id = ObserverId::new();
+        self.observers.add(id, box f);
+        id
+    }
+    /// Subscribe with a given identifier (see `Repo::subscribe()`).
+    pub fn subscribe_with_id(&mut self, id: ObserverId, f: Box<FnMut(&ChangeEvent) + Send>) {
+        self.observers.add(id, f);
+    }
+    /// Remove a subscription. Returns true if found.
+    pub fn unsubscribe(&mut self, id: ObserverId) -> bool {
+        self.observers.remove(id)
+    }
+    
+    // Add to a quarantine, ignoring commits already there
+    fn add_quarantined(quarantine: &mut Vec<Commit<E>>, commits: Vec<Commit<E>>) {
+        for commit in commits {
+            if !quarantine.iter().any(|c| c.statesum() == commit.statesum()) {
+                quarantine.push(commit);
+            }
+        }
+    }
+    
+    // Read a state from a snapshot (after the header). Fails unless the trust
+    // policy accepts the snapshot's signature. The initial (empty) state is
+    // written unsigned by `create()` and needs no signature.
+    fn decode_snapshot(&self, r: &mut Read, file_ver: u32) -> Result<PartitionState<E>> {
+        let (state, signature) = try!(match self.ss_reader {
+            Some(ref reader) => reader(r, self.part_id, file_ver),
+            None => read_snapshot(r, self.part_id, file_ver),
+        });
+        if state.parents().is_empty() && *state.statesum() == Sum::zero() {
+            return Ok(state);
+        }
+        match self.trust.judge(&snapshot_signature_status(&state, signature.as_ref())) {
+            Verdict::Accept => Ok(state),
+            _ => ReplayError::err("snapshot not accepted by trust policy"),
+        }
+    }
+    
+    // Apply the partition's indexes and identifier allocator to a state
+    // created from scratch or read from a snapshot.
+    fn init_state(&self, state: &mut PartitionState<E>) {
+        for (name, index) in self.indexes.iter() {
+            state.add_index_like(name, index);
+        }
+        state.set_id_allocator(self.allocator.clone());
+    }
+    
+    /// Set the strategy used to allocate identifiers for new elements (see
+    /// `ids`). This applies to all states in memory and states loaded later;
+    /// the default is `ids::RandomIds`.
+    pub fn set_id_allocator(&mut self, allocator: Arc<IdAllocator>) {
+        let sums: Vec<Sum> = self.states.iter().map(|s| s.statesum().clone()).collect();
+        for sum in sums {
+            let mut state = self.states.remove(&sum).expect("state exists");
+            state.set_id_allocator(allocator.clone());
+            self.states.insert(state);
+        }
+        self.allocator = allocator;
+    }
+    /// Get the strategy used to allocate identifiers for new elements
+    pub fn id_allocator(&self) -> &Arc<IdAllocator> {
+        &self.allocator
+    }
+    
+    /// Set a key with which to sign new commits and snapshots (see `sign`),
+    /// or `None` to stop signing. Commits made on this partition are signed
+    /// when pushed; snapshots when written.
+    pub fn set_signing_key(&mut self, key: Option<Arc<SigningKey>>) {
+        self.signing_key = key;
+    }
+    /// Get the key used to sign new commits, if any
+    pub fn signing_key(&self) -> Option<&Arc<SigningKey>> {
+        self.signing_key.as_ref()
+    }
+    /// Set the policy used to judge commits read from logs and snapshots
+    /// (see `sign`). This affects files loaded later; the default is
+    /// `sign::AcceptAll`. Loading fails on snapshots not accepted.
+    pub fn set_trust_policy(&mut self, trust: Arc<TrustPolicy>) {
+        self.trust = trust;
+    }
+    /// Get the policy used to judge commits read from logs
+    pub fn trust_policy(&self) -> &Arc<TrustPolicy> {
+        &self.trust
+    }
+    /// Get commits read from logs but not replayed due to the trust policy,
+    /// because a parent was quarantined or because the parent state was
+    /// removed by `compact()`, in the order found.
+    pub fn quarantined(&self) -> &[Commit<E>] {
+        &self.quarantine
+    }
+    /// Remove and return all quarantined commits. Commits found again when
+    /// reloading are quarantined again.
+    pub fn clear_quarantine(&mut self) -> Vec<Commit<E>> {
+        mem::replace(&mut self.quarantine, Vec::new())
+    }
+    /// True if the tip is running low on element identifiers (see
+    /// `PartitionState::ids_low()`); the partition should then be divided.
+    /// 
+    /// Fails when `tip()` fails.
+    pub fn ids_low(&self) -> result::Result<bool, TipError> {
+        Ok(try!(self.tip()).ids_low())
+    }
+    
+    /// Query an index on the tip (see `PartitionState::query()`).
+    pub fn query<K>(&self, index: &str, key: &K) -> Result<Vec<EltId>>
+        where E: 'static, K: Hash + Eq + Clone + 'static
+    {
+        try!(self.tip()).query(index, key)
+    }
+    
+    /// Iterate over the elements of the tip (see `State::iter()`).
+    /// 
+    /// Fails when `tip()` fails.
+    pub fn iter(&self) -> result::Result<EltIter<E>, TipError> {
+        Ok(try!(self.tip()).iter())
+    }
+    
+    /// Iterate over the elements of the tip in order of identifier (see
+    /// `State::iter_ordered()`).
+    pub fn iter_ordered(&self) -> result::Result<EltIter<E>, TipError> {
+        Ok(try!(self.tip()).iter_ordered())
+    }
+    
+    /// Iterate over the elements of the tip matching `predicate` (see
+    /// `State::filter()`).
+    pub fn filter<'a, P>(&'a self, predicate: P) -> result::Result<EltIter<'a, E>, TipError>
+        where P: FnMut(&E) -> bool + 'a
+    {
+        Ok(try!(self.tip()).filter(predicate))
+    }
+    
+    /// Get a read-only reference to a state by its statesum, if found.
+    /// 
+    /// If you want to keep a copy, clone it.
+    /// 
+    /// States evicted from memory (see `set_history_limit()`) are not found;
+    /// use `load_state()` to reload them.
+    pub fn state(&self, key: &Sum) -> Option<&PartitionState<E>> {
+        self.states.get(key)
+    }
+    
+    /// Get a state by its statesum, reloading it from disk if it was evicted
+    /// (see `set_history_limit()`).
+    pub fn load_state(&mut self, key: &Sum) -> Result<&PartitionState<E>> {
+        try!(self.restore(Some(key).into_iter()));
+        match self.states.get(key) {
+            Some(state) => Ok(state),
+            None => OtherError::err("state not found"),
+        }
+    }
+    
+    /// Try to find a state given a tag name or a string representation of the
+    /// key (as a byte array).
+    /// 
+    /// Tags are checked first. Like git, we accept partial keys (so long as
+    /// they uniquely resolve a key).
+    pub fn state_from_string(&self, string: String) -> Result<&PartitionState<E>, MatchError> {
+        if let Some(state) = self.tags.get(&string).and_then(|sum| self.states.get(sum)) {
+            return Ok(state);
+        }
+        let string = string.to_uppercase().replace(" ", "");
+        let mut matching = Vec::new();
+        for state in self.states.iter() {
+            if state.statesum().matches_string(&string.as_bytes()) {
+                matching.push(state.statesum());
+            }
+            if matching.len() > 1 {
+                return Err(MatchError::MultiMatch(
+                    matching[0].as_string(false), matching[1].as_string(false)));
+            }
+        }
+        if matching.len() == 1 {
+            Ok(self.states.get(&matching[0]).unwrap())
+        } else {
+            Err(MatchError::NoMatch)
+        }
+    }
+    
+    /// Get the state which was current at the given time (a UNIX timestamp,
+    /// see `CommitMeta::timestamp`).
+    /// 
+    /// This follows first parents from the tip until a state with a timestamp
+    /// no later than `timestamp` is found, reloading evicted states on the way
+    /// (see `set_history_limit()`). If history is not loaded far enough back,
+    /// all history is loaded (`load(true)`).
+    /// 
+    /// Fails if a merge is required, or if all known states are newer than
+    /// the given time.
+    pub fn state_at(&mut self, timestamp: i64) -> Result<&PartitionState<E>> {
+        let key = match try!(self.find_state_at(timestamp)) {
+            Some(key) => key,
+            None => {
+                try!(self.load(true));
+                match try!(self.find_state_at(timestamp)) {
+                    Some(key) => key,
+                    None => { return OtherError::err("history not found"); }
+                }
+            }
+        };
+        Ok(self.states.get(&key).expect("state exists"))
+    }
+    
+    // Find the key of the state current at `timestamp`, restoring evicted
+    // states along the first-parent chain, or `None` if a parent is not
+    // loaded.
+    fn find_state_at(&mut self, timestamp: i64) -> Result<Option<Sum>> {
+        let mut key = try!(self.tip_key()).clone();
+        loop {
+            try!(self.restore(Some(&key).into_iter()));
+            let parent = match self.states.get(&key) {
+                Some(state) if state.meta().timestamp > timestamp => state.parents().first().cloned(),
+                Some(_) => { return Ok(Some(key)); },
+                None => { return Ok(None); },
+            };
+            key = match parent {
+                Some(parent) => parent,
+                None => { return OtherError::err("no state at or before the given time"); }
+            };
+        }
+    }
+    
+    /// Merge all latest states into a single tip.
+    /// 
+    /// This is a convenience version of `merge_two`.
+    /// 
+    /// Given more than two tips, there are multiple orders in which a merge
+    /// could take place, or one could in theory merge more than two tips at
+    /// once. This function simply selects any two tips and merges, then
+    /// repeats until done.
+    pub fn merge<S: TwoWaySolver<E>+?Sized>(&mut self, solver: &S) -> Result<()> {
+        trace!("Partition::merge ({} tips)", self.tips.len());
+        while self.tips.len() > 1 {
+            let c = {
+                let mut merger = try!(self.merge_two());
+                merger.solve(solver);
+                merger.make_commit()
+            };
+            if let Some(commit) = c {
+                trace!("Pushing merge commit: {} ({} changes)", commit.statesum(), commit.num_changes());
+                try!(self.push_commit(commit));
+            } else {
+                return OtherError::err("merge failed");
+            }
+        }
+        Ok(())
+    }
+    
+    /// Create a `TwoWayMerge` for any two tips. Use this to make a commit,
+    /// then call `push_commit()`. Repeat while `self.merge_required()` holds
+    /// true.
+    /// 
+    /// This is not eligant, but provides the user full control over the merge.
+    /// Alternatively, use `self.merge(solver)`.
+    pub fn merge_two(&mut self) -> Result<TwoWayMerge<E>> {
+        if self.tips.len() < 2 {
+            return OtherError::err("merge_two() called when no states need merging");
+        }
+        // TODO: order is randomised (hash security). We want this operation to
+        // be reproducible, so should order tips or something.
+        let (tip1, tip2) = {
+            let mut iter = self.tips.iter();
+            let tip1 = iter.next().unwrap().clone();
+            let tip2 = iter.next().unwrap().clone();
+            (tip1, tip2)
+        };
+        let common = try!(self.latest_common_ancestor(&tip1, &tip2));
+        try!(self.restore(Some(&common).into_iter()));
+        let common_state = match self.states.get(&common) {
+            Some(state) => state,
+            None => { return OtherError::err("common ancestor state not found"); }
+        };
+        Ok(TwoWayMerge::new(
+            self.states.get(&tip1).unwrap(),
+            self.states.get(&tip2).unwrap(),
+            common_state))
+    }
+    
+    /// Create a new commit on the tip undoing the changes made when state
+    /// `sum` was created (i.e. the changes between its first parent and it).
+    /// 
+    /// Elements not touched since state `sum` are restored automatically.
+    /// Where an element has been changed again since (so that both the
+    /// reverted change and the later change would be lost or kept), the
+    /// conflict is passed to `solver`.
+    /// 
+    /// Returns true if a commit was created, false if there was nothing to
+    /// undo. Fails if the partition is not ready (see `tip()`), if state
+    /// `sum` or its parent is not loaded or if `solver` leaves any conflict
+    /// unresolved.
+    /// 
+    /// When the result equals a state already known (e.g. when reverting the
+    /// latest commit, giving its parent), the commit is still recorded on the
+    /// tip, but no new state is created: states are identified by their
+    /// elements, so the known state becomes the tip again (see
+    /// `push_commit()`).
+    pub fn revert<S: TwoWaySolver<E>+?Sized>(&mut self, sum: &Sum, solver: &S) -> Result<bool> {
+        let parent = try!(self.first_parent(sum));
+        // Merge the tip with the parent, taking the reverted state as the
+        // common ancestor: changes from `sum` to `parent` then look like
+        // changes made on a second tip.
+        self.apply_changes(&parent, sum, solver)
+    }
+    
+    /// Create a new commit on the tip re-applying the changes made when state
+    /// `sum` was created (i.e. the changes between its first parent and it).
+    /// 
+    /// State `sum` need not be an ancestor of the tip. Where the tip has a
+    /// different version of an element changed by that commit, the conflict
+    /// is passed to `solver`.
+    /// 
+    /// Return value and failures are as for `revert()`.
+    pub fn cherry_pick<S: TwoWaySolver<E>+?Sized>(&mut self, sum: &Sum, solver: &S) -> Result<bool> {
+        let parent = try!(self.first_parent(sum));
+        self.apply_changes(sum, &parent, solver)
+    }
+    
+    /// Get the differences between two states, identified by their sums.
+    /// 
+    /// The states need not be related; the result lists the changes needed
+    /// to get from `old` to `new`. Fails if either state is not loaded.
+    /// Evicted states are reloaded (see `set_history_limit()`).
+    pub fn diff(&mut self, old: &Sum, new: &Sum) -> Result<StateDiff<E>> {
+        try!(self.restore(vec![old, new].into_iter()));
+        match (self.states.get(old), self.states.get(new)) {
+            (Some(s1), Some(s2)) => Ok(StateDiff::between(s1, s2)),
+            _ => OtherError::err("diff: state not found"),
+        }
+    }
+    
+    /// Tag a state with a name. If the tag already exists it is moved to the
+    /// given state.
+    /// 
+    /// Tags are saved to the partition's files by `write()` and loaded by
+    /// `load()`. Tagged states should not be discarded by operations which
+    /// remove history.
+    /// 
+    /// Fails if the name is empty, too long or contains null bytes, or if the
+    /// state is not loaded.
+    pub fn tag(&mut self, name: &str, sum: &Sum) -> Result<()> {
+        try!(validate_tag_name(name));
+        if !self.states.contains(sum) {
+            return OtherError::err("cannot tag a state which is not loaded");
+        }
+        self.tags.insert(name.to_string(), sum.clone());
+        self.unsaved_tags.push((name.to_string(), Some(sum.clone())));
+        Ok(())
+    }
+    
+    /// Remove a tag. Returns true if the tag existed.
+    pub fn untag(&mut self, name: &str) -> bool {
+        if self.tags.remove(name).is_some() {
+            self.unsaved_tags.push((name.to_string(), None));
+            true
+        } else {
+            false
+        }
+    }
+    
+    /// Iterate over all tags (name and state-sum). The tagged state may not be
+    /// loaded (see `load()`).
+    pub fn tags(&self) -> hash_map::Iter<String, Sum> {
+        self.tags.iter()
+    }
+    
+    /// Get the state-sum for a tag, if found.
+    pub fn resolve_tag(&self, name: &str) -> Option<&Sum> {
+        self.tags.get(name)
+    }
+    
+    /// True if any tag refers to this state.
+    pub fn is_tagged(&self, sum: &Sum) -> bool {
+        self.tags.values().any(|s| s == sum)
+    }
+    
+    // #0003: allow getting a reference to other states listing snapshots,
+    // commits, getting non-current states and getting diffs.
+    
+    /// This adds a new commit to the list waiting to be written and updates
+    /// the states and 'tips' stored internally by creating a new state from
+    /// the commit.
+    /// 
+    /// Fails if there is a checksum collision (only detected when verifying;
+    /// see `set_verify()`) or the patch does not apply. A commit whose state
+    /// is already known (e.g. a fast-forward merge, or a revert returning to
+    /// an earlier state) creates no new state; the known state becomes a tip,
+    /// as when such a commit is replayed from a log.
+    /// 
+    /// TODO: this operation should not fail, since failure might result in
+    /// data loss.
+    pub fn push_commit(&mut self, commit: Commit<E>) -> Result<()> {
+        try!(self.push_commit_on(None, commit));
+        self.flush_if_due()
+    }
+    
+    /// This adds a new state to the partition, updating the 'tip', and adds a
+    /// new commit to the internal list waiting to be written to permanent
+    /// storage (see `write()`).
+    /// 
+    /// A merge might be required after calling this (if the parent state of
+    /// that passed is no longer a 'tip').
+    /// 
+    /// The commit is created from the state passed by finding the state's
+    /// parent and comparing. If there are no changes, nothing happens and
+    /// this function returns false, otherwise the function returns true.
+    /// 
+    /// Depending on the durability setting (`set_durability()`), the commit
+    /// may be written immediately, in which case this can fail due to IO
+    /// errors (the commit remains unsaved).
+    /// 
+    /// TODO: this operation should not fail, since failure might result in
+    /// data loss.
+    pub fn push_state(&mut self, state: PartitionState<E>) -> Result<bool> {
+        if let Some(commit) = try!(self.check_state(&state)) {
+            self.push_checked(commit, state);
+            try!(self.flush_if_due());
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+    
+    /// Do the checks of `push_state()` without committing: returns the commit
+    /// which would be made, or `None` if there are no changes. Fails where
+    /// `push_state()` would, except for writing.
+    /// 
+    /// This allows several partitions to be checked before committing to any
+    /// (as `Repo::transaction()` does); pass the result to `push_checked()`.
+    /// Evicted states may be restored, but nothing else changes.
+    pub fn check_state(&mut self, state: &PartitionState<E>) -> Result<Option<Commit<E>>> {
+        try!(self.restore(state.parents().iter().take(1)));
+        Ok(try!(self.commit_from_state(state)))
+    }
+    
+    /// Commit a state checked by `check_state()`, given the commit returned.
+    /// This must be called before any other change to the partition.
+    /// 
+    /// Unlike `push_state()`, this never writes; the durability setting can
+    /// be applied afterwards with `write_if_due()`.
+    pub fn push_checked(&mut self, commit: Commit<E>, state: PartitionState<E>) {
+        self.add_pair(None, commit, Some(state));
+    }
+    
+    // Create a commit from a state and its parent, or return `None` if there
+    // are no changes.
+    fn commit_from_state(&self, state: &PartitionState<E>) -> Result<Option<Commit<E>>, PatchOp> {
+        if self.verify {
+            if let Some(existing) = self.states.get(state.statesum()) {
+                if !existing.eq_elements(state) {
+                    return Err(PatchOp::Collision);
+                }
+            }
+        }
+        let c = if state.parents().len() == 1 && state.parents()[0] == *state.statesum() {
+            // Checksum equals that of parent: no changes
+            None
+        } else {
+            // `state` should have been created via `clone_child()` and should
+            // have one parent. If it wasn't, it doesn't matter except that
+            // someone pushing a merge result to create a commit will lose
+            // other parents. This shouldn't happen anyway.
+            match state.parents().iter().next().and_then(|p| self.states.get(p)) {
+                Some(ref parent) => Commit::from_state(parent, state),
+                None => { return Err(PatchOp::NoParent); },
+            }
+        };
+        Ok(c)
+    }
+    
+    // Push a commit to the default branch (`None`) or a named branch, which
+    // must exist.
+    fn push_commit_on(&mut self, branch: Option<&str>, commit: Commit<E>) -> Result<(), PatchOp> {
+        if let Some(existing) = self.states.get(commit.statesum()) {
+            if self.verify {
+                let mut state = match commit.parents().iter().next()
+                        .and_then(|p| self.states.get(p))
+                {
+                    Some(ref state) => state.clone_child(),
+                    None => return Err(PatchOp::NoParent),
+                };
+                try!(commit.patch(&mut state));
+                if !existing.eq_elements(&state) {
+                    return Err(PatchOp::Collision);
+                }
+            }
+            // There is no new state; the known one replaces the parents as a tip
+            self.add_pair(branch, commit, None);
+            return Ok(());
+        }
+        let mut state = match  commit.parents().iter().next()
+                .and_then(|p| self.states.get(p))
+        {
+            Some(ref state) => state.child_with_parents(commit.parents().clone()),
+            None => return Err(PatchOp::NoParent),
+        };
+        try!(commit.patch(&mut state));
+        self.add_pair(branch, commit, Some(state));
+        Ok(())
+    }
+    
+    // Add a commit and its state (`None` if the state is already known) to
+    // the default branch (`None`) or a named branch.
+    // Assumptions: checksums match, parent state is present and the branch
+    // exists.
+    fn add_pair(&mut self, branch: Option<&str>, mut commit: Commit<E>,
+        state: Option<PartitionState<E>>)
+    {
+        trace!("Partition {}: new commit {}", self.part_id.into_num(), commit.statesum());
+        if let Some(ref key) = self.signing_key {
+            commit.sign(key);
+        }
+        self.ss_policy.add_commits(1);
+        self.ss_policy.add_edits(commit.num_changes());
+        if self.unsaved_since.is_none() {
+            self.unsaved_since = Some(Instant::now());
+        }
+        let (tips, unsaved) = match branch {
+            Some(name) => {
+                let branch = self.branches.get_mut(name).expect("branch exists");
+                (&mut branch.tips, &mut branch.unsaved)
+            },
+            None => (&mut self.tips, &mut self.unsaved),
+        };
+        // This might fail (if the parent was not a tip), but it doesn't matter:
+        for parent in commit.parents() {
+            tips.remove(parent);
+        }
+        tips.insert(commit.statesum().clone());
+        let event = if self.observers.is_empty() { None } else {
+            Some(ChangeEvent::from_commit(self.part_id, branch, &commit))
+        };
+        unsaved.push_back(commit);
+        if let Some(state) = state {
+            if state.ids_low() {
+                warn!("Partition {}: running low on element identifiers ({:.0}% used)",
+                    self.part_id.into_num(), state.id_usage() * 100.0);
+            }
+            // Logs are written for the current snapshot or a later one
+            self.sources.insert(state.statesum().clone(), Source { ss: self.ss_num, snapshot: false });
+            self.states.insert(state);
+        }
+        self.evict();
+        // Notify last, so that the partition is consistent should an
+        // observer panic
+        if let Some(event) = event {
+            self.observers.notify(&event);
+        }
+    }
+    
+    /// Make changes to the tip within a transaction.
+    /// 
+    /// The closure `f` is run on a child of the tip; if it succeeds, the
+    /// result is committed (see `push_state()`) and the closure's result
+    /// returned. If `f` fails, nothing is committed.
+    /// 
+    /// If `flush` is true, data is (re)loaded before running `f` and again
+    /// before committing, in order to find changes made by other writers, and
+    /// the commit is written to disk afterwards (`write(true)`). If the tip
+    /// changed while `f` was running, the changes are merged using `solver`;
+    /// if no solver is given the closure is run again on the new tip (up to
+    /// `MAX_TRANSACTION_ATTEMPTS` times).
+    /// 
+    /// If a merge is required beforehand it is done using `solver`; without a
+    /// solver this fails with `TipError::MergeRequired`.
+    pub fn transaction<T, F>(&mut self, solver: Option<&TwoWaySolver<E>>, flush: bool,
+        mut f: F) -> Result<T>
+        where F: FnMut(&mut PartitionState<E>) -> Result<T>
+    {
+        for _ in 0..MAX_TRANSACTION_ATTEMPTS {
+            if flush {
+                try!(self.load(false));
+            }
+            if self.merge_required() {
+                match solver {
+                    Some(solver) => try!(self.merge(solver)),
+                    None => { return Err(box TipError::MergeRequired); },
+                }
+            }
+            
+            let mut state = try!(self.tip()).clone_child();
+            let result = try!(f(&mut state));
+            
+            if flush {
+                try!(self.load(false));
+                let moved = self.tip_key().map_or(true, |tip| *tip != state.parents()[0]);
+                if moved && solver.is_none() {
+                    trace!("Partition {}: tip changed during transaction; retrying",
+                        self.part_id.into_num());
+                    continue;
+                }
+            }
+            try!(self.push_state(state));
+            if let Some(solver) = solver {
+                if self.merge_required() {
+                    try!(self.merge(solver));
+                }
+            }
+            if flush {
+                try!(self.write(true));
+            }
+            return Ok(result);
+        }
+        OtherError::err("transaction failed: tip changed on each attempt")
+    }
+    
+    /// Get statistics on the partition.
+    /// 
+    /// File numbers and sizes are found via the `PartitionIO`, which may be
+    /// slow (depending on the implementation). Element counts require the
+    /// partition to be loaded; computing the serialised size of elements is
+    /// `O(n)` in the size of the tip.
+    pub fn stats(&self) -> Result<PartitionStats> {
+        let mut stats = PartitionStats::default();
+        for ss in 0..self.io.ss_len() {
+            if let Some(bytes) = try!(self.io.ss_size(ss)) {
+                stats.snapshots += 1;
+                stats.snapshot_bytes += bytes;
+            }
+            for cl in 0..self.io.ss_cl_len(ss) {
+                if let Some(bytes) = try!(self.io.ss_cl_size(ss, cl)) {
+                    stats.logs += 1;
+                    stats.log_bytes += bytes;
+                }
+            }
+        }
+        
+        if let Ok(tip) = self.tip() {
+            let mut bytes = 0;
+            let mut buf = Vec::new();
+            for elt in tip.map().values() {
+                buf.clear();
+                try!(elt.write_buf(&mut buf));
+                bytes += buf.len() as u64;
+            }
+            stats.elements = Some(tip.num_avail());
+            stats.element_bytes = Some(bytes);
+        }
+        stats.states = self.states.len();
+        stats.tips = self.tips.len();
+        stats.unsaved_commits = self.num_unsaved();
+        
+        // Commits in logs of the latest snapshot, plus those not yet written
+        stats.commits_since_snapshot = stats.unsaved_commits;
+        if let Some(ss) = self.io.ss_len().checked_sub(1) {
+            for cl in 0..self.io.ss_cl_len(ss) {
+                if let Some(mut r) = try!(self.io.read_ss_cl(ss, cl)) {
+                    try!(read_head(&mut r));
+                    let mut queue = CommitQueue::<E>::new();
+                    try!(read_log(&mut r, &mut queue));
+                    stats.commits_since_snapshot += queue.len();
+                }
+            }
+        }
+        Ok(stats)
+    }
+    
+    /// Returns true if verification is enabled.
+    pub fn verify(&self) -> bool { self.verify }
+    
+    /// Enable or disable verification (disabled by default).
+    /// 
+    /// Normally states with equal state-sums are assumed to be equal. When
+    /// verification is enabled, element maps are compared whenever a new or
+    /// reloaded state has the same sum as a known state (including when
+    /// snapshots are loaded over states replayed from logs), and differences
+    /// are reported with `PatchOp::Collision`. This makes commits and loading
+    /// slower.
+    pub fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+    }
+    
+    /// Get the durability setting.
+    pub fn durability(&self) -> Durability { self.durability }
+    
+    /// Set when commits are written to disk. The default is
+    /// `Durability::Manual`.
+    /// 
+    /// This does not write anything immediately; if needed, call `write()`.
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+    }
+    
+    /// Write unsaved commits (as `write(true)`) if required by the durability
+    /// setting. Returns true if a write was made.
+    /// 
+    /// This is done automatically when a commit is made. With
+    /// `Durability::Group`, call it periodically to write commits which have
+    /// reached the maximum age while no further commits were made.
+    pub fn write_if_due(&mut self) -> Result<bool> {
+        let due = self.has_unsaved() && match self.durability {
+            Durability::Manual => false,
+            Durability::WriteThrough => true,
+            Durability::Group { commits, max_age_millis } => {
+                self.num_unsaved() >= commits ||
+                    self.unsaved_since.map_or(false,
+                        |t| t.elapsed() >= Duration::from_millis(max_age_millis))
+            },
+        };
+        if due {
+            try!(self.write(true));
+        }
+        Ok(due)
+    }
+    
+    // Write unsaved commits if required by the durability setting
+    fn flush_if_due(&mut self) -> Result<()> {
+        try!(self.write_if_due());
+        Ok(())
+    }
+    
+    /// Insert many elements into the tip, writing the result directly as a
+    /// new snapshot instead of as a commit. Returns the new elements'
+    /// identifiers, in order.
+    /// 
+    /// This is intended for large initial loads: see
+    /// `PartitionState::insert_bulk()`. Unsaved commits are written first
+    /// (`write(true)`), since the snapshot supersedes them. The new state
+    /// replaces the tip; since there is no commit, logs written before the
+    /// snapshot do not include it.
+    /// 
+    /// Fails if a merge is required, identifiers cannot be allocated or
+    /// writing fails. If only writing the snapshot fails, the new state stays
+    /// in memory as an unsaved change and the snapshot is written by the next
+    /// `write()`.
+    pub fn bulk_load<I>(&mut self, elts: I) -> Result<Vec<EltId>>
+        where I: IntoIterator<Item = E>
+    {
+        let elts: Vec<Arc<E>> = elts.into_iter().map(Arc::new).collect();
+        if elts.is_empty() {
+            return Ok(Vec::new());
+        }
+        try!(self.write(true));
+        
+        let mut state = try!(self.tip()).clone_child();
+        let ids = try!(state.insert_bulk(elts));
+        info!("Partition {}: bulk loaded {} elements", self.part_id.into_num(), ids.len());
+        if self.states.contains(state.statesum()) {
+            return Err(box PatchOp::SumClash);
+        }
+        self.tips.remove(&state.parents()[0]);
+        self.tips.insert(state.statesum().clone());
+        let event = ChangeEvent {
+            part_id: self.part_id,
+            branch: None,
+            statesum: state.statesum().clone(),
+            parents: state.parents().clone(),
+            changes: None,
+        };
+        self.unsaved_snapshot = Some(state.statesum().clone());
+        self.sources.insert(state.statesum().clone(), Source { ss: self.ss_num + 1, snapshot: true });
+        self.states.insert(state);
+        if !self.observers.is_empty() {
+            self.observers.notify(&event);
+        }
+        try!(self.write(true));
+        Ok(ids)
+    }
+    
+    /// This will write all unsaved commits to a log on the disk.
+    /// 
+    /// If `fast` is true, no further actions will happen, otherwise required
+    /// maintenance operations will be carried out (e.g. creating a new
+    /// snapshot when the current commit-log is long).
+    /// 
+    /// Either way, this does nothing if no changes have been made and nothing
+    /// is loaded. If data has been loaded but no changes made it is still
+    /// possible that a snapshot will be written (when `fast == false`).
+    /// 
+    /// Commits on named branches are written to separate log files.
+    /// 
+    /// Returns true if any commits, tag or branch changes were written (i.e.
+    /// unsaved changes were found). Returns false if nothing needed doing.
+    /// 
+    /// Note that writing to disk can fail. In this case it may be worth trying
+    /// again.
+    /// 
+    /// This is `take_unsaved()`, `PendingWrite::write()` and `finish_write()`
+    /// in sequence.
+    pub fn write(&mut self, fast: bool) -> Result<bool> {
+        let has_changes = self.has_unsaved();
+        let mut pending = try!(self.take_unsaved());
+        let result = pending.write();
+        let finished = self.finish_write(pending, fast);
+        try!(result);
+        try!(finished);
+        Ok(has_changes)
+    }
+    
+    /// Take all unsaved commits, tag and branch changes, to be written by
+    /// `PendingWrite::write()` without access to the partition (e.g. by
+    /// `writer::BackgroundWriter`, which does not hold the partition's lock
+    /// during IO). The result must be passed to `finish_write()` afterwards.
+    /// 
+    /// A state from `bulk_load()` is written as a snapshot immediately,
+    /// since logs of commits made on it must follow the snapshot.
+    pub fn take_unsaved(&mut self) -> Result<PendingWrite<E>> {
+        // A bulk-loaded state is saved by a snapshot, which must precede logs
+        // of commits made on it
+        if let Some(sum) = self.unsaved_snapshot.clone() {
+            try!(self.write_snapshot_of(&sum));
+            self.unsaved_snapshot = None;
+        }
+        
+        let mut logs = Vec::new();
+        if self.has_unsaved() {
+            trace!("Partition {}: taking {} commits and {} tag changes to write",
+                self.part_id.into_num(), self.unsaved.len(), self.unsaved_tags.len());
+            if let Err(e) = self.take_logs(&mut logs) {
+                self.restore_logs(logs);
+                return Err(e);
+            }
+            self.unsaved_since = None;
+        }
+        
+        let ticket = self.next_ticket;
+        self.next_ticket += 1;
+        Ok(PendingWrite {
+            io: self.io.clone(),
+            ticket: ticket,
+            served: false,
+            ss_num: self.ss_num,
+            logs: logs,
+            written: 0,
+        })
+    }
+    
+    /// Return changes taken by `take_unsaved()` after writing. Anything not
+    /// written (e.g. because writing failed) is restored as unsaved.
+    /// 
+    /// If everything was written and `fast` is false, maintenance is done as
+    /// by `write()`.
+    pub fn finish_write(&mut self, mut pending: PendingWrite<E>, fast: bool) -> Result<()> {
+        if !pending.is_empty() {
+            let logs = pending.logs.split_off(pending.written);
+            self.restore_logs(logs);
+            return Ok(());
+        }
+        
+        // Maintenance operations
+        if !fast {
+            if self.is_ready() && self.ss_policy.snapshot() {
+                try!(self.write_snapshot());
+            }
+        }
+        Ok(())
+    }
+    
+    // Move unsaved commits to `logs`, in an order in which they can be
+    // written. Commits may depend on unsaved commits of another branch (e.g.
+    // merges), so we only take each log up to the first commit with a parent
+    // still waiting to be taken, and repeat until done.
+    fn take_logs(&mut self, logs: &mut Vec<PendingLog<E>>) -> Result<()> {
+        let mut pending: HashSet<Sum> = self.unsaved.iter()
+                .chain(self.branches.values().flat_map(|b| b.unsaved.iter()))
+                .map(|c| c.statesum().clone())
+                .collect();
+        let mut default_pending = !self.unsaved.is_empty() ||
+                !self.unsaved_tags.is_empty() || !self.deleted_branches.is_empty();
+        let mut names: Vec<String> = self.branches.iter()
+                .filter(|&(_, b)| !b.unsaved.is_empty() || b.unsaved_base.is_some())
+                .map(|(name, _)| name.clone())
+                .collect();
+        names.sort();
+        
+        while default_pending || !names.is_empty() {
+            let mut progress = false;
+            if default_pending {
+                let n = Self::num_writable(&self.unsaved, &pending);
+                if n > 0 || self.unsaved.is_empty() {
+                    let header = FileHeader {
+                        tags: mem::replace(&mut self.unsaved_tags, Vec::new()),
+                        deleted_branches: mem::replace(&mut self.deleted_branches, Vec::new()),
+                        ..FileHeader::new(FileType::CommitLog(0),
+                                self.repo_name.clone(), Some(self.part_id))
+                    };
+                    let commits = Self::take_commits(&mut self.unsaved, n, &mut pending);
+                    logs.push(PendingLog { header: header, commits: commits });
+                    default_pending = !self.unsaved.is_empty();
+                    progress = true;
+                }
+            }
+            
+            let mut remaining = Vec::new();
+            for name in names {
+                let branch = self.branches.get_mut(&name).expect("branch exists");
+                let n = Self::num_writable(&branch.unsaved, &pending);
+                let base = if branch.unsaved_base.is_some() && pending.contains(&branch.base) {
+                    None
+                } else {
+                    Some(branch.base.clone())
+                };
+                if let Some(base) = base {
+                    if n > 0 || branch.unsaved.is_empty() {
+                        let header = FileHeader {
+                            branch: Some((name.clone(), base)),
+                            ..FileHeader::new(FileType::CommitLog(0),
+                                    self.repo_name.clone(), Some(self.part_id))
+                        };
+                        let commits = Self::take_commits(&mut branch.unsaved, n, &mut pending);
+                        logs.push(PendingLog { header: header, commits: commits });
+                        branch.unsaved_base = None;
+                        progress = true;
+                    }
+                }
+                if !branch.unsaved.is_empty() || branch.unsaved_base.is_some() {
+                    remaining.push(name);
+                }
+            }
+            names = remaining;
+            
+            if !progress {
+                return OtherError::err("unable to order unsaved commits for writing");
+            }
+        }
+        Ok(())
+    }
+    
+    // Remove the first `n` commits from `queue` and from `pending`
+    fn take_commits(queue: &mut VecDeque<Commit<E>>, n: usize, pending: &mut HashSet<Sum>) ->
+        VecDeque<Commit<E>>
+    {
+        let rest = queue.split_off(n);
+        let taken = mem::replace(queue, rest);
+        for commit in &taken {
+            pending.remove(commit.statesum());
+        }
+        taken
+    }
+    
+    // Restore logs taken but not written (in order) as unsaved, before any
+    // changes made since. Header changes of a partially written log are
+    // restored too; recording them twice is harmless.
+    fn restore_logs(&mut self, logs: Vec<PendingLog<E>>) {
+        if logs.is_empty() {
+            return;
+        }
+        for log in logs.into_iter().rev() {
+            let header = log.header;
+            let queue = match header.branch {
+                None => {
+                    let mut tags = header.tags;
+                    tags.extend(self.unsaved_tags.drain(..));
+                    self.unsaved_tags = tags;
+                    let mut deleted = header.deleted_branches;
+                    deleted.extend(self.deleted_branches.drain(..));
+                    self.deleted_branches = deleted;
+                    &mut self.unsaved
+                },
+                Some((name, _)) => match self.branches.get_mut(&name) {
+                    Some(branch) => {
+                        if log.commits.is_empty() {
+                            // The log only records the branch
+                            branch.unsaved_base = Some(branch.base.clone());
+                        }
+                        &mut branch.unsaved
+                    },
+                    None => { continue; },  // deleted meanwhile
+                },
+            };
+            for commit in log.commits.into_iter().rev() {
+                queue.push_front(commit);
+            }
+        }
+        if self.unsaved_since.is_none() {
+            self.unsaved_since = Some(Instant::now());
+        }
+    }
+    
+    /// Write a new snapshot from the tip.
+    /// 
+    /// Normally you can just call `write()` and let the library figure out
+    /// when to write a new snapshot, though you can also call this directly.
+    /// 
+    /// Named branches are carried forward by writing a log for each, with
+    /// commits recreating the states on the branch from its base. Loading
+    /// reads all history if a base is from before the snapshot.
+    /// 
+    /// Does nothing when `tip()` fails (returning `Ok(())`).
+    pub fn write_snapshot(&mut self) -> Result<()> {
+        // fail early if not ready:
+        let tip_key = try!(self.tip_key()).clone();
+        self.write_snapshot_of(&tip_key)
+    }
+    
+    // Write a snapshot of a state and carry branches forward (see
+    // `write_snapshot()`).
+    fn write_snapshot_of(&mut self, key: &Sum) -> Result<()> {
+        try!(self.write_state_snapshot(key));
+        
+        // Loading the snapshot only loads logs from after it, so each branch
+        // needs a new log. This records the branch's original base and all
+        // its states since, so that merges find the true common ancestor.
+        let mut names: Vec<String> = self.branches.keys().cloned().collect();
+        names.sort();
+        for name in names {
+            let base = self.branches[&name].base.clone();
+            let sums = self.branch_states(&self.branches[&name]);
+            try!(self.restore(sums.iter()));
+            let mut order: Vec<(u32, &Sum)> = sums.iter()
+                    .filter_map(|sum| self.states.get(sum))
+                    .map(|state| (state.meta().number, state.statesum()))
+                    .collect();
+            order.sort();
+            let mut queue = VecDeque::new();
+            for (_, sum) in order {
+                let state = self.states.get(sum).expect("state");
+                let parent = match state.parents().first().and_then(|p| self.states.get(p)) {
+                    Some(parent) => parent,
+                    None => continue,
+                };
+                if let Some(mut commit) = Commit::from_diff(parent, state) {
+                    commit.set_parents(state.parents().clone());
+                    *commit.meta_mut() = state.meta().clone();
+                    if let Some(ref key) = self.signing_key {
+                        commit.sign(key);
+                    }
+                    queue.push_back(commit);
+                }
+            }
+            let header = FileHeader {
+                branch: Some((name.clone(), base)),
+                ..FileHeader::new(FileType::CommitLog(0),
+                        self.repo_name.clone(), Some(self.part_id))
+            };
+            let n = queue.len();
+            try!(Self::write_log(&mut *self.io.lock().io, self.ss_num, &header, &mut queue, n));
+            self.branches.get_mut(&name).unwrap().unsaved_base = None;
+        }
+        Ok(())
+    }
+}
+
+// Support functions
+impl<E: ElementT> Partition<E> {
+    // Number of commits not yet written (on all branches)
+    fn num_unsaved(&self) -> usize {
+        self.unsaved.len() + self.branches.values().map(|b| b.unsaved.len()).sum::<usize>()
+    }
+    
+    // True if any commits, tag changes, branch changes or bulk-loaded states
+    // are not yet saved
+    fn has_unsaved(&self) -> bool {
+        self.unsaved_snapshot.is_some() ||
+            !self.unsaved.is_empty() || !self.unsaved_tags.is_empty() ||
+            !self.deleted_branches.is_empty() ||
+            self.branches.values().any(|b| !b.unsaved.is_empty() || b.unsaved_base.is_some())
+    }
+    
+    // Number of commits from the front of `queue` which can be written now:
+    // those whose parents are not `pending` (waiting to be written) or are
+    // earlier in the queue.
+    fn num_writable(queue: &VecDeque<Commit<E>>, pending: &HashSet<Sum>) -> usize {
+        let mut prefix = HashSet::new();
+        for (i, commit) in queue.iter().enumerate() {
+            if commit.parents().iter().any(|p| pending.contains(p) && !prefix.contains(p)) {
+                return i;
+            }
+            prefix.insert(commit.statesum());
+        }
+        queue.len()
+    }
+    
+    // Write a snapshot of a state (which must be loaded) to the next free
+    // snapshot number, and make this the current snapshot.
+    fn write_state_snapshot(&mut self, key: &Sum) -> Result<()> {
+        let mut ss_num = self.ss_num + 1;
+        let mut io = self.io.lock();
+        loop {
+            // Try to get a writer for this snapshot number:
+            if let Some(mut writer) = try!(io.io.new_ss(ss_num)) {
+                info!("Partition {}: writing snapshot {}: {}",
+                    self.part_id.into_num(), ss_num, key);
+                
+                // The snapshot header records all tags
+                let mut tags: Vec<_> = self.tags.iter()
+                        .map(|(name, sum)| (name.clone(), Some(sum.clone())))
+                        .collect();
+                tags.sort_by(|a, b| a.0.cmp(&b.0));
+                // ... and all states removed by compaction
+                let mut removed: Vec<Sum> = self.removed.iter().cloned().collect();
+                removed.sort();
+                let header = FileHeader {
+                    tags: tags,
+                    removed_states: removed,
+                    ..FileHeader::new(FileType::Snapshot(0),
+                            self.repo_name.clone(), Some(self.part_id))
+                };
+                //TODO: also write classifier stuff
+                try!(write_head(&header, &mut writer));
+                let key_ref = self.signing_key.as_ref().map(|k| &**k);
+                try!(write_snapshot(self.states.get(key).unwrap(), key_ref, &mut writer));
+                self.snapshot_states.insert(key.clone());
+                self.unsaved_tags.clear();
+                self.ss_num = ss_num;
+                self.ss_policy.reset();
+                break;
+            } else {
+                // Snapshot file already exists! So try another number.
+                if ss_num > 1000_000 {
+                    // We should give up eventually. When is arbitrary.
+                    return Err(box OtherError::new("Snapshot number too high"));
+                }
+                ss_num += 1;
+            }
+        }
+        Ok(())
+    }
+    
+    // Write a new log file for snapshot `ss_num` with the given header, then
+    // the first `n` commits from `commits`, removing each from `commits` once
+    // written.
+    fn write_log(io: &mut PartitionIO, ss_num: usize, header: &FileHeader,
+        commits: &mut VecDeque<Commit<E>>, n: usize) -> Result<()>
+    {
+        // #0012: extend existing logs instead of always writing a new log file.
+        let mut cl_num = io.ss_cl_len(ss_num);
+        loop {
+            if let Some(mut writer) = try!(io.new_ss_cl(ss_num, cl_num)) {
+                // Write a header since this is a new file:
+                try!(write_head(header, &mut writer));
+                try!(start_log(&mut writer));
+                
+                // Now write commits:
+                for _ in 0..n {
+                    // We try to write the commit, then when successful remove it
+                    // from the list of 'unsaved' commits.
+                    try!(write_commit(&commits.front().unwrap(), &mut writer));
+                    commits.pop_front().expect("pop_front");
+                }
+                return Ok(());
+            } else {
+                // Log file already exists! So try another number.
+                if cl_num > 1000_000 {
+                    // We should give up eventually. When is arbitrary.
+                    return Err(box OtherError::new("Commit log number too high"));
+                }
+                cl_num += 1;
+            }
+        }
+    }
+    
+    // Get the first parent of a state. Fails if the state is not loaded or
+    // has no parent.
+    fn first_parent(&self, sum: &Sum) -> Result<Sum> {
+        match self.parents_of(sum) {
+            Some(parents) => match parents.first() {
+                Some(parent) => Ok(parent.clone()),
+                None => OtherError::err("state has no parent"),
+            },
+            None => OtherError::err("state not found"),
+        }
+    }
+    
+    // Get the parents of a state, whether held in memory or evicted.
+    fn parents_of(&self, sum: &Sum) -> Option<&Vec<Sum>> {
+        self.states.get(sum).map(|state| state.parents())
+            .or_else(|| self.evicted.get(sum).map(|evicted| &evicted.parents))
+    }
+    
+    // Take the changes from state `from` to state `to` and apply them to the
+    // tip as a new commit. Conflicts are resolved by comparison with `from`
+    // and then by `solver`.
+    fn apply_changes<S: TwoWaySolver<E>+?Sized>(&mut self, to: &Sum, from: &Sum,
+        solver: &S) -> Result<bool>
+    {
+        try!(self.restore(vec![to, from].into_iter()));
+        let commit = {
+            let tip = try!(self.tip());
+            let (to_state, from_state) = match (self.states.get(to), self.states.get(from)) {
+                (Some(t), Some(f)) => (t, f),
+                _ => { return OtherError::err("state not found"); }
+            };
+            let mut merger = TwoWayMerge::new(tip, to_state, from_state);
+            let ancestor_solver = AncestorSolver2W::new();
+            merger.solve(&TwoWaySolverChain::new(&ancestor_solver, solver));
+            if !merger.is_solved() {
+                return OtherError::err("solver left conflicts unresolved");
+            }
+            match merger.make_commit_on_a() {
+                Some(commit) => commit,
+                None => { return OtherError::err("unable to create commit"); }
+            }
+        };
+        if commit.num_changes() == 0 {
+            return Ok(false);
+        }
+        trace!("Pushing commit: {} ({} changes)", commit.statesum(), commit.num_changes());
+        try!(self.push_commit(commit));
+        Ok(true)
+    }
+    
+    // Take self and two sums. Return a copy of a key to avoid lifetime issues.
+    // 
+    // TODO: enable loading of additional history on demand. Or do we not need
+    // this?
+    fn latest_common_ancestor(&self, k1: &Sum, k2: &Sum) -> Result<Sum> {
+        // #0019: there are multiple strategies here; we just find all
+        // ancestors of one, then of the other. This simplifies lopic.
+        let mut a1 = HashSet::new();
+        
+        let mut next = VecDeque::new();
+        next.push_back(k1);
+        loop {
+            let k = match next.pop_back() {
+                Some(k) => k,
+                None => { break; }
+            };
+            if a1.contains(k) { continue; }
+            a1.insert(k);
+            if let Some(parents) = self.parents_of(k) {
+                for p in parents {
+                    next.push_back(p);
+                }
+            }
+        }
+        
+        // We track ancestors of k2 just to check we don't end up in a loop.
+        let mut a2 = HashSet::new();
+        
+        // next is empty
+        next.push_back(k2);
+        loop {
+            let k = match next.pop_back() {
+                Some(k) => k,
+                None => { break; }
+            };
+            if a2.contains(k) { continue; }
+            a2.insert(k);
+            if a1.contains(k) {
+                return Ok(k.clone());
+            }
+            if let Some(parents) = self.parents_of(k) {
+                for p in parents {
+                    next.push_back(p);
+                }
+            }
+        }
+        
+        Err(box OtherError::new("unable to find a common ancestor"))
+    }
+}
+
+// Apply tag changes read from a header (in order) to a tag map
+fn apply_tag_changes(tags: &mut HashMap<String, Sum>, changes: Vec<(String, Option<Sum>)>) {
+    for (name, sum) in changes {
+        if let Some(sum) = sum {
+            tags.insert(name, sum);
+        } else {
+            tags.remove(&name);
+        }
+    }
+}
+
+
+impl<E: ElementT> Drop for Partition<E> {
+    fn drop(&mut self) {
+        if self.has_unsaved() {
+            warn!("Partition {}: dropped with unsaved changes ({} commits)",
+                self.part_id.into_num(), self.num_unsaved());
+        }
+    }
+}
+
+#[test]
+fn on_new_partition() {
+    let io = box PartitionDummyIO::new();
+    let mut part = Partition::<String>::create(io, "on_new_partition").expect("partition creation");
+    assert_eq!(part.tips.len(), 1);
+    
+    let state = part.tip().expect("getting tip").clone_child();
+    assert_eq!(state.parents()[0], Sum::zero());
+    assert_eq!(part.push_state(state).expect("committing"), false);
+    
+    let mut state = part.tip().expect("getting tip").clone_child();
+    assert!(!state.any_avail());
+    assert_eq!(state.statesum(), &Sum::zero());
+    
+    let elt1 = "This is element one.".to_string();
+    let elt2 = "Element two data.".to_string();
+    let mut key = elt1.sum().clone();
+    key.permute(&elt2.sum());
+    let e1id = state.insert(elt1).expect("inserting elt");
+    let e2id = state.insert(elt2).expect("inserting elt");
+    assert_eq!(state.statesum(), &key);
+    
+    assert_eq!(part.push_state(state).expect("comitting"), true);
+    assert_eq!(part.unsaved.len(), 1);
+    assert_eq!(part.states.len(), 2);
+    {
+        let state = part.state(&key).expect("getting state by key");
+        assert!(state.is_avail(e1id));
+        assert_eq!(state.get(e2id), Ok(&"Element two data.".to_string()));
+    }   // `state` goes out of scope
+    assert_eq!(part.tips.len(), 1);
+    let state = part.tip().expect("getting tip").clone_child();
+    assert_eq!(state.parents().len(), 1);
+    assert_eq!(state.parents()[0], key);
+    
+    assert_eq!(part.push_state(state).expect("committing"), false);
+}
+
+#[test]
+fn revert_and_cherry_pick() {
+    use merge::TwoWaySolveNoResult;
+    
+    let io = box PartitionDummyIO::new();
+    let mut part = Partition::<String>::create(io, "revert").expect("partition creation");
+    let solver = TwoWaySolveNoResult::new();
+    
+    let mut state = part.tip().expect("getting tip").clone_child();
+    let e1 = state.insert("one".to_string()).expect("inserting elt");
+    let e2 = state.insert("two".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    
+    let mut state = part.tip().expect("getting tip").clone_child();
+    state.replace(e1, "ONE".to_string()).expect("replacing elt");
+    let changed = state.statesum().clone();
+    part.push_state(state).expect("committing");
+    
+    let mut state = part.tip().expect("getting tip").clone_child();
+    let e3 = state.insert("three".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    
+    assert_eq!(part.revert(&changed, &solver).expect("reverting"), true);
+    {
+        let tip = part.tip().expect("getting tip");
+        assert_eq!(tip.get(e1), Ok(&"one".to_string()));
+        assert_eq!(tip.get(e2), Ok(&"two".to_string()));
+        assert_eq!(tip.get(e3), Ok(&"three".to_string()));
+    }
+    
+    let mut state = part.tip().expect("getting tip").clone_child();
+    state.remove(e2).expect("removing elt");
+    part.push_state(state).expect("committing");
+    
+    assert_eq!(part.cherry_pick(&changed, &solver).expect("cherry-picking"), true);
+    let tip = part.tip().expect("getting tip");
+    assert_eq!(tip.get(e1), Ok(&"ONE".to_string()));
+    assert!(!tip.is_avail(e2));
+    assert_eq!(tip.parents().len(), 1);
+    assert_eq!(part.unsaved.len(), 6);
+}
+
+#[test]
+fn revert_tip() {
+    use merge::TwoWaySolveNoResult;
+    
+    let io = box PartitionDummyIO::new();
+    let mut part = Partition::<String>::create(io, "revert_tip").expect("partition creation");
+    let solver = TwoWaySolveNoResult::new();
+    
+    let mut state = part.tip().expect("getting tip").clone_child();
+    let e1 = state.insert("one".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    let parent = part.tip_key().expect("getting tip").clone();
+    
+    let mut state = part.tip().expect("getting tip").clone_child();
+    state.replace(e1, "ONE".to_string()).expect("replacing elt");
+    part.push_state(state).expect("committing");
+    let reverted = part.tip_key().expect("getting tip").clone();
+    
+    // Reverting the latest commit returns to its parent, recorded by a
+    // commit on the old tip
+    assert_eq!(part.revert(&reverted, &solver).expect("reverting"), true);
+    assert!(part.is_ready());
+    assert_eq!(part.tip_key().expect("getting tip"), &parent);
+    assert_eq!(part.tip().expect("getting tip").get(e1), Ok(&"one".to_string()));
+    {
+        let commit = part.unsaved.back().expect("commit");
+        assert_eq!(commit.statesum(), &parent);
+        assert_eq!(commit.parents(), &vec![reverted.clone()]);
+        assert_eq!(commit.num_changes(), 1);
+    }
+    
+    // ... and re-applied by a cherry-pick
+    assert_eq!(part.cherry_pick(&reverted, &solver).expect("cherry-picking"), true);
+    assert_eq!(part.tip_key().expect("getting tip"), &reverted);
+    assert_eq!(part.unsaved.len(), 4);
+}
+
+#[test]
+fn revert_then_merge() {
+    use merge::{AncestorSolver2W, TwoWaySolveNoResult};
+    
+    let io = box PartitionDummyIO::new();
+    let mut part = Partition::<String>::create(io, "revert_merge").expect("partition creation");
+    let solver = TwoWaySolveNoResult::new();
+    
+    let mut state = part.tip().expect("getting tip").clone_child();
+    let e1 = state.insert("one".to_string()).expect("inserting elt");
+    let e2 = state.insert("two".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    
+    let mut state = part.tip().expect("getting tip").clone_child();
+    state.replace(e1, "ONE".to_string()).expect("replacing elt");
+    part.push_state(state).expect("committing");
+    let changed = part.tip_key().expect("getting tip").clone();
+    
+    let mut state = part.tip().expect("getting tip").clone_child();
+    let e3 = state.insert("three".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    let before = part.tip().expect("getting tip").clone_child();
+    
+    assert_eq!(part.revert(&changed, &solver).expect("reverting"), true);
+    
+    // A peer edits the state from before the revert
+    let mut peer = before;
+    peer.replace(e2, "TWO".to_string()).expect("replacing elt");
+    part.push_state(peer).expect("committing");
+    assert!(part.merge_required());
+    
+    part.merge(&AncestorSolver2W::new()).expect("merging");
+    let tip = part.tip().expect("getting tip");
+    assert_eq!(tip.get(e1), Ok(&"one".to_string()));
+    assert_eq!(tip.get(e2), Ok(&"TWO".to_string()));
+    assert_eq!(tip.get(e3), Ok(&"three".to_string()));
+}
+
+#[test]
+fn transactions() {
+    use merge::AncestorSolver2W;
+    
+    let io = box PartitionDummyIO::new();
+    let mut part = Partition::<String>::create(io, "transactions").expect("partition creation");
+    
+    let e1 = part.transaction(None, false, |state| {
+        Ok(try!(state.insert("one".to_string())))
+    }).expect("transaction");
+    assert_eq!(part.unsaved.len(), 1);
+    assert_eq!(part.tip().expect("getting tip").get(e1), Ok(&"one".to_string()));
+    
+    // A failing closure commits nothing
+    let r: Result<()> = part.transaction(None, false, |state| {
+        try!(state.insert("two".to_string()));
+        OtherError::err("abort")
+    });
+    assert!(r.is_err());
+    assert_eq!(part.unsaved.len(), 1);
+    assert_eq!(part.tip().expect("getting tip").num_avail(), 1);
+    
+    // Create two tips; without a solver the transaction cannot proceed
+    let parent = part.tip().expect("getting tip").clone_child();
+    let mut state = parent.clone_exact();
+    state.replace(e1, "ONE".to_string()).expect("replacing elt");
+    part.push_state(state).expect("committing");
+    let mut state = parent.clone_exact();
+    let e3 = state.insert("three".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    assert!(part.merge_required());
+    assert!(part.transaction(None, false, |_| Ok(())).is_err());
+    
+    let solver = AncestorSolver2W::new();
+    part.transaction(Some(&solver), false, |state| {
+        try!(state.remove(e3));
+        Ok(())
+    }).expect("transaction");
+    assert!(!part.merge_required());
+    let tip = part.tip().expect("getting tip");
+    assert_eq!(tip.get(e1), Ok(&"ONE".to_string()));
+    assert!(!tip.is_avail(e3));
+}
+
+#[test]
+fn durability() {
+    let io = box PartitionDummyIO::new();
+    let mut part = Partition::<String>::create(io, "durability").expect("partition creation");
+    assert_eq!(part.durability(), Durability::Manual);
+    
+    part.set_durability(Durability::WriteThrough);
+    let mut state = part.tip().expect("getting tip").clone_child();
+    state.insert("one".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    assert!(!part.has_unsaved());
+    
+    part.set_durability(Durability::Group { commits: 2, max_age_millis: 1_000_000 });
+    let mut state = part.tip().expect("getting tip").clone_child();
+    state.insert("two".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    assert_eq!(part.num_unsaved(), 1);
+    let mut state = part.tip().expect("getting tip").clone_child();
+    state.insert("three".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    assert!(!part.has_unsaved());
+    
+    // Checking commits nothing; pushing a checked state does not write
+    part.set_durability(Durability::WriteThrough);
+    let mut state = part.tip().expect("getting tip").clone_child();
+    state.insert("four".to_string()).expect("inserting elt");
+    let commit = part.check_state(&state).expect("checking").expect("commit");
+    assert_eq!(part.tip().expect("getting tip").num_avail(), 3);
+    part.push_checked(commit, state);
+    assert_eq!(part.tip().expect("getting tip").num_avail(), 4);
+    assert_eq!(part.num_unsaved(), 1);
+    assert!(part.write_if_due().expect("writing"));
+    assert!(!part.has_unsaved());
+    let state = part.tip().expect("getting tip").clone_child();
+    assert!(part.check_state(&state).expect("checking").is_none());
+}
+
+#[test]
+fn push_state_untracked() {
+    // A state built without `clone_child()` (so without tracked changes)
+    // gives the same commit as one built with it
+    let make_part = || {
+        let io = box PartitionDummyIO::new();
+        let mut part = Partition::<String>::create(io, "untracked").expect("partition creation");
+        let mut state = part.tip().expect("getting tip").clone_child();
+        let part_id = state.part_id();
+        for (num, elt) in vec![(1, "one"), (2, "two")] {
+            state.insert_with_id(part_id.elt_id(num), Arc::new(elt.to_string())).expect("inserting elt");
+        }
+        part.push_state(state).expect("committing");
+        part
+    };
+    
+    let mut part1 = make_part();
+    let mut tracked = part1.tip().expect("getting tip").clone_child();
+    let part_id = tracked.part_id();
+    tracked.replace(part_id.elt_id(1), "ONE".to_string()).expect("replacing elt");
+    tracked.insert_with_id(part_id.elt_id(3), Arc::new("three".to_string())).expect("inserting elt");
+    
+    let mut part2 = make_part();
+    let parent = part2.tip_key().expect("getting tip").clone();
+    let mut untracked = PartitionState::new_with(part_id, vec![parent], tracked.meta().clone());
+    for (id, elt) in tracked.map() {
+        untracked.insert_with_id(*id, elt.clone()).expect("inserting elt");
+    }
+    assert!(untracked.changed_ids().is_none());
+    
+    assert!(part1.push_state(tracked).expect("committing"));
+    assert!(part2.push_state(untracked).expect("committing"));
+    let c1 = part1.unsaved.back().expect("commit");
+    let c2 = part2.unsaved.back().expect("commit");
+    assert_eq!(c1.num_changes(), 2);
+    assert_eq!(c1.statesum(), c2.statesum());
+    assert_eq!(c1.parents(), c2.parents());
+    for (id, change) in c1.changes_iter() {
+        assert_eq!(c2.changes_iter().find(|&(id2, _)| id2 == id).map(|(_, c)| c), Some(change));
+    }
+}
+
+#[test]
+fn durability_max_age() {
+    use std::thread;
+    
+    let io = box PartitionDummyIO::new();
+    let mut part = Partition::<String>::create(io, "durability").expect("partition creation");
+    part.set_durability(Durability::Group { commits: 100, max_age_millis: 20 });
+    
+    let mut state = part.tip().expect("getting tip").clone_child();
+    state.insert("one".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    assert_eq!(part.num_unsaved(), 1);
+    assert_eq!(part.write_if_due().expect("writing"), false);
+    
+    // Once old enough, the commit is written without another commit
+    thread::sleep(Duration::from_millis(30));
+    assert_eq!(part.write_if_due().expect("writing"), true);
+    assert!(!part.has_unsaved());
+    
+    // ... or by the next commit
+    let mut state = part.tip().expect("getting tip").clone_child();
+    state.insert("two".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    assert_eq!(part.num_unsaved(), 1);
+    thread::sleep(Duration::from_millis(30));
+    let mut state = part.tip().expect("getting tip").clone_child();
+    state.insert("three".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    assert!(!part.has_unsaved());
+}
+
+#[test]
+fn state_at() {
+    let io = box PartitionDummyIO::new();
+    let mut part = Partition::<String>::create(io, "state_at").expect("partition creation");
+    
+    let mut sums = Vec::new();
+    for &(name, timestamp) in &[("one", 1000), ("two", 2000), ("three", 3000)] {
+        let tip = part.tip().expect("getting tip").clone_exact();
+        let mut state = tip.clone_child();
+        state.insert(name.to_string()).expect("inserting elt");
+        let mut commit = Commit::from_diff(&tip, &state).expect("commit");
+        commit.meta_mut().timestamp = timestamp;
+        part.push_commit(commit).expect("committing");
+        sums.push(state.statesum().clone());
+    }
+    
+    assert_eq!(part.state_at(2500).expect("state at 2500").statesum(), &sums[1]);
+    assert_eq!(part.state_at(3000).expect("state at 3000").statesum(), &sums[2]);
+    assert_eq!(part.state_at(1000).expect("state at 1000").meta().timestamp, 1000);
+    assert!(part.state_at(500).is_err());
+}
+
+#[test]
+fn verify_collisions() {
+    let io = box PartitionDummyIO::new();
+    let mut part = Partition::<String>::create(io, "verify").expect("partition creation");
+    
+    // Sums of equal elements cancel, so this has the same sum as the parent
+    let mut state = part.tip().expect("getting tip").clone_child();
+    state.insert("x".to_string()).expect("inserting elt");
+    state.insert("x".to_string()).expect("inserting elt");
+    assert_eq!(state.statesum(), &Sum::zero());
+    assert_eq!(part.push_state(state.clone_exact()).expect("committing"), false);
+    
+    part.set_verify(true);
+    let err = part.push_state(state).unwrap_err();
+    assert_eq!(err.downcast_ref::<PatchOp>(), Some(&PatchOp::Collision));
+    assert_eq!(part.tip().expect("getting tip").num_avail(), 0);
+}
+
+#[test]
+fn iteration() {
+    let io = box PartitionDummyIO::new();
+    let mut part = Partition::<String>::create(io, "iteration").expect("partition creation");
+    
+    let mut state = part.tip().expect("getting tip").clone_child();
+    for name in &["one", "two", "three", "four"] {
+        state.insert(name.to_string()).expect("inserting elt");
+    }
+    part.push_state(state).expect("committing");
+    
+    assert_eq!(part.iter().expect("iter").count(), 4);
+    let ids: Vec<_> = part.iter_ordered().expect("iter_ordered").map(|(id, _)| id).collect();
+    let mut sorted = ids.clone();
+    sorted.sort();
+    assert_eq!(ids, sorted);
+    let mut matched: Vec<_> = part.filter(|elt| elt.starts_with('t')).expect("filter")
+            .map(|(_, elt)| elt.clone()).collect();
+    matched.sort();
+    assert_eq!(matched, vec!["three".to_string(), "two".to_string()]);
+}
+
+#[test]
+fn indexes() {
+    let io = box PartitionDummyIO::new();
+    let mut part = Partition::<String>::create(io, "indexes").expect("partition creation");
+    part.add_index("initial", |elt: &String| elt.chars().next()).expect("adding index");
+    assert!(part.add_index("initial", |elt: &String| elt.chars().next()).is_err());
+    
+    let mut state = part.tip().expect("getting tip").clone_child();
+    let e1 = state.insert("one".to_string()).expect("inserting elt");
+    let e2 = state.insert("two".to_string()).expect("inserting elt");
+    let e3 = state.insert("three".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    let mut expected = vec![e2, e3];
+    expected.sort();
+    assert_eq!(part.query("initial", &'t').expect("query"), expected);
+    assert_eq!(part.query("initial", &'o').expect("query"), vec![e1]);
+    assert!(part.query("initial", &"t").is_err());
+    assert!(part.query("length", &3).is_err());
+    
+    let mut state = part.tip().expect("getting tip").clone_child();
+    state.replace(e1, "ONE".to_string()).expect("replacing elt");
+    state.remove(e2).expect("removing elt");
+    assert_eq!(state.query("initial", &'o').expect("query"), vec![]);
+    assert_eq!(state.query("initial", &'O').expect("query"), vec![e1]);
+    assert_eq!(state.query("initial", &'t').expect("query"), vec![e3]);
+    // The parent's (shared) index is unchanged
+    assert_eq!(part.query("initial", &'o').expect("query"), vec![e1]);
+    assert_eq!(part.query("initial", &'t').expect("query"), expected);
+    
+    state.add_index("length", |elt: &String| Some(elt.len()));
+    assert_eq!(state.query("length", &5usize).expect("query"), vec![e3]);
+}
+
+#[test]
+fn id_allocation() {
+    use ids::{SequentialIds, ExplicitIds};
+    use error::ElementOp;
+    
+    let io = box PartitionDummyIO::new();
+    let mut part = Partition::<String>::create(io, "id_allocation").expect("partition creation");
+    part.set_id_allocator(Arc::new(SequentialIds::new()));
+    let mut state = part.tip().expect("getting tip").clone_child();
+    let e1 = state.insert("one".to_string()).expect("inserting elt");
+    let e2 = state.insert("two".to_string()).expect("inserting elt");
+    assert_eq!((e1.elt_num(), e2.elt_num()), (1, 2));
+    part.push_state(state).expect("committing");
+    let usage = part.tip().expect("getting tip").id_usage();
+    assert!(usage > 0.0 && usage < 0.001);
+    assert_eq!(part.ids_low(), Ok(false));
+    
+    part.set_id_allocator(Arc::new(ExplicitIds));
+    let mut state = part.tip().expect("getting tip").clone_child();
+    assert_eq!(state.insert("three".to_string()), Err(ElementOp::IdRequired));
+    let id = part.part_id().elt_id(7);
+    assert_eq!(state.insert_with_id(id, Arc::new("seven".to_string())), Ok(id));
+}
+
+#[test]
+fn aliases() {
+    use merge::{AncestorSolver2W, EltMerge};
+    use error::ElementOp;
+    
+    let io = box PartitionDummyIO::new();
+    let mut part = Partition::<String>::create(io, "aliases").expect("partition creation");
+    let mut state = part.tip().expect("getting tip").clone_child();
+    let e1 = state.insert("one".to_string()).expect("inserting elt");
+    let e2 = state.insert("two".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    
+    // Aliases added on two tips are merged
+    let old1 = PartId::from_num(7).elt_id(1);
+    let old2 = PartId::from_num(7).elt_id(2);
+    let parent = part.tip().expect("getting tip").clone_child();
+    let mut state = parent.clone_exact();
+    state.insert("three".to_string()).expect("inserting elt");
+    state.add_alias(old1, e1).expect("adding alias");
+    assert_eq!(state.add_alias(e2, e1), Err(ElementOp::IdClash));
+    part.push_state(state).expect("committing");
+    let mut state = parent.clone_exact();
+    state.remove(e2).expect("removing elt");
+    state.add_alias(old2, e1).expect("adding alias");
+    part.push_state(state).expect("committing");
+    assert!(part.merge_required());
+    part.merge(&AncestorSolver2W::new()).expect("merging");
+    
+    let tip = part.tip().expect("getting tip");
+    assert!(!tip.is_avail(e2));
+    assert_eq!(tip.aliases_of(e1), vec![old1, old2]);
+    assert_eq!(tip.resolve(old2), Some(e1));
+    assert_eq!(tip.resolve(e1), Some(e1));
+    assert_eq!(tip.resolve(e2), None);
+    
+    // Aliases are part of the state-sum; notes of moves elsewhere are not
+    let old3 = part.part_id().elt_id(3);
+    let mut state = tip.clone_child();
+    let usage = state.id_usage();
+    state.add_alias(old3, e1).expect("adding alias");
+    assert!(state.id_usage() > usage);
+    let mut other = tip.clone_child();
+    other.set_move(old3, PartId::from_num(7).elt_id(3));
+    assert!(other.eq_elements(tip) && !state.eq_elements(tip));
+    assert!(state.statesum() != tip.statesum() && other.statesum() == tip.statesum());
+    let mut state2 = state.clone_exact();
+    assert_eq!(state2.remove_move(old3), Some(e1));
+    assert_eq!(state2.remove_move(old3), None);
+    assert_eq!(state2.statesum(), tip.statesum());
+    
+    // An alias alone makes a commit, which can be undone
+    assert!(part.push_state(state).expect("committing"));
+    assert_eq!(part.tip().expect("getting tip").resolve(old3), Some(e1));
+    let mut state = part.tip().expect("getting tip").clone_child();
+    state.remove_move(old3);
+    assert!(part.push_state(state).expect("committing"));
+    assert_eq!(part.tip().expect("getting tip").resolve(old3), None);
+    let mut state = part.tip().expect("getting tip").clone_child();
+    state.add_alias(old3, e1).expect("adding alias");
+    assert!(part.push_state(state).expect("committing"));
+    
+    // A renamed element keeps its old identifier in state A
+    struct Rename;
+    impl TwoWaySolver<String> for Rename {
+        fn solve<'a>(&self, _: Option<&'a Arc<String>>, _: Option<&'a Arc<String>>,
+            _: Option<&'a Arc<String>>) -> EltMerge<String>
+        {
+            EltMerge::Rename
+        }
+    }
+    let parent = part.tip().expect("getting tip").clone_child();
+    let mut state = parent.clone_exact();
+    state.replace(e1, "uno".to_string()).expect("replacing elt");
+    part.push_state(state).expect("committing");
+    let mut state = parent.clone_exact();
+    state.replace(e1, "eins".to_string()).expect("replacing elt");
+    part.push_state(state).expect("committing");
+    part.merge(&Rename).expect("merging");
+    let tip = part.tip().expect("getting tip");
+    assert_eq!(tip.num_avail(), 3);
+    let values: HashSet<String> = tip.map().values().map(|elt| (**elt).clone()).collect();
+    assert!(values.contains("uno") && values.contains("eins"));
+}
+
+#[test]
+fn observers() {
+    use std::sync::Mutex;
+    use merge::AncestorSolver2W;
+    use observe::ChangeKind;
+    
+    let io = box PartitionDummyIO::new();
+    let mut part = Partition::<String>::create(io, "observers").expect("partition creation");
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events2 = events.clone();
+    let id = part.subscribe(move |event| events2.lock().unwrap().push(event.clone()));
+    
+    let mut state = part.tip().expect("getting tip").clone_child();
+    let e1 = state.insert("one".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    assert_eq!(events.lock().unwrap().len(), 1);
+    assert_eq!(events.lock().unwrap()[0].changes, Some(vec![(e1, ChangeKind::Inserted)]));
+    assert_eq!(&events.lock().unwrap()[0].statesum, part.tip_key().expect("tip"));
+    
+    let parent = part.tip().expect("getting tip").clone_child();
+    let mut state = parent.clone_exact();
+    state.replace(e1, "ONE".to_string()).expect("replacing elt");
+    part.push_state(state).expect("committing");
+    let mut state = parent.clone_exact();
+    let e2 = state.insert("two".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    part.merge(&AncestorSolver2W::new()).expect("merging");
+    assert_eq!(events.lock().unwrap().len(), 4);
+    let merge = events.lock().unwrap()[3].clone();
+    assert_eq!(merge.parents.len(), 2);
+    let changes = merge.changes.expect("has changes");
+    assert!(changes == vec![(e1, ChangeKind::Replaced)] || changes == vec![(e2, ChangeKind::Inserted)]);
+    
+    assert!(part.unsubscribe(id));
+    assert!(!part.unsubscribe(id));
+    let mut state = part.tip().expect("getting tip").clone_child();
+    state.remove(e2).expect("removing elt");
+    part.push_state(state).expect("committing");
+    assert_eq!(events.lock().unwrap().len(), 4);
+}
+
+#[test]
+fn thread_safety() {
+    use std::thread;
+    use repo::{Repo, RepoState, RepoT, ClassifierT};
+    
+    fn is_send<T: Send>() {}
+    fn is_sync<T: Sync>() {}
+    #[allow(dead_code)]
+    fn repo_state_is_send<C: ClassifierT + Send>() where C::Element: Send + Sync {
+        is_send::<RepoState<C>>();
+    }
+    #[allow(dead_code)]
+    fn repo_is_send<C: ClassifierT, R: RepoT<C>>() where C::Element: Send + Sync {
+        is_send::<Repo<C, R>>();
+    }
+    is_send::<Partition<String>>();
+    is_send::<PartitionState<String>>();
+    is_sync::<PartitionState<String>>();
+    
+    let io = box PartitionDummyIO::new();
+    let mut part = Partition::<String>::create(io, "threads").expect("partition creation");
+    let mut state = part.tip().expect("getting tip").clone_child();
+    let e1 = state.insert("one".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    
+    // Copies of states can be used by other threads
+    let state = part.tip().expect("getting tip").clone_exact();
+    let elt = thread::spawn(move || state.get_arc(e1).expect("getting elt").clone())
+            .join().expect("joining thread");
+    assert_eq!(*elt, "one");
+}
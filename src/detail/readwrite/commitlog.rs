@@ -8,7 +8,7 @@
 
 use std::io::{Read, Write};
 use std::collections::HashMap;
-use std::rc::Rc;
+use std::sync::Arc;
 use std::u32;
 
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
@@ -17,6 +17,7 @@ use detail::readwrite::{sum};
 use detail::{Commit, EltChange, CommitMeta};
 use {ElementT, Sum};
 use detail::SUM_BYTES;
+use sign::{Signature, PUBLIC_KEY_BYTES, SIGNATURE_BYTES};
 use error::{Result, ReadError};
 
 /// Implement this to use read_log().
@@ -27,6 +28,8 @@ pub trait CommitReceiver<E: ElementT> {
 }
 
 /// Read a commit log from a stream
+/// 
+/// Signatures are read but not checked; see `LogReplay::set_trust_policy()`.
 pub fn read_log<E: ElementT>(reader_: &mut Read, receiver: &mut CommitReceiver<E>) -> Result<()> {
     let mut reader = reader_;
     let mut pos: usize = 0;
@@ -59,6 +62,8 @@ pub fn read_log<E: ElementT>(reader_: &mut Read, receiver: &mut CommitReceiver<E
         } else {
             return ReadError::err("unexpected contents (expected COMMIT or MERGE)", pos, (0, 6));
         };
+        // Signed commits use "\x00S" in place of "\x00U"
+        let signed = buf[6..8] == *b"\x00S";
         let meta = if buf[6..8] == *b"\x00\x00" {
             // Compatibility mode (2016_02_01 and older): no timestamp etc.
             pos += 16;
@@ -67,7 +72,7 @@ pub fn read_log<E: ElementT>(reader_: &mut Read, receiver: &mut CommitReceiver<E
                 timestamp: 0,
                 extra: None
             }
-        } else if buf[6..8] == *b"\x00U" {
+        } else if buf[6..8] == *b"\x00U" || signed {
             let secs = try!((&buf[8..16]).read_i64::<BigEndian>());
             pos += 16;
             
@@ -107,7 +112,7 @@ pub fn read_log<E: ElementT>(reader_: &mut Read, receiver: &mut CommitReceiver<E
                 extra: xm,
             }
         } else {
-            return ReadError::err("unexpected contents (expected \\x00U or \\x00\\x00)", pos, (6, 8));
+            return ReadError::err("unexpected contents (expected \\x00U, \\x00S or \\x00\\x00)", pos, (6, 8));
         };
         
         let mut parents = Vec::with_capacity(n_parents);
@@ -172,7 +177,7 @@ pub fn read_log<E: ElementT>(reader_: &mut Read, receiver: &mut CommitReceiver<E
                     }
                     pos += SUM_BYTES;
                     
-                    let elt = Rc::new(try!(E::from_vec(data)));
+                    let elt = Arc::new(try!(E::from_vec(data)));
                     match change_t {
                         Change::Insert => EltChange::insertion(elt),
                         Change::Replace => EltChange::replacement(elt),
@@ -195,6 +200,20 @@ pub fn read_log<E: ElementT>(reader_: &mut Read, receiver: &mut CommitReceiver<E
         let commit_sum = Sum::load(&buf[0..SUM_BYTES]);
         pos += SUM_BYTES;
         
+        let signature = if signed {
+            try!(r.read_exact(&mut buf[0..16]));
+            if buf[0..16] != *b"SIG ED25519\x00\x00\x00\x00\x00" {
+                return ReadError::err("unexpected contents (expected SIG ED25519)", pos, (0, 16));
+            }
+            pos += 16;
+            let mut sig_buf = vec![0; PUBLIC_KEY_BYTES + SIGNATURE_BYTES];
+            try!(r.read_exact(&mut sig_buf));
+            pos += sig_buf.len();
+            Some(try!(Signature::new(&sig_buf[0..PUBLIC_KEY_BYTES], &sig_buf[PUBLIC_KEY_BYTES..])))
+        } else {
+            None
+        };
+        
         let sum = r.sum();
         reader = r.into_inner();
         try!(reader.read_exact(&mut buf[0..SUM_BYTES]));
@@ -203,7 +222,9 @@ pub fn read_log<E: ElementT>(reader_: &mut Read, receiver: &mut CommitReceiver<E
         }
         
         trace!("Read commit ({} changes): {}; first parent: {}", changes.len(), commit_sum, parents[0]);
-        let cont = receiver.receive(Commit::new(commit_sum, parents, changes, meta));
+        let mut commit = Commit::new(commit_sum, parents, changes, meta);
+        commit.set_signature(signature);
+        let cont = receiver.receive(commit);
         if !cont { break; }
     }
     
@@ -230,15 +251,17 @@ pub fn write_commit<E: ElementT>(commit: &Commit<E>, writer: &mut Write) -> Resu
     // A writer which calculates the checksum of what was written:
     let mut w = sum::HashWriter::new(writer);
     
+    // Signed commits use "\x00S" in place of "\x00U"
+    let mode = if commit.signature().is_some() { b"\x00S" } else { b"\x00U" };
     if commit.parents().len() == 1 {
-        try!(w.write(b"COMMIT\x00U"));
+        try!(w.write(b"COMMIT"));
     } else {
         assert!(commit.parents().len() > 1 && commit.parents().len() < 0x100);
         try!(w.write(b"MERGE"));
         let n: [u8; 1] = [commit.parents().len() as u8];
         try!(w.write(&n));
-        try!(w.write(b"\x00U"));
     }
+    try!(w.write(mode));
     
     try!(w.write_i64::<BigEndian>(commit.meta().timestamp));
     
@@ -303,6 +326,12 @@ pub fn write_commit<E: ElementT>(commit: &Commit<E>, writer: &mut Write) -> Resu
     
     try!(commit.statesum().write(&mut w));
     
+    if let Some(sig) = commit.signature() {
+        try!(w.write(b"SIG ED25519\x00\x00\x00\x00\x00"));
+        try!(w.write(sig.public_key()));
+        try!(w.write(sig.signature()));
+    }
+    
     let sum = w.sum();
     try!(sum.write(&mut w.into_inner()));
     
@@ -327,16 +356,16 @@ fn commit_write_read(){
     
     let p = PartId::from_num(1681);
     let mut changes = HashMap::new();
-    changes.insert(p.elt_id(3), EltChange::insertion(Rc::new("three".to_string())));
-    changes.insert(p.elt_id(4), EltChange::insertion(Rc::new("four".to_string())));
-    changes.insert(p.elt_id(5), EltChange::insertion(Rc::new("five".to_string())));
+    changes.insert(p.elt_id(3), EltChange::insertion(Arc::new("three".to_string())));
+    changes.insert(p.elt_id(4), EltChange::insertion(Arc::new("four".to_string())));
+    changes.insert(p.elt_id(5), EltChange::insertion(Arc::new("five".to_string())));
     let meta1 = CommitMeta { number: 1, timestamp: 123456, extra: None };
     let commit_1 = Commit::new(seq, vec![squares], changes, meta1);
     
     changes = HashMap::new();
     changes.insert(p.elt_id(1), EltChange::deletion());
-    changes.insert(p.elt_id(9), EltChange::replacement(Rc::new("NINE!".to_string())));
-    changes.insert(p.elt_id(5), EltChange::insertion(Rc::new("five again?".to_string())));
+    changes.insert(p.elt_id(9), EltChange::replacement(Arc::new("NINE!".to_string())));
+    changes.insert(p.elt_id(5), EltChange::insertion(Arc::new("five again?".to_string())));
     let meta2 = CommitMeta { number: 1, timestamp: 321654, extra: Some("123".to_string()) };
     let commit_2 = Commit::new(nonsense, vec![quadr], changes, meta2);
     
@@ -362,3 +391,40 @@ fn commit_write_read(){
     assert_eq!(commits[0], commit_1);
     assert_eq!(commits[1], commit_2);
 }
+
+#[test]
+fn signed_commit_write_read() {
+    use PartId;
+    use sign::{SigningKey, SignatureStatus};
+    
+    let p = PartId::from_num(5);
+    let mut changes = HashMap::new();
+    changes.insert(p.elt_id(1), EltChange::insertion(Arc::new("one".to_string())));
+    changes.insert(p.elt_id(2), EltChange::moved(p.elt_id(3), false));
+    let meta = CommitMeta { number: 2, timestamp: 123456, extra: Some("me".to_string()) };
+    let mut commit = Commit::new(Sum::calculate(b"a"), vec![Sum::calculate(b"b")], changes, meta);
+    assert_eq!(commit.signature_status(), SignatureStatus::Unsigned);
+    
+    let key = SigningKey::from_seed(&[7u8; 32]).unwrap();
+    commit.sign(&key);
+    assert_eq!(commit.signature_status(), SignatureStatus::Valid(key.public_key()));
+    
+    let mut obj = Vec::new();
+    start_log(&mut obj).unwrap();
+    write_commit(&commit, &mut obj).unwrap();
+    struct Last(Option<Commit<String>>);
+    impl CommitReceiver<String> for Last {
+        fn receive(&mut self, commit: Commit<String>) -> bool { self.0 = Some(commit); true }
+    }
+    let mut last = Last(None);
+    read_log(&mut &obj[..], &mut last).unwrap();
+    let mut read = last.0.expect("has commit");
+    assert_eq!(read, commit);
+    assert_eq!(read.signature_status(), SignatureStatus::Valid(key.public_key()));
+    
+    // Signature no longer matches after modifying the commit
+    let sig = read.signature().cloned();
+    read.meta_mut().extra = Some("someone else".to_string());
+    read.set_signature(sig);
+    assert_eq!(read.signature_status(), SignatureStatus::Invalid);
+}
@@ -10,16 +10,16 @@ use std::result::Result as stdResult;
 
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
-use PartId;
+use {PartId, Sum};
 use detail::readwrite::{sum};
 use detail::SUM_BYTES;
 use error::{Result, ArgError, ReadError, make_io_err};
 use util::rtrim;
 
 // Snapshot header. This is the latest version.
-const HEAD_SNAPSHOT : [u8; 16] = *b"PIPPINSS20160227";
+const HEAD_SNAPSHOT : [u8; 16] = *b"PIPPINSS20160301";
 // Commit log header. This is the latest version.
-const HEAD_COMMITLOG : [u8; 16] = *b"PIPPINCL20160221";
+const HEAD_COMMITLOG : [u8; 16] = *b"PIPPINCL20160301";
 // Versions of header (all versions, including latest), encoded as an integer.
 // All restrictions to specific versions should mention `HEAD_VERSIONS` in
 // comments to aid searches.
@@ -27,17 +27,25 @@ const HEAD_COMMITLOG : [u8; 16] = *b"PIPPINCL20160221";
 // Note: new versions can be implemented just by updating the three HEAD_...
 // constants and updating code, so long as the code will still read old
 // versions. The file format documentation should also be updated.
-const HEAD_VERSIONS : [u32; 6] = [
+const HEAD_VERSIONS : [u32; 7] = [
     2015_09_29, // initial standardisation
     2016_01_05, // add 'PARTID' to header blocks (snapshot only)
     2016_02_01, // add memory of new names of moved elements
     2016_02_21, // add metadata to commits (logs only)
     2016_02_22, // add metadata to snapshots (snapshots only)
     2016_02_27, // add parent state-sums to snapshots (snapshots only)
+    2016_03_01, // aliases (notes of moves within a partition) contribute to state-sums
 ];
 const SUM_SHA256 : [u8; 16] = *b"HSUM SHA-2 256\x00\x00";
 const SUM_BLAKE2_16 : [u8; 16] = *b"HSUM BLAKE2 16\x00\x00";
 const PARTID : [u8; 8] = *b"HPARTID ";
+const TAG : [u8; 4] = *b"OTAG";
+const UNTAG : [u8; 4] = *b"OUTG";
+const BRANCH : [u8; 7] = *b"OBRANCH";
+const DELBRANCH : [u8; 10] = *b"ODELBRANCH";
+const REMOVED : [u8; 8] = *b"OREMOVED";
+// Number of state-sums in each OREMOVED block (filling a "QZ" block)
+const REMOVED_PER_BLOCK : usize = (16 * 35 - 2 - 8) / SUM_BYTES;
 
 /// File type and version.
 /// 
@@ -73,7 +81,36 @@ pub struct FileHeader {
     /// User remarks
     pub remarks: Vec<String>,
     /// User data
-    pub user_fields: Vec<Vec<u8>>
+    pub user_fields: Vec<Vec<u8>>,
+    /// Tags: name and tagged state-sum, or `None` where the tag was removed.
+    /// Snapshot headers list all tags; log headers list changes, in order.
+    pub tags: Vec<(String, Option<Sum>)>,
+    /// For log files only: if not `None`, commits in this log belong to the
+    /// named branch, which starts from the given state when not yet known.
+    pub branch: Option<(String, Sum)>,
+    /// For log files only: named branches deleted.
+    pub deleted_branches: Vec<String>,
+    /// For snapshot files only: states removed by compaction. Commits based
+    /// on these are expected to be found without their parents.
+    pub removed_states: Vec<Sum>,
+}
+
+impl FileHeader {
+    /// Create a header with the given type, repo name and partition
+    /// identifier; all other fields are empty.
+    pub fn new(ftype: FileType, name: String, part_id: Option<PartId>) -> FileHeader {
+        FileHeader {
+            ftype: ftype,
+            name: name,
+            part_id: part_id,
+            remarks: Vec::new(),
+            user_fields: Vec::new(),
+            tags: Vec::new(),
+            branch: None,
+            deleted_branches: Vec::new(),
+            removed_states: Vec::new(),
+        }
+    }
 }
 
 // Decodes from a string to the format used in HEAD_VERSIONS. Returns zero on
@@ -97,6 +134,38 @@ pub fn validate_repo_name(name: &str) -> stdResult<(), ArgError> {
     Ok(())
 }
 
+/// Check that a tag name can be stored in a header.
+pub fn validate_tag_name(name: &str) -> stdResult<(), ArgError> {
+    if name.len() == 0 {
+        return Err(ArgError::new("tag name missing (length 0)"));
+    }
+    if name.as_bytes().contains(&0) {
+        return Err(ArgError::new("tag name may not contain null bytes"));
+    }
+    // Limit is that of the longest 'Q' block ("QZ", 35 lines) containing
+    // "OTAG", a sum and the name
+    if name.as_bytes().len() > 16 * 35 - 2 - TAG.len() - SUM_BYTES {
+        return Err(ArgError::new("tag name too long"));
+    }
+    Ok(())
+}
+
+/// Check that a branch name can be stored in a header.
+pub fn validate_branch_name(name: &str) -> stdResult<(), ArgError> {
+    if name.len() == 0 {
+        return Err(ArgError::new("branch name missing (length 0)"));
+    }
+    if name.as_bytes().contains(&0) {
+        return Err(ArgError::new("branch name may not contain null bytes"));
+    }
+    // Limit is that of the longest 'Q' block ("QZ", 35 lines) containing
+    // "OBRANCH", a sum and the name
+    if name.as_bytes().len() > 16 * 35 - 2 - BRANCH.len() - SUM_BYTES {
+        return Err(ArgError::new("branch name too long"));
+    }
+    Ok(())
+}
+
 /// Read a file header.
 pub fn read_head(r: &mut Read) -> Result<FileHeader> {
     // A reader which also calculates a checksum:
@@ -126,13 +195,7 @@ pub fn read_head(r: &mut Read) -> Result<FileHeader> {
     };
     pos += 16;
     
-    let mut header = FileHeader{
-        ftype: ftype,
-        name: repo_name,
-        part_id: None,
-        remarks: Vec::new(),
-        user_fields: Vec::new(),
-    };
+    let mut header = FileHeader::new(ftype, repo_name, None);
     
     loop {
         try!(sum_reader.read_exact(&mut buf[0..16]));
@@ -180,9 +243,32 @@ pub fn read_head(r: &mut Read) -> Result<FileHeader> {
         } else if block[0] == b'U' {
             header.user_fields.push(rtrim(&block[1..], 0).to_vec());
         } else if block[0] == b'O' {
-            // Match optional extensions here; we currently have none
+            // Match optional extensions here
+            if block[0..4] == TAG && block.len() > 4 + SUM_BYTES {
+                let sum = Sum::load(&block[4..4+SUM_BYTES]);
+                let name = try!(String::from_utf8(rtrim(&block[4+SUM_BYTES..], 0).to_vec()));
+                header.tags.push((name, Some(sum)));
+            } else if block[0..4] == UNTAG {
+                let name = try!(String::from_utf8(rtrim(&block[4..], 0).to_vec()));
+                header.tags.push((name, None));
+            } else if block[0..7] == BRANCH && block.len() > 7 + SUM_BYTES {
+                if header.branch.is_some() {
+                    return ReadError::err("repeat of OBRANCH", pos, (off, off+7));
+                }
+                let sum = Sum::load(&block[7..7+SUM_BYTES]);
+                let name = try!(String::from_utf8(rtrim(&block[7+SUM_BYTES..], 0).to_vec()));
+                header.branch = Some((name, sum));
+            } else if block[0..10] == DELBRANCH {
+                let name = try!(String::from_utf8(rtrim(&block[10..], 0).to_vec()));
+                header.deleted_branches.push(name);
+            } else if block[0..8] == REMOVED {
+                // Any padding is shorter than a sum
+                for sum in block[8..].chunks(SUM_BYTES).filter(|b| b.len() == SUM_BYTES) {
+                    header.removed_states.push(Sum::load(sum));
+                }
+            }
         } else if block[0] >= b'A' && block[0] <= b'Z' {
-            // Match important extensions here; we currently have none
+            // Match other important extensions here
             // No match:
             // #0017: proper output of warnings
             println!("Warning: unrecognised file extension:");
@@ -264,12 +350,65 @@ pub fn write_head(header: &FileHeader, writer: &mut Write) -> Result<()> {
         }
     }
     
+    for &(ref name, ref sum) in &header.tags {
+        try!(validate_tag_name(name));
+        let mut b = Vec::with_capacity(4 + SUM_BYTES + name.len());
+        if let &Some(ref sum) = sum {
+            b.extend_from_slice(&TAG);
+            try!(sum.write(&mut b));
+        } else {
+            b.extend_from_slice(&UNTAG);
+        }
+        b.extend_from_slice(name.as_bytes());
+        try!(write_block(&mut w, &b));
+    }
+    
+    if let Some((ref name, ref sum)) = header.branch {
+        try!(validate_branch_name(name));
+        let mut b = Vec::with_capacity(BRANCH.len() + SUM_BYTES + name.len());
+        b.extend_from_slice(&BRANCH);
+        try!(sum.write(&mut b));
+        b.extend_from_slice(name.as_bytes());
+        try!(write_block(&mut w, &b));
+    }
+    for name in &header.deleted_branches {
+        try!(validate_branch_name(name));
+        let mut b = Vec::with_capacity(DELBRANCH.len() + name.len());
+        b.extend_from_slice(&DELBRANCH);
+        b.extend_from_slice(name.as_bytes());
+        try!(write_block(&mut w, &b));
+    }
+    for sums in header.removed_states.chunks(REMOVED_PER_BLOCK) {
+        let mut b = Vec::with_capacity(REMOVED.len() + sums.len() * SUM_BYTES);
+        b.extend_from_slice(&REMOVED);
+        for sum in sums {
+            try!(sum.write(&mut b));
+        }
+        try!(write_block(&mut w, &b));
+    }
+    
     try!(w.write(&SUM_BLAKE2_16));
     
     // Write the checksum of everything above:
     let sum = w.sum();
     try!(sum.write(&mut w.into_inner()));
     
+    // Write a block as an 'H' line if short enough, otherwise as 'Qx'.
+    // Length must already have been checked.
+    fn write_block<W: Write>(w: &mut W, b: &[u8]) -> Result<()> {
+        if b.len() <= 15 {
+            try!(w.write(b"H"));
+            try!(w.write(b));
+            try!(pad(w, 15 - b.len()));
+        } else {
+            let n = (b.len() + 2 /* Qx */ + 15 /* round up */) / 16;
+            let l = [b'Q', if n <= 9 { b'0' + n as u8 } else { b'A' - 10 + n as u8 } ];
+            try!(w.write(&l));
+            try!(w.write(b));
+            try!(pad(w, n * 16 - b.len() - 2));
+        }
+        Ok(())
+    }
     fn pad<W: Write>(w: &mut W, n1: usize) -> Result<()> {
         let zeros = [0u8; 16];
         let mut n = n1;
@@ -313,16 +452,15 @@ fn read_header() {
 #[test]
 fn write_header() {
     let header = FileHeader {
-        ftype: FileType::Snapshot(0 /*version should be ignored*/),
-        name: "Ähnliche Unsinn".to_string(),
-        part_id: None,
         remarks: vec!["Remark ω".to_string(), "R Quatsch Quatsch Quatsch".to_string()],
-        user_fields: vec![b" rsei noasr auyv 10()% xovn".to_vec()]
+        user_fields: vec![b" rsei noasr auyv 10()% xovn".to_vec()],
+        ..FileHeader::new(FileType::Snapshot(0 /*version should be ignored*/),
+                "Ähnliche Unsinn".to_string(), None)
     };
     let mut buf = Vec::new();
     write_head(&header, &mut buf).unwrap();
     
-    let expected = b"PIPPINSS20160227\
+    let expected = b"PIPPINSS20160301\
             \xc3\x84hnliche Unsinn\
             HRemark \xcf\x89\x00\x00\x00\x00\x00\x00\
             Q2R Quatsch Quatsch \
@@ -330,8 +468,69 @@ fn write_header() {
             Q2U rsei noasr a\
             uyv 10()% xovn\x00\x00\
             HSUM BLAKE2 16\x00\x00\
-            YN.\x9ft\x11r\x89y`\x9d\x9b\x09\xeb\x83\xbfE\x98M\x9c\x15\x95\x03l;\xe7\xf8\xceLb=\xc0";
+            L'FE}\x16\xb0\xa7\\\xcf\xcb\xf5|\x0c\xfe\x9d(\xc0\xc2_\x84\x8a\x00(9%0\xefQ\xba\xd6.";
     use ::util::ByteFormatter;
     println!("Checksum: '{}'", ByteFormatter::from(&buf[buf.len()-SUM_BYTES..buf.len()]));;
     assert_eq!(&buf[..], &expected[..]);
 }
+
+#[test]
+fn header_tags_and_branches() {
+    let sum = Sum::calculate(b"some state");
+    let header = FileHeader {
+        tags: vec![("before-migration".to_string(), Some(sum.clone())),
+            ("old".to_string(), None),
+            ("a rather long tag name which needs several blocks".to_string(), None)],
+        branch: Some(("import".to_string(), sum.clone())),
+        deleted_branches: vec!["old-import".to_string()],
+        ..FileHeader::new(FileType::CommitLog(0), "tag test".to_string(), None)
+    };
+    let mut buf = Vec::new();
+    write_head(&header, &mut buf).unwrap();
+    assert_eq!(buf.len() % 16, 0);
+    
+    let header2 = read_head(&mut &buf[..]).unwrap();
+    assert_eq!(header2.tags, header.tags);
+    assert_eq!(header2.branch, header.branch);
+    assert_eq!(header2.deleted_branches, header.deleted_branches);
+    
+    // Removed states span several blocks
+    let mut header = header;
+    header.removed_states = (0..40).map(|i| Sum::calculate(&[i as u8])).collect();
+    let mut buf = Vec::new();
+    write_head(&header, &mut buf).unwrap();
+    let header2 = read_head(&mut &buf[..]).unwrap();
+    assert_eq!(header2.removed_states, header.removed_states);
+    
+    assert!(validate_tag_name("").is_err());
+    assert!(validate_tag_name("a\x00b").is_err());
+}
+
+#[test]
+fn name_limits() {
+    // The longest allowed name fills a "QZ" block
+    let max = 16 * 35 - 2 - TAG.len() - SUM_BYTES;
+    let name = |n| ::std::iter::repeat('x').take(n).collect::<String>();
+    assert!(validate_tag_name(&name(max + 1)).is_err());
+    let header = FileHeader {
+        tags: vec![(name(max), Some(Sum::calculate(b"state")))],
+        ..FileHeader::new(FileType::Snapshot(0), "tag test".to_string(), None)
+    };
+    let mut buf = Vec::new();
+    write_head(&header, &mut buf).unwrap();
+    let header2 = read_head(&mut &buf[..]).unwrap();
+    assert_eq!(header2.tags, header.tags);
+    
+    let max = 16 * 35 - 2 - BRANCH.len() - SUM_BYTES;
+    assert!(validate_branch_name(&name(max + 1)).is_err());
+    let header = FileHeader {
+        branch: Some((name(max), Sum::calculate(b"state"))),
+        deleted_branches: vec![name(max)],
+        ..FileHeader::new(FileType::CommitLog(0), "branch test".to_string(), None)
+    };
+    let mut buf = Vec::new();
+    write_head(&header, &mut buf).unwrap();
+    let header2 = read_head(&mut &buf[..]).unwrap();
+    assert_eq!(header2.branch, header.branch);
+    assert_eq!(header2.deleted_branches, header.deleted_branches);
+}
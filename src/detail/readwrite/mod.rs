@@ -11,6 +11,8 @@ mod header;
 mod snapshot;
 mod commitlog;
 
-pub use self::header::{FileHeader, FileType, read_head, write_head, validate_repo_name};
-pub use self::snapshot::{read_snapshot, write_snapshot};
+pub use self::header::{FileHeader, FileType, read_head, write_head, validate_repo_name,
+    validate_tag_name, validate_branch_name};
+pub use self::snapshot::{read_snapshot, read_snapshot_parallel, write_snapshot};
+pub use self::snapshot::signature_status as snapshot_signature_status;
 pub use self::commitlog::{CommitReceiver, read_log, start_log, write_commit};
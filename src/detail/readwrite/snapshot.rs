@@ -5,16 +5,24 @@
 //! Support for reading and writing Rust snapshots
 
 use std::io::{Read, Write};
-use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::collections::HashMap;
 use std::{u8, u32};
+use std::cmp::min;
+use std::mem::replace;
+use std::thread::{self, JoinHandle};
+use std::panic::{self, AssertUnwindSafe};
 
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
 use detail::readwrite::{sum};
 use partition::{PartitionState, State};
-use {ElementT, PartId, Sum, CommitMeta};
+use detail::states::insert_bulk_with_ids;
+use {ElementT, PartId, EltId, Sum, CommitMeta};
 use detail::SUM_BYTES;
-use error::{Result, ReadError};
+use sign::{Signature, SignatureStatus, SigningKey, PUBLIC_KEY_BYTES, SIGNATURE_BYTES};
+use error::{Result, ReadError, OtherError};
 
 /// Read a snapshot of a set of elements from a stream.
 /// 
@@ -26,8 +34,184 @@ use error::{Result, ReadError};
 /// 
 /// The file version affects how data is read. Get it from a header with
 /// `header.ftype.ver()`.
+/// 
+/// The snapshot's signature, if any, is returned but not checked; see
+/// `signature_status()`.
 pub fn read_snapshot<T: ElementT>(reader: &mut Read, part_id: PartId,
-        file_ver: u32) -> Result<PartitionState<T>>
+        file_ver: u32) -> Result<(PartitionState<T>, Option<Signature>)>
+{
+    read_snapshot_with(reader, part_id, file_ver, 1, |state, batch, _| {
+        for raw in batch {
+            let (id, elt) = try!(decode_elt::<T>(raw));
+            try!(state.insert_with_id(id, Arc::new(elt)));
+        }
+        Ok(())
+    })
+}
+
+/// Number of elements per chunk passed to a worker thread by
+/// `read_snapshot_parallel`.
+pub const PARALLEL_BATCH: usize = 256;
+
+/// As `read_snapshot`, but checksums and decodes elements on `threads` worker
+/// threads.
+/// 
+/// The workers are started once per snapshot and fed chunks of
+/// `PARALLEL_BATCH` elements while reading continues; results are inserted
+/// in order. The result is the same as that of `read_snapshot`; on failure
+/// the error reported is that for the first bad element in the snapshot.
+pub fn read_snapshot_parallel<T>(reader: &mut Read, part_id: PartId,
+        file_ver: u32, threads: usize) -> Result<(PartitionState<T>, Option<Signature>)>
+    where T: ElementT + Send + Sync + 'static
+{
+    if threads <= 1 {
+        return read_snapshot(reader, part_id, file_ver);
+    }
+    let mut decoders = Decoders::new(threads);
+    read_snapshot_with(reader, part_id, file_ver, PARALLEL_BATCH, |state, batch, last| {
+        if !batch.is_empty() {
+            try!(decoders.send(state, batch));
+        }
+        if last {
+            try!(decoders.finish(state));
+        }
+        Ok(())
+    })
+}
+
+// Elements of a chunk, decoded, and the combination of their sums
+type Decoded<T> = Result<(Vec<(EltId, Arc<T>)>, Sum)>;
+
+// Worker threads decoding chunks of elements for `read_snapshot_parallel`.
+// Chunks are numbered in order and sent to workers in turn; results come
+// back in any order and are inserted in order.
+struct Decoders<T: ElementT> {
+    jobs: Vec<Sender<(usize, Vec<RawElt>)>>,
+    results: Receiver<(usize, Decoded<T>)>,
+    handles: Vec<JoinHandle<()>>,
+    // Number of chunks sent and inserted
+    sent: usize,
+    inserted: usize,
+    // Results received before those of earlier chunks
+    early: HashMap<usize, Decoded<T>>,
+}
+
+impl<T: ElementT + Send + Sync + 'static> Decoders<T> {
+    fn new(threads: usize) -> Decoders<T> {
+        let (result_tx, results) = channel();
+        let mut jobs = Vec::with_capacity(threads);
+        let mut handles = Vec::with_capacity(threads);
+        for _ in 0..threads {
+            let (job_tx, job_rx) = channel::<(usize, Vec<RawElt>)>();
+            let result_tx = result_tx.clone();
+            handles.push(thread::spawn(move || {
+                for (num, chunk) in job_rx {
+                    // Other chunks' results are awaited, so always send one
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| decode_chunk(chunk)))
+                            .unwrap_or_else(|_| OtherError::err("snapshot decoding panicked"));
+                    if result_tx.send((num, result)).is_err() {
+                        break;
+                    }
+                }
+            }));
+            jobs.push(job_tx);
+        }
+        Decoders {
+            jobs: jobs,
+            results: results,
+            handles: handles,
+            sent: 0,
+            inserted: 0,
+            early: HashMap::new(),
+        }
+    }
+    
+    // Send a chunk to the next worker. Results are inserted while too many
+    // chunks are outstanding, to bound memory use.
+    fn send(&mut self, state: &mut PartitionState<T>, chunk: Vec<RawElt>) -> Result<()> {
+        let threads = self.jobs.len();
+        if self.jobs[self.sent % threads].send((self.sent, chunk)).is_err() {
+            return OtherError::err("snapshot decoding thread exited");
+        }
+        self.sent += 1;
+        while self.sent - self.inserted > 2 * threads {
+            try!(self.insert_next(state));
+        }
+        Ok(())
+    }
+    
+    // Insert all outstanding chunks
+    fn finish(&mut self, state: &mut PartitionState<T>) -> Result<()> {
+        while self.inserted < self.sent {
+            try!(self.insert_next(state));
+        }
+        Ok(())
+    }
+    
+    // Insert the next chunk's elements, waiting for them if necessary
+    fn insert_next(&mut self, state: &mut PartitionState<T>) -> Result<()> {
+        let num = self.inserted;
+        let result = loop {
+            if let Some(result) = self.early.remove(&num) {
+                break result;
+            }
+            match self.results.recv() {
+                Ok((n, result)) => { self.early.insert(n, result); },
+                Err(_) => { return OtherError::err("snapshot decoding thread exited"); },
+            }
+        };
+        self.inserted += 1;
+        let (elts, sum) = try!(result);
+        try!(insert_bulk_with_ids(state, elts, &sum));
+        Ok(())
+    }
+}
+
+impl<T: ElementT> Drop for Decoders<T> {
+    fn drop(&mut self) {
+        // Workers stop once their queue is empty
+        self.jobs.clear();
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+// Verify and decode a chunk of elements
+fn decode_chunk<T: ElementT>(chunk: Vec<RawElt>) -> Decoded<T> {
+    let mut elts = Vec::with_capacity(chunk.len());
+    let mut sum = Sum::zero();
+    for raw in chunk {
+        let (id, elt) = try!(decode_elt::<T>(raw));
+        sum.permute(&elt.sum());
+        elts.push((id, Arc::new(elt)));
+    }
+    Ok((elts, sum))
+}
+
+// An element as read from a snapshot, not yet verified or decoded
+struct RawElt {
+    id: EltId,
+    data: Vec<u8>,
+    sum: Sum,
+    // Position of the checksum, for error reporting
+    pos: usize,
+}
+
+// Verify an element's checksum and decode it
+fn decode_elt<T: ElementT>(raw: RawElt) -> Result<(EltId, T)> {
+    if Sum::calculate(&raw.data) != raw.sum {
+        return ReadError::err("element checksum mismatch", raw.pos, (0, SUM_BYTES));
+    }
+    Ok((raw.id, try!(T::from_vec(raw.data))))
+}
+
+// Read a snapshot, passing elements to `add_elts` in batches of up to
+// `batch_size`, in order. The last call (whose batch may be empty) is marked
+// by passing true.
+fn read_snapshot_with<T, F>(reader: &mut Read, part_id: PartId, file_ver: u32,
+        batch_size: usize, mut add_elts: F) -> Result<(PartitionState<T>, Option<Signature>)>
+    where T: ElementT, F: FnMut(&mut PartitionState<T>, Vec<RawElt>, bool) -> Result<()>
 {
     // A reader which calculates the checksum of what was read:
     let mut r = sum::HashReader::new(reader);
@@ -37,16 +221,16 @@ pub fn read_snapshot<T: ElementT>(reader: &mut Read, part_id: PartId,
     assert!(buf.len() >= SUM_BYTES);
     
     try!(r.read_exact(&mut buf[0..16]));
-    let num_parents = if file_ver < 2016_02_27 {
+    let (num_parents, signed) = if file_ver < 2016_02_27 {
         if buf[0..8] != *b"SNAPSHOT" {
             return ReadError::err("unexpected contents (expected SNAPSHOT)", pos, (0, 8));
         }
-        0
+        (0, false)
     } else {
-        if buf[0..6] != *b"SNAPSH" || buf[7] != b'U' {
-            return ReadError::err("unexpected contents (expected SNAPSH_U where _ is any)", pos, (0, 8));
+        if buf[0..6] != *b"SNAPSH" || (buf[7] != b'U' && buf[7] != b'S') {
+            return ReadError::err("unexpected contents (expected SNAPSH_U or SNAPSH_S where _ is any)", pos, (0, 8));
         }
-        buf[6] as usize
+        (buf[6] as usize, buf[7] == b'S')
     };
     let secs = try!((&buf[8..16]).read_i64::<BigEndian>());
     pos += 16;
@@ -111,6 +295,7 @@ pub fn read_snapshot<T: ElementT>(reader: &mut Read, part_id: PartId,
     // #0016: here we don't set any parent sums. This isn't *correct*,
     // but since we won't be creating a commit from it it doesn't actually matter.
     let mut state = PartitionState::new_with(part_id, parents, meta);
+    let mut batch = Vec::with_capacity(min(batch_size, num_elts));
     for _ in 0..num_elts {
         try!(r.read_exact(&mut buf[0..32]));
         if buf[0..8] != *b"ELEMENT\x00" {
@@ -136,16 +321,16 @@ pub fn read_snapshot<T: ElementT>(reader: &mut Read, part_id: PartId,
             pos += pad_len;
         }
         
-        let elt_sum = Sum::calculate(&data);
         try!(r.read_exact(&mut buf[0..SUM_BYTES]));
-        if !elt_sum.eq(&buf[0..SUM_BYTES]) {
-            return ReadError::err("element checksum mismatch", pos, (0, SUM_BYTES));
-        }
+        batch.push(RawElt { id: ident, data: data, sum: Sum::load(&buf[0..SUM_BYTES]), pos: pos });
         pos += SUM_BYTES;
         
-        let elt = try!(T::from_vec(data));
-        try!(state.insert_with_id(ident, Rc::new(elt)));
+        if batch.len() >= batch_size {
+            let full = replace(&mut batch, Vec::with_capacity(batch_size));
+            try!(add_elts(&mut state, full, false));
+        }
     }
+    try!(add_elts(&mut state, batch, true));
     
     try!(r.read_exact(&mut buf[0..16]));
     if buf[0..8] == *b"ELTMOVES" /*versions from 20160201, optional*/ {
@@ -176,6 +361,20 @@ pub fn read_snapshot<T: ElementT>(reader: &mut Read, part_id: PartId,
     }
     pos += SUM_BYTES;
     
+    let signature = if signed {
+        try!(r.read_exact(&mut buf[0..16]));
+        if buf[0..16] != *b"SIG ED25519\x00\x00\x00\x00\x00" {
+            return ReadError::err("unexpected contents (expected SIG ED25519)", pos, (0, 16));
+        }
+        pos += 16;
+        let mut sig_buf = [0u8; PUBLIC_KEY_BYTES + SIGNATURE_BYTES];
+        try!(r.read_exact(&mut sig_buf));
+        pos += sig_buf.len();
+        Some(try!(Signature::new(&sig_buf[0..PUBLIC_KEY_BYTES], &sig_buf[PUBLIC_KEY_BYTES..])))
+    } else {
+        None
+    };
+    
     let sum = r.sum();
     let mut r = r.into_inner();
     try!(r.read_exact(&mut buf[0..SUM_BYTES]));
@@ -185,15 +384,15 @@ pub fn read_snapshot<T: ElementT>(reader: &mut Read, part_id: PartId,
     
     trace!("Read snapshot (partition {} with {} elements): {}",
         part_id.into_num(), num_elts, state.statesum());
-    Ok(state)
+    Ok((state, signature))
 }
 
 /// Write a snapshot of a set of elements to a stream
 /// 
 /// The snapshot is derived from a partition state, but also includes a
-/// partition identifier range.
+/// partition identifier range. If a key is given, the snapshot is signed.
 pub fn write_snapshot<T: ElementT>(state: &PartitionState<T>,
-    writer: &mut Write) -> Result<()>
+    key: Option<&SigningKey>, writer: &mut Write) -> Result<()>
 {
     trace!("Writing snapshot (partition {} with {} elements): {}",
         state.part_id().into_num(), state.num_avail(), state.statesum());
@@ -204,6 +403,9 @@ pub fn write_snapshot<T: ElementT>(state: &PartitionState<T>,
     let mut snapsh_u: [u8; 8] = *b"SNAPSH_U";
     assert!(state.parents().len() <= (u8::MAX as usize));
     snapsh_u[6] = state.parents().len() as u8;
+    if key.is_some() {
+        snapsh_u[7] = b'S';
+    }
     try!(w.write(&snapsh_u));
     try!(w.write_i64::<BigEndian>(state.meta().timestamp));
     
@@ -271,6 +473,13 @@ pub fn write_snapshot<T: ElementT>(state: &PartitionState<T>,
     try!(w.write_u64::<BigEndian>(num_elts));
     try!(state.statesum().write(&mut w));
     
+    if let Some(key) = key {
+        let sig = key.sign(&signed_data(state));
+        try!(w.write(b"SIG ED25519\x00\x00\x00\x00\x00"));
+        try!(w.write(sig.public_key()));
+        try!(w.write(sig.signature()));
+    }
+    
     // Write the checksum of everything above:
     let sum = w.sum();
     try!(sum.write(&mut w.into_inner()));
@@ -278,6 +487,56 @@ pub fn write_snapshot<T: ElementT>(state: &PartitionState<T>,
     Ok(())
 }
 
+/// Get the status of a snapshot's signature, as returned by `read_snapshot()`.
+pub fn signature_status<'a, T: ElementT>(state: &PartitionState<T>,
+        signature: Option<&'a Signature>) -> SignatureStatus<'a>
+{
+    match signature {
+        None => SignatureStatus::Unsigned,
+        Some(sig) if sig.verify(&signed_data(state)) =>
+            SignatureStatus::Valid(sig.public_key()),
+        Some(_) => SignatureStatus::Invalid,
+    }
+}
+
+// The data covered by a snapshot signature: state-sum, parents, meta-data and
+// each element's identifier and sum (in identifier order), then the aliases.
+// The state-sum alone is not enough: it is an XOR of element sums, so other
+// sets of elements with the same state-sum are easy to find.
+fn signed_data<T: ElementT>(state: &PartitionState<T>) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(b"PIPPIN SNAPSHOT\x00");
+    // Writing to a Vec does not fail
+    state.statesum().write(&mut data).expect("writing to Vec");
+    for parent in state.parents() {
+        parent.write(&mut data).expect("writing to Vec");
+    }
+    data.write_u32::<BigEndian>(state.meta().number).expect("writing to Vec");
+    data.write_i64::<BigEndian>(state.meta().timestamp).expect("writing to Vec");
+    if let Some(ref txt) = state.meta().extra {
+        data.write_u64::<BigEndian>(txt.len() as u64 + 1).expect("writing to Vec");
+        data.extend_from_slice(txt.as_bytes());
+    } else {
+        data.write_u64::<BigEndian>(0).expect("writing to Vec");
+    }
+    
+    let mut ids: Vec<&EltId> = state.map().keys().collect();
+    ids.sort();
+    data.write_u64::<BigEndian>(ids.len() as u64).expect("writing to Vec");
+    for id in ids {
+        data.write_u64::<BigEndian>((*id).into()).expect("writing to Vec");
+        state.map()[id].sum().write(&mut data).expect("writing to Vec");
+    }
+    let mut moves: Vec<(&EltId, &EltId)> = state.moved_map().iter().collect();
+    moves.sort();
+    data.write_u64::<BigEndian>(moves.len() as u64).expect("writing to Vec");
+    for (id, new_id) in moves {
+        data.write_u64::<BigEndian>((*id).into()).expect("writing to Vec");
+        data.write_u64::<BigEndian>((*new_id).into()).expect("writing to Vec");
+    }
+    data
+}
+
 #[test]
 fn snapshot_writing() {
     let part_id = PartId::from_num(1);
@@ -307,8 +566,70 @@ fn snapshot_writing() {
     state.insert(data.to_string()).unwrap();
     
     let mut result = Vec::new();
-    assert!(write_snapshot(&state, &mut result).is_ok());
+    assert!(write_snapshot(&state, None, &mut result).is_ok());
+    
+    let (state2, sig) = read_snapshot(&mut &result[..], part_id, 2016_02_27).unwrap();
+    assert_eq!(state, state2);
+    assert_eq!(signature_status(&state2, sig.as_ref()), SignatureStatus::Unsigned);
     
-    let state2 = read_snapshot(&mut &result[..], part_id, 2016_02_27).unwrap();
+    let key = SigningKey::from_seed(&[7; 32]).unwrap();
+    let mut result = Vec::new();
+    write_snapshot(&state, Some(&key), &mut result).unwrap();
+    let (state2, sig) = read_snapshot(&mut &result[..], part_id, 2016_02_27).unwrap();
     assert_eq!(state, state2);
+    assert_eq!(signature_status(&state2, sig.as_ref()),
+            SignatureStatus::Valid(key.public_key()));
+    
+    // The signature does not cover other states
+    let mut other = state2.clone_child();
+    other.insert("another".to_string()).unwrap();
+    assert_eq!(signature_status(&other, sig.as_ref()), SignatureStatus::Invalid);
+}
+
+#[test]
+fn snapshot_signature_covers_elements() {
+    let part_id = PartId::from_num(1);
+    let meta = CommitMeta::new_from(12, None);
+    let make = |first: &str, second: &str| {
+        let mut state = PartitionState::<String>::new_with(part_id, vec![], meta.clone());
+        state.insert_with_id(part_id.elt_id(1), Arc::new(first.to_string())).unwrap();
+        state.insert_with_id(part_id.elt_id(2), Arc::new(second.to_string())).unwrap();
+        state
+    };
+    let state = make("one", "two");
+    let key = SigningKey::from_seed(&[3; 32]).unwrap();
+    let mut result = Vec::new();
+    write_snapshot(&state, Some(&key), &mut result).unwrap();
+    let (_, sig) = read_snapshot::<String>(&mut &result[..], part_id, 2016_02_27).unwrap();
+    assert_eq!(signature_status(&state, sig.as_ref()),
+            SignatureStatus::Valid(key.public_key()));
+    
+    // Swapping elements keeps the state-sum (an XOR of element sums) but not
+    // the signature
+    let swapped = make("two", "one");
+    assert_eq!(swapped.statesum(), state.statesum());
+    assert_eq!(signature_status(&swapped, sig.as_ref()), SignatureStatus::Invalid);
+}
+
+#[test]
+fn snapshot_parallel() {
+    let part_id = PartId::from_num(1);
+    let mut state = PartitionState::<String>::new(part_id);
+    for i in 0..3000 {
+        state.insert(format!("element {:04}", i)).unwrap();
+    }
+    let mut result = Vec::new();
+    write_snapshot(&state, None, &mut result).unwrap();
+    
+    for threads in 1..5 {
+        let (state2, _) = read_snapshot_parallel(&mut &result[..], part_id, 2016_02_27, threads).unwrap();
+        assert_eq!(state, state2);
+    }
+    
+    // Corruption gives the same error as sequential reading
+    let pos = result.windows(12).position(|w| w == b"element 2345").unwrap();
+    result[pos + 8] = b'9';
+    let e1 = read_snapshot::<String>(&mut &result[..], part_id, 2016_02_27).unwrap_err();
+    let e2 = read_snapshot_parallel::<String>(&mut &result[..], part_id, 2016_02_27, 4).unwrap_err();
+    assert_eq!(e1.to_string(), e2.to_string());
 }
@@ -18,18 +18,27 @@
 //!     the `RepoIO` implementation
 
 use std::result;
-use std::collections::HashMap;
-use std::rc::Rc;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
 use std::mem::swap;
+use std::cmp::max;
+use std::thread;
+use std::panic::{self, AssertUnwindSafe};
+use std::hash::Hash;
+use std::any::Any;
 
 // Re-export these. We pretend these are part of the same module while keeping files smaller.
 pub use detail::repo_traits::{RepoIO, ClassifierT, ClassifyFallback, RepoT,
     RepoDivideError, DummyClassifier};
-use partition::{Partition, State, PartitionState};
+use partition::{Partition, State, PartitionState, EltIter, Durability, PendingWrite,
+    MAX_TRANSACTION_ATTEMPTS};
 use detail::{EltId};
 use merge::{TwoWaySolver};
+use stats::RepoStats;
+use observe::{ChangeEvent, ObserverId};
+use sign::{SigningKey, TrustPolicy};
 use PartId;
-use error::{Result, OtherError, TipError, ElementOp};
+use error::{Result, ArgError, OtherError, TipError, ElementOp};
 
 /// Handle on a repository.
 /// 
@@ -37,8 +46,8 @@ use error::{Result, OtherError, TipError, ElementOp};
 /// 
 /// Elements of a repository can be retrieved in a read-only fashion by
 /// specifying a partition identifier and element identifier, or elements can
-/// be searched for via various criteria TODO. These operations block access to
-/// the in-memory copy of the repository during their usage.
+/// be searched for with a predicate (see `search()`). These operations block
+/// access to the in-memory copy of the repository during their usage.
 /// 
 /// Additionally, a copy of the current state of a partition can be retrieved
 /// and used to read and write elements. The copy may be accessed without
@@ -134,7 +143,64 @@ impl<C: ClassifierT, R: RepoT<C>> Repo<C, R> {
         Ok(())
     }
     
+    /// As `load_all(all_history)`, but loads partitions in parallel on up to
+    /// `threads` threads (at least one).
+    /// 
+    /// Partitions are moved to worker threads while loading, and returned
+    /// afterwards. If loading a partition panics, the panic is caught and
+    /// this fails, but all partitions are kept (that partition may be only
+    /// partly loaded).
+    pub fn load_all_parallel(&mut self, all_history: bool, threads: usize) -> Result<()>
+        where C::Element: Send + Sync + 'static
+    {
+        let threads = max(threads, 1);
+        let mut groups: Vec<Vec<(PartId, Partition<C::Element>)>> =
+                (0..threads).map(|_| Vec::new()).collect();
+        for (i, (num, part)) in self.partitions.drain().enumerate() {
+            groups[i % threads].push((num, part));
+        }
+        
+        let handles: Vec<_> = groups.into_iter()
+            .filter(|group| !group.is_empty())
+            .map(|mut group| thread::spawn(move || {
+                let mut result = Ok(());
+                for &mut (_, ref mut part) in &mut group {
+                    // Catch panics so that the partition is returned
+                    let r = match panic::catch_unwind(AssertUnwindSafe(|| part.load(all_history))) {
+                        Ok(r) => r,
+                        Err(_) => OtherError::err("partition loading panicked"),
+                    };
+                    if let Err(e) = r {
+                        result = Err(e);
+                        break;
+                    }
+                }
+                (group, result)
+            }))
+            .collect();
+        
+        let mut result = Ok(());
+        for handle in handles {
+            match handle.join() {
+                Ok((group, r)) => {
+                    self.partitions.extend(group);
+                    if result.is_ok() {
+                        result = r;
+                    }
+                },
+                Err(_) => {
+                    if result.is_ok() {
+                        result = OtherError::err("partition loading thread panicked");
+                    }
+                },
+            }
+        }
+        result
+    }
+    
     /// Call `Partition::write(fast)` on all loaded partitions.
+    /// 
+    /// To write without blocking, see `writer::BackgroundWriter`.
     pub fn write_all(&mut self, fast: bool) -> Result<()> {
         for (_, part) in &mut self.partitions {
             try!(part.write(fast));
@@ -142,6 +208,239 @@ impl<C: ClassifierT, R: RepoT<C>> Repo<C, R> {
         Ok(())
     }
     
+    /// Call `Partition::take_unsaved()` on all partitions, for writing
+    /// without access to the repository.
+    /// 
+    /// On failure, changes already taken are restored.
+    pub fn take_unsaved_all(&mut self) -> Result<Vec<(PartId, PendingWrite<C::Element>)>> {
+        let mut taken = Vec::new();
+        let mut result = Ok(());
+        for (num, part) in &mut self.partitions {
+            match part.take_unsaved() {
+                Ok(pending) => taken.push((*num, pending)),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                },
+            }
+        }
+        if let Err(e) = result {
+            let _ = self.finish_write_all(taken, true);
+            return Err(e);
+        }
+        Ok(taken)
+    }
+    
+    /// Call `Partition::finish_write(pending, fast)` for each partition's
+    /// changes taken by `take_unsaved_all()`.
+    pub fn finish_write_all(&mut self, taken: Vec<(PartId, PendingWrite<C::Element>)>,
+        fast: bool) -> Result<()>
+    {
+        let mut result = Ok(());
+        for (num, pending) in taken {
+            let part = self.partitions.get_mut(&num).expect("partition exists");
+            let r = part.finish_write(pending, fast);
+            if result.is_ok() {
+                result = r;
+            }
+        }
+        result
+    }
+    
+    /// Insert many elements, writing each affected partition's new state
+    /// directly as a snapshot (see `Partition::bulk_load()`). Partitions are
+    /// loaded if necessary. Returns the new elements' identifiers, in the
+    /// order given.
+    /// 
+    /// Fails if classification fails; partitions processed before an error
+    /// keep their new elements.
+    pub fn bulk_load<I>(&mut self, elts: I) -> Result<Vec<EltId>>
+        where I: IntoIterator<Item = C::Element>
+    {
+        let classifier = self.classifier.clone_classifier();
+        let mut groups: HashMap<PartId, (Vec<usize>, Vec<C::Element>)> = HashMap::new();
+        let mut n = 0;
+        for elt in elts {
+            let num = try!(classify_new(&classifier, &elt));
+            let group = groups.entry(num).or_insert_with(|| (Vec::new(), Vec::new()));
+            group.0.push(n);
+            group.1.push(elt);
+            n += 1;
+        }
+        
+        let mut ids = vec![None; n];
+        for (num, (indices, elts)) in groups {
+            let part = match self.partitions.get_mut(&num) {
+                Some(part) => part,
+                None => { return OtherError::err("classifier returned unknown partition"); },
+            };
+            if !part.is_loaded() {
+                try!(part.load(false));
+            }
+            let part_ids = try!(part.bulk_load(elts));
+            for (i, id) in indices.into_iter().zip(part_ids) {
+                ids[i] = Some(id);
+            }
+        }
+        Ok(ids.into_iter().map(|id| id.expect("id assigned")).collect())
+    }
+    
+    /// Get statistics on all partitions (see `Partition::stats()`), along
+    /// with totals.
+    pub fn stats(&self) -> Result<RepoStats> {
+        let mut stats = RepoStats::default();
+        for (num, part) in &self.partitions {
+            let part_stats = try!(part.stats());
+            stats.total += &part_stats;
+            stats.partitions.push((*num, part_stats));
+        }
+        stats.partitions.sort_by_key(|&(num, _)| num.into_num());
+        Ok(stats)
+    }
+    
+    /// Find all elements matching `predicate`, returning identifiers and
+    /// elements in order of identifier.
+    /// 
+    /// If `hint` is given, partitions for which the classifier's
+    /// `may_match(num, hint)` returns false are skipped. Other partitions are
+    /// loaded if necessary (only the latest state). If `unload` is true,
+    /// partitions loaded by the search are unloaded again afterwards, so that
+    /// only one is loaded at a time.
+    /// 
+    /// Fails if loading fails or a searched partition requires a merge.
+    pub fn search<P>(&mut self, mut predicate: P, hint: Option<&Any>, unload: bool) ->
+        Result<Vec<(EltId, Arc<C::Element>)>>
+        where P: FnMut(&C::Element) -> bool
+    {
+        let mut nums: Vec<PartId> = self.partitions.keys().cloned().collect();
+        nums.sort_by_key(|num| num.into_num());
+        
+        let mut results = Vec::new();
+        for num in nums {
+            if let Some(hint) = hint {
+                if !self.classifier.may_match(num, hint) {
+                    trace!("Repo {}: search skips partition {}", self.name, num.into_num());
+                    continue;
+                }
+            }
+            let part = self.partitions.get_mut(&num).expect("partition exists");
+            let was_loaded = part.is_loaded();
+            if !was_loaded {
+                try!(part.load(false));
+            }
+            // unload even if getting the tip fails, then report the error
+            let result = part.tip().map(|tip| {
+                for (id, elt) in tip.map() {
+                    if predicate(elt) {
+                        results.push((*id, elt.clone()));
+                    }
+                }
+            });
+            if unload && !was_loaded {
+                part.unload(false);
+            }
+            try!(result);
+        }
+        results.sort_by_key(|&(id, _)| id);
+        Ok(results)
+    }
+    
+    /// List loaded partitions running low on element identifiers (see
+    /// `Partition::ids_low()`). These should be divided (see
+    /// `RepoT::divide()`) before identifier allocation starts failing.
+    /// 
+    /// Partitions which are not loaded or require a merge are not listed.
+    pub fn ids_low(&self) -> Vec<PartId> {
+        let mut nums: Vec<PartId> = self.partitions.iter()
+            .filter(|&(_, part)| part.ids_low().unwrap_or(false))
+            .map(|(num, _)| *num)
+            .collect();
+        nums.sort_by_key(|num| num.into_num());
+        nums
+    }
+    
+    /// Subscribe to change events on all partitions (see
+    /// `Partition::subscribe()`).
+    /// 
+    /// Only partitions the repository has now are subscribed to; a partition
+    /// added later (e.g. by a future partition division) would need its own
+    /// subscription via `subscribe_with_id()`.
+    pub fn subscribe<F: FnMut(&ChangeEvent) + Send + 'static>(&mut self, f: F) -> ObserverId {
+        let id = ObserverId::new();
+        let f = Arc::new(Mutex::new(f));
+        for part in self.partitions.values_mut() {
+            let f = f.clone();
+            // if a previous call panicked the lock is poisoned; carry on
+            // regardless rather than failing every later commit
+            part.subscribe_with_id(id, box (move |event: &ChangeEvent| {
+                let mut f = f.lock().unwrap_or_else(|e| e.into_inner());
+                (&mut *f)(event)
+            }));
+        }
+        id
+    }
+    /// Remove a subscription from all partitions. Returns true if found.
+    pub fn unsubscribe(&mut self, id: ObserverId) -> bool {
+        let mut found = false;
+        for part in self.partitions.values_mut() {
+            found |= part.unsubscribe(id);
+        }
+        found
+    }
+    
+    /// Call `Partition::add_index(name, extract)` on all partitions.
+    /// 
+    /// States obtained from `clone_state()` afterwards can be queried with
+    /// `RepoState::query()`.
+    /// 
+    /// Fails without changing anything if any partition already has an index
+    /// with this name.
+    pub fn add_index<K, F>(&mut self, name: &str, extract: F) -> Result<()>
+        where C::Element: 'static, K: Hash + Eq + Clone + Send + Sync + 'static,
+            F: Fn(&C::Element) -> Option<K> + Send + Sync + 'static
+    {
+        // Check first so that no partition is left with the index on failure
+        if self.partitions.values().any(|part| part.has_index(name)) {
+            return ArgError::err("index already exists");
+        }
+        let extract = Arc::new(extract);
+        for (_, part) in &mut self.partitions {
+            let extract = extract.clone();
+            try!(part.add_index(name, move |elt| extract(elt)));
+        }
+        Ok(())
+    }
+    
+    /// Call `Partition::set_durability(durability)` on all partitions.
+    pub fn set_durability(&mut self, durability: Durability) {
+        for (_, part) in &mut self.partitions {
+            part.set_durability(durability);
+        }
+    }
+    
+    /// Call `Partition::set_signing_key(key)` on all partitions.
+    pub fn set_signing_key(&mut self, key: Option<Arc<SigningKey>>) {
+        for (_, part) in &mut self.partitions {
+            part.set_signing_key(key.clone());
+        }
+    }
+    
+    /// Call `Partition::set_trust_policy(trust)` on all partitions.
+    pub fn set_trust_policy(&mut self, trust: Arc<TrustPolicy>) {
+        for (_, part) in &mut self.partitions {
+            part.set_trust_policy(trust.clone());
+        }
+    }
+    
+    /// Call `Partition::set_load_threads(threads)` on all partitions.
+    pub fn set_load_threads(&mut self, threads: usize)
+        where C::Element: Send + Sync + 'static
+    {
+        for (_, part) in &mut self.partitions {
+            part.set_load_threads(threads);
+        }
+    }
+    
     /// Call `Partition::write_snapshot()` on all loaded partitions.
     pub fn write_snapshot_all(&mut self) -> Result<()> {
         for (_, part) in &mut self.partitions {
@@ -184,7 +483,7 @@ impl<C: ClassifierT, R: RepoT<C>> Repo<C, R> {
     /// this should be roughly as efficient as calling `merge_required()`.
     /// 
     /// TODO: clearer names, maybe move some of the work around.
-    pub fn merge<S: TwoWaySolver<C::Element>>(&mut self, solver: &S) -> Result<()> {
+    pub fn merge<S: TwoWaySolver<C::Element>+?Sized>(&mut self, solver: &S) -> Result<()> {
         for (_, part) in &mut self.partitions {
             try!(part.merge(solver));
         }
@@ -214,6 +513,19 @@ impl<C: ClassifierT, R: RepoT<C>> Repo<C, R> {
         Ok(rs)
     }
     
+    /// Get a `RepoState` with a copy of the state of each loaded partition at
+    /// the given time (see `Partition::state_at()`). Older history is loaded
+    /// as required.
+    pub fn state_at(&mut self, timestamp: i64) -> Result<RepoState<C>> {
+        let mut rs = RepoState::new(self.classifier.clone_classifier());
+        for (num, part) in &mut self.partitions {
+            if part.is_loaded() {
+                rs.add_part(*num, try!(part.state_at(timestamp)).clone_exact());
+            }
+        }
+        Ok(rs)
+    }
+    
     /// Merge changes from a `RepoState` into the repo, consuming the
     /// `RepoState`.
     /// 
@@ -282,6 +594,87 @@ impl<C: ClassifierT, R: RepoT<C>> Repo<C, R> {
         }
         Ok(merge_required)
     }
+    
+    /// Make changes to all loaded partitions within a transaction.
+    /// 
+    /// This is the `Repo` equivalent of `Partition::transaction()`: the
+    /// closure `f` is run on a `RepoState` with a child of the tip of each
+    /// loaded partition. If it succeeds, changes are committed in each
+    /// partition and the closure's result returned; if it fails nothing is
+    /// committed. Commits are checked in every partition (see
+    /// `Partition::check_state()`) before any is made, so if one partition
+    /// refuses its commit nothing is committed anywhere. Writing is done only
+    /// once all commits are made; if that fails, the commits stay unsaved.
+    /// 
+    /// If `flush` is true, `load_all(false)` is called before running `f` and
+    /// again before committing, and `write_all(true)` afterwards. If any
+    /// changed partition's tip moved while `f` was running, the changes are
+    /// merged using `solver`, or, if no solver is given, the closure is run
+    /// again (up to `MAX_TRANSACTION_ATTEMPTS` times).
+    /// 
+    /// If a merge is required beforehand it is done using `solver`; without a
+    /// solver this fails with `TipError::MergeRequired`.
+    pub fn transaction<T, F>(&mut self, solver: Option<&TwoWaySolver<C::Element>>,
+        flush: bool, mut f: F) -> Result<T>
+        where F: FnMut(&mut RepoState<C>) -> Result<T>
+    {
+        for _ in 0..MAX_TRANSACTION_ATTEMPTS {
+            if flush {
+                try!(self.load_all(false));
+            }
+            if self.merge_required() {
+                match solver {
+                    Some(solver) => try!(self.merge(solver)),
+                    None => { return Err(box TipError::MergeRequired); },
+                }
+            }
+            
+            let mut state = RepoState::new(self.classifier.clone_classifier());
+            for (num, part) in &self.partitions {
+                if part.is_loaded() {
+                    state.add_part(*num, try!(part.tip()).clone_child());
+                }
+            }
+            let result = try!(f(&mut state));
+            
+            if flush {
+                try!(self.load_all(false));
+                let partitions = &self.partitions;
+                let moved = state.states.iter().any(|(num, pstate)| {
+                    let parent = &pstate.parents()[0];
+                    parent != pstate.statesum() &&
+                        partitions.get(num).and_then(|p| p.tip_key().ok()) != Some(parent)
+                });
+                if moved && solver.is_none() {
+                    trace!("Repo {}: tips changed during transaction; retrying", self.name);
+                    continue;
+                }
+            }
+            let mut checked = Vec::new();
+            for (num, pstate) in state.states {
+                let part = self.partitions.get_mut(&num).expect("partition in RepoState");
+                if let Some(commit) = try!(part.check_state(&pstate)) {
+                    checked.push((num, commit, pstate));
+                }
+            }
+            // All checks passed: commit everywhere before writing anything
+            let nums: Vec<PartId> = checked.iter().map(|&(num, _, _)| num).collect();
+            for (num, commit, pstate) in checked {
+                self.partitions.get_mut(&num).expect("partition").push_checked(commit, pstate);
+            }
+            for num in nums {
+                try!(self.partitions.get_mut(&num).expect("partition").write_if_due());
+            }
+            if let Some(solver) = solver {
+                try!(self.merge(solver));
+            }
+            if flush {
+                try!(self.write_all(true));
+            }
+            return Ok(result);
+        }
+        OtherError::err("transaction failed: tips changed on each attempt")
+    }
 }
 
 /// Provides read-write access to some or all partitions in a non-blocking
@@ -314,65 +707,68 @@ impl<C: ClassifierT> RepoState<C> {
         self.states.len()
     }
     
+    /// Query an index (see `PartitionState::query()`) in all partitions,
+    /// returning all matching identifiers in order.
+    /// 
+    /// Fails if the index is missing from any partition or has the wrong key
+    /// type.
+    pub fn query<K>(&self, index: &str, key: &K) -> Result<Vec<EltId>>
+        where C::Element: 'static, K: Hash + Eq + Clone + 'static
+    {
+        let mut ids = Vec::new();
+        for state in self.states.values() {
+            ids.extend(try!(state.query(index, key)));
+        }
+        ids.sort();
+        Ok(ids)
+    }
+    
     /// Find an element that may have moved. This method returns an EltId on
     /// success which can then be used by other methods (`get()`, etc.).
     /// 
     /// If the element has not been moved and its partition is loaded, this
     /// will return the same identifier and be fast.
     /// 
-    /// If the element's partition is not loaded, this will fail, since a
-    /// `RepoState` cannot load partitions. It will normally indicate which
-    /// partition should be loaded, however without checking the partition it
-    /// cannot be sure that this is correct.
-    /// 
-    /// This may also fail completely. In this case searching all partitions
-    /// may still find the element (either use `Repo::locate(...)` or
-    /// `Repo::load_all()` then call this again on a fresh `RepoState` or after
-    /// synchronising). This method does search all loaded partitions when
-    /// other strategies fail.
-    pub fn locate(&mut self, mut id: EltId) -> Result<EltId, ElementOp> {
-        let mut to_update = Vec::<EltId>::new();
-        loop {
-            let part_id = id.part_id();
-            if let Some(state) = self.states.get(&part_id) {
-                if state.is_avail(id) {
-                    // Partition is loaded and has element
-                    /*TODO: should we do this? Need to resolve lifetime issue if so.
-                    if to_update.len() > 1 {
-                        // Update notes in loaded partitions, excepting the last
-                        // which is already correct:
-                        to_update.pop();
-                        for old_id in to_update{
-                            let part_id = old_id.part_id();
-                            if let Some(mut state) = self.states.get_mut(&part_id) {
-                                state.set_move(old_id, id);
-                            }
-                        }
-                    }*/
-                    return Ok(id);
-                } else if let Some(new_id) = state.is_moved(id) {
-                    // We have a new lead, check whether the element is in fact
-                    // there. Remember this note.
-                    to_update.push(id);
-                    id = new_id;
-                    continue;
-                }
-                // else: Partition is loaded but does not have element!
-            } else {
-                return Err(ElementOp::NotLoaded);
+    /// Otherwise notes are followed: those in the partition an element moved
+    /// from, and the alias tables of loaded partitions, which map any
+    /// identifier an element has had to its current one (see
+    /// `PartitionState::add_alias()`).
+    /// 
+    /// If the element's partition is not loaded and no loaded partition has
+    /// an alias, this will fail with `ElementOp::NotLoaded`, since a
+    /// `RepoState` cannot load partitions; the identifier normally indicates
+    /// which partition should be loaded. Loading more partitions (e.g.
+    /// `Repo::load_all()`) then calling this again on a fresh `RepoState` may
+    /// find the element.
+    pub fn locate(&self, id: EltId) -> Result<EltId, ElementOp> {
+        let mut id = id;
+        let mut seen = HashSet::new();
+        let mut not_loaded = false;
+        while seen.insert(id) {
+            let next = match self.states.get(&id.part_id()) {
+                Some(state) => {
+                    if state.is_avail(id) {
+                        return Ok(id);
+                    }
+                    not_loaded = false;
+                    state.is_moved(id)
+                },
+                None => {
+                    not_loaded = true;
+                    None
+                },
+            };
+            // If the partition has no note, the partition the element moved
+            // to may remember the old name
+            let next = next.or_else(|| self.states.values()
+                    .filter_map(|state| state.is_moved(id)).next());
+            match next {
+                Some(new_id) => { id = new_id; },
+                None => { break; },
             }
-            break;
         }
         
-        // We didn't find the element. In this case we could naively
-        // search all partitions, however if so it would have a new identifier.
-        // We *could* try finding another element with the same `elt_num()`,
-        // but we might find the wrong element in this case (and could also
-        // miss the element we are looking for, since it might have a new num).
-        // TODO: should elements remember their old names?
-        
-        // No success; fail
-        Err(ElementOp::NotFound)
+        Err(if not_loaded { ElementOp::NotLoaded } else { ElementOp::NotFound })
     }
 }
 
@@ -387,32 +783,26 @@ impl<C: ClassifierT> State<C::Element> for RepoState<C> {
         let part_id = id.part_id();
         self.states.get(&part_id).map_or(false, |state| state.is_avail(id))
     }
-    fn get_rc(&self, id: EltId) -> Result<&Rc<C::Element>, ElementOp> {
-        let part_id = id.part_id();
-        match self.states.get(&part_id) {
-            Some(state) => state.get_rc(id),
+    fn get_arc(&self, id: EltId) -> Result<&Arc<C::Element>, ElementOp> {
+        let id = try!(self.locate(id));
+        match self.states.get(&id.part_id()) {
+            Some(state) => state.get_arc(id),
             None => Err(ElementOp::NotLoaded),
         }
     }
-    fn insert_rc(&mut self, elt: Rc<C::Element>) -> Result<EltId, ElementOp> {
-        let part_id = if let Some(part_id) = self.classifier.classify(&*elt) {
-            part_id
-        } else {
-            match self.classifier.fallback() {
-                ClassifyFallback::Default(part_id) | ClassifyFallback::ReplacedOrDefault(part_id) => part_id,
-                ClassifyFallback::ReplacedOrFail | ClassifyFallback::Fail => {
-                    return Err(ElementOp::ClassifyFailure);
-                },
-            }
-        };
+    fn iter<'a>(&'a self) -> EltIter<'a, C::Element> {
+        box self.states.values().flat_map(|state| state.iter())
+    }
+    fn insert_arc(&mut self, elt: Arc<C::Element>) -> Result<EltId, ElementOp> {
+        let part_id = try!(classify_new(&self.classifier, &*elt));
         if let Some(mut state) = self.states.get_mut(&part_id) {
             // Now insert into our PartitionState (may also fail):
-            state.insert_rc(elt)
+            state.insert_arc(elt)
         } else {
             Err(ElementOp::NotLoaded)
         }
     }
-    fn replace_rc(&mut self, id: EltId, elt: Rc<C::Element>) -> Result<Rc<C::Element>, ElementOp> {
+    fn replace_arc(&mut self, id: EltId, elt: Arc<C::Element>) -> Result<Arc<C::Element>, ElementOp> {
         let class_id = if let Some(class_id) = self.classifier.classify(&*elt) {
             class_id
         } else {
@@ -427,21 +817,30 @@ impl<C: ClassifierT> State<C::Element> for RepoState<C> {
         if class_id != id.part_id() {
             // Different partition; we need to move.
             // 1: Confirm we have the source partition available or abort.
+            // Also find the element's old names, to move these too.
             let source_id = id.part_id();
-            try!(if let Some(mut _source_state) = self.states.get_mut(&source_id) {
+            let aliases = try!(if let Some(source_state) = self.states.get(&source_id) {
                 // TODO: do we want to notify that `id` is about to be moved?
-                Ok(())
+                Ok(source_state.aliases_of(id))
             } else {
                 Err(ElementOp::NotLoaded)
             });
             // 2: Find target partition and insert element.
+            // The target remembers the element's old names.
             let new_id = try!(if let Some(mut target_state) = self.states.get_mut(&class_id) {
-                match target_state.insert_with_id(class_id.elt_id(id.elt_num()), elt.clone()) {
+                let new_id = try!(match target_state.insert_with_id(class_id.elt_id(id.elt_num()), elt.clone()) {
                     // success with the same element part of the id:
                     Ok(id) => Ok(id),
                     // failure; try with a new id:
-                    Err(_) => target_state.insert_rc(elt)
+                    Err(_) => target_state.insert_arc(elt)
+                });
+                for old_id in aliases.iter().chain(Some(&id)) {
+                    // skip names now used by other elements (or this one)
+                    if !target_state.is_avail(*old_id) {
+                        target_state.set_move(*old_id, new_id);
+                    }
                 }
+                Ok(new_id)
             } else {
                 Err(ElementOp::NotLoaded)
             });
@@ -450,7 +849,9 @@ impl<C: ClassifierT> State<C::Element> for RepoState<C> {
             // mutable references to two of its elements.
             if let Some(mut source_state) = self.states.get_mut(&source_id) {
                 let removed = try!(source_state.remove(id));
-                source_state.set_move(id, new_id);
+                for old_id in aliases.iter().chain(Some(&id)) {
+                    source_state.set_move(*old_id, new_id);
+                }
                 Ok(removed)
             } else {
                 Err(ElementOp::NotLoaded)
@@ -458,13 +859,13 @@ impl<C: ClassifierT> State<C::Element> for RepoState<C> {
         } else {
             // Same partition: just replace
             if let Some(mut state) = self.states.get_mut(&class_id) {
-                state.replace_rc(id, elt)
+                state.replace_arc(id, elt)
             } else {
                 Err(ElementOp::NotLoaded)
             }
         }
     }
-    fn remove(&mut self, id: EltId) -> Result<Rc<C::Element>, ElementOp> {
+    fn remove(&mut self, id: EltId) -> Result<Arc<C::Element>, ElementOp> {
         let part_id = id.part_id();
         if let Some(mut state) = self.states.get_mut(&part_id) {
             state.remove(id)
@@ -473,3 +874,14 @@ impl<C: ClassifierT> State<C::Element> for RepoState<C> {
         }
     }
 }
+
+// Classify a new element, using the classifier's fallback if necessary.
+fn classify_new<C: ClassifierT>(classifier: &C, elt: &C::Element) -> Result<PartId, ElementOp> {
+    if let Some(part_id) = classifier.classify(elt) {
+        return Ok(part_id);
+    }
+    match classifier.fallback() {
+        ClassifyFallback::Default(part_id) | ClassifyFallback::ReplacedOrDefault(part_id) => Ok(part_id),
+        ClassifyFallback::ReplacedOrFail | ClassifyFallback::Fail => Err(ElementOp::ClassifyFailure),
+    }
+}
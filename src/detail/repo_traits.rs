@@ -14,7 +14,10 @@ use error::{Error, Result};
 
 
 /// Provides file discovery and creation for a repository.
-pub trait RepoIO {
+/// 
+/// This must be `Send` (like `PartitionIO`) so that repositories can be moved
+/// between threads.
+pub trait RepoIO: Send {
     /// Convert self to a `&Any`
     fn as_any(&self) -> &Any;
     
@@ -82,6 +85,13 @@ pub trait ClassifierT {
     /// despite classification not being available in all cases. The default
     /// implementation returns `ClassifyFallback::Fail`.
     fn fallback(&self) -> ClassifyFallback { ClassifyFallback::Fail }
+    
+    /// Used by `Repo::search()` to skip partitions: given a search hint
+    /// (whose type is chosen by the user), return false if partition `num`
+    /// cannot contain any matching element.
+    /// 
+    /// The default implementation returns true (search all partitions).
+    fn may_match(&self, _num: PartId, _hint: &Any) -> bool { true }
 }
 
 /// Specifies what to do when classification fails and an element is to be
@@ -104,7 +114,9 @@ pub enum ClassifyFallback {
 
 /// Encapsulates a RepoIO and a ClassifierT, handling repartitioning and
 /// serialisation.
-pub trait RepoT<C: ClassifierT+Sized>: ClassifierT {
+/// 
+/// This must be `Send`, so that a `Repo` is `Send` when its elements are.
+pub trait RepoT<C: ClassifierT+Sized>: ClassifierT + Send {
     /// Get access to the I/O provider. This could be an instance of
     /// `DiscoverRepoFiles` or could be self (among other possibilities).
     fn repo_io<'a>(&'a mut self) -> &'a mut RepoIO;
@@ -0,0 +1,183 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Commit signatures
+//!
+//! Commits and snapshots may be signed with an Ed25519 key (see
+//! `Partition::set_signing_key()`); the signature is stored with the commit in
+//! the commit log. When logs are replayed, a `TrustPolicy` (see
+//! `Partition::set_trust_policy()`) decides whether each commit is accepted,
+//! quarantined (set aside, along with commits based on it; see
+//! `Partition::quarantined()`) or rejected (loading fails). Snapshots cannot
+//! be quarantined: loading fails unless the policy accepts them.
+//!
+//! A commit signature covers the commit's state-sum, parents, meta-data and
+//! the sums of all changed elements. A snapshot signature covers its
+//! state-sum, parents, meta-data, the sum of each element and the aliases.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use crypto::ed25519;
+use rand::{OsRng, Rng};
+
+use error::{Result, ArgError};
+
+/// Number of bytes in a public key
+pub const PUBLIC_KEY_BYTES: usize = 32;
+/// Number of bytes in a signature
+pub const SIGNATURE_BYTES: usize = 64;
+
+/// A key pair used to sign commits.
+pub struct SigningKey {
+    secret: Vec<u8>,
+    public: Vec<u8>,
+}
+
+impl SigningKey {
+    /// Derive a key pair from a 32-byte seed.
+    /// 
+    /// The seed is the secret: it should be random and kept private.
+    pub fn from_seed(seed: &[u8]) -> Result<SigningKey> {
+        if seed.len() != 32 {
+            return ArgError::err("signing key seed must be 32 bytes");
+        }
+        let (secret, public) = ed25519::keypair(seed);
+        Ok(SigningKey { secret: secret.to_vec(), public: public.to_vec() })
+    }
+    /// Generate a new key pair from the operating system's random number
+    /// generator. Returns the key and its seed (to be stored securely).
+    pub fn generate() -> Result<(SigningKey, Vec<u8>)> {
+        let mut seed = vec![0; 32];
+        try!(OsRng::new()).fill_bytes(&mut seed);
+        let key = try!(SigningKey::from_seed(&seed));
+        Ok((key, seed))
+    }
+    /// Get the public key, used to verify signatures
+    pub fn public_key(&self) -> &[u8] {
+        &self.public
+    }
+    /// Sign a message
+    pub fn sign(&self, message: &[u8]) -> Signature {
+        Signature {
+            public_key: self.public.clone(),
+            signature: ed25519::signature(message, &self.secret).to_vec(),
+        }
+    }
+}
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SigningKey {{ public: {:?} }}", self.public)
+    }
+}
+
+/// A signature, along with the public key of the signer.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Signature {
+    public_key: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl Signature {
+    /// Create from a public key and signature (e.g. as read from a file).
+    /// Fails if either has the wrong length.
+    pub fn new(public_key: &[u8], signature: &[u8]) -> Result<Signature> {
+        if public_key.len() != PUBLIC_KEY_BYTES || signature.len() != SIGNATURE_BYTES {
+            return ArgError::err("bad public key or signature length");
+        }
+        Ok(Signature { public_key: public_key.to_vec(), signature: signature.to_vec() })
+    }
+    /// Get the signer's public key
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+    /// Get the signature bytes
+    pub fn signature(&self) -> &[u8] {
+        &self.signature
+    }
+    /// True if this is a valid signature of `message`
+    pub fn verify(&self, message: &[u8]) -> bool {
+        ed25519::verify(message, &self.public_key, &self.signature)
+    }
+}
+
+/// The signature status of a commit (see `Commit::signature_status()`)
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SignatureStatus<'a> {
+    /// The commit is not signed
+    Unsigned,
+    /// The commit has a valid signature by the given public key
+    Valid(&'a [u8]),
+    /// The commit's signature does not match its contents
+    Invalid,
+}
+
+/// What to do with a commit read from a log
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Verdict {
+    /// Replay the commit
+    Accept,
+    /// Do not replay the commit (or its descendants), but keep it for
+    /// inspection
+    Quarantine,
+    /// Fail to load
+    Reject,
+}
+
+/// Decides which commits to trust.
+pub trait TrustPolicy: fmt::Debug + Send + Sync {
+    /// Judge a commit by its signature status
+    fn judge(&self, status: &SignatureStatus) -> Verdict;
+}
+
+/// Accept unsigned commits and commits signed by any key, but reject invalid
+/// signatures. This is the default.
+#[derive(Debug, Default)]
+pub struct AcceptAll;
+
+impl TrustPolicy for AcceptAll {
+    fn judge(&self, status: &SignatureStatus) -> Verdict {
+        match *status {
+            SignatureStatus::Invalid => Verdict::Reject,
+            _ => Verdict::Accept,
+        }
+    }
+}
+
+/// Accept commits signed by known keys. Invalid signatures are rejected;
+/// the verdicts for unsigned commits and unknown keys are configurable.
+#[derive(Debug)]
+pub struct TrustedKeys {
+    keys: HashSet<Vec<u8>>,
+    unsigned: Verdict,
+    unknown: Verdict,
+}
+
+impl TrustedKeys {
+    /// Create, with no keys. `unsigned` and `unknown` are the verdicts for
+    /// unsigned commits and for commits signed by keys not added.
+    pub fn new(unsigned: Verdict, unknown: Verdict) -> TrustedKeys {
+        TrustedKeys { keys: HashSet::new(), unsigned: unsigned, unknown: unknown }
+    }
+    /// Trust a public key
+    pub fn add_key(&mut self, public_key: &[u8]) {
+        self.keys.insert(public_key.to_vec());
+    }
+    /// Stop trusting a public key. Returns true if it was trusted.
+    pub fn remove_key(&mut self, public_key: &[u8]) -> bool {
+        self.keys.remove(public_key)
+    }
+}
+
+impl TrustPolicy for TrustedKeys {
+    fn judge(&self, status: &SignatureStatus) -> Verdict {
+        match *status {
+            SignatureStatus::Unsigned => self.unsigned,
+            SignatureStatus::Valid(key) if self.keys.contains(key) => Verdict::Accept,
+            SignatureStatus::Valid(_) => self.unknown,
+            SignatureStatus::Invalid => Verdict::Reject,
+        }
+    }
+}
@@ -4,16 +4,21 @@
 
 //! Pippin: support for dealing with log replay, commit creation, etc.
 
-use std::collections::{HashMap};
+use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::{Keys};
 use std::clone::Clone;
-use std::rc::Rc;
+use std::hash::Hash;
+use std::sync::Arc;
 
 use hashindexed::KeyComparator;
-use rand::random;
 
 use {ElementT, Sum, PartId, EltId, CommitMeta};
-use error::ElementOp;
+use detail::index::{Index, IndexT, Indexes};
+use detail::ids::{IdAllocator, RandomIds, ID_USAGE_WARN, id_space};
+use error::{self, ElementOp, ArgError};
+
+/// A boxed iterator over elements, as returned by `State::iter()`.
+pub type EltIter<'a, E> = Box<Iterator<Item = (EltId, &'a E)> + 'a>;
 
 /// Trait abstracting over operations on the state of a partition or
 /// repository.
@@ -39,19 +44,46 @@ pub trait State<E: ElementT> {
     /// Get a reference to some element (which can be cloned if required).
     /// 
     /// This fails if the relevant partition is not loaded or the element is
-    /// not found. In the case of a multi-partition repository it is possible
-    /// that the element has been moved, in which case `RepoState::locate(id)`
-    /// may be helpful.
+    /// not found. On a `RepoState`, this also finds elements which have been
+    /// moved or renamed since being known as `id` (see
+    /// `RepoState::locate(id)`, which gives the current identifier).
     /// 
     /// Note that elements can't be modified directly but must instead be
     /// replaced with a new version, hence there is no version of this function
     /// returning a mutable reference.
     fn get(&self, id: EltId) -> Result<&E, ElementOp> {
-        self.get_rc(id).map(|rc| &**rc)
+        self.get_arc(id).map(|rc| &**rc)
     }
     /// Low-level version of `get(id)`: returns a reference to the
     /// reference-counter wrapped container of the element.
-    fn get_rc(&self, id: EltId) -> Result<&Rc<E>, ElementOp>;
+    fn get_arc(&self, id: EltId) -> Result<&Arc<E>, ElementOp>;
+    /// Old name of `get_arc(id)`.
+    #[deprecated(note = "renamed to get_arc; elements are now wrapped in Arc, not Rc")]
+    fn get_rc(&self, id: EltId) -> Result<&Arc<E>, ElementOp> {
+        self.get_arc(id)
+    }
+    
+    /// Iterate over all available elements (on a repository, those in
+    /// *loaded* partitions), as `(id, element)` pairs.
+    /// 
+    /// Order is unspecified and may differ between calls; see
+    /// `iter_ordered()`.
+    fn iter<'a>(&'a self) -> EltIter<'a, E>;
+    /// Iterate over available elements in order of identifier.
+    /// 
+    /// This must collect and sort all elements first, so is `O(n log n)`.
+    fn iter_ordered<'a>(&'a self) -> EltIter<'a, E> {
+        let mut elts: Vec<_> = self.iter().collect();
+        elts.sort_by_key(|&(id, _)| id);
+        box elts.into_iter()
+    }
+    /// Iterate over available elements for which `predicate` returns true.
+    /// Order is as for `iter()`.
+    fn filter<'a, P>(&'a self, mut predicate: P) -> EltIter<'a, E>
+        where P: FnMut(&E) -> bool + 'a, Self: Sized
+    {
+        box self.iter().filter(move |&(_, elt)| predicate(elt))
+    }
     
     /// Insert a new element and return the identifier.
     /// 
@@ -65,11 +97,16 @@ pub trait State<E: ElementT> {
     /// allowed since the partition is determined automatically and the
     /// partition number becomes part of the element identifier.
     fn insert(&mut self, elt: E) -> Result<EltId, ElementOp> {
-        self.insert_rc(Rc::new(elt))
+        self.insert_arc(Arc::new(elt))
     }
     /// Low-level version of `insert(id)`: takes a reference-counter wrapper
     /// for an element.
-    fn insert_rc(&mut self, elt: Rc<E>) -> Result<EltId, ElementOp>;
+    fn insert_arc(&mut self, elt: Arc<E>) -> Result<EltId, ElementOp>;
+    /// Old name of `insert_arc(elt)`.
+    #[deprecated(note = "renamed to insert_arc; elements are now wrapped in Arc, not Rc")]
+    fn insert_rc(&mut self, elt: Arc<E>) -> Result<EltId, ElementOp> {
+        self.insert_arc(elt)
+    }
     
     /// Replace an existing element and return the identifier of the newly
     /// inserted element and the replaced element. Note that the identifier
@@ -81,14 +118,19 @@ pub trait State<E: ElementT> {
     /// that the element has been moved, in which case `RepoState::locate(id)`
     /// may be helpful.
     /// 
-    /// Note that the returned `Rc<E>` cannot be unwrapped automatically since
+    /// Note that the returned `Arc<E>` cannot be unwrapped automatically since
     /// we do not know that we have the only reference.
-    fn replace(&mut self, id: EltId, elt: E) -> Result<Rc<E>, ElementOp> {
-        self.replace_rc(id, Rc::new(elt))
+    fn replace(&mut self, id: EltId, elt: E) -> Result<Arc<E>, ElementOp> {
+        self.replace_arc(id, Arc::new(elt))
     }
-    /// Low-level version of `replace(id, elt)` which takes an Rc-wrapped
+    /// Low-level version of `replace(id, elt)` which takes an Arc-wrapped
     /// element.
-    fn replace_rc(&mut self, id: EltId, elt: Rc<E>) -> Result<Rc<E>, ElementOp>;
+    fn replace_arc(&mut self, id: EltId, elt: Arc<E>) -> Result<Arc<E>, ElementOp>;
+    /// Old name of `replace_arc(id, elt)`.
+    #[deprecated(note = "renamed to replace_arc; elements are now wrapped in Arc, not Rc")]
+    fn replace_rc(&mut self, id: EltId, elt: Arc<E>) -> Result<Arc<E>, ElementOp> {
+        self.replace_arc(id, elt)
+    }
     
     /// Remove an element, returning the element removed or failing.
     /// 
@@ -97,9 +139,9 @@ pub trait State<E: ElementT> {
     /// that the element has been moved, in which case `RepoState::locate(id)`
     /// may be helpful.
     /// 
-    /// Note that the returned `Rc<E>` cannot be unwrapped automatically since
+    /// Note that the returned `Arc<E>` cannot be unwrapped automatically since
     /// we do not know that we have the only reference.
-    fn remove(&mut self, id: EltId) -> Result<Rc<E>, ElementOp>;
+    fn remove(&mut self, id: EltId) -> Result<Arc<E>, ElementOp>;
 }
 
 /// A state of elements within a partition.
@@ -113,14 +155,25 @@ pub trait State<E: ElementT> {
 /// 
 /// Elements may be inserted, deleted or replaced. Direct modification is not
 /// supported.
-#[derive(PartialEq, Debug)]
+/// 
+/// States created via `clone_child()` or `child_with_parents()` track which
+/// elements are changed, allowing `Commit::from_state()` to create a commit
+/// in time proportional to the number of changes.
+#[derive(Debug)]
 pub struct PartitionState<E: ElementT> {
     part_id: PartId,
     parents: Vec<Sum>,
     statesum: Sum,
-    elts: HashMap<EltId, Rc<E>>,
+    elts: HashMap<EltId, Arc<E>>,
     moved: HashMap<EltId, EltId>,
     meta: CommitMeta,
+    // Identifiers of elements and move notes changed since the first parent,
+    // if tracked
+    changed: Option<HashSet<EltId>>,
+    // Secondary indexes over elements
+    indexes: Indexes<E>,
+    // Strategy for choosing new element identifiers
+    allocator: Arc<IdAllocator>,
 }
 
 impl<E: ElementT> PartitionState<E> {
@@ -136,6 +189,9 @@ impl<E: ElementT> PartitionState<E> {
             elts: HashMap::new(),
             moved: HashMap::new(),
             meta: CommitMeta::new_empty(),
+            changed: None,
+            indexes: Indexes::new(),
+            allocator: Arc::new(RandomIds),
         }
     }
     /// As `new()`, but letting the user specify commit meta-data and parents.
@@ -149,6 +205,9 @@ impl<E: ElementT> PartitionState<E> {
             elts: HashMap::new(),
             moved: HashMap::new(),
             meta: meta,
+            changed: None,
+            indexes: Indexes::new(),
+            allocator: Arc::new(RandomIds),
         }
     }
     
@@ -160,70 +219,147 @@ impl<E: ElementT> PartitionState<E> {
     /// Note: 'parents' is not persisted by snapshots; currently it doesn't
     /// need to be.
     pub fn parents(&self) -> &Vec<Sum> { &self.parents }
+    /// Replace the parents' sums. This is used when history is rewritten
+    /// (see `Partition::compact()`).
+    pub fn set_parents(&mut self, parents: Vec<Sum>) { self.parents = parents; }
     /// Get the partition identifier
     pub fn part_id(&self) -> PartId { self.part_id }
     /// Get the commit meta-data associated with this state
     pub fn meta(&self) -> &CommitMeta { &self.meta }
+    /// Set the commit meta-data associated with this state
+    pub fn set_meta(&mut self, meta: CommitMeta) { self.meta = meta; }
     
     /// Get access to the map holding elements
-    pub fn map(&self) -> &HashMap<EltId, Rc<E>> {
+    pub fn map(&self) -> &HashMap<EltId, Arc<E>> {
         &self.elts
     }
     /// Destroy the PartitionState, extracting its maps
     /// 
     /// First is map of elements (`self.map()`), second is map of moved elements
     /// (`self.moved_map()`).
-    pub fn into_maps(self) -> (HashMap<EltId, Rc<E>>, HashMap<EltId, EltId>) {
+    pub fn into_maps(self) -> (HashMap<EltId, Arc<E>>, HashMap<EltId, EltId>) {
         (self.elts, self.moved)
     }
     /// Get access to the map of moved elements to new identifiers
     pub fn moved_map(&self) -> &HashMap<EltId, EltId> {
         &self.moved
     }
+    /// Returns true if both states have the same elements and aliases (see
+    /// `add_alias()`).
+    /// 
+    /// Unlike `==`, this ignores parents, meta-data and notes of elements
+    /// moved to other partitions (none of which affect the state-sum), and
+    /// does not rely on state-sums. Operation is `O(n)` in the number of
+    /// elements.
+    pub fn eq_elements(&self, other: &PartitionState<E>) -> bool {
+        let part_id = self.part_id;
+        let aliases = |state: &PartitionState<E>| state.moved.iter()
+                .filter(|&(_, new_id)| new_id.part_id() == part_id).count();
+        self.elts == other.elts && aliases(self) == aliases(other) &&
+            self.moved.iter().filter(|&(_, new_id)| new_id.part_id() == part_id)
+                .all(|(id, new_id)| other.moved.get(id) == Some(new_id))
+    }
+    /// Add a secondary index named `name`, replacing any existing index with
+    /// this name. The index maps keys returned by `extract` to identifiers of
+    /// elements; elements for which `extract` returns `None` are not indexed.
+    /// 
+    /// The index is built now (`O(n)`), then updated as elements are
+    /// inserted, replaced and removed. Child states (`clone_child()`) inherit
+    /// indexes. See `query()`.
+    pub fn add_index<K, F>(&mut self, name: &str, extract: F)
+        where E: 'static, K: Hash + Eq + Clone + Send + Sync + 'static,
+            F: Fn(&E) -> Option<K> + Send + Sync + 'static
+    {
+        let index: Index<E, K> = Index::new(Arc::new(extract));
+        self.add_index_like(name, &index);
+    }
+    /// Add an index using the same key extractor as `index` (which may belong
+    /// to another state), building it from this state's elements.
+    pub fn add_index_like(&mut self, name: &str, index: &IndexT<E>) {
+        let mut index = index.new_empty();
+        for (id, elt) in &self.elts {
+            index.insert(*id, elt);
+        }
+        self.indexes.add(name.to_string(), index);
+    }
+    /// Get the set of indexes
+    pub fn indexes(&self) -> &Indexes<E> {
+        &self.indexes
+    }
+    /// Get the identifiers of all elements whose key in index `index` equals
+    /// `key`, in order.
+    /// 
+    /// Fails if there is no such index or its key type is not `K`.
+    pub fn query<K>(&self, index: &str, key: &K) -> error::Result<Vec<EltId>>
+        where E: 'static, K: Hash + Eq + Clone + 'static
+    {
+        let index = match self.indexes.get(index) {
+            Some(index) => index,
+            None => { return ArgError::err("index not found"); }
+        };
+        match index.as_any().downcast_ref::<Index<E, K>>() {
+            Some(index) => Ok(index.get(key)),
+            None => ArgError::err("wrong key type for index"),
+        }
+    }
+    /// Set the strategy used to allocate identifiers for new elements
+    /// (default: `RandomIds`). Child states inherit this.
+    pub fn set_id_allocator(&mut self, allocator: Arc<IdAllocator>) {
+        self.allocator = allocator;
+    }
+    /// Get the strategy used to allocate identifiers for new elements
+    pub fn id_allocator(&self) -> &Arc<IdAllocator> {
+        &self.allocator
+    }
+    /// Get the fraction of this partition's element identifier space in use
+    /// (by elements and move notes), between 0 and 1. Aliases of identifiers
+    /// from other partitions are not counted.
+    pub fn id_usage(&self) -> f64 {
+        let part_id = self.part_id;
+        let notes = self.moved.keys().filter(|id| id.part_id() == part_id).count();
+        (self.elts.len() + notes) as f64 / id_space() as f64
+    }
+    /// True when `id_usage()` exceeds `ID_USAGE_WARN`, in which case the
+    /// partition should be divided before identifier allocation fails.
+    pub fn ids_low(&self) -> bool {
+        self.id_usage() > ID_USAGE_WARN
+    }
     /// Get the element keys
-    pub fn elt_ids(&self) -> Keys<EltId, Rc<E>> {
+    pub fn elt_ids(&self) -> Keys<EltId, Arc<E>> {
         self.elts.keys()
     }
-    
-    /// Generate an element identifier.
+    /// Get the identifiers of elements and move notes which may have changed
+    /// since this state was created from its first parent, or `None` if
+    /// changes are not tracked (see `clone_child()`).
     /// 
-    /// This generates a pseudo-random number
-    pub fn gen_id(&self) -> Result<EltId, ElementOp> {
-        // Generate an identifier: (1) use a random sample, (2) increment if
-        // taken, (3) add the partition identifier.
-        let initial = self.part_id.elt_id(random::<u32>() & 0xFF_FFFF);
-        let mut id = initial;
-        loop {
-            if !self.elts.contains_key(&id) && !self.moved.contains_key(&id) { break; }
-            id = id.next_elt();
-            // #0019: is this too many to check exhaustively? We could use a
-            // lower limit, and possibly resample a few times.
-            // Note that gen_id_binary uses a slightly different algorithm.
-            if id == initial {
-                return Err(ElementOp::IdGenFailure);
-            }
+    /// Identifiers may be listed even when the element is unchanged (e.g. if
+    /// it was inserted then removed).
+    pub fn changed_ids(&self) -> Option<&HashSet<EltId>> {
+        self.changed.as_ref()
+    }
+    
+    // Note a change to the element or move note with the given identifier
+    fn note_change(&mut self, id: EltId) {
+        if let Some(ref mut changed) = self.changed {
+            changed.insert(id);
         }
-        Ok(id)
+    }
+    
+    /// Generate an element identifier, using the state's allocator (see
+    /// `set_id_allocator()`).
+    pub fn gen_id(&self) -> Result<EltId, ElementOp> {
+        self.allocator.allocate(self.part_id, &|id| self.is_free(id))
     }
     /// As `gen_id()`, but ensure the generated id is free in both self and
     /// another state. Note that the other state is assumed to have the same
     /// `part_id`; if not this is equivalent to `gen_id()`.
     pub fn gen_id_binary(&self, s2: &PartitionState<E>) -> Result<EltId, ElementOp> {
-        let mut id = try!(self.gen_id());
-        let mut tries = 1000;
-        loop {
-            if !self.elts.contains_key(&id) && !s2.elts.contains_key(&id) &&
-                !self.moved.contains_key(&id) && !s2.moved.contains_key(&id)
-            {
-                break;
-            }
-            id = id.next_elt();
-            tries -= 1;
-            if tries == 0 {
-                return Err(ElementOp::IdGenFailure);
-            }
-        }
-        Ok(id)
+        self.allocator.allocate(self.part_id, &|id| self.is_free(id) && s2.is_free(id))
+    }
+    
+    // True if neither an element nor a move note uses this identifier
+    fn is_free(&self, id: EltId) -> bool {
+        !self.elts.contains_key(&id) && !self.moved.contains_key(&id)
     }
     
     /// Insert an element and return the id (the one inserted).
@@ -232,14 +368,37 @@ impl<E: ElementT> PartitionState<E> {
     /// if the id is already in use.
     /// It is suggested to use insert() instead if you do not need to specify
     /// the identifier.
-    pub fn insert_with_id(&mut self, id: EltId, elt: Rc<E>) -> Result<EltId, ElementOp> {
+    pub fn insert_with_id(&mut self, id: EltId, elt: Arc<E>) -> Result<EltId, ElementOp> {
         if id.part_id() != self.part_id { return Err(ElementOp::WrongPartition); }
         if self.elts.contains_key(&id) { return Err(ElementOp::IdClash); }
         self.statesum.permute(&elt.sum());
+        self.indexes.insert(id, &elt);
         self.elts.insert(id, elt);
+        self.note_change(id);
         Ok(id)
     }
     
+    /// Insert many elements at once, returning their identifiers (in the
+    /// same order).
+    /// 
+    /// This is faster than repeated use of `insert()`: identifiers are
+    /// allocated as a batch (see `IdAllocator::allocate_batch()`) and the
+    /// state-sum is updated once. Nothing is inserted if allocation fails.
+    pub fn insert_bulk(&mut self, elts: Vec<Arc<E>>) -> Result<Vec<EltId>, ElementOp> {
+        let ids = try!(self.allocator.allocate_batch(self.part_id, elts.len(),
+                &|id| self.is_free(id)));
+        self.elts.reserve(elts.len());
+        let mut sum = Sum::zero();
+        for (id, elt) in ids.iter().zip(elts) {
+            sum.permute(&elt.sum());
+            self.indexes.insert(*id, &elt);
+            self.elts.insert(*id, elt);
+            self.note_change(*id);
+        }
+        self.statesum.permute(&sum);
+        Ok(ids)
+    }
+    
     /// Add a note about where an element has been moved to.
     /// 
     /// The point of doing this is that someone looking for the element later
@@ -248,15 +407,78 @@ impl<E: ElementT> PartitionState<E> {
     /// This should be used when an element is moved to another partition,
     /// after calling `remove_elt()` on this partition. It can also be used
     /// when an element which was here has been moved *again* to inform of the
-    /// current name (though this is not currently easy to do, since we don't
-    /// track elements' old names).
+    /// current name (see `aliases_of()`).
+    /// 
+    /// Notes are also kept in the partition an element moves to, mapping its
+    /// old names to the new one (see `add_alias()`); together these form the
+    /// state's alias table, which is saved in snapshots and commits.
     /// 
     /// In the case the element has been moved back to this partition, the
     /// current code may or may not give it its original identity back
     /// (depending on whether the element number part has already been
     /// changed).
+    /// 
+    /// Any previous note on `id` is replaced; use `remove_move()` to remove
+    /// it. Notes whose new identifier is in this partition (aliases) are part
+    /// of the state-sum; those pointing to other partitions are not.
     pub fn set_move(&mut self, id: EltId, new_id: EltId) {
+        self.remove_move(id);
+        if let Some(sum) = note_sum(self.part_id, id, new_id) {
+            self.statesum.permute(&sum);
+        }
         self.moved.insert(id, new_id);
+        self.note_change(id);
+    }
+    /// Remove the note on `id` made by `set_move()` or `add_alias()`, if any,
+    /// returning the noted identifier.
+    pub fn remove_move(&mut self, id: EltId) -> Option<EltId> {
+        let removed = self.moved.remove(&id);
+        if let Some(new_id) = removed {
+            if let Some(sum) = note_sum(self.part_id, id, new_id) {
+                self.statesum.permute(&sum);
+            }
+            self.note_change(id);
+        }
+        removed
+    }
+    /// Record that the element identified by `id` was previously known as
+    /// `old_id`, which may be an identifier from another partition.
+    /// 
+    /// Fails if `id` is not an element of this state or `old_id` is (an
+    /// element's current name cannot also be an alias).
+    /// 
+    /// Aliases are part of the state-sum, so adding one alone is enough for
+    /// `Partition::push_state()` to make a commit.
+    pub fn add_alias(&mut self, old_id: EltId, id: EltId) -> Result<(), ElementOp> {
+        if !self.elts.contains_key(&id) { return Err(ElementOp::NotFound); }
+        if self.elts.contains_key(&old_id) { return Err(ElementOp::IdClash); }
+        self.set_move(old_id, id);
+        Ok(())
+    }
+    /// List all identifiers noted as being old names of `id`, in order.
+    /// 
+    /// Operation is `O(m)` in the size of the alias table.
+    pub fn aliases_of(&self, id: EltId) -> Vec<EltId> {
+        let mut ids: Vec<EltId> = self.moved.iter()
+            .filter(|&(_, new_id)| *new_id == id)
+            .map(|(old_id, _)| *old_id)
+            .collect();
+        ids.sort();
+        ids
+    }
+    /// Find the current identifier of an element of this state which may
+    /// previously have been known as `id`, following notes in the alias
+    /// table. Returns `None` if there is no such element in this state.
+    pub fn resolve(&self, mut id: EltId) -> Option<EltId> {
+        // Bound the number of steps in case notes form a cycle
+        for _ in 0..(self.moved.len() + 1) {
+            if self.elts.contains_key(&id) { return Some(id); }
+            match self.moved.get(&id) {
+                Some(new_id) => { id = *new_id; },
+                None => { return None; },
+            }
+        }
+        None
     }
     /// Check our notes tracking moved elements, and return a new `EltId` if
     /// we have one. Note that this method ignores stored elements.
@@ -286,6 +508,9 @@ impl<E: ElementT> PartitionState<E> {
             elts: self.elts.clone(),
             moved: self.moved.clone(),
             meta: meta,
+            changed: Some(HashSet::new()),
+            indexes: self.indexes.clone(),
+            allocator: self.allocator.clone(),
         }
     }
     
@@ -303,11 +528,14 @@ impl<E: ElementT> PartitionState<E> {
             elts: self.elts.clone(),
             moved: self.moved.clone(),
             meta: meta,
+            changed: Some(HashSet::new()),
+            indexes: self.indexes.clone(),
+            allocator: self.allocator.clone(),
         }
     }
     
     /// Clone the state, creating an exact copy. The new state will have the
-    /// same parents as the current one.
+    /// same parents (and tracked changes) as the current one.
     /// 
     /// Elements are considered Copy-On-Write so cloning the
     /// state is not particularly expensive.
@@ -319,6 +547,9 @@ impl<E: ElementT> PartitionState<E> {
             elts: self.elts.clone(),
             moved: self.moved.clone(),
             meta: self.meta.clone(),
+            changed: self.changed.clone(),
+            indexes: self.indexes.clone(),
+            allocator: self.allocator.clone(),
         }
     }
 }
@@ -333,35 +564,98 @@ impl<E: ElementT> State<E> for PartitionState<E> {
     fn is_avail(&self, id: EltId) -> bool {
         self.elts.contains_key(&id)
     }
-    fn get_rc(&self, id: EltId) -> Result<&Rc<E>, ElementOp> {
+    fn get_arc(&self, id: EltId) -> Result<&Arc<E>, ElementOp> {
         self.elts.get(&id).ok_or(ElementOp::NotFound)
     }
-    fn insert_rc(&mut self, elt: Rc<E>) -> Result<EltId, ElementOp> {
+    fn iter<'a>(&'a self) -> EltIter<'a, E> {
+        box self.elts.iter().map(|(id, elt)| (*id, &**elt))
+    }
+    fn insert_arc(&mut self, elt: Arc<E>) -> Result<EltId, ElementOp> {
         let id = try!(self.gen_id());
         try!(self.insert_with_id(id, elt));
         Ok(id)
     }
-    fn replace_rc(&mut self, id: EltId, elt: Rc<E>) -> Result<Rc<E>, ElementOp> {
+    fn replace_arc(&mut self, id: EltId, elt: Arc<E>) -> Result<Arc<E>, ElementOp> {
+        self.note_change(id);
         self.statesum.permute(&elt.sum());
+        let new_elt = elt.clone();
         match self.elts.insert(id, elt) {
-            None => Err(ElementOp::NotFound),
+            None => {
+                self.indexes.insert(id, &new_elt);
+                Err(ElementOp::NotFound)
+            },
             Some(removed) => {
                 self.statesum.permute(&removed.sum());
+                self.indexes.remove(id, &removed);
+                self.indexes.insert(id, &new_elt);
                 Ok(removed)
             }
         }
     }
-    fn remove(&mut self, id: EltId) -> Result<Rc<E>, ElementOp> {
+    fn remove(&mut self, id: EltId) -> Result<Arc<E>, ElementOp> {
         match self.elts.remove(&id) {
             None => Err(ElementOp::NotFound),
             Some(removed) => {
                 self.statesum.permute(&removed.sum());
+                self.indexes.remove(id, &removed);
+                self.note_change(id);
                 Ok(removed)
             }
         }
     }
 }
 
+/// Insert many elements with given identifiers into a state, where `sum` is
+/// the combination (see `Sum::permute()`) of the sums of all elements. This
+/// allows sums to be calculated elsewhere, e.g. on other threads.
+/// 
+/// The sum is not checked (an incorrect sum corrupts the state-sum), so this
+/// is only for use by snapshot readers, which verify the final state-sum.
+/// Nothing is inserted if any identifier is invalid or in use (or repeated).
+pub fn insert_bulk_with_ids<E: ElementT>(state: &mut PartitionState<E>,
+    elts: Vec<(EltId, Arc<E>)>, sum: &Sum) -> Result<(), ElementOp>
+{
+    let mut ids = HashSet::with_capacity(elts.len());
+    for &(id, _) in &elts {
+        if id.part_id() != state.part_id { return Err(ElementOp::WrongPartition); }
+        if state.elts.contains_key(&id) || !ids.insert(id) {
+            return Err(ElementOp::IdClash);
+        }
+    }
+    state.elts.reserve(elts.len());
+    for (id, elt) in elts {
+        state.indexes.insert(id, &elt);
+        state.elts.insert(id, elt);
+        state.note_change(id);
+    }
+    state.statesum.permute(sum);
+    Ok(())
+}
+
+/// Get the contribution of a move note `id → new_id` in a state of partition
+/// `part_id` to the state-sum, if any: notes where `new_id` is in the same
+/// partition (aliases) contribute, others do not.
+pub fn note_sum(part_id: PartId, id: EltId, new_id: EltId) -> Option<Sum> {
+    if new_id.part_id() != part_id {
+        return None;
+    }
+    let mut buf = b"ELTALIAS".to_vec();
+    let nums: [u64; 2] = [id.into(), new_id.into()];
+    for &n in &nums {
+        buf.extend((0..8).rev().map(|i| (n >> (8 * i)) as u8));
+    }
+    Some(Sum::calculate(&buf))
+}
+
+// Tracked changes are not part of the state itself
+impl<E: ElementT> PartialEq for PartitionState<E> {
+    fn eq(&self, other: &PartitionState<E>) -> bool {
+        self.part_id == other.part_id && self.parents == other.parents &&
+            self.statesum == other.statesum && self.elts == other.elts &&
+            self.moved == other.moved && self.meta == other.meta
+    }
+}
+
 /// Helper to use PartitionState with HashIndexed
 pub struct PartitionStateSumComparator;
 impl<E: ElementT> KeyComparator<PartitionState<E>, Sum> for PartitionStateSumComparator {
@@ -0,0 +1,77 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Statistics on partitions and repositories
+//!
+//! These are intended for capacity planning, e.g. deciding when to write a
+//! snapshot or re-partition. See `Partition::stats()` and `Repo::stats()`.
+
+use std::ops::AddAssign;
+
+use PartId;
+
+/// Statistics about a single partition.
+/// 
+/// File counts and sizes come from the partition's `PartitionIO` and are
+/// available whether or not the partition is loaded; element counts are only
+/// available when loaded.
+#[derive(Clone, PartialEq, Eq, Default, Debug)]
+pub struct PartitionStats {
+    /// Number of elements in the tip, if loaded and not requiring a merge
+    pub elements: Option<usize>,
+    /// Total size of the tip's elements when serialised, in bytes (as for
+    /// `elements`)
+    pub element_bytes: Option<u64>,
+    /// Number of snapshot files
+    pub snapshots: usize,
+    /// Total size of snapshot files, in bytes
+    pub snapshot_bytes: u64,
+    /// Number of commit log files
+    pub logs: usize,
+    /// Total size of commit log files, in bytes
+    pub log_bytes: u64,
+    /// Number of states held in memory
+    pub states: usize,
+    /// Number of tips (states without a known successor) on the default
+    /// branch; more than one means a merge is required
+    pub tips: usize,
+    /// Number of commits not yet written to disk
+    pub unsaved_commits: usize,
+    /// Number of commits since the latest snapshot: those in its logs plus
+    /// those not yet written
+    pub commits_since_snapshot: usize,
+}
+
+impl<'a> AddAssign<&'a PartitionStats> for PartitionStats {
+    /// Add another partition's numbers to these. Element counts are summed
+    /// over partitions where available.
+    fn add_assign(&mut self, other: &'a PartitionStats) {
+        fn add<T: AddAssign + Copy>(a: &mut Option<T>, b: Option<T>) {
+            *a = match (*a, b) {
+                (Some(mut x), Some(y)) => { x += y; Some(x) },
+                (x, None) => x,
+                (None, y) => y,
+            };
+        }
+        add(&mut self.elements, other.elements);
+        add(&mut self.element_bytes, other.element_bytes);
+        self.snapshots += other.snapshots;
+        self.snapshot_bytes += other.snapshot_bytes;
+        self.logs += other.logs;
+        self.log_bytes += other.log_bytes;
+        self.states += other.states;
+        self.tips += other.tips;
+        self.unsaved_commits += other.unsaved_commits;
+        self.commits_since_snapshot += other.commits_since_snapshot;
+    }
+}
+
+/// Statistics about a repository: those of each partition and totals.
+#[derive(Clone, PartialEq, Eq, Default, Debug)]
+pub struct RepoStats {
+    /// Statistics for each partition, ordered by partition number
+    pub partitions: Vec<(PartId, PartitionStats)>,
+    /// Sum over all partitions
+    pub total: PartitionStats,
+}
@@ -0,0 +1,238 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Background writing
+//!
+//! A `BackgroundWriter` writes unsaved commits of a `Repo` or `Partition` on
+//! a dedicated thread, so that callers need not wait for file IO. The target
+//! is shared behind a mutex which the writer holds only while taking unsaved
+//! commits (see `Partition::take_unsaved()`) and returning them afterwards;
+//! the target may be used while logs are written.
+
+use std::sync::{Arc, Mutex, MutexGuard, Condvar};
+use std::thread::{self, JoinHandle};
+
+use {ElementT, PartId};
+use partition::{Partition, PendingWrite};
+use repo::{Repo, RepoT, ClassifierT};
+use error::{Error, Result, OtherError};
+
+/// Something a `BackgroundWriter` can write.
+pub trait WriteTarget: Send {
+    /// Unsaved changes taken for writing
+    type Pending: Send;
+    
+    /// Take all unsaved changes (called with the target locked).
+    fn take_pending(&mut self) -> Result<Self::Pending>;
+    
+    /// Write taken changes (called without the lock).
+    fn write_taken(pending: &mut Self::Pending) -> Result<()>;
+    
+    /// Return taken changes after writing, restoring any not written; `fast`
+    /// is as for `Partition::write()` (called with the target locked).
+    fn finish_pending(&mut self, pending: Self::Pending, fast: bool) -> Result<()>;
+}
+
+impl<E: ElementT + Send + Sync> WriteTarget for Partition<E> {
+    type Pending = PendingWrite<E>;
+    fn take_pending(&mut self) -> Result<PendingWrite<E>> {
+        self.take_unsaved()
+    }
+    fn write_taken(pending: &mut PendingWrite<E>) -> Result<()> {
+        pending.write()
+    }
+    fn finish_pending(&mut self, pending: PendingWrite<E>, fast: bool) -> Result<()> {
+        self.finish_write(pending, fast)
+    }
+}
+
+impl<C: ClassifierT, R: RepoT<C>> WriteTarget for Repo<C, R>
+    where C::Element: Send + Sync
+{
+    type Pending = Vec<(PartId, PendingWrite<C::Element>)>;
+    fn take_pending(&mut self) -> Result<Self::Pending> {
+        self.take_unsaved_all()
+    }
+    fn write_taken(pending: &mut Self::Pending) -> Result<()> {
+        // Write all partitions, reporting the first failure
+        let mut result = Ok(());
+        for &mut (_, ref mut part) in pending.iter_mut() {
+            let r = part.write();
+            if result.is_ok() {
+                result = r;
+            }
+        }
+        result
+    }
+    fn finish_pending(&mut self, pending: Self::Pending, fast: bool) -> Result<()> {
+        self.finish_write_all(pending, fast)
+    }
+}
+
+// Progress, shared between handle and thread. Requests are numbered; `done`
+// is the last request served.
+struct Progress {
+    requested: u64,
+    done: u64,
+    // Whether the last write failed
+    failed: bool,
+    stop: bool,
+    // Set when the thread exits (including by panic)
+    exited: bool,
+}
+
+struct Shared {
+    progress: Mutex<Progress>,
+    cond: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<Progress> {
+        // No user code runs under this lock, so poisoning is not a concern
+        self.progress.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// Marks the thread as exited when dropped, even on panic.
+struct ExitGuard(Arc<Shared>);
+
+impl Drop for ExitGuard {
+    fn drop(&mut self) {
+        self.0.lock().exited = true;
+        self.0.cond.notify_all();
+    }
+}
+
+/// Writes a `Repo` or `Partition` on a dedicated thread.
+/// 
+/// Call `request_write()` after making changes; this returns immediately.
+/// Multiple requests made while a write is in progress are served by a single
+/// further write. `flush()` waits for all requested writes; `shutdown()` (or
+/// dropping the writer) also stops the thread.
+/// 
+/// Errors are passed to the callback given to `start()` (and not logged);
+/// `flush()` and `shutdown()` also report whether the last write failed.
+pub struct BackgroundWriter {
+    shared: Arc<Shared>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl BackgroundWriter {
+    /// Start a writer thread for `target`. `fast` is passed to
+    /// `Partition::write()` (or `Repo::write_all()`); `on_error` is called on
+    /// the writer thread for each failed write.
+    pub fn start<T, F>(target: Arc<Mutex<T>>, fast: bool, mut on_error: F) -> BackgroundWriter
+        where T: WriteTarget + 'static, F: FnMut(Error) + Send + 'static
+    {
+        let shared = Arc::new(Shared {
+            progress: Mutex::new(Progress {
+                requested: 0, done: 0, failed: false, stop: false, exited: false
+            }),
+            cond: Condvar::new(),
+        });
+        let shared2 = shared.clone();
+        let handle = thread::spawn(move || {
+            let _guard = ExitGuard(shared2.clone());
+            loop {
+                let request = {
+                    let mut progress = shared2.lock();
+                    while progress.done == progress.requested && !progress.stop {
+                        progress = shared2.cond.wait(progress).unwrap_or_else(|e| e.into_inner());
+                    }
+                    if progress.done == progress.requested {
+                        break;  // stop requested and nothing pending
+                    }
+                    progress.requested
+                };
+    
+                let result = Self::write_once(&target, fast);
+                let failed = result.is_err();
+                if let Err(e) = result {
+                    on_error(e);
+                }
+    
+                let mut progress = shared2.lock();
+                progress.done = request;
+                progress.failed = failed;
+                shared2.cond.notify_all();
+            }
+        });
+        BackgroundWriter { shared: shared, handle: Some(handle) }
+    }
+    
+    // Take pending changes, write them without holding the lock, then return
+    // them to the target
+    fn write_once<T: WriteTarget>(target: &Mutex<T>, fast: bool) -> Result<()> {
+        let mut pending = match target.lock() {
+            Ok(mut target) => try!(target.take_pending()),
+            Err(_) => { return OtherError::err("background writer: target lock poisoned"); },
+        };
+        let written = T::write_taken(&mut pending);
+        let finished = match target.lock() {
+            Ok(mut target) => target.finish_pending(pending, fast),
+            Err(_) => OtherError::err("background writer: target lock poisoned"),
+        };
+        written.and(finished)
+    }
+    
+    /// Ask for unsaved changes to be written. Returns immediately.
+    pub fn request_write(&self) {
+        self.shared.lock().requested += 1;
+        self.shared.cond.notify_all();
+    }
+    
+    /// Request a write and wait until it completes.
+    /// 
+    /// Fails if the last write failed (details are passed to the error
+    /// callback) or if the writer thread has exited.
+    pub fn flush(&self) -> Result<()> {
+        let request = {
+            let mut progress = self.shared.lock();
+            progress.requested += 1;
+            progress.requested
+        };
+        self.shared.cond.notify_all();
+    
+        let mut progress = self.shared.lock();
+        while progress.done < request && !progress.exited {
+            progress = self.shared.cond.wait(progress).unwrap_or_else(|e| e.into_inner());
+        }
+        if progress.done < request {
+            OtherError::err("background writer thread exited")
+        } else if progress.failed {
+            OtherError::err("background write failed")
+        } else {
+            Ok(())
+        }
+    }
+    
+    /// Write pending changes (as `flush()`), then stop the thread.
+    pub fn shutdown(mut self) -> Result<()> {
+        let result = self.flush();
+        let joined = self.stop();
+        result.and(joined)
+    }
+    
+    // Stop the thread and wait for it; pending requests are served first.
+    fn stop(&mut self) -> Result<()> {
+        let handle = match self.handle.take() {
+            Some(handle) => handle,
+            None => { return Ok(()); },
+        };
+        self.shared.lock().stop = true;
+        self.shared.cond.notify_all();
+        match handle.join() {
+            Ok(()) => Ok(()),
+            Err(_) => OtherError::err("background writer thread panicked"),
+        }
+    }
+}
+
+impl Drop for BackgroundWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop() {
+            warn!("{}", e);
+        }
+    }
+}
@@ -6,7 +6,8 @@
 
 use std::path::{Path, PathBuf};
 use std::io::{Read, Write, ErrorKind};
-use std::fs::{read_dir, File, OpenOptions};
+use std::fs::{read_dir, metadata, remove_file, File, OpenOptions};
+use std::mem::replace;
 use std::any::Any;
 use std::collections::HashMap;
 
@@ -240,6 +241,16 @@ impl DiscoverPartitionFiles {
         }
         None
     }
+    
+    // Remove the entry for snapshot number `ss_num` if it has neither a
+    // snapshot nor logs, unless it is the last (so `ss_len()` does not change)
+    fn remove_if_empty(&mut self, ss_num: usize) {
+        let empty = self.ss.get(&ss_num).map_or(false,
+                |&(ref p, ref logs)| *p == PathBuf::new() && logs.is_empty());
+        if empty && ss_num + 1 < self.ss_len() {
+            self.ss.remove(&ss_num);
+        }
+    }
 }
 
 impl PartitionIO for DiscoverPartitionFiles {
@@ -257,11 +268,11 @@ impl PartitionIO for DiscoverPartitionFiles {
     fn read_ss<'a>(&self, ss_num: usize) -> Result<Option<Box<Read+'a>>> {
         // Cannot replace `match` with `map` since `try!()` cannot be used in a closure
         Ok(match self.ss.get(&ss_num) {
-            Some(&(ref p, _)) => {
+            Some(&(ref p, _)) if *p != PathBuf::new() => {
                 trace!("Reading snapshot file: {}", p.display());
                 Some(box try!(File::open(p)))
             },
-            None => None
+            _ => None
         })
     }
     
@@ -275,6 +286,20 @@ impl PartitionIO for DiscoverPartitionFiles {
         })
     }
     
+    fn ss_size(&self, ss_num: usize) -> Result<Option<u64>> {
+        Ok(match self.ss.get(&ss_num) {
+            Some(&(ref p, _)) if *p != PathBuf::new() => Some(try!(metadata(p)).len()),
+            _ => None
+        })
+    }
+    
+    fn ss_cl_size(&self, ss_num: usize, cl_num: usize) -> Result<Option<u64>> {
+        Ok(match self.ss.get(&ss_num).and_then(|&(_, ref logs)| logs.get(&cl_num)) {
+            Some(p) => Some(try!(metadata(p)).len()),
+            None => None,
+        })
+    }
+    
     fn new_ss<'a>(&mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>> {
         let p = self.dir.join(PathBuf::from(format!("{}-ss{}.pip", self.basename, ss_num)));
         if self.ss.get(&ss_num).map_or(false, |&(ref p, _)| *p != PathBuf::new()) || p.exists() {
@@ -310,6 +335,46 @@ impl PartitionIO for DiscoverPartitionFiles {
         logs.insert(cl_num, p);
         Ok(Some(box stream))
     }
+    
+    fn can_delete(&self) -> bool {
+        true
+    }
+    
+    fn delete_ss(&mut self, ss_num: usize) -> Result<bool> {
+        let p = match self.ss.get_mut(&ss_num) {
+            Some(&mut (ref mut p, _)) if *p != PathBuf::new() => replace(p, PathBuf::new()),
+            _ => { return Ok(false); },
+        };
+        trace!("Deleting snapshot file: {}", p.display());
+        try!(remove_file(&p));
+        self.remove_if_empty(ss_num);
+        Ok(true)
+    }
+    
+    fn delete_ss_cl(&mut self, ss_num: usize, cl_num: usize) -> Result<bool> {
+        let p = match self.ss.get_mut(&ss_num).and_then(|&mut (_, ref mut logs)| logs.remove(&cl_num)) {
+            Some(p) => p,
+            None => { return Ok(false); },
+        };
+        trace!("Deleting log file: {}", p.display());
+        try!(remove_file(&p));
+        self.remove_if_empty(ss_num);
+        Ok(true)
+    }
+    
+    fn sync_ss(&mut self, ss_num: usize) -> Result<()> {
+        if let Some(&(ref ss, ref logs)) = self.ss.get(&ss_num) {
+            for p in Some(ss).into_iter().filter(|p| **p != PathBuf::new()).chain(logs.values()) {
+                trace!("Syncing file: {}", p.display());
+                try!(try!(File::open(p)).sync_all());
+            }
+        }
+        // Directory entries of new files must also be durable
+        if cfg!(unix) {
+            try!(try!(File::open(&self.dir)).sync_all());
+        }
+        Ok(())
+    }
 }
 
 /// A helper to discover a partition number from a file name (e.g.
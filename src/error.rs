@@ -11,8 +11,9 @@ use std::cmp::{min, max};
 /// Our custom result type
 pub type Result<T, E = Error> = result::Result<T, E>;
 
-/// Our custom compound error type
-pub type Error = Box<ErrorTrait>;
+/// Our custom compound error type. This is `Send` and `Sync` so that errors
+/// can be passed between threads.
+pub type Error = Box<ErrorTrait + Send + Sync>;
 
 pub use std::error::Error as ErrorTrait;
 
@@ -145,6 +146,9 @@ pub enum ElementOp {
     ClassifyFailure,
     /// The relevant partition is not loaded within a repository
     NotLoaded,
+    /// An identifier must be supplied, since the partition does not allocate
+    /// identifiers (see `ids::ExplicitIds`)
+    IdRequired,
 }
 impl ErrorTrait for ElementOp {
     fn description(&self) -> &'static str {
@@ -155,6 +159,7 @@ impl ErrorTrait for ElementOp {
             ElementOp::IdClash => "identifier already in use",
             ElementOp::ClassifyFailure => "classification of element failed",
             ElementOp::NotLoaded => "partition must be loaded",
+            ElementOp::IdRequired => "element identifier must be supplied",
         }
     }
 }
@@ -177,6 +182,9 @@ pub enum PatchOp {
     WrongParent,
     /// Patch fails to apply cleanly
     PatchApply,
+    /// Two different states have the same state-sum (only detected when
+    /// verification is enabled; see `Partition::set_verify()`)
+    Collision,
 }
 impl ErrorTrait for PatchOp {
     fn description(&self) -> &'static str {
@@ -185,6 +193,7 @@ impl ErrorTrait for PatchOp {
             PatchOp::NoParent => "parent state of commit not found",
             PatchOp::WrongParent => "applying commit patch failed: wrong parent",
             PatchOp::PatchApply => "applying commit patch failed: data mismatch",
+            PatchOp::Collision => "state-sum collision: different states have the same sum",
         }
     }
 }
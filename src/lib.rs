@@ -29,11 +29,12 @@
 //! The library has good support for checking for corruption of data, though
 //! currently limited facilities for dealing with corrupt data.
 //! 
-//! Potentially, it could be extended to support the following, however so far
-//! there has been no need for these features:
+//! Partitions may have named branches in addition to the default branch (see
+//! `Partition::create_branch`), for example to stage changes without
+//! blocking other writers.
 //! 
-//! *   Multiple branches within a single repository (like 'git branch')
-//! *   Indexes of stored objects
+//! Elements may be looked up by user-defined secondary indexes (see
+//! `Partition::add_index`).
 //! 
 //! Terminology:
 //! 
@@ -96,6 +97,15 @@ pub use detail::{ElementT, PartId, EltId, Sum, Commit, CommitMeta};
 pub use detail::repo;
 pub use detail::partition;
 pub use detail::merge;
+pub use detail::diff;
+pub use detail::stats;
+pub use detail::index;
+pub use detail::ids;
+pub use detail::keyed;
+pub use detail::observe;
+pub use detail::writer;
+pub use detail::sign;
+pub use detail::compact;
 
 // Most Pippin code is put in this private module to allow inter-module
 // dependencies without making the details public. In the future there may
@@ -13,6 +13,9 @@ extern crate env_logger;
 
 use std::io::{Read, Write, ErrorKind};
 use std::any::Any;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
 // Used to write results out:
 // use std::io::stderr;
 // use std::path::Path;
@@ -26,6 +29,7 @@ use pippin::error::{make_io_err, Result};
 
 /// Allows writing to in-memory streams. Refers to external data so that it
 /// can be recovered after the `Partition` is destroyed in the tests.
+#[derive(Clone)]
 struct PartitionStreams {
     // Map of snapshot-number to pair (snapshot, map of log number to log)
     ss: VecMap<(Vec<u8>, VecMap<Vec<u8>>)>,
@@ -78,6 +82,72 @@ impl PartitionIO for PartitionStreams {
             make_io_err(ErrorKind::NotFound, "no snapshot corresponding to new commit log")
         }
     }
+    fn can_delete(&self) -> bool { true }
+    fn delete_ss(&mut self, ss_num: usize) -> Result<bool> {
+        // Logs cannot be kept without their snapshot here
+        if self.ss_cl_len(ss_num) > 0 {
+            return make_io_err(ErrorKind::Other, "cannot delete a snapshot with logs");
+        }
+        Ok(self.ss.remove(&ss_num).is_some())
+    }
+    fn delete_ss_cl(&mut self, ss_num: usize, cl_num: usize) -> Result<bool> {
+        Ok(self.ss.get_mut(&ss_num)
+            .and_then(|&mut (_, ref mut logs)| logs.remove(&cl_num))
+            .is_some())
+    }
+}
+
+/// Hook run on the streams before a snapshot or log is created.
+type Hook = Box<FnMut(&mut PartitionStreams) -> Result<()> + Send>;
+
+/// Wraps `PartitionStreams`, running `before_new_ss` / `before_new_ss_cl`
+/// before creating a snapshot / log. A hook may fail the operation, or change
+/// the streams first (as another process might). Deletion is only supported
+/// if `delete` is set.
+struct HookedStreams {
+    inner: PartitionStreams,
+    before_new_ss: Hook,
+    before_new_ss_cl: Hook,
+    delete: bool,
+}
+
+impl HookedStreams {
+    fn new(inner: PartitionStreams) -> HookedStreams {
+        HookedStreams {
+            inner: inner,
+            before_new_ss: box |_| Ok(()),
+            before_new_ss_cl: box |_| Ok(()),
+            delete: true,
+        }
+    }
+}
+
+impl PartitionIO for HookedStreams {
+    fn as_any(&self) -> &Any { self }
+    fn ss_len(&self) -> usize { self.inner.ss_len() }
+    fn ss_cl_len(&self, ss_num: usize) -> usize { self.inner.ss_cl_len(ss_num) }
+    fn read_ss<'a>(&'a self, ss_num: usize) -> Result<Option<Box<Read+'a>>> {
+        self.inner.read_ss(ss_num)
+    }
+    fn read_ss_cl<'a>(&'a self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Read+'a>>> {
+        self.inner.read_ss_cl(ss_num, cl_num)
+    }
+    fn new_ss<'a>(&'a mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>> {
+        try!((self.before_new_ss)(&mut self.inner));
+        self.inner.new_ss(ss_num)
+    }
+    fn append_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
+        self.inner.append_ss_cl(ss_num, cl_num)
+    }
+    fn new_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
+        try!((self.before_new_ss_cl)(&mut self.inner));
+        self.inner.new_ss_cl(ss_num, cl_num)
+    }
+    fn can_delete(&self) -> bool { self.delete }
+    fn delete_ss(&mut self, ss_num: usize) -> Result<bool> { self.inner.delete_ss(ss_num) }
+    fn delete_ss_cl(&mut self, ss_num: usize, cl_num: usize) -> Result<bool> {
+        self.inner.delete_ss_cl(ss_num, cl_num)
+    }
 }
 
 #[test]
@@ -163,3 +233,902 @@ fn create_small() {
         part2.state(state1.statesum()).expect("get state1 by sum").clone_child());
     assert_eq!(state3, *part2.tip().expect("part2 tip"));
 }
+
+#[test]
+fn stats() {
+    use pippin::State;
+    
+    let part_streams = PartitionStreams { ss: VecMap::new() };
+    let part_id = PartId::from_num(57);
+    let mut part = Partition::<String>::create_part(box part_streams,
+        "stats", part_id).expect("creating partition");
+    
+    for i in 0..3 {
+        let mut state = part.tip().expect("has tip").clone_child();
+        state.insert(format!("element {}", 2 * i)).expect("inserting elt");
+        state.insert(format!("element {}", 2 * i + 1)).expect("inserting elt");
+        part.push_state(state).expect("committing");
+    }
+    assert_eq!(part.stats().expect("stats").unsaved_commits, 3);
+    
+    part.write(true).expect("writing");
+    let stats = part.stats().expect("stats");
+    assert_eq!(stats.elements, Some(6));
+    assert_eq!((stats.snapshots, stats.logs), (1, 1));
+    assert!(stats.snapshot_bytes > 0 && stats.log_bytes > stats.element_bytes.unwrap());
+    assert_eq!((stats.states, stats.tips), (4, 1));
+    assert_eq!((stats.unsaved_commits, stats.commits_since_snapshot), (0, 3));
+    
+    // Reloading does not count commits again
+    part.load(true).expect("load");
+    part.load(false).expect("load");
+    assert_eq!(part.stats().expect("stats").commits_since_snapshot, 3);
+    part.write_snapshot().expect("writing snapshot");
+    assert_eq!(part.stats().expect("stats").commits_since_snapshot, 0);
+}
+
+#[test]
+fn tags() {
+    use pippin::State;
+    
+    let part_streams = PartitionStreams { ss: VecMap::new() };
+    let part_id = PartId::from_num(7);
+    let mut part = Partition::<String>::create_part(box part_streams,
+        "tags", part_id).expect("creating partition");
+    let sum0 = part.tip_key().expect("has tip").clone();
+    
+    let mut state = part.tip().expect("has tip").clone_child();
+    state.insert("an element".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    let sum1 = part.tip_key().expect("has tip").clone();
+    
+    part.tag("empty", &sum0).expect("tagging");
+    part.tag("before-migration", &sum1).expect("tagging");
+    assert!(part.tag("", &sum1).is_err());
+    assert_eq!(part.resolve_tag("before-migration"), Some(&sum1));
+    assert!(part.write(true).expect("writing"));
+    
+    // Tag changes alone are written to a new log
+    assert!(part.untag("empty"));
+    assert!(!part.untag("empty"));
+    part.tag("latest", &sum1).expect("tagging");
+    assert!(part.write(true).expect("writing"));
+    assert!(!part.write(true).expect("writing"));
+    
+    let mut part2 = Partition::<String>::open(part.unwrap_io(), part_id);
+    part2.load(true).expect("part2.load");
+    assert_eq!(part2.tags().count(), 2);
+    assert_eq!(part2.resolve_tag("empty"), None);
+    assert_eq!(part2.resolve_tag("latest"), Some(&sum1));
+    assert!(part2.is_tagged(&sum1));
+    assert_eq!(part2.state_from_string("before-migration".to_string())
+            .expect("finding state by tag").statesum(), &sum1);
+    
+    // Snapshots carry all tags
+    part2.write_snapshot().expect("writing snapshot");
+    let mut part3 = Partition::<String>::open(part2.unwrap_io(), part_id);
+    part3.load(false).expect("part3.load");
+    assert_eq!(part3.resolve_tag("before-migration"), Some(&sum1));
+    assert_eq!(part3.resolve_tag("latest"), Some(&sum1));
+    assert_eq!(part3.tags().count(), 2);
+}
+
+#[test]
+fn branches() {
+    use pippin::State;
+    use pippin::merge::TwoWaySolveNoResult;
+    
+    let part_streams = PartitionStreams { ss: VecMap::new() };
+    let part_id = PartId::from_num(8);
+    let mut part = Partition::<String>::create_part(box part_streams,
+        "branches", part_id).expect("creating partition");
+    let solver = TwoWaySolveNoResult::new();
+    
+    let mut state = part.tip().expect("has tip").clone_child();
+    let e1 = state.insert("one".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    let base = part.tip_key().expect("has tip").clone();
+    
+    // Changes on a branch do not block the default branch
+    part.create_branch("import", &base).expect("creating branch");
+    assert!(part.create_branch("import", &base).is_err());
+    let mut state = part.branch_tip("import").expect("branch tip").clone_child();
+    let e2 = state.insert("imported".to_string()).expect("inserting elt");
+    assert!(part.push_state_to("import", state).expect("committing to branch"));
+    let mut state = part.tip().expect("has tip").clone_child();
+    let e3 = state.insert("three".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    assert!(part.is_ready());
+    assert!(!part.tip().expect("has tip").is_avail(e2));
+    
+    // An empty branch and a branch to delete
+    part.create_branch("empty", &base).expect("creating branch");
+    part.create_branch("old", &base).expect("creating branch");
+    assert!(part.write(true).expect("writing"));
+    assert!(part.delete_branch("old"));
+    assert!(part.write(true).expect("writing"));
+    
+    let mut part = Partition::<String>::open(part.unwrap_io(), part_id);
+    part.load(false).expect("load");
+    assert_eq!(part.branches(), vec!["empty", "import"]);
+    assert_eq!(part.branch_tip_key("empty").expect("branch tip"), &base);
+    assert!(part.branch_tip("import").expect("branch tip").is_avail(e2));
+    
+    // Merge with changes on both sides
+    assert!(part.merge_branch("import", None, &solver).expect("merging"));
+    assert!(!part.merge_branch("import", None, &solver).expect("merging"));
+    {
+        let tip = part.tip().expect("has tip");
+        assert!(tip.is_avail(e1) && tip.is_avail(e2) && tip.is_avail(e3));
+        assert_eq!(tip.parents().len(), 2);
+    }
+    
+    // Fast-forward merge, before the branch commit has been written
+    let mut state = part.branch_tip("import").expect("branch tip").clone_child();
+    let e4 = state.insert("four".to_string()).expect("inserting elt");
+    part.push_state_to("import", state).expect("committing to branch");
+    assert!(part.merge_branch("import", Some("empty"), &solver).expect("merging"));
+    assert_eq!(part.branch_tip_key("empty").expect("branch tip"),
+            part.branch_tip_key("import").expect("branch tip"));
+    let merged = part.branch_tip_key("empty").expect("branch tip").clone();
+    assert!(part.write(true).expect("writing"));
+    
+    let mut part = Partition::<String>::open(part.unwrap_io(), part_id);
+    part.load(true).expect("load");
+    assert_eq!(part.branch_tip_key("empty").expect("branch tip"), &merged);
+    assert!(part.tip().expect("has tip").is_avail(e2));
+    
+    // Snapshots carry branches forward
+    part.write_snapshot().expect("writing snapshot");
+    let tip = part.tip_key().expect("has tip").clone();
+    let mut part = Partition::<String>::open(part.unwrap_io(), part_id);
+    part.load(false).expect("load");
+    assert_eq!(part.tip_key().expect("has tip"), &tip);
+    assert_eq!(part.branches(), vec!["empty", "import"]);
+    assert_eq!(part.branch_tip_key("empty").expect("branch tip"), &merged);
+    assert!(part.branch_tip("import").expect("branch tip").is_avail(e4));
+}
+
+#[test]
+fn branch_across_snapshot() {
+    use pippin::State;
+    use pippin::merge::TwoWaySolveNoResult;
+    
+    let part_streams = PartitionStreams { ss: VecMap::new() };
+    let part_id = PartId::from_num(15);
+    let mut part = Partition::<String>::create_part(box part_streams,
+        "branch snapshot", part_id).expect("creating partition");
+    let solver = TwoWaySolveNoResult::new();
+    
+    let mut state = part.tip().expect("has tip").clone_child();
+    let e1 = state.insert("one".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    let base = part.tip_key().expect("has tip").clone();
+    part.create_branch("topic", &base).expect("creating branch");
+    let mut state = part.branch_tip("topic").expect("branch tip").clone_child();
+    let e2 = state.insert("topic".to_string()).expect("inserting elt");
+    part.push_state_to("topic", state).expect("committing to branch");
+    
+    // Default branch changes after the branch was created
+    let mut state = part.tip().expect("has tip").clone_child();
+    let e3 = state.insert("three".to_string()).expect("inserting elt");
+    state.remove(e1).expect("removing elt");
+    part.push_state(state).expect("committing");
+    assert!(part.write(true).expect("writing"));
+    part.write_snapshot().expect("writing snapshot");
+    
+    // The branch's base is from before the snapshot
+    let mut part = Partition::<String>::open(part.unwrap_io(), part_id);
+    part.load(false).expect("load");
+    assert!(part.merge_branch("topic", None, &solver).expect("merging"));
+    {
+        let tip = part.tip().expect("has tip");
+        assert!(!tip.is_avail(e1) && tip.is_avail(e2) && tip.is_avail(e3));
+        assert_eq!(tip.parents().len(), 2);
+    }
+    
+    // And again, after another snapshot
+    assert!(part.write(true).expect("writing"));
+    part.write_snapshot().expect("writing snapshot");
+    let tip = part.tip_key().expect("has tip").clone();
+    let mut part = Partition::<String>::open(part.unwrap_io(), part_id);
+    part.load(false).expect("load");
+    assert_eq!(part.tip_key().expect("has tip"), &tip);
+    assert!(!part.merge_branch("topic", None, &solver).expect("merging"));
+}
+
+#[test]
+fn history_limit() {
+    use pippin::State;
+    use pippin::merge::TwoWaySolveNoResult;
+    
+    let part_streams = PartitionStreams { ss: VecMap::new() };
+    let part_id = PartId::from_num(9);
+    let mut part = Partition::<String>::create_part(box part_streams,
+        "history_limit", part_id).expect("creating partition");
+    let solver = TwoWaySolveNoResult::new();
+    
+    let mut sums = vec![part.tip_key().expect("has tip").clone()];
+    let mut ids = Vec::new();
+    for i in 0..6 {
+        let mut state = part.tip().expect("has tip").clone_child();
+        ids.push(state.insert(format!("element {}", i)).expect("inserting elt"));
+        part.push_state(state).expect("committing");
+        sums.push(part.tip_key().expect("has tip").clone());
+    }
+    part.tag("second", &sums[2]).expect("tagging");
+    part.write(true).expect("writing");
+    
+    // Only the tip and tagged state are kept
+    part.set_history_limit(Some(2));
+    assert_eq!(part.stats().expect("stats").states, 2);
+    assert!(part.state(&sums[2]).is_some());
+    assert!(part.state(&sums[3]).is_none());
+    
+    // Evicted states are reloaded on demand
+    assert!(part.load_state(&sums[3]).expect("loading state").is_avail(ids[2]));
+    assert_eq!(part.diff(&sums[4], &sums[5]).expect("diff").num_inserted(), 1);
+    assert!(part.revert(&sums[1], &solver).expect("reverting"));
+    assert!(!part.tip().expect("has tip").is_avail(ids[0]));
+    // Reloaded states are dropped again
+    assert_eq!(part.stats().expect("stats").states, 2);
+    assert!(part.state(&sums[3]).is_none());
+    
+    // States are reloaded from logs of a later snapshot too
+    part.write_snapshot().expect("writing snapshot");
+    for i in 6..9 {
+        let mut state = part.tip().expect("has tip").clone_child();
+        ids.push(state.insert(format!("element {}", i)).expect("inserting elt"));
+        part.push_state(state).expect("committing");
+        sums.push(part.tip_key().expect("has tip").clone());
+    }
+    part.write(true).expect("writing");
+    part.set_history_limit(Some(2));
+    assert_eq!(part.stats().expect("stats").states, 2);
+    assert!(part.load_state(&sums[8]).expect("loading state").is_avail(ids[6]));
+    // ... along with ancestors, down to the tagged state
+    assert!(part.state(&sums[4]).is_some());
+    assert!(part.load_state(&sums[1]).expect("loading state").is_avail(ids[0]));
+    assert!(part.state(&sums[8]).is_none());
+}
+
+#[test]
+fn bulk_load() {
+    use pippin::State;
+    
+    let part_streams = PartitionStreams { ss: VecMap::new() };
+    let part_id = PartId::from_num(10);
+    let mut part = Partition::<String>::create_part(box part_streams,
+        "bulk_load", part_id).expect("creating partition");
+    
+    let mut state = part.tip().expect("has tip").clone_child();
+    let e0 = state.insert("before".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    let snapshots = part.stats().expect("stats").snapshots;
+    
+    let ids = part.bulk_load((0..1000).map(|i| format!("bulk {}", i))).expect("bulk load");
+    assert_eq!(ids.len(), 1000);
+    let stats = part.stats().expect("stats");
+    assert_eq!((stats.elements, stats.snapshots, stats.unsaved_commits),
+            (Some(1001), snapshots + 1, 0));
+    
+    let tip = part.tip_key().expect("has tip").clone();
+    let mut part = Partition::<String>::open(part.unwrap_io(), part_id);
+    part.load(false).expect("load");
+    assert_eq!(part.tip_key().expect("has tip"), &tip);
+    let state = part.tip().expect("has tip");
+    assert_eq!(state.get(e0), Ok(&"before".to_string()));
+    assert_eq!(state.get(ids[999]), Ok(&"bulk 999".to_string()));
+}
+
+#[test]
+fn bulk_load_retry() {
+    use pippin::State;
+    
+    let fail = Arc::new(AtomicBool::new(false));
+    let fail2 = fail.clone();
+    let mut io = HookedStreams::new(PartitionStreams { ss: VecMap::new() });
+    io.before_new_ss = box move |_| if fail2.load(Ordering::SeqCst) {
+        make_io_err(ErrorKind::Other, "snapshot writes disabled")
+    } else {
+        Ok(())
+    };
+    let part_id = PartId::from_num(11);
+    let mut part = Partition::<String>::create_part(box io,
+        "bulk_load_retry", part_id).expect("creating partition");
+    
+    // A failed snapshot leaves the state unsaved, to be written later
+    fail.store(true, Ordering::SeqCst);
+    assert!(part.bulk_load((0..10).map(|i| format!("bulk {}", i))).is_err());
+    assert_eq!(part.tip().expect("has tip").num_avail(), 10);
+    assert!(!part.unload(false));
+    fail.store(false, Ordering::SeqCst);
+    assert!(part.write(true).expect("writing"));
+    assert!(!part.write(true).expect("writing"));
+    
+    let tip = part.tip_key().expect("has tip").clone();
+    let mut part = Partition::<String>::open(part.unwrap_io(), part_id);
+    part.load(false).expect("load");
+    assert_eq!(part.tip_key().expect("has tip"), &tip);
+}
+
+#[test]
+fn observe_load() {
+    use std::sync::{Arc, Mutex};
+    use pippin::State;
+    use pippin::observe::ChangeKind;
+    
+    let part_streams = PartitionStreams { ss: VecMap::new() };
+    let part_id = PartId::from_num(16);
+    let mut part = Partition::<String>::create_part(box part_streams,
+        "observe_load", part_id).expect("creating partition");
+    let mut ids = Vec::new();
+    for i in 0..3 {
+        let mut state = part.tip().expect("has tip").clone_child();
+        ids.push(state.insert(format!("element {}", i)).expect("inserting elt"));
+        part.push_state(state).expect("committing");
+    }
+    part.write(true).expect("writing");
+    
+    // Loading reports the snapshot, then each replayed commit
+    let mut part = Partition::<String>::open(part.unwrap_io(), part_id);
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events2 = events.clone();
+    part.subscribe(move |event| events2.lock().unwrap().push(event.clone()));
+    part.load(false).expect("load");
+    let events = events.lock().unwrap();
+    assert_eq!(events.len(), 4);
+    assert_eq!(events[0].changes, None);
+    for i in 0..3 {
+        assert_eq!(events[i + 1].changes, Some(vec![(ids[i], ChangeKind::Inserted)]));
+    }
+    assert_eq!(&events[3].statesum, part.tip_key().expect("has tip"));
+    
+    // States loaded from snapshots are reported with their parents
+    part.write_snapshot().expect("writing snapshot");
+    let parents = part.tip().expect("has tip").parents().clone();
+    assert_eq!(parents.len(), 1);
+    let mut part = Partition::<String>::open(part.unwrap_io(), part_id);
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events2 = events.clone();
+    part.subscribe(move |event| events2.lock().unwrap().push(event.clone()));
+    part.load(false).expect("load");
+    let events = events.lock().unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].parents, parents);
+}
+
+#[test]
+fn background_write() {
+    use std::sync::{Arc, Mutex};
+    use pippin::State;
+    use pippin::writer::BackgroundWriter;
+    
+    let part_streams = PartitionStreams { ss: VecMap::new() };
+    let part_id = PartId::from_num(12);
+    let part = Partition::<String>::create_part(box part_streams,
+        "background_write", part_id).expect("creating partition");
+    let part = Arc::new(Mutex::new(part));
+    let errors = Arc::new(Mutex::new(0));
+    let errors2 = errors.clone();
+    let writer = BackgroundWriter::start(part.clone(), true,
+        move |_| *errors2.lock().unwrap() += 1);
+    
+    let mut ids = Vec::new();
+    for i in 0..10 {
+        {
+            let mut part = part.lock().unwrap();
+            let mut state = part.tip().expect("has tip").clone_child();
+            ids.push(state.insert(format!("element {}", i)).expect("inserting elt"));
+            part.push_state(state).expect("committing");
+        }
+        writer.request_write();
+    }
+    writer.flush().expect("flushing");
+    assert_eq!(part.lock().unwrap().stats().expect("stats").unsaved_commits, 0);
+    writer.shutdown().expect("shutting down writer");
+    assert_eq!(*errors.lock().unwrap(), 0);
+    
+    let part = Arc::try_unwrap(part).ok().expect("sole owner").into_inner().unwrap();
+    let tip = part.tip_key().expect("has tip").clone();
+    let mut part = Partition::<String>::open(part.unwrap_io(), part_id);
+    part.load(false).expect("load");
+    assert_eq!(part.tip_key().expect("has tip"), &tip);
+    assert_eq!(part.tip().expect("has tip").get(ids[9]), Ok(&"element 9".to_string()));
+}
+
+#[test]
+fn background_write_unlocked() {
+    use std::sync::{Arc, Mutex};
+    use pippin::State;
+    use pippin::writer::BackgroundWriter;
+    
+    let (started, started_rx) = channel();
+    let (release_tx, release) = channel();
+    let fail = Arc::new(AtomicBool::new(false));
+    let fail2 = fail.clone();
+    let mut io = HookedStreams::new(PartitionStreams { ss: VecMap::new() });
+    io.before_new_ss_cl = box move |_| {
+        if fail2.load(Ordering::SeqCst) {
+            return make_io_err(ErrorKind::Other, "log writes disabled");
+        }
+        let _ = started.send(());
+        release.recv().expect("release");
+        Ok(())
+    };
+    let part_id = PartId::from_num(14);
+    let part = Partition::<String>::create_part(box io,
+        "bg_unlocked", part_id).expect("creating partition");
+    let part = Arc::new(Mutex::new(part));
+    let errors = Arc::new(Mutex::new(0));
+    let errors2 = errors.clone();
+    let writer = BackgroundWriter::start(part.clone(), true,
+        move |_| *errors2.lock().unwrap() += 1);
+    
+    let insert = |name: &str| {
+        let mut part = part.lock().unwrap();
+        let mut state = part.tip().expect("has tip").clone_child();
+        let id = state.insert(name.to_string()).expect("inserting elt");
+        part.push_state(state).expect("committing");
+        id
+    };
+    insert("one");
+    writer.request_write();
+    
+    // The partition is not locked while the log is written
+    started_rx.recv().expect("started");
+    assert!(part.try_lock().is_ok());
+    let id2 = insert("two");
+    release_tx.send(()).unwrap();
+    release_tx.send(()).unwrap();
+    writer.flush().expect("flushing");
+    assert_eq!(part.lock().unwrap().stats().expect("stats").unsaved_commits, 0);
+    
+    // Commits which could not be written remain unsaved, and errors are
+    // reported once
+    fail.store(true, Ordering::SeqCst);
+    let id3 = insert("three");
+    assert!(writer.flush().is_err());
+    assert_eq!(*errors.lock().unwrap(), 1);
+    assert_eq!(part.lock().unwrap().stats().expect("stats").unsaved_commits, 1);
+    fail.store(false, Ordering::SeqCst);
+    release_tx.send(()).unwrap();
+    writer.shutdown().expect("shutting down writer");
+    assert_eq!(*errors.lock().unwrap(), 1);
+    
+    let part = Arc::try_unwrap(part).ok().expect("sole owner").into_inner().unwrap();
+    let tip = part.tip_key().expect("has tip").clone();
+    let mut part = Partition::<String>::open(part.unwrap_io(), part_id);
+    part.load(false).expect("load");
+    assert_eq!(part.tip_key().expect("has tip"), &tip);
+    let state = part.tip().expect("has tip");
+    assert_eq!(state.get(id2), Ok(&"two".to_string()));
+    assert_eq!(state.get(id3), Ok(&"three".to_string()));
+}
+
+#[test]
+fn signed_commits() {
+    use std::sync::Arc;
+    use pippin::State;
+    use pippin::sign::{SigningKey, TrustedKeys, Verdict};
+    
+    let part_streams = PartitionStreams { ss: VecMap::new() };
+    let part_id = PartId::from_num(13);
+    let mut part = Partition::<String>::create_part(box part_streams,
+        "signed_commits", part_id).expect("creating partition");
+    let key = Arc::new(SigningKey::from_seed(&[42u8; 32]).expect("key"));
+    part.set_signing_key(Some(key.clone()));
+    for i in 0..2 {
+        let mut state = part.tip().expect("has tip").clone_child();
+        state.insert(format!("signed {}", i)).expect("inserting elt");
+        part.push_state(state).expect("committing");
+    }
+    part.write(true).expect("writing");
+    let signed_tip = part.tip_key().expect("has tip").clone();
+    
+    // Another writer, without a key
+    let mut part = Partition::<String>::open(part.unwrap_io(), part_id);
+    part.load(false).expect("load");
+    for i in 0..2 {
+        let mut state = part.tip().expect("has tip").clone_child();
+        state.insert(format!("unsigned {}", i)).expect("inserting elt");
+        part.push_state(state).expect("committing");
+    }
+    part.write(true).expect("writing");
+    let unsigned_tip = part.tip_key().expect("has tip").clone();
+    
+    // A signed commit on top, in another log
+    let mut part = Partition::<String>::open(part.unwrap_io(), part_id);
+    part.set_signing_key(Some(key.clone()));
+    part.load(false).expect("load");
+    let mut state = part.tip().expect("has tip").clone_child();
+    state.insert("signed again".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    part.write(true).expect("writing");
+    let last_tip = part.tip_key().expect("has tip").clone();
+    
+    // Unsigned commits (and their descendants, in any log) are quarantined
+    let mut trust = TrustedKeys::new(Verdict::Quarantine, Verdict::Reject);
+    trust.add_key(key.public_key());
+    let trust = Arc::new(trust);
+    let mut part = Partition::<String>::open(part.unwrap_io(), part_id);
+    part.set_trust_policy(trust.clone());
+    part.load(false).expect("load");
+    assert_eq!(part.tip_key().expect("has tip"), &signed_tip);
+    assert_eq!(part.quarantined().len(), 3);
+    assert_eq!(part.quarantined()[1].statesum(), &unsigned_tip);
+    assert_eq!(part.quarantined()[2].statesum(), &last_tip);
+    
+    // ... or rejected
+    let mut part = Partition::<String>::open(part.unwrap_io(), part_id);
+    part.set_trust_policy(Arc::new(TrustedKeys::new(Verdict::Reject, Verdict::Reject)));
+    assert!(part.load(false).is_err());
+    
+    // By default all valid commits are accepted
+    let mut part = Partition::<String>::open(part.unwrap_io(), part_id);
+    part.load(false).expect("load");
+    assert_eq!(part.tip_key().expect("has tip"), &last_tip);
+    assert!(part.quarantined().is_empty());
+    
+    // Signed snapshots are accepted
+    part.set_signing_key(Some(key.clone()));
+    part.write_snapshot().expect("writing snapshot");
+    let mut part = Partition::<String>::open(part.unwrap_io(), part_id);
+    part.set_trust_policy(trust.clone());
+    part.load(false).expect("load");
+    assert_eq!(part.tip_key().expect("has tip"), &last_tip);
+    assert!(part.quarantined().is_empty());
+    
+    // Unsigned snapshots are refused unless the policy accepts them
+    part.set_signing_key(None);
+    part.write_snapshot().expect("writing snapshot");
+    let mut part = Partition::<String>::open(part.unwrap_io(), part_id);
+    part.set_trust_policy(trust.clone());
+    assert!(part.load(false).is_err());
+    let mut part = Partition::<String>::open(part.unwrap_io(), part_id);
+    part.load(false).expect("load");
+    assert_eq!(part.tip_key().expect("has tip"), &last_tip);
+}
+
+#[test]
+fn compact() {
+    use pippin::{State, Commit};
+    use pippin::compact::{CompactSchedule, DAY};
+    
+    // Get a copy of a partition's data
+    fn streams(part: Partition<String>) -> PartitionStreams {
+        part.unwrap_io().as_any().downcast_ref::<PartitionStreams>()
+                .expect("streams").clone()
+    }
+    
+    let part_streams = PartitionStreams { ss: VecMap::new() };
+    let part_id = PartId::from_num(17);
+    let mut part = Partition::<String>::create_part(box part_streams,
+        "compact", part_id).expect("creating partition");
+    // One commit per hour for four days
+    let start = 17_000 * DAY;
+    let mut sums = Vec::new();
+    for i in 0..96 {
+        let commit = {
+            let tip = part.tip().expect("has tip");
+            let mut state = tip.clone_child();
+            state.insert(format!("hour {}", i)).expect("inserting elt");
+            let mut commit = Commit::from_diff(tip, &state).expect("commit");
+            commit.meta_mut().timestamp = start + i * 3600;
+            commit
+        };
+        part.push_commit(commit).expect("committing");
+        sums.push(part.tip_key().expect("has tip").clone());
+    }
+    part.tag("mid", &sums[30]).expect("tagging");
+    part.write(true).expect("writing");
+    let uncompacted = streams(part);
+    
+    // Days 0-2 are compacted to one state each, day 3 is kept
+    let schedule = CompactSchedule::new(vec![(DAY, DAY)]).expect("schedule")
+            .at(start + 4 * DAY + 12 * 3600);
+    let mut part = Partition::<String>::open(box uncompacted.clone(), part_id);
+    assert_eq!(part.compact(&schedule).expect("compacting"), 97 - 29);
+    assert_eq!(part.stats().expect("stats").states, 29);
+    assert!(part.state(&sums[24]).is_none());
+    assert_eq!(part.state(&sums[47]).expect("kept").parents(), &vec![sums[30].clone()]);
+    let compacted = streams(part);
+    // Superseded snapshots and logs are gone
+    for ss in 0..uncompacted.ss_len() {
+        assert!(!compacted.ss.contains_key(&ss));
+    }
+    
+    let mut part = Partition::<String>::open(box compacted.clone(), part_id);
+    part.load(true).expect("load");
+    assert_eq!(part.tip_key().expect("has tip"), &sums[95]);
+    assert_eq!(part.resolve_tag("mid"), Some(&sums[30]));
+    assert_eq!(part.stats().expect("stats").states, 29);
+    assert_eq!(part.state(&sums[71]).expect("kept").meta().timestamp, start + 71 * 3600);
+    
+    // A peer with both uncompacted and compacted files loads all history and
+    // continues from the compacted snapshot
+    let mut peer_streams = uncompacted.clone();
+    for (ss, data) in compacted.ss.iter() {
+        if !peer_streams.ss.contains_key(&ss) {
+            peer_streams.ss.insert(ss, data.clone());
+        }
+    }
+    let mut peer = Partition::<String>::open(box peer_streams, part_id);
+    peer.load(true).expect("load");
+    assert_eq!(peer.tip_key().expect("has tip"), &sums[95]);
+    assert_eq!(peer.stats().expect("stats").states, 97);
+    let mut state = peer.tip().expect("has tip").clone_child();
+    state.insert("from peer".to_string()).expect("inserting elt");
+    peer.push_state(state).expect("committing");
+    peer.write(true).expect("writing");
+    let peer_tip = peer.tip_key().expect("has tip").clone();
+    let peer_streams = streams(peer);
+    let ss_num = compacted.ss_len() - 1;
+    
+    // A commit whose parent is neither known nor removed is an error
+    let mut peer = Partition::<String>::open(box peer_streams.clone(), part_id);
+    peer.load(false).expect("load");
+    let mut state = peer.tip().expect("has tip").clone_child();
+    state.insert("after peer".to_string()).expect("inserting elt");
+    peer.push_state(state).expect("committing");
+    peer.write(true).expect("writing");
+    let later_log = streams(peer).ss[ss_num].1[1].clone();
+    let mut broken = compacted.clone();
+    broken.ss.get_mut(&ss_num).expect("snapshot").1.insert(0, later_log);
+    let mut part = Partition::<String>::open(box broken, part_id);
+    assert!(part.load(false).is_err());
+    
+    let mut compacted = compacted;
+    let log = peer_streams.ss[ss_num].1[0].clone();
+    compacted.ss.get_mut(&ss_num).expect("snapshot").1.insert(0, log);
+    let mut part = Partition::<String>::open(box compacted, part_id);
+    part.load(false).expect("load");
+    assert_eq!(part.tip_key().expect("has tip"), &peer_tip);
+    
+    // Without deletion support nothing is changed
+    let mut io = HookedStreams::new(uncompacted.clone());
+    io.delete = false;
+    let mut part = Partition::<String>::open(box io, part_id);
+    assert!(part.compact(&schedule).is_err());
+    assert!(!part.is_loaded());
+    
+    // A peer which has not seen the compaction commits on a removed state
+    // while compacting. Its log is kept (with its snapshot) and the commit is
+    // quarantined and reported when loading.
+    let mut peer = Partition::<String>::open(box uncompacted.clone(), part_id);
+    peer.load(true).expect("load");
+    let mut state = peer.state(&sums[24]).expect("state").clone_child();
+    state.insert("orphan".to_string()).expect("inserting elt");
+    let orphan = state.statesum().clone();
+    peer.push_state(state).expect("committing");
+    peer.write(true).expect("writing");
+    let peer_streams = streams(peer);
+    let ss = peer_streams.ss_len() - 1;
+    let log = peer_streams.ss[ss].1.values().next_back().expect("log").clone();
+    let mut io = HookedStreams::new(uncompacted.clone());
+    let mut late_log = Some(log);
+    io.before_new_ss = box move |streams| {
+        if let Some(log) = late_log.take() {
+            let cl = streams.ss_cl_len(ss);
+            streams.ss.get_mut(&ss).expect("snapshot").1.insert(cl, log);
+        }
+        Ok(())
+    };
+    let mut part = Partition::<String>::open(box io, part_id);
+    part.compact(&schedule).expect("compacting");
+    let late = part.unwrap_io().as_any().downcast_ref::<HookedStreams>()
+            .expect("hooked streams").inner.clone();
+    assert!(!late.ss[ss].0.is_empty());
+    assert_eq!(late.ss[ss].1.len(), 1);
+    let mut part = Partition::<String>::open(box late, part_id);
+    part.load(true).expect("load");
+    assert_eq!(part.tip_key().expect("has tip"), &sums[95]);
+    assert_eq!(part.quarantined().len(), 1);
+    assert_eq!(part.quarantined()[0].statesum(), &orphan);
+}
+
+#[test]
+fn gc() {
+    use pippin::{State, CommitMeta};
+    use pippin::merge::TwoWaySolveNoResult;
+    
+    let part_streams = PartitionStreams { ss: VecMap::new() };
+    let part_id = PartId::from_num(19);
+    let mut part = Partition::<String>::create_part(box part_streams,
+        "gc", part_id).expect("creating partition");
+    let solver = TwoWaySolveNoResult::new();
+    let mut state = part.tip().expect("has tip").clone_child();
+    state.insert("one".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    let base = part.tip_key().expect("has tip").clone();
+    
+    // A branch which is abandoned, and one which is partly merged
+    let mut sums = Vec::new();
+    for name in &["old", "merged"] {
+        part.create_branch(name, &base).expect("creating branch");
+        for i in 0..2 {
+            let mut state = part.branch_tip(name).expect("branch tip").clone_child();
+            state.insert(format!("{} {}", name, i)).expect("inserting elt");
+            if *name == "old" && i == 1 {
+                // Older than its parent (e.g. due to clock skew)
+                let mut meta = state.meta().clone();
+                meta.timestamp = 1000;
+                state.set_meta(meta);
+            }
+            part.push_state_to(name, state).expect("committing to branch");
+            sums.push(part.branch_tip_key(name).expect("branch tip").clone());
+            if *name == "merged" && i == 0 {
+                assert!(part.merge_branch(name, None, &solver).expect("merging"));
+            }
+        }
+    }
+    part.write(true).expect("writing");
+    assert!(part.delete_branch("old"));
+    assert!(part.delete_branch("merged"));
+    part.write(true).expect("writing");
+    let tip = part.tip_key().expect("has tip").clone();
+    let num_states = part.stats().expect("stats").states;
+    
+    // Nothing is younger than the horizon
+    let horizon = CommitMeta::timestamp_now() + 1000;
+    assert!(part.gc_report(Some(horizon)).expect("gc report").is_empty());
+    // Old states are kept along with their ancestors
+    let horizon = CommitMeta::timestamp_now() - 1000;
+    assert_eq!(part.gc_report(Some(horizon)).expect("gc report").states, vec![sums[3].clone()]);
+    
+    let report = part.gc_report(None).expect("gc report");
+    let mut expected = vec![sums[0].clone(), sums[1].clone(), sums[3].clone()];
+    expected.sort_by_key(|sum| part.state(sum).expect("state").meta().number);
+    assert_eq!(report.states, expected);
+    assert_eq!((report.deleted_logs.len(), report.rewritten_logs.len()), (1, 1));
+    assert_eq!(part.stats().expect("stats").states, num_states);
+    
+    assert_eq!(part.gc(None).expect("gc"), report);
+    assert_eq!(part.stats().expect("stats").states, num_states - 3);
+    assert!(part.state(&sums[1]).is_none());
+    
+    let mut part = Partition::<String>::open(part.unwrap_io(), part_id);
+    part.load(true).expect("load");
+    assert_eq!(part.tip_key().expect("has tip"), &tip);
+    assert!(part.branches().is_empty());
+    assert_eq!(part.stats().expect("stats").states, num_states - 3);
+    assert!(part.state(&sums[2]).is_some());
+    assert!(part.gc_report(None).expect("gc report").is_empty());
+    
+    // An unreachable state which is also in a snapshot (here written by a
+    // peer with equal elements) is kept, since it would be loaded again
+    let mut state = part.tip().expect("has tip").clone_child();
+    state.insert("side".to_string()).expect("inserting elt");
+    let side = state.statesum().clone();
+    let elts: Vec<String> = state.map().values().map(|elt| (**elt).clone()).collect();
+    part.create_branch("side", &tip).expect("creating branch");
+    part.push_state_to("side", state).expect("committing to branch");
+    part.write(true).expect("writing");
+    assert!(part.delete_branch("side"));
+    part.write(true).expect("writing");
+    let mut peer = Partition::<String>::create_part(box PartitionStreams { ss: VecMap::new() },
+        "gc", part_id).expect("creating partition");
+    let mut state = peer.tip().expect("has tip").clone_child();
+    for elt in elts {
+        state.insert(elt).expect("inserting elt");
+    }
+    assert_eq!(state.statesum(), &side);
+    peer.push_state(state).expect("committing");
+    peer.write_snapshot().expect("writing snapshot");
+    let peer_streams = peer.unwrap_io().as_any().downcast_ref::<PartitionStreams>()
+            .expect("streams").clone();
+    let mut streams = part.unwrap_io().as_any().downcast_ref::<PartitionStreams>()
+            .expect("streams").clone();
+    let ss = streams.ss_len();
+    streams.ss.insert(ss, (peer_streams.ss[1].0.clone(), VecMap::new()));
+    let mut part = Partition::<String>::open(box streams, part_id);
+    part.load(true).expect("load");
+    assert_eq!(part.tip_key().expect("has tip"), &tip);
+    assert!(part.gc_report(None).expect("gc report").is_empty());
+}
+
+#[test]
+fn revert_reload() {
+    use pippin::State;
+    use pippin::merge::TwoWaySolveNoResult;
+    
+    let part_streams = PartitionStreams { ss: VecMap::new() };
+    let part_id = PartId::from_num(20);
+    let mut part = Partition::<String>::create_part(box part_streams,
+        "revert", part_id).expect("creating partition");
+    let solver = TwoWaySolveNoResult::new();
+    let mut state = part.tip().expect("has tip").clone_child();
+    let e1 = state.insert("one".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    let parent = part.tip_key().expect("has tip").clone();
+    let mut state = part.tip().expect("has tip").clone_child();
+    state.replace(e1, "ONE".to_string()).expect("replacing elt");
+    part.push_state(state).expect("committing");
+    let reverted = part.tip_key().expect("has tip").clone();
+    
+    // Undoing the last commit returns to its parent, also after reloading
+    assert!(part.revert(&reverted, &solver).expect("reverting"));
+    assert_eq!(part.tip_key().expect("has tip"), &parent);
+    part.write(true).expect("writing");
+    let mut part = Partition::<String>::open(part.unwrap_io(), part_id);
+    part.load(true).expect("load");
+    assert!(!part.merge_required());
+    assert_eq!(part.tip_key().expect("has tip"), &parent);
+    
+    // Redoing it also replays
+    assert!(part.cherry_pick(&reverted, &solver).expect("cherry-picking"));
+    part.write(true).expect("writing");
+    let mut part = Partition::<String>::open(part.unwrap_io(), part_id);
+    part.load(true).expect("load");
+    assert_eq!(part.tip_key().expect("has tip"), &reverted);
+    assert_eq!(part.tip().expect("has tip").get(e1), Ok(&"ONE".to_string()));
+}
+
+#[test]
+fn aliases_reload() {
+    use pippin::State;
+    
+    let part_streams = PartitionStreams { ss: VecMap::new() };
+    let part_id = PartId::from_num(21);
+    let mut part = Partition::<String>::create_part(box part_streams,
+        "aliases", part_id).expect("creating partition");
+    let mut state = part.tip().expect("has tip").clone_child();
+    let e1 = state.insert("one".to_string()).expect("inserting elt");
+    part.push_state(state).expect("committing");
+    
+    // An alias alone is committed and replayed from the log
+    let old = part_id.elt_id(1);
+    let mut state = part.tip().expect("has tip").clone_child();
+    state.add_alias(old, e1).expect("adding alias");
+    assert!(part.push_state(state).expect("committing"));
+    let tip = part.tip_key().expect("has tip").clone();
+    part.write(true).expect("writing");
+    let mut part = Partition::<String>::open(part.unwrap_io(), part_id);
+    part.load(true).expect("load");
+    assert_eq!(part.tip_key().expect("has tip"), &tip);
+    assert_eq!(part.tip().expect("has tip").resolve(old), Some(e1));
+    
+    // ... and kept in snapshots
+    part.write_snapshot().expect("writing snapshot");
+    let mut part = Partition::<String>::open(part.unwrap_io(), part_id);
+    part.load(false).expect("load");
+    assert_eq!(part.tip_key().expect("has tip"), &tip);
+    assert_eq!(part.tip().expect("has tip").aliases_of(e1), vec![old]);
+}
+
+#[test]
+fn state_at_evicted() {
+    use pippin::{State, Commit};
+    
+    let part_streams = PartitionStreams { ss: VecMap::new() };
+    let part_id = PartId::from_num(22);
+    let mut part = Partition::<String>::create_part(box part_streams,
+        "state_at", part_id).expect("creating partition");
+    let mut sums = Vec::new();
+    for timestamp in vec![1000, 2000, 3000, 4000] {
+        let tip = part.tip().expect("has tip").clone_exact();
+        let mut state = tip.clone_child();
+        state.insert(format!("at {}", timestamp)).expect("inserting elt");
+        let mut commit = Commit::from_diff(&tip, &state).expect("commit");
+        commit.meta_mut().timestamp = timestamp;
+        part.push_commit(commit).expect("committing");
+        sums.push(state.statesum().clone());
+    }
+    part.create_branch("side", &sums[0]).expect("creating branch");
+    let mut side = Vec::new();
+    for i in 0..2 {
+        let mut state = part.branch_tip("side").expect("has tip").clone_child();
+        state.insert(format!("side {}", i)).expect("inserting elt");
+        side.push(state.statesum().clone());
+        part.push_state_to("side", state).expect("committing");
+    }
+    part.write(true).expect("writing");
+    part.set_history_limit(Some(1));
+    assert!(part.state(&sums[1]).is_none());
+    
+    // Only states along the first-parent chain are reloaded
+    assert_eq!(part.state_at(2500).expect("state at 2500").statesum(), &sums[1]);
+    assert!(part.state(&side[0]).is_none());
+    assert_eq!(part.state_at(1000).expect("state at 1000").statesum(), &sums[0]);
+    assert!(part.state_at(500).is_err());
+}
@@ -0,0 +1,448 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Test Pippin operations on repositories with multiple partitions
+
+extern crate pippin;
+
+use std::io::{self, Read, Write, Cursor, ErrorKind};
+use std::any::Any;
+use std::panic;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use pippin::{PartId, Partition, PartitionIO, Repo, RepoIO, RepoT, ClassifierT, State, CommitMeta};
+use pippin::repo::RepoDivideError;
+use pippin::observe::{ChangeEvent, ChangeKind};
+use pippin::error::{make_io_err, Result};
+
+/// Files of all partitions, by (partition number, snapshot number, log
+/// number); a snapshot has no log number.
+type Files = Arc<Mutex<HashMap<(u64, usize, Option<usize>), Vec<u8>>>>;
+
+/// In-memory files of one partition. Reading the snapshot panics while
+/// `panic_on` is this partition's number.
+struct MemPartIO {
+    files: Files,
+    num: u64,
+    panic_on: Arc<AtomicUsize>,
+}
+
+/// Appends to a file in `Files`.
+struct MemWriter {
+    files: Files,
+    key: (u64, usize, Option<usize>),
+}
+
+impl Write for MemWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut files = self.files.lock().unwrap();
+        files.get_mut(&self.key).unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+impl MemPartIO {
+    fn read<'a>(&self, key: (u64, usize, Option<usize>)) -> Option<Box<Read+'a>> {
+        let files = self.files.lock().unwrap();
+        files.get(&key).map(|data| Box::new(Cursor::new(data.clone())) as Box<Read+'a>)
+    }
+    fn create<'a>(&mut self, key: (u64, usize, Option<usize>)) -> Option<Box<Write+'a>> {
+        let mut files = self.files.lock().unwrap();
+        if files.contains_key(&key) {
+            return None;
+        }
+        files.insert(key, Vec::new());
+        Some(Box::new(MemWriter { files: self.files.clone(), key: key }))
+    }
+}
+
+impl PartitionIO for MemPartIO {
+    fn as_any(&self) -> &Any { self }
+    fn ss_len(&self) -> usize {
+        let files = self.files.lock().unwrap();
+        files.keys().filter(|k| k.0 == self.num).map(|k| k.1 + 1).max().unwrap_or(0)
+    }
+    fn ss_cl_len(&self, ss_num: usize) -> usize {
+        let files = self.files.lock().unwrap();
+        files.keys().filter(|k| k.0 == self.num && k.1 == ss_num)
+            .filter_map(|k| k.2).map(|cl| cl + 1).max().unwrap_or(0)
+    }
+    fn read_ss<'a>(&'a self, ss_num: usize) -> Result<Option<Box<Read+'a>>> {
+        if self.panic_on.load(Ordering::SeqCst) as u64 == self.num {
+            panic!("reading partition {}", self.num);
+        }
+        Ok(self.read((self.num, ss_num, None)))
+    }
+    fn read_ss_cl<'a>(&'a self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Read+'a>>> {
+        Ok(self.read((self.num, ss_num, Some(cl_num))))
+    }
+    fn new_ss<'a>(&'a mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>> {
+        let key = (self.num, ss_num, None);
+        Ok(self.create(key))
+    }
+    fn append_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
+        let key = (self.num, ss_num, Some(cl_num));
+        if !self.files.lock().unwrap().contains_key(&key) {
+            return Ok(None);
+        }
+        Ok(Some(Box::new(MemWriter { files: self.files.clone(), key: key })))
+    }
+    fn new_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
+        let key = (self.num, ss_num, Some(cl_num));
+        Ok(self.create(key))
+    }
+}
+
+/// Classifies strings by initial letter: up to 'm' in partition 1, others
+/// in partition 2. Search hints are initial letters.
+#[derive(Clone)]
+struct Classifier;
+
+fn classify_letter(c: char) -> PartId {
+    PartId::from_num(if c <= 'm' { 1 } else { 2 })
+}
+
+impl ClassifierT for Classifier {
+    type Element = String;
+    fn classify(&self, elt: &String) -> Option<PartId> {
+        elt.chars().next().map(classify_letter)
+    }
+    fn may_match(&self, num: PartId, hint: &Any) -> bool {
+        match hint.downcast_ref::<char>() {
+            Some(c) => classify_letter(*c) == num,
+            None => true,
+        }
+    }
+}
+
+/// A repository of two partitions in memory.
+struct MemRepo {
+    files: Files,
+    panic_on: Arc<AtomicUsize>,
+}
+
+impl MemRepo {
+    /// Create the files of a new repository
+    fn create(name: &str) -> MemRepo {
+        let mut repo = MemRepo {
+            files: Arc::new(Mutex::new(HashMap::new())),
+            panic_on: Arc::new(AtomicUsize::new(0)),
+        };
+        Repo::create(repo.reopen(), name).unwrap();
+        let io = repo.make_partition_io(PartId::from_num(2)).unwrap();
+        Partition::<String>::create_part(io, name, PartId::from_num(2)).unwrap();
+        repo
+    }
+    /// Get another handle on the same files
+    fn reopen(&mut self) -> MemRepo {
+        MemRepo { files: self.files.clone(), panic_on: self.panic_on.clone() }
+    }
+}
+
+impl RepoIO for MemRepo {
+    fn as_any(&self) -> &Any { self }
+    fn num_partitions(&self) -> usize { self.partitions().len() }
+    fn partitions(&self) -> Vec<PartId> {
+        let files = self.files.lock().unwrap();
+        let mut nums: Vec<u64> = files.keys().map(|k| k.0).collect();
+        nums.sort();
+        nums.dedup();
+        nums.into_iter().map(PartId::from_num).collect()
+    }
+    fn add_partition(&mut self, num: PartId, _prefix: &str) -> Result<()> {
+        if self.partitions().contains(&num) {
+            return make_io_err(ErrorKind::AlreadyExists, "partition exists");
+        }
+        Ok(())
+    }
+    fn make_partition_io(&self, num: PartId) -> Result<Box<PartitionIO>> {
+        Ok(Box::new(MemPartIO {
+            files: self.files.clone(),
+            num: num.into_num(),
+            panic_on: self.panic_on.clone(),
+        }))
+    }
+}
+
+impl ClassifierT for MemRepo {
+    type Element = String;
+    fn classify(&self, elt: &String) -> Option<PartId> { Classifier.classify(elt) }
+    fn may_match(&self, num: PartId, hint: &Any) -> bool { Classifier.may_match(num, hint) }
+}
+
+impl RepoT<Classifier> for MemRepo {
+    fn repo_io<'a>(&'a mut self) -> &'a mut RepoIO { self }
+    fn clone_classifier(&self) -> Classifier { Classifier }
+    fn divide(&mut self, _class: PartId) ->
+        Result<(Vec<PartId>, Vec<PartId>), RepoDivideError>
+    {
+        Err(RepoDivideError::NotSubdivisible)
+    }
+    fn write_buf(&self, _num: PartId, _writer: &mut Write) -> Result<()> { Ok(()) }
+    fn read_buf(&mut self, _num: PartId, _buf: &[u8]) -> Result<()> { Ok(()) }
+}
+
+/// Create a repository with elements in both partitions
+fn make_repo(name: &str, elts: &[&str]) -> MemRepo {
+    let mut io = MemRepo::create(name);
+    let mut repo = Repo::open(io.reopen()).unwrap();
+    repo.load_all(false).unwrap();
+    repo.transaction(None, true, |state| {
+        for elt in elts {
+            try!(state.insert(elt.to_string()));
+        }
+        Ok(())
+    }).unwrap();
+    io
+}
+
+#[test]
+fn load_parallel_panic() {
+    let mut io = make_repo("parallel", &["apple", "banana", "orange", "pear"]);
+    
+    let mut repo = Repo::open(io.reopen()).unwrap();
+    repo.load_all_parallel(false, 2).unwrap();
+    assert_eq!(repo.clone_state().unwrap().num_avail(), 4);
+    
+    // Partitions must survive a panic while loading
+    let mut repo = Repo::open(io.reopen()).unwrap();
+    io.panic_on.store(2, Ordering::SeqCst);
+    assert!(repo.load_all_parallel(false, 2).is_err());
+    io.panic_on.store(0, Ordering::SeqCst);
+    repo.load_all(false).unwrap();
+    let state = repo.clone_state().unwrap();
+    assert_eq!(state.num_parts(), 2);
+    assert_eq!(state.num_avail(), 4);
+}
+
+/// Get the elements of `state` in order
+fn elements<S: State<String>>(state: &S) -> Vec<String> {
+    let mut elts: Vec<String> = state.iter().map(|(_, elt)| elt.clone()).collect();
+    elts.sort();
+    elts
+}
+
+#[test]
+fn search() {
+    let mut io = make_repo("search", &["apple", "banana", "orange", "pear", "melon"]);
+    let mut repo = Repo::open(io.reopen()).unwrap();
+    
+    // The hint excludes partition 2; partition 1 is unloaded again
+    let found = repo.search(|elt| elt.contains('a'), Some(&'b'), true).unwrap();
+    assert!(found[0].0 < found[1].0);
+    let mut found: Vec<&str> = found.iter().map(|&(_, ref elt)| &elt[..]).collect();
+    found.sort();
+    assert_eq!(found, vec!["apple", "banana"]);
+    assert_eq!(repo.clone_state().unwrap().num_parts(), 0);
+    
+    let found = repo.search(|elt| elt.contains('e'), Some(&'x'), false).unwrap();
+    assert_eq!(found.len(), 2);
+    assert!(found.iter().all(|&(id, _)| id.part_id() == PartId::from_num(2)));
+    let state = repo.clone_state().unwrap();
+    assert_eq!(state.num_parts(), 1);
+    assert!(state.has_part(PartId::from_num(2)));
+    
+    // Without a hint all partitions are searched; only those loaded by the
+    // search are unloaded
+    let found = repo.search(|elt| elt.contains('e'), None, true).unwrap();
+    let mut found: Vec<&str> = found.iter().map(|&(_, ref elt)| &elt[..]).collect();
+    found.sort();
+    assert_eq!(found, vec!["apple", "melon", "orange", "pear"]);
+    let state = repo.clone_state().unwrap();
+    assert_eq!(state.num_parts(), 1);
+    assert!(state.has_part(PartId::from_num(2)));
+    
+    // A hint of another type does not prune
+    let found = repo.search(|_| true, Some(&1u32), false).unwrap();
+    assert_eq!(found.len(), 5);
+    assert_eq!(repo.clone_state().unwrap().num_parts(), 2);
+}
+
+#[test]
+fn search_unloads_on_error() {
+    let mut io = make_repo("search_err", &["apple", "orange"]);
+    
+    // Two concurrent commits leave partition 1 with two tips
+    let mut repos = vec![Repo::open(io.reopen()).unwrap(), Repo::open(io.reopen()).unwrap()];
+    for (repo, elt) in repos.iter_mut().zip(&["cherry", "date"]) {
+        repo.load_all(false).unwrap();
+        repo.transaction(None, false, |state| {
+            try!(state.insert(elt.to_string()));
+            Ok(())
+        }).unwrap();
+    }
+    for repo in &mut repos {
+        repo.write_all(false).unwrap();
+    }
+    
+    // Searching fails since a merge is required, but the partition loaded by
+    // the search is unloaded again
+    let mut repo = Repo::open(io.reopen()).unwrap();
+    assert!(repo.search(|_| true, Some(&'a'), true).is_err());
+    assert!(!repo.merge_required());
+    assert_eq!(repo.clone_state().unwrap().num_parts(), 0);
+    
+    assert!(repo.search(|_| true, Some(&'a'), false).is_err());
+    assert!(repo.merge_required());
+}
+
+#[test]
+fn transaction() {
+    let mut io = make_repo("transaction", &["apple", "pear"]);
+    let mut repo = Repo::open(io.reopen()).unwrap();
+    repo.load_all(false).unwrap();
+    
+    let ids = repo.transaction(None, true, |state| {
+        let id1 = try!(state.insert("kiwi".to_string()));
+        let id2 = try!(state.insert("zebra".to_string()));
+        Ok((id1, id2))
+    }).unwrap();
+    assert_eq!(ids.0.part_id(), PartId::from_num(1));
+    assert_eq!(ids.1.part_id(), PartId::from_num(2));
+    
+    // A failing transaction commits nothing, in any partition
+    let result: Result<()> = repo.transaction(None, true, |state| {
+        try!(state.insert("lemon".to_string()));
+        try!(state.insert("quince".to_string()));
+        try!(state.remove(ids.0));
+        try!(state.remove(ids.0));
+        Ok(())
+    });
+    assert!(result.is_err());
+    
+    let mut repo = Repo::open(io.reopen()).unwrap();
+    repo.load_all(false).unwrap();
+    let state = repo.clone_state().unwrap();
+    assert_eq!(elements(&state), vec!["apple", "kiwi", "pear", "zebra"]);
+    assert_eq!(state.get(ids.1).unwrap(), "zebra");
+}
+
+#[test]
+fn stats_and_state_at() {
+    let mut io = make_repo("stats", &["apple", "banana", "orange"]);
+    let mut repo = Repo::open(io.reopen()).unwrap();
+    
+    // Statistics on files are available without loading
+    let stats = repo.stats().unwrap();
+    let nums: Vec<u64> = stats.partitions.iter().map(|&(num, _)| num.into_num()).collect();
+    assert_eq!(nums, vec![1, 2]);
+    assert_eq!(stats.total.snapshots, 2);
+    assert!(stats.total.logs >= 2);
+    assert_eq!(stats.total.elements, None);
+    
+    repo.load_all(false).unwrap();
+    let stats = repo.stats().unwrap();
+    assert_eq!(stats.partitions[0].1.elements, Some(2));
+    assert_eq!(stats.partitions[1].1.elements, Some(1));
+    assert_eq!(stats.total.elements, Some(3));
+    assert_eq!(stats.total.element_bytes, Some(17));
+    
+    let state = repo.state_at(CommitMeta::timestamp_now()).unwrap();
+    assert_eq!(state.num_parts(), 2);
+    assert_eq!(elements(&state), vec!["apple", "banana", "orange"]);
+    assert!(repo.state_at(0).is_err());
+}
+
+#[test]
+fn index_query() {
+    let mut io = make_repo("index", &["apple", "banana", "orange", "pear"]);
+    let mut repo = Repo::open(io.reopen()).unwrap();
+    repo.load_all(false).unwrap();
+    repo.add_index("len", |elt: &String| Some(elt.len())).unwrap();
+    assert!(repo.add_index("len", |elt: &String| Some(elt.len())).is_err());
+    
+    let state = repo.clone_state().unwrap();
+    let ids = state.query("len", &6usize).unwrap();
+    let elts: Vec<&str> = ids.iter().map(|id| &state.get(*id).unwrap()[..]).collect();
+    assert_eq!(elts, vec!["banana", "orange"]);
+    assert!(state.query("len", &6u32).is_err());
+    assert!(state.query("initial", &'a').is_err());
+}
+
+#[test]
+fn bulk_load() {
+    let mut io = make_repo("bulk", &["apple"]);
+    let mut repo = Repo::open(io.reopen()).unwrap();
+    let ids = repo.bulk_load(vec!["plum".to_string(), "cherry".to_string(),
+            "lime".to_string()]).unwrap();
+    assert_eq!(ids.len(), 3);
+    assert_eq!(ids[0].part_id(), PartId::from_num(2));
+    assert_eq!(ids[1].part_id(), PartId::from_num(1));
+    assert_eq!(ids[2].part_id(), PartId::from_num(1));
+    assert_eq!(repo.ids_low(), vec![]);
+    
+    let mut repo = Repo::open(io.reopen()).unwrap();
+    repo.load_all(false).unwrap();
+    let state = repo.clone_state().unwrap();
+    assert_eq!(elements(&state), vec!["apple", "cherry", "lime", "plum"]);
+    assert_eq!(state.get(ids[0]).unwrap(), "plum");
+}
+
+#[test]
+fn subscribe() {
+    let mut io = make_repo("subscribe", &["apple"]);
+    let mut repo = Repo::open(io.reopen()).unwrap();
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let id = {
+        let events = events.clone();
+        repo.subscribe(move |event: &ChangeEvent| events.lock().unwrap().push(event.clone()))
+    };
+    repo.load_all(false).unwrap();
+    let loaded = events.lock().unwrap().len();
+    assert!(loaded >= 2);
+    
+    let ids = repo.transaction(None, false, |state| {
+        let id1 = try!(state.insert("kiwi".to_string()));
+        let id2 = try!(state.insert("zebra".to_string()));
+        Ok(vec![id1, id2])
+    }).unwrap();
+    {
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), loaded + 2);
+        // One event per partition, in any order
+        for id in ids {
+            let event = events[loaded..].iter()
+                .find(|event| event.part_id == id.part_id()).unwrap();
+            assert_eq!(event.changes, Some(vec![(id, ChangeKind::Inserted)]));
+        }
+    }
+    
+    assert!(repo.unsubscribe(id));
+    assert!(!repo.unsubscribe(id));
+    repo.transaction(None, false, |state| Ok(try!(state.insert("lime".to_string())))).unwrap();
+    assert_eq!(events.lock().unwrap().len(), loaded + 2);
+}
+
+#[test]
+fn subscribe_after_panic() {
+    let mut io = make_repo("subscribe_panic", &["apple"]);
+    let mut repo = Repo::open(io.reopen()).unwrap();
+    repo.load_all(false).unwrap();
+    let events = Arc::new(AtomicUsize::new(0));
+    let panic = Arc::new(AtomicUsize::new(1));
+    {
+        let (events, panic) = (events.clone(), panic.clone());
+        repo.subscribe(move |_: &ChangeEvent| {
+            events.fetch_add(1, Ordering::SeqCst);
+            if panic.load(Ordering::SeqCst) != 0 {
+                panic!("bad observer");
+            }
+        });
+    }
+    
+    // The observer panics while holding its lock, poisoning it
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        repo.transaction(None, false, |state| Ok(try!(state.insert("kiwi".to_string())))).unwrap();
+    }));
+    assert!(result.is_err());
+    assert_eq!(events.load(Ordering::SeqCst), 1);
+    
+    // Later commits (in any partition) still notify it
+    panic.store(0, Ordering::SeqCst);
+    repo.transaction(None, false, |state| Ok(try!(state.insert("zebra".to_string())))).unwrap();
+    assert_eq!(events.load(Ordering::SeqCst), 2);
+}